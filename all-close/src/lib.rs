@@ -17,7 +17,7 @@ impl TensorAllClose for Tensor {
                 .to_device(self.device())?
                 .broadcast_as(self.shape())?,
         };
-        let shape = same_shape_binary_op(&self, &rhs, "all_close")?;
+        let shape = same_shape_binary_op(self, &rhs, "all_close")?;
         let all = self
             .sub(&rhs)?
             .abs()?
@@ -50,15 +50,15 @@ pub(crate) fn same_shape_binary_op<'a>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use candle_core::{Device, test_device};
+    use candle_core::Device;
 
     fn all_close(device: &Device) -> Result<()> {
         let t1 = Tensor::new(&[1.0004_f32, 1.0005], device)?;
         let t2 = Tensor::new(&[1.0005_f32, 1.0004], device)?;
         let x = t1.all_close(&t2, 0.001)?;
         let y = t1.all_close(&t2, 0.00001)?;
-        assert_eq!(x, true);
-        assert_eq!(y, false);
+        assert!(x);
+        assert!(!y);
         Ok(())
     }
 
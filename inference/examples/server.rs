@@ -1 +1,23 @@
-fn main() {}
+//! cargo run --example server -- model.safetensors [tokenizer.json]
+
+use llama_rust::{
+    inference::InferenceEngine,
+    server::{self, ServerState},
+    tokenizer::Tokenizer,
+};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let model = args
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("usage: server <model.safetensors> [tokenizer.json]"))?;
+    let tokenizer = match args.next() {
+        Some(path) => Tokenizer::from_file(&path)?,
+        None => Tokenizer::new("bert-base-cased")?,
+    };
+
+    let engine = InferenceEngine::load(&model, true)?;
+    let state = ServerState::new(engine, tokenizer, "llama2-c");
+    server::serve("127.0.0.1:8080", state).await
+}
@@ -0,0 +1,169 @@
+//! 请求准入
+//!
+//! Requests wait in an [`AdmissionQueue`] before they enter the batch. The queue is bounded, a
+//! request that does not fit is rejected at once instead of waiting behind an unbounded backlog.
+//! Waiting requests are served by priority then by arrival, leave the queue when their deadline
+//! passes and can be cancelled by their request id.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// 准入错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmissionError {
+    /// The queue has no room for the request.
+    QueueFull { capacity: usize },
+    /// The deadline of the request passed before it was done.
+    TimedOut,
+    /// The request was cancelled by its id.
+    Cancelled,
+}
+
+impl fmt::Display for AdmissionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QueueFull { capacity } => {
+                write!(f, "the request queue is full ({capacity} requests waiting)")
+            }
+            Self::TimedOut => write!(f, "the request timed out"),
+            Self::Cancelled => write!(f, "the request was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for AdmissionError {}
+
+/// 准入参数
+#[derive(Debug, Clone)]
+pub struct AdmissionConfig {
+    /// Number of generations waiting for room in the batch, the choices of a request count
+    /// separately.
+    pub max_queue: usize,
+    /// Time a request may take, waiting included, when it does not set its own deadline.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_queue: 64,
+            timeout: None,
+        }
+    }
+}
+
+/// 单个请求的准入信息
+#[derive(Debug, Clone, Default)]
+pub struct Admission {
+    /// Id used to cancel the request, several requests may share one.
+    pub request_id: String,
+    /// Requests with a lower priority value are served first.
+    pub priority: i32,
+    pub deadline: Option<Instant>,
+    /// Name of the API key that made the request, its tokens are accounted to it.
+    pub owner: Option<String>,
+}
+
+impl Admission {
+    pub fn new(request_id: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+
+    /// Whether the API key named `owner`, `None` without authentication, may cancel the request.
+    /// A request without an owner may be cancelled by anyone.
+    pub fn cancellable_by(&self, owner: Option<&str>) -> bool {
+        self.owner.is_none() || self.owner.as_deref() == owner
+    }
+}
+
+/// 有界优先队列
+pub struct AdmissionQueue<T> {
+    capacity: usize,
+    // Ordered by priority, then by arrival.
+    entries: BTreeMap<(i32, u64), (Admission, T)>,
+    next_seq: u64,
+}
+
+impl<T> AdmissionQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether items of the request that `owner` may cancel are waiting.
+    pub fn contains(&self, request_id: &str, owner: Option<&str>) -> bool {
+        self.entries.values().any(|(admission, _)| {
+            admission.request_id == request_id && admission.cancellable_by(owner)
+        })
+    }
+
+    /// Queue all the items of a request or none of them.
+    pub fn push(&mut self, admission: &Admission, items: Vec<T>) -> Result<(), AdmissionError> {
+        if self.entries.len() + items.len() > self.capacity {
+            return Err(AdmissionError::QueueFull {
+                capacity: self.capacity,
+            });
+        }
+        for item in items {
+            let key = (admission.priority, self.next_seq);
+            self.next_seq += 1;
+            self.entries.insert(key, (admission.clone(), item));
+        }
+        Ok(())
+    }
+
+    /// The next item to serve.
+    pub fn pop(&mut self) -> Option<(Admission, T)> {
+        self.entries.pop_first().map(|(_, entry)| entry)
+    }
+
+    /// Take the items matching `f` out of the queue, in the order they would have been served.
+    pub fn remove(&mut self, mut f: impl FnMut(&Admission, &T) -> bool) -> Vec<(Admission, T)> {
+        let keys: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, (admission, item))| f(admission, item))
+            .map(|(&key, _)| key)
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| self.entries.remove(&key))
+            .collect()
+    }
+
+    /// Take the items whose deadline passed out of the queue.
+    pub fn expire(&mut self, now: Instant) -> Vec<(Admission, T)> {
+        self.remove(|admission, _| admission.expired(now))
+    }
+
+    /// Take the items of a request that `owner` may cancel out of the queue.
+    pub fn cancel(&mut self, request_id: &str, owner: Option<&str>) -> Vec<(Admission, T)> {
+        self.remove(|admission, _| {
+            admission.request_id == request_id && admission.cancellable_by(owner)
+        })
+    }
+}
@@ -1,18 +1,79 @@
-use clap::Parser;
+use {
+    crate::model::Config as ModelConfig,
+    clap::{Parser, Subcommand, ValueEnum},
+    kv_cache::KvQuantization,
+    serde::{Deserialize, Serialize},
+};
 
-/// LLaMA 推理引擎配置参数
+/// llama-serve 命令行入口
 #[derive(Parser, Debug)]
 #[command(name = "llama-serve")]
 #[command(
     about = "A simple LLaMA inference engine using Candle",
     version,
-    author
+    author,
+    args_conflicts_with_subcommands = true
 )]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub args: Option<Args>,
+}
+
+/// 子命令
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Evaluate the perplexity of a model over a text file.
+    Perplexity(PerplexityArgs),
+    /// Serve the model over an OpenAI compatible HTTP API.
+    Serve(ServeArgs),
+    /// Check a Modelfile and print it in its canonical form.
+    Modelfile(ModelfileArgs),
+    /// Replay a JSONL file of requests against the model or a server and report the latencies.
+    BenchServe(BenchServeArgs),
+}
+
+/// llama2.c 模型规格
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModelSize {
+    Tiny260k,
+    Tiny15m,
+    Tiny42m,
+    Tiny110m,
+}
+
+impl ModelSize {
+    pub fn config(&self) -> ModelConfig {
+        match self {
+            Self::Tiny260k => ModelConfig::tiny_260k(),
+            Self::Tiny15m => ModelConfig::tiny_15m(),
+            Self::Tiny42m => ModelConfig::tiny_42m(),
+            Self::Tiny110m => ModelConfig::tiny_110m(),
+        }
+    }
+}
+
+/// LLaMA 推理引擎配置参数
+#[derive(clap::Args, Debug)]
 pub struct Args {
     /// 模型名称或本地检查点路径（Hugging Face格式，如 `meta-llama/Llama-3-70B`）
+    ///
+    /// GGUF files are detected from their content, `ollama:<name>` loads a model pulled by
+    /// Ollama, e.g. `ollama:llama3:8b`.
     #[arg(short, long)]
     pub model: String,
 
+    /// llama2.c 模型规格
+    #[arg(long, value_enum, default_value_t = ModelSize::Tiny15m)]
+    pub model_size: ModelSize,
+
+    /// `tokenizer.json` file or Hugging Face model id, `bert-base-cased` by default.
+    #[arg(long)]
+    pub tokenizer: Option<String>,
+
     /// 输入提示文本（需用引号包裹）
     #[arg(short, long)]
     pub prompt: String,
@@ -41,7 +102,212 @@ pub struct Args {
     #[arg(short = 'n', long, default_value_t = 100)]
     pub max_tokens: usize,
 
+    /// Decode with beam search of the given width instead of sampling.
+    #[arg(long)]
+    pub beam_width: Option<usize>,
+
+    /// Exponent of the length normalization used to rank beam search hypotheses.
+    #[arg(long, default_value_t = 1.0)]
+    pub length_penalty: f64,
+
+    /// Stop beam search as soon as `beam_width` hypotheses are finished.
+    #[arg(long)]
+    pub early_stopping: bool,
+
+    /// Number of beam search hypotheses to return.
+    #[arg(long, default_value_t = 1)]
+    pub num_return: usize,
+
+    /// Draft model checkpoint, enables speculative decoding.
+    #[arg(long)]
+    pub draft_model: Option<String>,
+
+    /// llama2.c 草稿模型规格, it must be smaller than the target model.
+    #[arg(long, value_enum, default_value_t = ModelSize::Tiny260k)]
+    pub draft_model_size: ModelSize,
+
+    /// Speculate by looking up n-grams of up to this size in the prompt and generated text.
+    #[arg(long, conflicts_with = "draft_model")]
+    pub prompt_lookup_ngram: Option<usize>,
+
+    /// Maximum number of draft tokens proposed at each speculative step.
+    #[arg(long, default_value_t = 4)]
+    pub draft_tokens: usize,
+
+    /// Store the KV cache quantized, `int8` or `int4`.
+    #[arg(long)]
+    pub kv_cache_quantization: Option<KvQuantization>,
+
     /// 是否启用调试模式（打印详细日志）
     #[arg(short, long, default_value_t = false)]
     pub debug: bool,
 }
+
+/// 困惑度评估参数
+#[derive(clap::Args, Debug)]
+pub struct PerplexityArgs {
+    /// 模型检查点路径, a GGUF file or `ollama:<name>` for a model pulled by Ollama
+    #[arg(short, long)]
+    pub model: String,
+
+    /// llama2.c 模型规格
+    #[arg(long, value_enum, default_value_t = ModelSize::Tiny15m)]
+    pub model_size: ModelSize,
+
+    /// `tokenizer.json` file or Hugging Face model id, `bert-base-cased` by default. It must be
+    /// the tokenizer the model was trained with for the perplexity to mean anything.
+    #[arg(long)]
+    pub tokenizer: Option<String>,
+
+    /// 评估语料（纯文本文件）
+    #[arg(short, long)]
+    pub file: String,
+
+    /// Device: CPU or CUDA
+    #[arg(long)]
+    pub cpu: bool,
+
+    /// Size of the evaluation window in tokens.
+    #[arg(long, default_value_t = 512)]
+    pub ctx: usize,
+
+    /// Number of tokens the window advances by (< ctx), the overlap is only used as context.
+    #[arg(long, default_value_t = 256)]
+    pub stride: usize,
+}
+
+/// HTTP 服务参数
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// 模型检查点路径, a GGUF file or `ollama:<name>` for a model pulled by Ollama. Overrides the
+    /// `FROM` of the Modelfile.
+    #[arg(short, long, required_unless_present_any = ["modelfile", "models"])]
+    pub model: Option<String>,
+
+    /// JSON file registering the models to serve, loaded on their first request, and the memory
+    /// budget beyond which the least recently used are unloaded. `--model` is served as well.
+    #[arg(long)]
+    pub models: Option<String>,
+
+    /// Ollama Modelfile giving the sampling defaults, stop strings, system prompt and chat
+    /// template of the model. `FROM` is a path relative to the Modelfile or an Ollama model name.
+    #[arg(long)]
+    pub modelfile: Option<String>,
+
+    /// llama2.c 模型规格
+    #[arg(long, value_enum, default_value_t = ModelSize::Tiny15m)]
+    pub model_size: ModelSize,
+
+    /// `tokenizer.json` file or Hugging Face model id, `bert-base-cased` by default.
+    #[arg(long)]
+    pub tokenizer: Option<String>,
+
+    /// Name of the model in the API, the checkpoint file stem or the Ollama model name by
+    /// default.
+    #[arg(long)]
+    pub model_id: Option<String>,
+
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub addr: String,
+
+    /// Number of requests generating at once.
+    #[arg(long, default_value_t = 4)]
+    pub max_batch_size: usize,
+
+    /// Number of tokens a batch step may run, prompts longer than this are read in chunks.
+    #[arg(long, default_value_t = 512)]
+    pub max_batch_tokens: usize,
+
+    /// Number of generations waiting for the batch, requests beyond it get a 429.
+    #[arg(long, default_value_t = 64)]
+    pub max_queue: usize,
+
+    /// Store the paged KV cache of the batch quantized, `int8` or `int4`.
+    #[arg(long)]
+    pub kv_cache_quantization: Option<KvQuantization>,
+
+    /// Seconds a request may take, waiting included, unless it sets its own `timeout`.
+    #[arg(long)]
+    pub request_timeout: Option<f64>,
+
+    /// JSON file of the API keys the clients must send as bearer tokens, with their names,
+    /// limits and admin rights. No authentication without it.
+    #[arg(long)]
+    pub api_keys: Option<String>,
+
+    /// Device: CPU or CUDA
+    #[arg(long)]
+    pub cpu: bool,
+}
+
+/// Modelfile 检查参数
+#[derive(clap::Args, Debug)]
+pub struct ModelfileArgs {
+    /// Modelfile 路径
+    pub file: String,
+}
+
+/// 服务压测参数
+#[derive(clap::Args, Debug)]
+pub struct BenchServeArgs {
+    /// JSONL file of requests, one `{"prompt", "params", "offset"}` object per line.
+    pub file: String,
+
+    /// Base URL of an OpenAI compatible server, e.g. `http://127.0.0.1:8080`. The model is run
+    /// in process without it.
+    #[arg(long)]
+    pub url: Option<String>,
+
+    /// 模型检查点路径, a GGUF file or `ollama:<name>`, for the in-process engine.
+    #[arg(short, long, required_unless_present = "url")]
+    pub model: Option<String>,
+
+    /// llama2.c 模型规格
+    #[arg(long, value_enum, default_value_t = ModelSize::Tiny15m)]
+    pub model_size: ModelSize,
+
+    /// `tokenizer.json` file or Hugging Face model id, `bert-base-cased` by default.
+    #[arg(long)]
+    pub tokenizer: Option<String>,
+
+    /// Name of the model on the server, the first model it lists by default.
+    #[arg(long)]
+    pub model_id: Option<String>,
+
+    /// API key sent to the server as a bearer token.
+    #[arg(long)]
+    pub api_key: Option<String>,
+
+    /// Requests sent per second, replacing the offsets of the file.
+    #[arg(long)]
+    pub rate: Option<f64>,
+
+    /// Replay only the first requests of the file.
+    #[arg(long)]
+    pub limit: Option<usize>,
+
+    /// Tokens generated by the requests that do not set `max_tokens`.
+    #[arg(long, default_value_t = 64)]
+    pub max_tokens: usize,
+
+    /// Number of requests generating at once in process.
+    #[arg(long, default_value_t = 4)]
+    pub max_batch_size: usize,
+
+    /// Number of tokens a batch step may run in process.
+    #[arg(long, default_value_t = 512)]
+    pub max_batch_tokens: usize,
+
+    /// Number of generations waiting for the batch in process.
+    #[arg(long, default_value_t = 64)]
+    pub max_queue: usize,
+
+    /// Store the paged KV cache of the batch quantized in process, `int8` or `int4`.
+    #[arg(long)]
+    pub kv_cache_quantization: Option<KvQuantization>,
+
+    /// Device: CPU or CUDA
+    #[arg(long)]
+    pub cpu: bool,
+}
@@ -0,0 +1,318 @@
+//! 接口鉴权与限流
+//!
+//! API keys are read from a JSON file listing each key with the name its usage is accounted to,
+//! its limits and whether it may read the usage of all keys:
+//!
+//! ```json
+//! [
+//!     {"key": "sk-team-a", "name": "team-a", "requests_per_minute": 60, "tokens_per_minute": 20000},
+//!     {"key": "sk-ops", "name": "ops", "admin": true}
+//! ]
+//! ```
+//!
+//! Each limit is a bucket refilled continuously over a minute. A request takes one request from
+//! its bucket and is only admitted while the token bucket holds a token. The prompt and generated
+//! tokens of a request are taken from the token bucket when it is done, which may leave it in
+//! debt until it refills. Requests whose tokens are known upfront, embeddings, are only run once
+//! the bucket holds them.
+
+use {
+    anyhow::{Context, Result, bail},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{HashMap, HashSet},
+        fmt,
+        path::Path,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+};
+
+/// 鉴权错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// No `Authorization: Bearer` header.
+    MissingKey,
+    /// The key is not in the key file.
+    InvalidKey,
+    /// The key may not use the endpoint.
+    Forbidden,
+    /// A limit of the key is reached, `retry_after` is the time until it refills enough.
+    RateLimited { limit: Limit, retry_after: Duration },
+}
+
+/// 限额
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    RequestsPerMinute,
+    TokensPerMinute,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingKey => write!(
+                f,
+                "no API key was provided, send it in an `Authorization: Bearer` header"
+            ),
+            Self::InvalidKey => write!(f, "the API key is not valid"),
+            Self::Forbidden => write!(f, "the API key may not use this endpoint"),
+            Self::RateLimited { limit, retry_after } => {
+                let limit = match limit {
+                    Limit::RequestsPerMinute => "requests per minute",
+                    Limit::TokensPerMinute => "tokens per minute",
+                };
+                write!(
+                    f,
+                    "rate limit of {limit} reached, retry in {:.1} s",
+                    retry_after.as_secs_f64()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// API 密钥
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Bearer token of the clients.
+    pub key: String,
+    /// Name the usage of the key is accounted to.
+    pub name: String,
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+    /// Prompt and generated tokens per minute.
+    #[serde(default)]
+    pub tokens_per_minute: Option<u64>,
+    /// The key may read the usage of every key.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// 密钥用量
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyUsage {
+    pub name: String,
+    /// Requests admitted.
+    pub requests: u64,
+    /// Requests rejected by a limit.
+    pub rate_limited: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u64>,
+}
+
+/// Refilled continuously at `per_minute` a minute, up to `per_minute`.
+#[derive(Debug)]
+struct Bucket {
+    per_minute: f64,
+    level: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: f64, now: Instant) -> Self {
+        Self {
+            per_minute,
+            level: per_minute,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.level = (self.level + elapsed * self.per_minute / 60.0).min(self.per_minute);
+        self.updated = now;
+    }
+
+    /// Time until the level reaches `amount`.
+    fn wait(&self, amount: f64) -> Duration {
+        let missing = (amount - self.level).max(0.0);
+        Duration::from_secs_f64(missing * 60.0 / self.per_minute)
+    }
+}
+
+#[derive(Debug)]
+struct AccountState {
+    usage: KeyUsage,
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+/// 密钥账户, the limits and usage of a key
+#[derive(Debug)]
+pub struct Account {
+    pub key: ApiKey,
+    state: Mutex<AccountState>,
+}
+
+impl Account {
+    fn new(key: ApiKey, now: Instant) -> Self {
+        let state = AccountState {
+            usage: KeyUsage {
+                name: key.name.clone(),
+                requests_per_minute: key.requests_per_minute,
+                tokens_per_minute: key.tokens_per_minute,
+                ..Default::default()
+            },
+            requests: key
+                .requests_per_minute
+                .map(|limit| Bucket::new(limit as f64, now)),
+            tokens: key
+                .tokens_per_minute
+                .map(|limit| Bucket::new(limit as f64, now)),
+        };
+        Self {
+            key,
+            state: Mutex::new(state),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, AccountState> {
+        self.state.lock().expect("account lock poisoned")
+    }
+
+    /// Admit a request made at `now`, taking it from the request bucket.
+    pub fn admit(&self, now: Instant) -> Result<(), AuthError> {
+        let mut state = self.state();
+        let state = &mut *state;
+        let mut limited = None;
+        if let Some(tokens) = state.tokens.as_mut() {
+            tokens.refill(now);
+            if tokens.level < 1.0 {
+                limited = Some((Limit::TokensPerMinute, tokens.wait(1.0)));
+            }
+        }
+        if let Some(requests) = state.requests.as_mut() {
+            requests.refill(now);
+            if limited.is_none() && requests.level < 1.0 {
+                limited = Some((Limit::RequestsPerMinute, requests.wait(1.0)));
+            }
+        }
+        if let Some((limit, retry_after)) = limited {
+            state.usage.rate_limited += 1;
+            return Err(AuthError::RateLimited { limit, retry_after });
+        }
+        if let Some(requests) = state.requests.as_mut() {
+            requests.level -= 1.0;
+        }
+        state.usage.requests += 1;
+        Ok(())
+    }
+
+    /// Account the tokens of a generation done at `now`.
+    pub fn record(&self, prompt_tokens: usize, completion_tokens: usize, now: Instant) {
+        let mut state = self.state();
+        state.usage.prompt_tokens += prompt_tokens as u64;
+        state.usage.completion_tokens += completion_tokens as u64;
+        if let Some(tokens) = state.tokens.as_mut() {
+            tokens.refill(now);
+            tokens.level -= (prompt_tokens + completion_tokens) as f64;
+        }
+    }
+
+    /// Account the `prompt_tokens` of a request whose cost is known before it runs, such as an
+    /// embedding, at `now`. The request is refused while the token bucket does not hold them, or
+    /// is not full for a request beyond the limit.
+    pub fn charge(&self, prompt_tokens: usize, now: Instant) -> Result<(), AuthError> {
+        let mut state = self.state();
+        if let Some(tokens) = state.tokens.as_mut() {
+            tokens.refill(now);
+            let needed = (prompt_tokens as f64).min(tokens.per_minute);
+            if tokens.level < needed {
+                let retry_after = tokens.wait(needed);
+                state.usage.rate_limited += 1;
+                return Err(AuthError::RateLimited {
+                    limit: Limit::TokensPerMinute,
+                    retry_after,
+                });
+            }
+            tokens.level -= prompt_tokens as f64;
+        }
+        state.usage.prompt_tokens += prompt_tokens as u64;
+        Ok(())
+    }
+
+    pub fn usage(&self) -> KeyUsage {
+        self.state().usage.clone()
+    }
+}
+
+/// 密钥表
+#[derive(Debug)]
+pub struct ApiKeys {
+    // Sorted by name.
+    accounts: Vec<Arc<Account>>,
+    by_key: HashMap<String, usize>,
+}
+
+impl ApiKeys {
+    /// Keys and names must be unique and not empty, limits positive.
+    pub fn new(mut keys: Vec<ApiKey>) -> Result<Self> {
+        let now = Instant::now();
+        let mut names = HashSet::new();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut by_key = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            if key.key.is_empty() || key.name.is_empty() {
+                bail!("API keys and their names must not be empty");
+            }
+            if key.requests_per_minute == Some(0) || key.tokens_per_minute == Some(0) {
+                bail!("the limits of `{}` must be positive", key.name);
+            }
+            if !names.insert(key.name.as_str()) {
+                bail!("the API key name `{}` is used twice", key.name);
+            }
+            if by_key.insert(key.key.clone(), i).is_some() {
+                bail!("the API key of `{}` is used twice", key.name);
+            }
+        }
+        let accounts = keys
+            .into_iter()
+            .map(|key| Arc::new(Account::new(key, now)))
+            .collect();
+        Ok(Self { accounts, by_key })
+    }
+
+    /// Read the JSON list of keys of `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let keys = serde_json::from_str(&text)
+            .with_context(|| format!("{}: invalid API keys", path.display()))?;
+        Self::new(keys)
+    }
+
+    /// Account of the `Authorization` header of a request.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<Arc<Account>, AuthError> {
+        let authorization = authorization.ok_or(AuthError::MissingKey)?;
+        let key = match authorization.split_once(' ') {
+            Some((scheme, key)) if scheme.eq_ignore_ascii_case("bearer") => key.trim(),
+            _ => return Err(AuthError::MissingKey),
+        };
+        self.by_key
+            .get(key)
+            .map(|&i| self.accounts[i].clone())
+            .ok_or(AuthError::InvalidKey)
+    }
+
+    /// Account of the key named `name`.
+    pub fn account(&self, name: &str) -> Option<Arc<Account>> {
+        self.accounts
+            .iter()
+            .find(|account| account.key.name == name)
+            .cloned()
+    }
+
+    /// Usage of every key, by name.
+    pub fn usage(&self) -> Vec<KeyUsage> {
+        self.accounts
+            .iter()
+            .map(|account| account.usage())
+            .collect()
+    }
+}
@@ -0,0 +1,529 @@
+//! 批处理推理线程
+//!
+//! [`BatchEngine`] owns a [`Scheduler`] on a dedicated thread and generates the completions
+//! submitted from any thread together, turning the sampled tokens into text and applying the stop
+//! strings of each of them.
+//!
+//! Submitted completions wait in an [`AdmissionQueue`] until the batch has room for them, the
+//! thread aborts the ones that are cancelled or pass their deadline, waiting or running. The
+//! choices of a request for the same prompt enter the scheduler together, so that they read the
+//! prompt once and share its KV blocks.
+//!
+//! The thread records the token counts, the latencies, the state of the batch and the lookups of
+//! the prefix cache in [`Metrics`].
+
+use {
+    crate::{
+        admission::{Admission, AdmissionConfig, AdmissionError, AdmissionQueue},
+        completion::{Completion, CompletionParams, CompletionText, FinishReason},
+        inference::{GenerationStats, InferenceEngine},
+        metrics::{GaugeShare, Metrics},
+        scheduler::{Scheduler, SchedulerConfig, SequenceEvent, SequenceRequest},
+        tokenizer::Tokenizer,
+    },
+    anyhow::Result,
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex, MutexGuard, mpsc},
+        time::{Duration, Instant},
+    },
+    tokio::sync::mpsc::UnboundedSender,
+};
+
+/// 补全事件, tagged with the index of the choice given to [`BatchEngine::submit`]
+#[derive(Debug)]
+pub enum CompletionEvent {
+    /// Text that became final.
+    Text {
+        index: usize,
+        text: String,
+    },
+    /// Tokens run for a choice, sent as it samples them so that they are accounted even when
+    /// the choice does not finish. The prompt comes with the first token.
+    Tokens {
+        index: usize,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+    },
+    Finished {
+        index: usize,
+        completion: Completion,
+    },
+    Failed(String),
+    /// The request was cancelled or ran out of time, sent once for all its choices.
+    Aborted(AdmissionError),
+}
+
+struct Job {
+    index: usize,
+    prompt: Vec<u32>,
+    params: CompletionParams,
+    events: UnboundedSender<CompletionEvent>,
+    submitted: Instant,
+}
+
+/// State shared by the submitting threads and the batch thread.
+struct Shared {
+    queue: AdmissionQueue<Job>,
+    // Admissions of the jobs in the scheduler.
+    running: Vec<Admission>,
+}
+
+enum Message {
+    Queued,
+    /// Cancel the jobs of a request that the API key `owner` may cancel.
+    Cancel {
+        request_id: String,
+        owner: Option<String>,
+    },
+}
+
+/// A job in the scheduler.
+struct Running {
+    index: usize,
+    admission: Admission,
+    events: UnboundedSender<CompletionEvent>,
+    text: CompletionText,
+    prompt_tokens: usize,
+    generated_tokens: usize,
+    logprob: f64,
+    submitted: Instant,
+    time_to_first_token: Option<f64>,
+    last_token: Option<Instant>,
+}
+
+impl Running {
+    /// Record the latencies of a sampled token and send its count, false when the receiver is
+    /// gone.
+    fn token(&mut self, logprob: f32, metrics: &Metrics) -> bool {
+        let now = Instant::now();
+        self.generated_tokens += 1;
+        self.logprob += f64::from(logprob);
+        metrics.generation_tokens.add(1);
+        match self.last_token {
+            Some(last) => metrics
+                .inter_token_latency
+                .observe((now - last).as_secs_f64()),
+            None => {
+                let ttft = (now - self.submitted).as_secs_f64();
+                self.time_to_first_token = Some(ttft);
+                metrics.time_to_first_token.observe(ttft);
+            }
+        }
+        self.last_token = Some(now);
+        let prompt_tokens = if self.generated_tokens == 1 {
+            self.prompt_tokens
+        } else {
+            0
+        };
+        self.events
+            .send(CompletionEvent::Tokens {
+                index: self.index,
+                prompt_tokens,
+                completion_tokens: 1,
+            })
+            .is_ok()
+    }
+
+    /// Send the text held back and the completion.
+    fn finish(mut self, reason: FinishReason, metrics: &Metrics) {
+        let rest = match self.text.finish() {
+            Ok(rest) => rest.to_string(),
+            Err(err) => {
+                let _ = self.events.send(CompletionEvent::Failed(err.to_string()));
+                return;
+            }
+        };
+        if !rest.is_empty() {
+            let index = self.index;
+            let _ = self
+                .events
+                .send(CompletionEvent::Text { index, text: rest });
+        }
+        let finish_reason = if self.text.stopped() {
+            FinishReason::Stop
+        } else {
+            reason
+        };
+        let elapsed = self.submitted.elapsed().as_secs_f64();
+        metrics.e2e_request_latency.observe(elapsed);
+        let completion = Completion {
+            finish_reason,
+            stats: GenerationStats {
+                prompt_tokens: self.prompt_tokens,
+                generated_tokens: self.generated_tokens,
+                elapsed,
+                time_to_first_token: self.time_to_first_token.unwrap_or(elapsed),
+                ..Default::default()
+            },
+            text: self.text.into_text(),
+            logprob: Some(self.logprob),
+        };
+        let index = self.index;
+        let _ = self
+            .events
+            .send(CompletionEvent::Finished { index, completion });
+    }
+}
+
+/// 批处理推理引擎
+///
+/// The thread exits once the engine is dropped and the submitted completions are done.
+pub struct BatchEngine {
+    shared: Arc<Mutex<Shared>>,
+    messages: mpsc::Sender<Message>,
+    tokenizer: Tokenizer,
+    timeout: Option<Duration>,
+}
+
+impl BatchEngine {
+    pub fn spawn(
+        engine: Arc<InferenceEngine>,
+        tokenizer: Tokenizer,
+        config: SchedulerConfig,
+        admission: AdmissionConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let shared = Arc::new(Mutex::new(Shared {
+            queue: AdmissionQueue::new(admission.max_queue),
+            running: Vec::new(),
+        }));
+        let (messages, rx) = mpsc::channel();
+        let worker_shared = shared.clone();
+        let worker_tokenizer = tokenizer.clone();
+        std::thread::Builder::new()
+            .name("batch-engine".to_string())
+            .spawn(move || {
+                run(
+                    &engine,
+                    &worker_tokenizer,
+                    config,
+                    &worker_shared,
+                    &metrics,
+                    rx,
+                )
+            })
+            .expect("failed to spawn the batch engine thread");
+        Self {
+            shared,
+            messages,
+            tokenizer,
+            timeout: admission.timeout,
+        }
+    }
+
+    /// Queue the completion of every prompt with its parameters, the choices are indexed by their
+    /// position in `choices`. Their text and their results are sent to `events`, the generation
+    /// stops early once `events` is closed.
+    ///
+    /// Fails with [`AdmissionError::QueueFull`] when the queue has no room for all the choices.
+    /// Without a deadline the request gets the timeout of the [`AdmissionConfig`].
+    pub fn submit(
+        &self,
+        mut admission: Admission,
+        choices: Vec<(String, CompletionParams)>,
+        events: UnboundedSender<CompletionEvent>,
+    ) -> Result<()> {
+        let submitted = Instant::now();
+        if admission.deadline.is_none() {
+            admission.deadline = self.timeout.map(|timeout| submitted + timeout);
+        }
+        let jobs = choices
+            .into_iter()
+            .enumerate()
+            .map(|(index, (prompt, params))| {
+                Ok(Job {
+                    index,
+                    prompt: self.tokenizer.encode(&prompt)?,
+                    params,
+                    events: events.clone(),
+                    submitted,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.shared().queue.push(&admission, jobs)?;
+        self.send(Message::Queued)
+    }
+
+    /// Cancel the choices of a request, waiting or running, on behalf of the API key named
+    /// `owner`, `None` without authentication. Only the choices the key may cancel, see
+    /// [`Admission::cancellable_by`], are cancelled. Returns whether any of them was in flight,
+    /// their events then end with [`AdmissionError::Cancelled`].
+    pub fn cancel(&self, request_id: &str, owner: Option<&str>) -> Result<bool> {
+        let found = {
+            let shared = self.shared();
+            shared.queue.contains(request_id, owner)
+                || shared.running.iter().any(|admission| {
+                    admission.request_id == request_id && admission.cancellable_by(owner)
+                })
+        };
+        if found {
+            self.send(Message::Cancel {
+                request_id: request_id.to_string(),
+                owner: owner.map(str::to_string),
+            })?;
+        }
+        Ok(found)
+    }
+
+    fn shared(&self) -> MutexGuard<'_, Shared> {
+        self.shared
+            .lock()
+            .expect("the batch engine thread panicked")
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.messages
+            .send(message)
+            .map_err(|_| anyhow::anyhow!("the batch engine has stopped"))
+    }
+}
+
+/// Send `error` once to each request.
+fn abort_requests(aborted: Vec<(UnboundedSender<CompletionEvent>, AdmissionError)>) {
+    let mut notified: Vec<UnboundedSender<CompletionEvent>> = Vec::new();
+    for (events, error) in aborted {
+        if !notified.iter().any(|other| other.same_channel(&events)) {
+            let _ = events.send(CompletionEvent::Aborted(error));
+            notified.push(events);
+        }
+    }
+}
+
+fn run(
+    engine: &InferenceEngine,
+    tokenizer: &Tokenizer,
+    config: SchedulerConfig,
+    shared: &Mutex<Shared>,
+    metrics: &Metrics,
+    messages: mpsc::Receiver<Message>,
+) {
+    let lock = || shared.lock().expect("a submitting thread panicked");
+    let max_batch_size = config.max_batch_size;
+    let mut scheduler = match Scheduler::new(engine, config) {
+        Ok(scheduler) => scheduler,
+        Err(err) => {
+            for _ in messages {
+                for (_, job) in lock().queue.remove(|_, _| true) {
+                    let _ = job.events.send(CompletionEvent::Failed(err.to_string()));
+                }
+            }
+            return;
+        }
+    };
+    let eos_token_id = tokenizer.eos_token_id();
+    let mut running: HashMap<u64, Running> = HashMap::new();
+    // Other engines, serving other models, set their own part of the gauges.
+    let mut queue_depth = GaugeShare::new(&metrics.queue_depth);
+    let mut running_sequences = GaugeShare::new(&metrics.running_sequences);
+    let mut kv_cache_used = GaugeShare::new(&metrics.kv_cache_used);
+    let mut kv_cache_capacity = GaugeShare::new(&metrics.kv_cache_capacity);
+    kv_cache_capacity.set(scheduler.cache_capacity() as f64);
+    // Counted since the scheduler started, the metrics get the difference after each step.
+    let mut prefix_cache = scheduler.prefix_cache_stats().unwrap_or_default();
+    loop {
+        // Wait for work when idle, otherwise only take the messages that already arrived.
+        let idle = scheduler.is_idle() && lock().queue.is_empty();
+        let first = if idle {
+            match messages.recv() {
+                Ok(message) => Some(message),
+                Err(_) => return,
+            }
+        } else {
+            None
+        };
+        let cancelled: Vec<(String, Option<String>)> = first
+            .into_iter()
+            .chain(messages.try_iter())
+            .filter_map(|message| match message {
+                Message::Queued => None,
+                Message::Cancel { request_id, owner } => Some((request_id, owner)),
+            })
+            .collect();
+
+        let now = Instant::now();
+        let abort_reason = |admission: &Admission| {
+            let is_cancelled = cancelled.iter().any(|(request_id, owner)| {
+                admission.request_id == *request_id && admission.cancellable_by(owner.as_deref())
+            });
+            if is_cancelled {
+                Some(AdmissionError::Cancelled)
+            } else if admission.expired(now) {
+                Some(AdmissionError::TimedOut)
+            } else {
+                None
+            }
+        };
+        let mut shared = lock();
+        let mut aborted = Vec::new();
+        let removed = shared
+            .queue
+            .remove(|admission, job| job.events.is_closed() || abort_reason(admission).is_some());
+        for (admission, job) in removed {
+            if let Some(error) = abort_reason(&admission) {
+                aborted.push((job.events, error));
+            }
+        }
+        running.retain(|&id, job| {
+            let error = abort_reason(&job.admission);
+            if let Some(error) = error {
+                aborted.push((job.events.clone(), error));
+            }
+            let keep = error.is_none() && !job.events.is_closed();
+            if !keep {
+                scheduler.abort(id);
+            }
+            keep
+        });
+        abort_requests(aborted);
+        // The queue keeps the order of the jobs until the batch has room for them.
+        while scheduler.num_running() + scheduler.num_waiting() < max_batch_size {
+            let Some(first) = shared.queue.pop() else {
+                break;
+            };
+            // The other choices of the request for the same prompt, as many as a batch holds.
+            let mut room = max_batch_size - 1;
+            let siblings = shared.queue.remove(|_, job| {
+                let sibling = room > 0
+                    && job.events.same_channel(&first.1.events)
+                    && job.prompt == first.1.prompt;
+                room -= usize::from(sibling);
+                sibling
+            });
+            add(
+                &mut scheduler,
+                &mut running,
+                tokenizer,
+                eos_token_id,
+                metrics,
+                std::iter::once(first).chain(siblings).collect(),
+            );
+        }
+        queue_depth.set(shared.queue.len() as f64);
+        shared.running = running.values().map(|job| job.admission.clone()).collect();
+        drop(shared);
+
+        let output = scheduler.step();
+        running_sequences.set(scheduler.num_running() as f64);
+        kv_cache_used.set(scheduler.cached_positions() as f64);
+        if let Some(stats) = scheduler.prefix_cache_stats() {
+            let hits = stats.hits - prefix_cache.hits;
+            metrics.prefix_cache_hits.add(hits);
+            metrics
+                .prefix_cache_misses
+                .add(stats.lookups - prefix_cache.lookups - hits);
+            metrics
+                .prefix_cache_query_tokens
+                .add(stats.query_tokens - prefix_cache.query_tokens);
+            metrics
+                .prefix_cache_hit_tokens
+                .add(stats.hit_tokens - prefix_cache.hit_tokens);
+            prefix_cache = stats;
+        }
+        let output = match output {
+            Ok(Some(output)) => output,
+            Ok(None) => continue,
+            Err(err) => {
+                for (id, job) in running.drain() {
+                    scheduler.abort(id);
+                    let _ = job.events.send(CompletionEvent::Failed(err.to_string()));
+                }
+                continue;
+            }
+        };
+        for event in output.events {
+            match event {
+                SequenceEvent::Token { id, token, logprob } => {
+                    let Some(job) = running.get_mut(&id) else {
+                        continue;
+                    };
+                    // The receiver was dropped, the client is gone.
+                    if !job.token(logprob, metrics) {
+                        scheduler.abort(id);
+                        running.remove(&id);
+                        continue;
+                    }
+                    if token == eos_token_id {
+                        continue;
+                    }
+                    let sent = match job.text.push(token) {
+                        Ok("") => true,
+                        Ok(text) => {
+                            let text = text.to_string();
+                            let index = job.index;
+                            job.events
+                                .send(CompletionEvent::Text { index, text })
+                                .is_ok()
+                        }
+                        Err(err) => {
+                            let _ = job.events.send(CompletionEvent::Failed(err.to_string()));
+                            false
+                        }
+                    };
+                    if !sent {
+                        scheduler.abort(id);
+                        running.remove(&id);
+                    } else if job.text.stopped() {
+                        scheduler.abort(id);
+                        if let Some(job) = running.remove(&id) {
+                            job.finish(FinishReason::Stop, metrics);
+                        }
+                    }
+                }
+                SequenceEvent::Finished { id, reason } => {
+                    if let Some(job) = running.remove(&id) {
+                        job.finish(reason, metrics);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Add the jobs, choices of one request for the same prompt, to the scheduler.
+fn add(
+    scheduler: &mut Scheduler,
+    running: &mut HashMap<u64, Running>,
+    tokenizer: &Tokenizer,
+    eos_token_id: u32,
+    metrics: &Metrics,
+    jobs: Vec<(Admission, Job)>,
+) {
+    let requests = jobs
+        .iter()
+        .map(|(_, job)| SequenceRequest {
+            prompt: job.prompt.clone(),
+            sampling: job.params.sampling.clone(),
+            max_tokens: job.params.max_tokens,
+            eos_token_id: Some(eos_token_id),
+        })
+        .collect();
+    match scheduler.add_parallel(requests) {
+        Ok(ids) => {
+            for (id, (admission, job)) in ids.into_iter().zip(jobs) {
+                let prompt_tokens = job.prompt.len();
+                metrics.prompt_tokens.add(prompt_tokens as u64);
+                running.insert(
+                    id,
+                    Running {
+                        index: job.index,
+                        admission,
+                        events: job.events,
+                        text: CompletionText::new(tokenizer, job.params.stop),
+                        prompt_tokens,
+                        generated_tokens: 0,
+                        logprob: 0.0,
+                        submitted: job.submitted,
+                        time_to_first_token: None,
+                        last_token: None,
+                    },
+                );
+            }
+        }
+        Err(err) => {
+            for (_, job) in jobs {
+                let _ = job.events.send(CompletionEvent::Failed(err.to_string()));
+            }
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use {
+    crate::{inference::InferenceEngine, model::Cache},
+    anyhow::{Result, bail},
+    candle_core::{D, Tensor},
+};
+
+/// 束搜索参数
+#[derive(Debug, Clone)]
+pub struct BeamSearchParams {
+    /// Number of hypotheses kept alive at each step.
+    pub beam_width: usize,
+    /// Exponent applied to the hypothesis length when ranking finished hypotheses, values above
+    /// 1. favour longer sequences and values below 1. favour shorter ones.
+    pub length_penalty: f64,
+    /// Stop as soon as `beam_width` hypotheses are finished rather than when no live beam can
+    /// beat the finished ones anymore.
+    pub early_stopping: bool,
+    /// Number of hypotheses to return, at most `beam_width`.
+    pub num_return: usize,
+    pub max_tokens: usize,
+    pub eos_token_id: Option<u32>,
+}
+
+impl Default for BeamSearchParams {
+    fn default() -> Self {
+        Self {
+            beam_width: 4,
+            length_penalty: 1.0,
+            early_stopping: false,
+            num_return: 1,
+            max_tokens: 100,
+            eos_token_id: None,
+        }
+    }
+}
+
+/// 束搜索得到的候选序列
+#[derive(Debug, Clone)]
+pub struct Hypothesis {
+    /// Generated tokens, the prompt and the end of sequence token are not included.
+    pub tokens: Vec<u32>,
+    /// Sum of the log-probabilities of the generated tokens, including the end of sequence token
+    /// when the hypothesis produced one.
+    pub log_prob: f64,
+    /// Length normalized score used for ranking.
+    pub score: f64,
+    /// Whether the hypothesis ended with the end of sequence token.
+    pub eos: bool,
+}
+
+struct Beam {
+    tokens: Vec<u32>,
+    log_prob: f64,
+    cache: Cache,
+    // Logits predicting the next token of the beam.
+    logits: Tensor,
+}
+
+fn length_normalized(log_prob: f64, len: usize, length_penalty: f64) -> f64 {
+    log_prob / (len.max(1) as f64).powf(length_penalty)
+}
+
+impl InferenceEngine {
+    /// Beam search decoding of a continuation for `prompt`.
+    ///
+    /// The prompt is processed once, each beam then owns a forked copy of the cache so that
+    /// expanding a beam only runs the newly selected token through the model. Hypotheses are
+    /// returned best first.
+    pub fn beam_search(
+        &self,
+        prompt: &[u32],
+        params: &BeamSearchParams,
+    ) -> Result<Vec<Hypothesis>> {
+        if prompt.is_empty() {
+            bail!("the prompt must contain at least one token");
+        }
+        if params.beam_width == 0 {
+            bail!("the beam width must be positive");
+        }
+        if params.num_return == 0 || params.num_return > params.beam_width {
+            bail!(
+                "the number of returned hypotheses must be in 1..={}",
+                params.beam_width
+            );
+        }
+        let max_tokens = params
+            .max_tokens
+            .min(self.config().seq_len.saturating_sub(prompt.len()));
+
+        let mut cache = self.new_cache()?;
+        let logits = self.forward(prompt, 0, &mut cache)?;
+        let logits = logits.get(prompt.len() - 1)?;
+        let mut beams = vec![Beam {
+            tokens: Vec::new(),
+            log_prob: 0.0,
+            cache,
+            logits,
+        }];
+        let mut finished: Vec<Hypothesis> = Vec::new();
+
+        for step in 0..max_tokens {
+            // Expand every live beam with its 2 * beam_width best tokens so that enough candidates
+            // remain when some of them end the sequence.
+            let mut candidates = Vec::new();
+            for (beam_index, beam) in beams.iter().enumerate() {
+                let log_probs = candle_nn::ops::log_softmax(&beam.logits, D::Minus1)?;
+                let log_probs = log_probs.to_vec1::<f32>()?;
+                let mut order: Vec<usize> = (0..log_probs.len()).collect();
+                order.sort_by(|&a, &b| log_probs[b].total_cmp(&log_probs[a]));
+                for &token in order.iter().take(2 * params.beam_width) {
+                    let log_prob = beam.log_prob + log_probs[token] as f64;
+                    candidates.push((beam_index, token as u32, log_prob));
+                }
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut selected = Vec::with_capacity(params.beam_width);
+            for (rank, &(beam_index, token, log_prob)) in candidates.iter().enumerate() {
+                if Some(token) == params.eos_token_id {
+                    // End of sequence tokens that did not make it in the top beam_width are
+                    // ignored, as they would not have been kept otherwise.
+                    if rank < params.beam_width {
+                        finished.push(Hypothesis {
+                            tokens: beams[beam_index].tokens.clone(),
+                            log_prob,
+                            score: length_normalized(log_prob, step + 1, params.length_penalty),
+                            eos: true,
+                        });
+                    }
+                } else {
+                    selected.push((beam_index, token, log_prob));
+                    if selected.len() == params.beam_width {
+                        break;
+                    }
+                }
+            }
+
+            // The last child of a beam takes over its cache, the other ones get a fork of it.
+            let mut children = vec![0; beams.len()];
+            for &(beam_index, _, _) in selected.iter() {
+                children[beam_index] += 1;
+            }
+            let mut parents: Vec<Option<Beam>> = beams.into_iter().map(Some).collect();
+            beams = Vec::with_capacity(selected.len());
+            for (beam_index, token, log_prob) in selected {
+                children[beam_index] -= 1;
+                let (mut tokens, mut cache) = if children[beam_index] == 0 {
+                    let parent = parents[beam_index]
+                        .take()
+                        .expect("parent beam already taken");
+                    (parent.tokens, parent.cache)
+                } else {
+                    let parent = parents[beam_index]
+                        .as_ref()
+                        .expect("parent beam already taken");
+                    (parent.tokens.clone(), parent.cache.fork()?)
+                };
+                tokens.push(token);
+                let logits = self.forward(&[token], prompt.len() + step, &mut cache)?;
+                beams.push(Beam {
+                    tokens,
+                    log_prob,
+                    cache,
+                    logits: logits.get(0)?,
+                });
+            }
+
+            if finished.len() >= params.beam_width {
+                if params.early_stopping {
+                    break;
+                }
+                // Same heuristic as Hugging Face transformers: a live beam is assumed not to
+                // improve on its current score, so stop once none of them beats the beam_width-th
+                // best finished hypothesis.
+                let mut scores: Vec<f64> = finished.iter().map(|h| h.score).collect();
+                scores.sort_by(|a, b| b.total_cmp(a));
+                let worst_finished = scores[params.beam_width - 1];
+                let best_live = beams
+                    .iter()
+                    .map(|b| length_normalized(b.log_prob, b.tokens.len(), params.length_penalty))
+                    .fold(f64::NEG_INFINITY, f64::max);
+                if best_live <= worst_finished {
+                    break;
+                }
+            }
+            if beams.is_empty() {
+                break;
+            }
+        }
+
+        finished.extend(beams.into_iter().map(|beam| Hypothesis {
+            score: length_normalized(beam.log_prob, beam.tokens.len(), params.length_penalty),
+            tokens: beam.tokens,
+            log_prob: beam.log_prob,
+            eos: false,
+        }));
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(params.num_return);
+        Ok(finished)
+    }
+}
@@ -0,0 +1,465 @@
+//! 服务压测
+//!
+//! Replays a JSONL file of completion requests against the in-process [`BatchEngine`] or an
+//! OpenAI compatible HTTP server, each request sent at its arrival time, and reports the
+//! throughput, the percentiles of the time to first token, of the inter-token latency and of the
+//! end-to-end latency, and the errors by kind.
+//!
+//! The text of a completion arrives in chunks, a server-sent event over HTTP, a text event in
+//! process. The first chunk gives the time to first token, the time between two chunks the
+//! inter-token latency.
+
+use {
+    crate::{
+        admission::{Admission, AdmissionError},
+        batching::{BatchEngine, CompletionEvent},
+        completion::CompletionParams,
+        openai::{CompletionRequest, StreamOptions, StringOrArray},
+        sampling::SamplingParams,
+    },
+    anyhow::{Context, Result, bail},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        fmt,
+        path::Path,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    tokio::sync::mpsc,
+};
+
+/// 压测请求参数, the sampling options of `/v1/completions`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BenchParams {
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+}
+
+impl BenchParams {
+    /// Parameters of the in-process engine, with the defaults of the OpenAI API of the server.
+    fn completion_params(&self) -> CompletionParams {
+        CompletionParams {
+            sampling: SamplingParams {
+                temperature: self.temperature.unwrap_or(1.0),
+                top_p: self.top_p,
+                repeat_penalty: 1.0,
+                seed: self.seed.unwrap_or_else(rand::random),
+                ..Default::default()
+            },
+            max_tokens: self.max_tokens.unwrap_or(16),
+            stop: self.stop.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// 压测请求, a line of the JSONL file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchRequest {
+    /// Prompt of the completion, also read from `body`.
+    #[serde(alias = "body")]
+    pub prompt: String,
+    #[serde(default)]
+    pub params: BenchParams,
+    /// Seconds from the start of the run at which the request is sent, 0 by default.
+    #[serde(default)]
+    pub offset: Option<f64>,
+}
+
+/// Read the requests of a JSONL file, blank lines are skipped.
+pub fn read_requests(path: impl AsRef<Path>) -> Result<Vec<BenchRequest>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("{}:{}: invalid request", path.display(), i + 1))
+        })
+        .collect()
+}
+
+/// Seconds from the start of the run at which each request is sent: one every `1 / rate` seconds
+/// with a rate in requests per second, the offsets of the requests otherwise.
+pub fn arrivals(requests: &[BenchRequest], rate: Option<f64>) -> Result<Vec<f64>> {
+    match rate {
+        Some(rate) if !(rate > 0.0 && rate.is_finite()) => bail!("the rate must be positive"),
+        Some(rate) => Ok((0..requests.len()).map(|i| i as f64 / rate).collect()),
+        None => requests
+            .iter()
+            .map(|request| match request.offset {
+                Some(offset) if !(offset >= 0.0 && offset.is_finite()) => {
+                    bail!("offsets must be non-negative numbers of seconds")
+                }
+                offset => Ok(offset.unwrap_or(0.0)),
+            })
+            .collect(),
+    }
+}
+
+/// 单个请求的测量结果
+#[derive(Debug, Clone, Default)]
+pub struct RequestResult {
+    /// Seconds to the first chunk of text.
+    pub ttft: Option<f64>,
+    /// Seconds between consecutive chunks of text.
+    pub itl: Vec<f64>,
+    /// Seconds to the end of the request.
+    pub latency: f64,
+    pub generated_tokens: usize,
+    /// Kind of error of a failed request.
+    pub error: Option<String>,
+}
+
+/// Times the chunks of a request from the moment it is sent.
+struct Timer {
+    start: Instant,
+    last: Option<Instant>,
+    result: RequestResult,
+}
+
+impl Timer {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: None,
+            result: RequestResult::default(),
+        }
+    }
+
+    fn chunk(&mut self) {
+        let now = Instant::now();
+        match self.last {
+            Some(last) => self.result.itl.push((now - last).as_secs_f64()),
+            None => self.result.ttft = Some((now - self.start).as_secs_f64()),
+        }
+        self.last = Some(now);
+    }
+
+    fn finish(mut self) -> RequestResult {
+        self.result.latency = self.start.elapsed().as_secs_f64();
+        self.result
+    }
+
+    fn fail(mut self, error: impl Into<String>) -> RequestResult {
+        self.result.error = Some(error.into());
+        self.finish()
+    }
+}
+
+fn admission_error_kind(err: &AdmissionError) -> &'static str {
+    match err {
+        AdmissionError::QueueFull { .. } => "queue full",
+        AdmissionError::TimedOut => "timed out",
+        AdmissionError::Cancelled => "cancelled",
+    }
+}
+
+/// 压测目标
+pub enum BenchTarget {
+    /// The engine of this process.
+    InProcess(Box<BatchEngine>),
+    /// `POST {url}/v1/completions` of a server, streamed.
+    Http {
+        client: reqwest::Client,
+        url: String,
+        model: String,
+    },
+}
+
+impl BenchTarget {
+    /// Target a server at `url`, e.g. `http://127.0.0.1:8080`. Without `model` the first model
+    /// listed by the server is used. `api_key` is sent as a bearer token.
+    pub async fn http(url: &str, model: Option<String>, api_key: Option<&str>) -> Result<Self> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(api_key) = api_key {
+            let value = reqwest::header::HeaderValue::from_str(&format!("Bearer {api_key}"))
+                .context("invalid API key")?;
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()?;
+        let url = url.trim_end_matches('/').to_string();
+        let model = match model {
+            Some(model) => model,
+            None => {
+                let models: serde_json::Value = client
+                    .get(format!("{url}/v1/models"))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                models["data"][0]["id"]
+                    .as_str()
+                    .context("the server lists no model")?
+                    .to_string()
+            }
+        };
+        Ok(Self::Http { client, url, model })
+    }
+
+    async fn send(&self, id: usize, request: BenchRequest) -> RequestResult {
+        match self {
+            Self::InProcess(batch) => send_in_process(batch, id, request).await,
+            Self::Http { client, url, model } => send_http(client, url, model, request).await,
+        }
+    }
+}
+
+async fn send_in_process(batch: &BatchEngine, id: usize, request: BenchRequest) -> RequestResult {
+    let mut timer = Timer::new();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let params = request.params.completion_params();
+    let admission = Admission::new(format!("bench-{id}"));
+    if let Err(err) = batch.submit(admission, vec![(request.prompt, params)], tx) {
+        return match err.downcast_ref::<AdmissionError>() {
+            Some(err) => timer.fail(admission_error_kind(err)),
+            None => timer.fail("engine"),
+        };
+    }
+    while let Some(event) = rx.recv().await {
+        match event {
+            CompletionEvent::Text { .. } => timer.chunk(),
+            CompletionEvent::Tokens { .. } => {}
+            CompletionEvent::Finished { completion, .. } => {
+                timer.result.generated_tokens = completion.stats.generated_tokens;
+            }
+            CompletionEvent::Failed(_) => return timer.fail("engine"),
+            CompletionEvent::Aborted(err) => return timer.fail(admission_error_kind(&err)),
+        }
+    }
+    timer.finish()
+}
+
+async fn send_http(
+    client: &reqwest::Client,
+    url: &str,
+    model: &str,
+    request: BenchRequest,
+) -> RequestResult {
+    let mut timer = Timer::new();
+    let params = request.params;
+    let body = CompletionRequest {
+        model: model.to_string(),
+        prompt: StringOrArray::String(request.prompt),
+        max_tokens: params.max_tokens,
+        temperature: params.temperature,
+        top_p: params.top_p,
+        n: None,
+        best_of: None,
+        stop: params.stop.map(StringOrArray::Array),
+        seed: params.seed,
+        stream: Some(true),
+        stream_options: Some(StreamOptions {
+            include_usage: true,
+        }),
+        priority: None,
+        timeout: None,
+    };
+    let response = match client
+        .post(format!("{url}/v1/completions"))
+        .json(&body)
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(_) => return timer.fail("connection"),
+    };
+    let status = response.status();
+    if !status.is_success() {
+        return timer.fail(format!("http {}", status.as_u16()));
+    }
+
+    let mut response = response;
+    let mut buffer = Vec::new();
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => return timer.fail("connection"),
+        };
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data: ") else {
+                continue;
+            };
+            if data == "[DONE]" {
+                return timer.finish();
+            }
+            let Ok(event) = serde_json::from_str::<serde_json::Value>(data) else {
+                return timer.fail("invalid event");
+            };
+            if event.get("error").is_some() {
+                return timer.fail("stream error");
+            }
+            if let Some(tokens) = event["usage"]["completion_tokens"].as_u64() {
+                timer.result.generated_tokens = tokens as usize;
+            }
+            let text = event["choices"][0]["text"].as_str().unwrap_or_default();
+            if !text.is_empty() {
+                timer.chunk();
+            }
+        }
+    }
+    timer.fail("incomplete stream")
+}
+
+/// Send every request at its arrival time, in seconds from the start of the run, and wait for
+/// all of them.
+pub async fn run(
+    target: BenchTarget,
+    requests: Vec<BenchRequest>,
+    arrivals: Vec<f64>,
+) -> BenchReport {
+    let target = Arc::new(target);
+    let start = tokio::time::Instant::now();
+    let tasks: Vec<_> = requests
+        .into_iter()
+        .zip(arrivals)
+        .enumerate()
+        .map(|(id, (request, arrival))| {
+            let target = target.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep_until(start + Duration::from_secs_f64(arrival)).await;
+                target.send(id, request).await
+            })
+        })
+        .collect();
+    let mut results = Vec::new();
+    for task in tasks {
+        results.push(task.await.unwrap_or_else(|_| RequestResult {
+            error: Some("panic".to_string()),
+            ..Default::default()
+        }));
+    }
+    BenchReport::new(&results, start.elapsed().as_secs_f64())
+}
+
+/// 分位数, in seconds
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Percentiles {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+impl Percentiles {
+    /// Nearest-rank percentiles, all zero without samples.
+    pub fn new(mut samples: Vec<f64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_by(f64::total_cmp);
+        let rank = |p: f64| {
+            let rank = (p / 100.0 * samples.len() as f64).ceil() as usize;
+            samples[rank.clamp(1, samples.len()) - 1]
+        };
+        Self {
+            mean: samples.iter().sum::<f64>() / samples.len() as f64,
+            p50: rank(50.0),
+            p90: rank(90.0),
+            p99: rank(99.0),
+        }
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mean {:.2}, p50 {:.2}, p90 {:.2}, p99 {:.2}",
+            self.mean * 1e3,
+            self.p50 * 1e3,
+            self.p90 * 1e3,
+            self.p99 * 1e3
+        )
+    }
+}
+
+/// 压测报告
+#[derive(Debug, Clone, Default)]
+pub struct BenchReport {
+    /// Seconds from the start of the run to the end of the last request.
+    pub duration: f64,
+    pub completed: usize,
+    /// Failed requests by kind of error.
+    pub errors: BTreeMap<String, usize>,
+    pub generated_tokens: usize,
+    pub ttft: Percentiles,
+    pub itl: Percentiles,
+    pub latency: Percentiles,
+}
+
+impl BenchReport {
+    /// Latencies are taken from the completed requests only.
+    pub fn new(results: &[RequestResult], duration: f64) -> Self {
+        let mut errors = BTreeMap::new();
+        for error in results.iter().filter_map(|result| result.error.as_ref()) {
+            *errors.entry(error.clone()).or_default() += 1;
+        }
+        let completed: Vec<_> = results.iter().filter(|r| r.error.is_none()).collect();
+        Self {
+            duration,
+            completed: completed.len(),
+            errors,
+            generated_tokens: completed.iter().map(|r| r.generated_tokens).sum(),
+            ttft: Percentiles::new(completed.iter().filter_map(|r| r.ttft).collect()),
+            itl: Percentiles::new(completed.iter().flat_map(|r| r.itl.clone()).collect()),
+            latency: Percentiles::new(completed.iter().map(|r| r.latency).collect()),
+        }
+    }
+
+    pub fn failed(&self) -> usize {
+        self.errors.values().sum()
+    }
+
+    /// Completed requests per second.
+    pub fn request_throughput(&self) -> f64 {
+        self.completed as f64 / self.duration.max(f64::EPSILON)
+    }
+
+    /// Generated tokens per second.
+    pub fn token_throughput(&self) -> f64 {
+        self.generated_tokens as f64 / self.duration.max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "requests: {} completed, {} failed in {:.2} s",
+            self.completed,
+            self.failed(),
+            self.duration
+        )?;
+        writeln!(
+            f,
+            "throughput: {:.2} requests/s, {:.2} tokens/s",
+            self.request_throughput(),
+            self.token_throughput()
+        )?;
+        writeln!(f, "time to first token (ms): {}", self.ttft)?;
+        writeln!(f, "inter-token latency (ms): {}", self.itl)?;
+        write!(f, "end-to-end latency (ms): {}", self.latency)?;
+        for (error, count) in self.errors.iter() {
+            write!(f, "\nerror {error}: {count}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+/// 对话消息
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// `system`, `user` or `assistant`.
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+}
+
+/// Stop string ending the assistant turn of a prompt built by [`render_chat_prompt`].
+pub const CHAT_TURN_STOP: &str = "\nuser:";
+
+/// Render a conversation with the default template, one `role: content` line per message
+/// followed by an open assistant turn.
+pub fn render_chat_prompt(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        prompt.push_str(&message.role);
+        prompt.push_str(": ");
+        prompt.push_str(&message.content);
+        prompt.push('\n');
+    }
+    prompt.push_str("assistant:");
+    prompt
+}
@@ -0,0 +1,197 @@
+use {
+    crate::{
+        inference::{GenerationStats, InferenceEngine},
+        sampling::SamplingParams,
+        token_output_stream::TokenOutputStream,
+        tokenizer::Tokenizer,
+    },
+    anyhow::Result,
+    serde::{Deserialize, Serialize},
+};
+
+/// 生成结束原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The end of sequence token or a stop string was produced.
+    Stop,
+    /// The token budget was exhausted.
+    Length,
+}
+
+/// 文本补全参数
+#[derive(Debug, Clone)]
+pub struct CompletionParams {
+    pub sampling: SamplingParams,
+    pub max_tokens: usize,
+    /// Generation stops before any of these strings, which are not part of the output.
+    pub stop: Vec<String>,
+}
+
+impl Default for CompletionParams {
+    fn default() -> Self {
+        Self {
+            sampling: SamplingParams::default(),
+            max_tokens: 100,
+            stop: Vec::new(),
+        }
+    }
+}
+
+/// 文本补全结果
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub stats: GenerationStats,
+    /// Sum of the log-probabilities of the generated tokens under the model, when known.
+    pub logprob: Option<f64>,
+}
+
+/// Byte offset of the first stop string in `text`.
+fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| text.find(s.as_str()))
+        .min()
+}
+
+/// Length of the longest suffix of `text` that could be the beginning of a stop string, this
+/// part is held back until it is known whether the stop string is actually produced.
+fn partial_stop_len(text: &str, stop: &[String]) -> usize {
+    stop.iter()
+        .flat_map(|s| {
+            (1..s.len())
+                .filter(|&len| s.is_char_boundary(len))
+                .map(|len| &s[..len])
+        })
+        .filter(|prefix| text.ends_with(prefix))
+        .map(|prefix| prefix.len())
+        .max()
+        .unwrap_or(0)
+}
+
+/// 补全文本
+///
+/// Text of a generation as its tokens come in. The end of a word and a possible beginning of a
+/// stop string are held back until they are complete, the text ends before the first stop string.
+pub struct CompletionText {
+    stream: TokenOutputStream,
+    stop: Vec<String>,
+    text: String,
+    emitted: usize,
+    stopped: bool,
+}
+
+impl CompletionText {
+    pub fn new(tokenizer: &Tokenizer, stop: Vec<String>) -> Self {
+        Self {
+            stream: TokenOutputStream::new(tokenizer.tokenizer.clone()),
+            stop,
+            text: String::new(),
+            emitted: 0,
+            stopped: false,
+        }
+    }
+
+    /// Add a sampled token and return the text that became final, which is empty while a word or
+    /// a potential stop string is incomplete.
+    pub fn push(&mut self, token: u32) -> Result<&str> {
+        if let Some(piece) = self.stream.next_token(token)? {
+            self.text.push_str(&piece);
+        }
+        let end = match find_stop(&self.text, &self.stop) {
+            Some(pos) => {
+                self.text.truncate(pos);
+                self.stopped = true;
+                pos
+            }
+            None => self.text.len() - partial_stop_len(&self.text, &self.stop),
+        };
+        let start = self.emitted;
+        self.emitted = end.max(start);
+        Ok(&self.text[start..self.emitted])
+    }
+
+    /// Flush the text held back once the generation is over and return it.
+    pub fn finish(&mut self) -> Result<&str> {
+        let start = self.emitted;
+        if !self.stopped {
+            if let Some(rest) = self.stream.decode_rest()? {
+                self.text.push_str(&rest);
+            }
+            if let Some(pos) = find_stop(&self.text, &self.stop) {
+                self.text.truncate(pos);
+                self.stopped = true;
+            }
+        }
+        self.emitted = self.text.len().max(start);
+        Ok(&self.text[start.min(self.text.len())..])
+    }
+
+    /// Whether a stop string was produced.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+impl InferenceEngine {
+    /// Generate text following `prompt` until the end of sequence token, a stop string or the
+    /// token budget is reached.
+    ///
+    /// `on_text` is called after every sampled token with the text that became final, which is
+    /// empty while a word or a potential stop string is incomplete, and can return `false` to
+    /// abort the generation, e.g. when the client went away.
+    pub fn complete(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        params: &CompletionParams,
+        mut on_text: impl FnMut(&str) -> bool,
+    ) -> Result<Completion> {
+        let prompt_tokens = tokenizer.encode(prompt)?;
+        let eos_token_id = tokenizer.eos_token_id();
+        let mut text = CompletionText::new(tokenizer, params.stop.clone());
+
+        let mut eos = false;
+        let mut aborted = false;
+        let stats = self.generate_tokens(
+            &prompt_tokens,
+            &params.sampling,
+            params.max_tokens,
+            |token| {
+                if token == eos_token_id {
+                    eos = true;
+                    return Ok(false);
+                }
+                if !on_text(text.push(token)?) {
+                    aborted = true;
+                    return Ok(false);
+                }
+                Ok(!text.stopped())
+            },
+        )?;
+
+        if !aborted {
+            let rest = text.finish()?;
+            if !rest.is_empty() {
+                on_text(rest);
+            }
+        }
+        let finish_reason = if eos || text.stopped() {
+            FinishReason::Stop
+        } else {
+            FinishReason::Length
+        };
+        Ok(Completion {
+            text: text.into_text(),
+            finish_reason,
+            stats,
+            logprob: None,
+        })
+    }
+}
@@ -0,0 +1,85 @@
+//! 句向量
+//!
+//! Sentence embeddings pooled from the final hidden states of the model. The models are causal
+//! decoders: the hidden state of a position only sees the tokens before it, so the last token is
+//! the only one that sees the whole input, and the first one, the `[CLS]` token of BERT-style
+//! encoders, only sees itself.
+
+use {
+    crate::inference::InferenceEngine,
+    anyhow::{Result, bail},
+    candle_core::{D, DType},
+    serde::{Deserialize, Serialize},
+};
+
+/// 池化方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    /// Mean of the hidden states over all positions.
+    #[default]
+    Mean,
+    /// Hidden state of the last token.
+    Last,
+    /// Hidden state of the first token.
+    Cls,
+}
+
+impl InferenceEngine {
+    /// Sentence embedding of `tokens`: the mean of the final hidden states over all positions.
+    pub fn embed(&self, tokens: &[u32]) -> Result<Vec<f32>> {
+        self.embed_with(tokens, Pooling::Mean, false)
+    }
+
+    /// Sentence embedding of `tokens` pooled with `pooling`, scaled to a unit L2 norm when
+    /// `normalize` is set.
+    pub fn embed_with(
+        &self,
+        tokens: &[u32],
+        pooling: Pooling,
+        normalize: bool,
+    ) -> Result<Vec<f32>> {
+        if tokens.is_empty() {
+            bail!("cannot embed an empty input");
+        }
+        if tokens.len() > self.config().seq_len {
+            bail!(
+                "the input has {} tokens but the context length of the model is {}",
+                tokens.len(),
+                self.config().seq_len
+            );
+        }
+        // Positions after the first do not change its hidden state.
+        let tokens = match pooling {
+            Pooling::Cls => &tokens[..1],
+            Pooling::Mean | Pooling::Last => tokens,
+        };
+        let mut cache = self.new_cache()?;
+        let hidden = self
+            .hidden_states(tokens, 0, &mut cache)?
+            .to_dtype(DType::F32)?;
+        let mut embedding = match pooling {
+            Pooling::Mean => hidden.mean(0)?,
+            Pooling::Last => hidden.get(tokens.len() - 1)?,
+            Pooling::Cls => hidden.get(0)?,
+        };
+        if normalize {
+            let norm = embedding.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+            embedding = embedding.broadcast_div(&(norm + 1e-12)?)?;
+        }
+        Ok(embedding.to_vec1::<f32>()?)
+    }
+
+    /// Embeddings of several inputs, see [`Self::embed_with`].
+    pub fn embed_batch(
+        &self,
+        inputs: &[Vec<u32>],
+        pooling: Pooling,
+        normalize: bool,
+    ) -> Result<Vec<Vec<f32>>> {
+        inputs
+            .iter()
+            .map(|tokens| self.embed_with(tokens, pooling, normalize))
+            .collect()
+    }
+}
@@ -0,0 +1,71 @@
+//! Model hyper-parameters stored in the metadata of GGUF files, see
+//! <https://github.com/ggml-org/ggml/blob/master/docs/gguf.md>.
+
+use {
+    crate::model::Config,
+    anyhow::{Context, Result, bail},
+    candle_core::quantized::gguf_file::{Content, Value},
+    std::{fs::File, io::Read, path::Path},
+};
+
+/// Base frequency of the rotary embeddings when the file does not set one.
+pub const DEFAULT_ROPE_THETA: f32 = 10000.;
+
+/// Whether `path` starts with the GGUF magic number, Ollama blobs have no file extension.
+pub fn is_gguf(path: impl AsRef<Path>) -> bool {
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|()| &magic == b"GGUF")
+}
+
+/// GGUF 模型元数据
+#[derive(Debug, Clone)]
+pub struct GgufConfig {
+    pub config: Config,
+    pub rope_theta: f32,
+}
+
+impl GgufConfig {
+    /// Read the hyper-parameters of a `llama` architecture model.
+    pub fn from_content(content: &Content) -> Result<Self> {
+        let arch = get(content, "general.architecture")?.to_string()?;
+        if arch != "llama" {
+            bail!("unsupported model architecture '{arch}'");
+        }
+        let usize_of = |key: &str| -> Result<usize> {
+            Ok(get(content, &format!("llama.{key}"))?.to_u32()? as usize)
+        };
+        let n_heads = usize_of("attention.head_count")?;
+        let n_kv_heads = match content.metadata.get("llama.attention.head_count_kv") {
+            Some(value) => value.to_u32()? as usize,
+            None => n_heads,
+        };
+        let vocab_size = match content.tensor_infos.get("token_embd.weight") {
+            Some(info) => info.shape.dims()[0],
+            None => bail!("missing tensor token_embd.weight"),
+        };
+        let config = Config {
+            dim: usize_of("embedding_length")?,
+            hidden_dim: usize_of("feed_forward_length")?,
+            n_layers: usize_of("block_count")?,
+            n_heads,
+            n_kv_heads,
+            vocab_size,
+            seq_len: usize_of("context_length")?,
+            norm_eps: get(content, "llama.attention.layer_norm_rms_epsilon")?.to_f32()? as f64,
+        };
+        let rope_theta = match content.metadata.get("llama.rope.freq_base") {
+            Some(value) => value.to_f32()?,
+            None => DEFAULT_ROPE_THETA,
+        };
+        Ok(Self { config, rope_theta })
+    }
+}
+
+fn get<'a>(content: &'a Content, key: &str) -> Result<&'a Value> {
+    content
+        .metadata
+        .get(key)
+        .with_context(|| format!("missing metadata {key}"))
+}
@@ -6,22 +6,29 @@
 
 use {
     crate::{args::Args, tokenizer::Tokenizer},
-    candle_core::{D, Tensor},
+    candle_core::{D, Device, Tensor},
 };
 
-use candle_transformers::models::llama2_c as model;
+use crate::model;
 // use candle_transformers::models::llama2_c_weights as weights;
 // use candle_transformers::models::quantized_llama2_c as qmodel;
 use anyhow::{Error as E, Result};
 // use clap::builder::Str;
-use model::{Cache, Config as ModelConfig};
+use model::{Cache, Config as ModelConfig, PagedCache, PagedChunk};
 // use qmodel::QLlama;
+use crate::gguf::{self, DEFAULT_ROPE_THETA, GgufConfig};
+use crate::sampling::{Sampler, SamplingParams};
+use crate::speculative::Draft;
 use candle_core::DType;
+use candle_core::quantized::gguf_file;
 use candle_core::safetensors;
-use candle_transformers::generation::LogitsProcessor;
+use kv_cache::KvQuantization;
 
-use candle_core::IndexOp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
 use std::io::Write;
+use std::path::Path;
 
 enum Model {
     Llama(model::Llama),
@@ -35,79 +42,305 @@ impl Model {
             // Self::QLlama(l) => Ok(l.forward(xs, pos, cache)?),
         }
     }
+
+    fn hidden_states(&self, xs: &Tensor, pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        match self {
+            Self::Llama(l) => Ok(l.hidden_states(xs, pos, cache)?),
+        }
+    }
+
+    fn forward_paged(
+        &self,
+        xs: &Tensor,
+        chunks: &[PagedChunk],
+        cache: &mut PagedCache,
+    ) -> Result<Tensor> {
+        match self {
+            Self::Llama(l) => Ok(l.forward_paged(xs, chunks, cache)?),
+        }
+    }
+}
+
+/// 生成统计
+#[derive(Debug, Clone, Default)]
+pub struct GenerationStats {
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    /// Time spent generating, in seconds.
+    pub elapsed: f64,
+    /// Time until the first token was sampled, mostly spent processing the prompt, in seconds.
+    pub time_to_first_token: f64,
+    /// Tokens proposed by the draft during speculative decoding.
+    pub draft_tokens: usize,
+    /// Draft tokens accepted by the target model.
+    pub accepted_tokens: usize,
+}
+
+impl GenerationStats {
+    pub fn tokens_per_second(&self) -> f64 {
+        self.generated_tokens as f64 / self.elapsed
+    }
+
+    /// Share of the draft tokens accepted by the target model, `None` when nothing was drafted.
+    pub fn acceptance_rate(&self) -> Option<f64> {
+        (self.draft_tokens > 0).then(|| self.accepted_tokens as f64 / self.draft_tokens as f64)
+    }
+}
+
+impl fmt::Display for GenerationStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} tokens generated ({:.2} token/s)",
+            self.generated_tokens,
+            self.tokens_per_second()
+        )?;
+        if let Some(rate) = self.acceptance_rate() {
+            write!(
+                f,
+                ", {}/{} draft tokens accepted ({:.1}%)",
+                self.accepted_tokens,
+                self.draft_tokens,
+                rate * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct InferenceEngine {
+    model: Model,
+    config: ModelConfig,
+    // Kept around so that fresh caches can pick up the rotary tables shipped with the checkpoint.
+    vb: candle_nn::VarBuilder<'static>,
+    rope_theta: f32,
+    kv_quantization: Option<KvQuantization>,
+    device: Device,
 }
 
-pub struct InferenceEngine;
 impl InferenceEngine {
+    /// Load a llama2.c checkpoint stored in the safetensors format.
+    pub fn load(model: &str, cpu: bool) -> Result<Self> {
+        Self::load_with_config(model, ModelConfig::tiny_15m(), cpu)
+    }
+
+    pub fn load_with_config(model: &str, config: ModelConfig, cpu: bool) -> Result<Self> {
+        let device = crate::device(cpu)?;
+        let tensors = safetensors::load(model, &device)?;
+        let vb = candle_nn::VarBuilder::from_tensors(tensors, DType::F32, &device);
+        Self::from_var_builder(vb, config)
+    }
+
+    /// Load a GGUF file when `model` is one, a llama2.c checkpoint of the given `config`
+    /// otherwise.
+    pub fn load_model(model: &str, config: ModelConfig, cpu: bool) -> Result<Self> {
+        if gguf::is_gguf(model) {
+            Self::load_gguf(model, cpu)
+        } else {
+            Self::load_with_config(model, config, cpu)
+        }
+    }
+
+    /// Load a llama model stored in a GGUF file, the projections stay quantized.
+    pub fn load_gguf(model: impl AsRef<Path>, cpu: bool) -> Result<Self> {
+        let device = crate::device(cpu)?;
+        let mut file = File::open(model.as_ref())?;
+        let content =
+            gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model.as_ref()))?;
+        let GgufConfig { config, rope_theta } = GgufConfig::from_content(&content)?;
+        let llama = model::Llama::load_gguf(&content, &mut file, &device, config.clone())?;
+        Ok(Self {
+            model: Model::Llama(llama),
+            config,
+            vb: candle_nn::VarBuilder::from_tensors(HashMap::new(), DType::F32, &device),
+            rope_theta,
+            kv_quantization: None,
+            device,
+        })
+    }
+
+    pub fn from_var_builder(
+        vb: candle_nn::VarBuilder<'static>,
+        config: ModelConfig,
+    ) -> Result<Self> {
+        let device = vb.device().clone();
+        let model = Model::Llama(model::Llama::load(vb.clone(), config.clone())?);
+        Ok(Self {
+            model,
+            config,
+            vb,
+            rope_theta: DEFAULT_ROPE_THETA,
+            kv_quantization: None,
+            device,
+        })
+    }
+
+    /// Store the keys and values of the caches made by the engine quantized, the paged caches of
+    /// the scheduler included.
+    pub fn with_kv_quantization(mut self, quantization: Option<KvQuantization>) -> Self {
+        self.kv_quantization = quantization;
+        self
+    }
+
+    pub fn config(&self) -> &ModelConfig {
+        &self.config
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub(crate) fn new_cache(&self) -> Result<Cache> {
+        let cache = Cache::with_rope_theta(&self.config, self.rope_theta, self.vb.pp("rot"))?;
+        Ok(match self.kv_quantization {
+            Some(quantization) => cache.with_kv_quantization(quantization),
+            None => cache,
+        })
+    }
+
+    /// Cache of `num_blocks` blocks of `block_size` tokens for [`InferenceEngine::forward_paged`].
+    pub fn new_paged_cache(&self, num_blocks: usize, block_size: usize) -> Result<PagedCache> {
+        Ok(PagedCache::new(
+            &self.config,
+            self.rope_theta,
+            num_blocks,
+            block_size,
+            self.kv_quantization,
+            self.vb.pp("rot"),
+        )?)
+    }
+
+    /// Run the chunks of several sequences, `tokens` holding their tokens one after the other,
+    /// and return the logits of the last token of each chunk with shape
+    /// `(chunks.len(), vocab_size)`.
+    pub(crate) fn forward_paged(
+        &self,
+        tokens: &[u32],
+        chunks: &[PagedChunk],
+        cache: &mut PagedCache,
+    ) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &self.device)?;
+        self.model.forward_paged(&input, chunks, cache)
+    }
+
+    /// Run `tokens` through the model starting at `index_pos` and return the logits for every
+    /// position, with shape `(tokens.len(), vocab_size)`.
+    pub(crate) fn forward(
+        &self,
+        tokens: &[u32],
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        let logits = self.model.forward(&input, index_pos, cache)?;
+        Ok(logits.squeeze(0)?)
+    }
+
+    /// Final hidden states of `tokens` placed at `index_pos`, with shape `(tokens.len(), dim)`.
+    pub(crate) fn hidden_states(
+        &self,
+        tokens: &[u32],
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        let hidden = self.model.hidden_states(&input, index_pos, cache)?;
+        Ok(hidden.squeeze(0)?)
+    }
+
+    /// Sample up to `max_tokens` tokens following `prompt`.
+    ///
+    /// `on_token` is called with every new token and can return `false` to stop the generation.
+    pub fn generate_tokens(
+        &self,
+        prompt: &[u32],
+        params: &SamplingParams,
+        max_tokens: usize,
+        mut on_token: impl FnMut(u32) -> Result<bool>,
+    ) -> Result<GenerationStats> {
+        if prompt.is_empty() {
+            anyhow::bail!("the prompt must contain at least one token");
+        }
+        let mut cache = self.new_cache()?;
+        let mut sampler = Sampler::new(params.seed);
+        let mut tokens = prompt.to_vec();
+        let mut index_pos = 0;
+
+        let start_gen = std::time::Instant::now();
+        let mut time_to_first_token = None;
+        for _ in 0..max_tokens {
+            if tokens.len() >= self.config.seq_len {
+                break;
+            }
+            let ctxt = &tokens[index_pos..];
+            let logits = self.forward(ctxt, index_pos, &mut cache)?;
+            let logits = logits.get(ctxt.len() - 1)?;
+            index_pos += ctxt.len();
+
+            let probs = params.probabilities(&logits, &tokens)?;
+            let next_token = sampler.sample(&probs)?;
+            tokens.push(next_token);
+            time_to_first_token.get_or_insert_with(|| start_gen.elapsed().as_secs_f64());
+            if !on_token(next_token)? {
+                break;
+            }
+        }
+
+        let elapsed = start_gen.elapsed().as_secs_f64();
+        Ok(GenerationStats {
+            prompt_tokens: prompt.len(),
+            generated_tokens: tokens.len() - prompt.len(),
+            elapsed,
+            time_to_first_token: time_to_first_token.unwrap_or(elapsed),
+            ..Default::default()
+        })
+    }
+
+    /// Generate a continuation of `prompt` and stream it to stdout. When a `draft` is given the
+    /// tokens are produced with speculative decoding.
     pub fn generate(
-        &mut self,
+        &self,
         prompt: &str,
         tokenizer: &Tokenizer,
         args: &Args,
-    ) -> anyhow::Result<(f64, Vec<String>)> {
+        draft: Option<Draft>,
+    ) -> anyhow::Result<(GenerationStats, Vec<String>)> {
         let mut rests = Vec::<String>::new();
 
-        let device = crate::device(args.cpu)?;
-
-        // Load model: safetensor
-        let config = ModelConfig::tiny_15m();
-        let tensors = safetensors::load(args.model.clone(), &device)?;
-        let vb = candle_nn::VarBuilder::from_tensors(tensors, DType::F32, &device);
-        let mut cache = model::Cache::new(true, &config, vb.pp("rot"))?;
-        let model = Model::Llama(model::Llama::load(vb, config.clone())?);
-
         println!("starting the inference loop");
-        let mut logits_processor =
-            LogitsProcessor::new(299792458, Some(args.temperature), args.top_p);
-        let mut index_pos = 0;
+        let params = SamplingParams::from(args);
 
         print!("{}", prompt);
-        let mut tokens = tokenizer.encode(prompt).map_err(E::msg)?;
+        let tokens = tokenizer.encode(prompt).map_err(E::msg)?;
         let mut tokenizer =
             crate::token_output_stream::TokenOutputStream::new(tokenizer.clone().tokenizer);
 
-        let start_gen = std::time::Instant::now();
-        for index in 0..args.max_tokens {
-            if tokens.len() >= config.seq_len {
-                break;
-            }
-            let context_size = if index > 0 { 1 } else { tokens.len() };
-            let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-            let input = Tensor::new(ctxt, &device)?.unsqueeze(0)?;
-            let logits = model.forward(&input, index_pos, &mut cache)?;
-            let logits = logits.i((0, logits.dim(1)? - 1))?;
-            let logits = if args.repeat_penalty == 1. || tokens.is_empty() {
-                logits
-            } else {
-                let start_at = tokens.len().saturating_sub(args.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    args.repeat_penalty,
-                    &tokens[start_at..],
-                )?
-            };
-            index_pos += ctxt.len();
-
-            let next_token = logits_processor.sample(&logits)?;
-            tokens.push(next_token);
+        let on_token = |next_token| {
             if let Some(t) = tokenizer.next_token(next_token)? {
                 print!("{t}");
                 std::io::stdout().flush()?;
             }
-        }
+            Ok(true)
+        };
+        let stats = match draft {
+            Some(draft) => self.generate_speculative(
+                draft,
+                &tokens,
+                &params,
+                args.max_tokens,
+                args.draft_tokens,
+                on_token,
+            )?,
+            None => self.generate_tokens(&tokens, &params, args.max_tokens, on_token)?,
+        };
         if let Some(rest) = tokenizer.decode_rest().map_err(E::msg)? {
             print!("{rest}");
             rests.push(rest);
         }
 
-        let dt = start_gen.elapsed();
-        println!(
-            "\n{} tokens generated ({:.2} token/s)\n",
-            tokens.len(),
-            tokens.len() as f64 / dt.as_secs_f64(),
-        );
+        println!("\n{stats}\n");
 
-        Ok((dt.as_secs_f64(), rests))
+        Ok((stats, rests))
     }
 
     pub fn sample_token(logits: &Tensor, _temperature: f32) -> anyhow::Result<u32> {
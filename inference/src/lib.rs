@@ -1,6 +1,25 @@
+pub mod admission;
 pub mod args;
+pub mod auth;
+pub mod batching;
+pub mod beam_search;
+pub mod bench;
+pub mod chat;
+pub mod completion;
 pub mod config;
+pub mod embedding;
+pub mod gguf;
 pub mod inference;
+pub mod metrics;
+pub mod model;
+pub mod openai;
+pub mod perplexity;
+pub mod registry;
+pub mod sampling;
+pub mod scheduler;
+pub mod scoring;
+pub mod server;
+pub mod speculative;
 pub mod token_output_stream;
 pub mod tokenizer;
 
@@ -1,8 +1,23 @@
 use {
     anyhow::Result,
     clap::Parser,
-    llama_rust::args::Args,
+    kv_cache::KvQuantization,
+    llama_rust::admission::AdmissionConfig,
+    llama_rust::args::{
+        Args, BenchServeArgs, Cli, Command, ModelSize, ModelfileArgs, PerplexityArgs, ServeArgs,
+    },
+    llama_rust::auth::ApiKeys,
+    llama_rust::batching::BatchEngine,
+    llama_rust::beam_search::BeamSearchParams,
+    llama_rust::bench::{self, BenchTarget},
+    llama_rust::metrics::Metrics,
+    llama_rust::registry::{ModelEntry, ModelRegistry, ModelsConfig, OLLAMA_PREFIX},
+    llama_rust::scheduler::SchedulerConfig,
+    llama_rust::server::{self, ServedModel, ServerState},
+    llama_rust::speculative::Draft,
     llama_rust::{inference::InferenceEngine, tokenizer::Tokenizer},
+    ollama::{LocalModel, ModelStore, Modelfile, Template},
+    std::{path::Path, sync::Arc, time::Duration},
 };
 
 /// Pretrain 分词模型
@@ -10,21 +25,296 @@ pub const PRETRAIN_TOKENIZER_BERT_BASE_CASED: &str = "bert-base-cased";
 pub const PRETRAIN_TOKENIZER_GPT2: &str = "gpt2";
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    println!("{:?}", args);
+    match cli.command {
+        Some(Command::Perplexity(args)) => perplexity(args),
+        Some(Command::Serve(args)) => serve(args),
+        Some(Command::Modelfile(args)) => modelfile(args),
+        Some(Command::BenchServe(args)) => bench_serve(args),
+        None => generate(cli.args.expect("clap requires the generation arguments")),
+    }
+}
 
+/// `name` is either a local `tokenizer.json` file or a Hugging Face model id.
+fn load_tokenizer_from(name: &str) -> Result<Tokenizer> {
     // 加载分词器
-    let tokenizer = Tokenizer::new(PRETRAIN_TOKENIZER_BERT_BASE_CASED)
-        .map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))?;
+    let tokenizer = if Path::new(name).is_file() {
+        Tokenizer::from_file(name)
+    } else {
+        Tokenizer::new(name)
+    }
+    .map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))?;
 
     println!("loaded tokenizer.");
+    Ok(tokenizer)
+}
+
+/// Load `model`, a checkpoint path or `ollama:<name>`, along with the Ollama model it resolved to.
+fn load_engine(
+    model: &str,
+    model_size: ModelSize,
+    cpu: bool,
+) -> Result<(InferenceEngine, Option<LocalModel>)> {
+    match model.strip_prefix(OLLAMA_PREFIX) {
+        Some(name) => {
+            let local = ModelStore::locate()?.load(name)?;
+            println!("resolved {} to {}", local.name, local.model.display());
+            let engine = InferenceEngine::load_gguf(&local.model, cpu)?;
+            Ok((engine, Some(local)))
+        }
+        None => Ok((
+            InferenceEngine::load_model(model, model_size.config(), cpu)?,
+            None,
+        )),
+    }
+}
+
+fn generate(args: Args) -> Result<()> {
+    println!("{:?}", args);
+
+    let tokenizer = load_tokenizer_from(
+        args.tokenizer
+            .as_deref()
+            .unwrap_or(PRETRAIN_TOKENIZER_BERT_BASE_CASED),
+    )?;
 
     // 执行推理并处理输出
-    let (_gen_time, ret) = InferenceEngine.generate(&args.prompt, &tokenizer, &args)?;
+    let (engine, _) = load_engine(&args.model, args.model_size, args.cpu)?;
+    let engine = engine.with_kv_quantization(args.kv_cache_quantization);
+    if let Some(beam_width) = args.beam_width {
+        let params = BeamSearchParams {
+            beam_width,
+            length_penalty: args.length_penalty,
+            early_stopping: args.early_stopping,
+            num_return: args.num_return,
+            max_tokens: args.max_tokens,
+            eos_token_id: Some(tokenizer.eos_token_id()),
+        };
+        let prompt = tokenizer.encode(&args.prompt)?;
+        for hypothesis in engine.beam_search(&prompt, &params)? {
+            println!(
+                "[score {:.4}] {}",
+                hypothesis.score,
+                tokenizer.decode(&hypothesis.tokens)?
+            );
+        }
+        return Ok(());
+    }
+    let draft_engine = match args.draft_model.as_ref() {
+        Some(draft_model) => {
+            let (draft_engine, _) = load_engine(draft_model, args.draft_model_size, args.cpu)?;
+            // A draft as large as the target costs as much as generating without it.
+            let size = |engine: &InferenceEngine| engine.config().dim * engine.config().n_layers;
+            if size(&draft_engine) >= size(&engine) {
+                anyhow::bail!(
+                    "the draft model must be smaller than the target model, see --draft-model-size"
+                );
+            }
+            Some(draft_engine)
+        }
+        None => None,
+    };
+    let draft = match (draft_engine.as_ref(), args.prompt_lookup_ngram) {
+        (Some(draft_engine), _) => Some(Draft::Model(draft_engine)),
+        (None, Some(ngram_size)) => Some(Draft::PromptLookup { ngram_size }),
+        (None, None) => None,
+    };
+    let (_stats, ret) = engine.generate(&args.prompt, &tokenizer, &args, draft)?;
 
     // 输出
     println!("Ret: {:?}", ret);
 
     Ok(())
 }
+
+fn perplexity(args: PerplexityArgs) -> Result<()> {
+    println!("{:?}", args);
+
+    let tokenizer = load_tokenizer_from(
+        args.tokenizer
+            .as_deref()
+            .unwrap_or(PRETRAIN_TOKENIZER_BERT_BASE_CASED),
+    )?;
+    let text = std::fs::read_to_string(&args.file)?;
+    let tokens = tokenizer.encode(&text)?;
+    println!("{} tokens in {}", tokens.len(), args.file);
+
+    let (engine, _) = load_engine(&args.model, args.model_size, args.cpu)?;
+    let report = engine.perplexity(&tokens, args.ctx, args.stride)?;
+    println!("{report}");
+
+    Ok(())
+}
+
+/// Model named by the `FROM` of a Modelfile: a file relative to the directory of the Modelfile
+/// when it exists, a model of the local Ollama store otherwise.
+fn modelfile_model(from: &str, modelfile: &Path) -> String {
+    let path = modelfile.parent().unwrap_or(Path::new("")).join(from);
+    if path.is_file() {
+        path.to_string_lossy().into_owned()
+    } else {
+        format!("{OLLAMA_PREFIX}{from}")
+    }
+}
+
+/// Load a model to serve along with the size of its weights, the estimate of its memory.
+/// `model` defaults to the `FROM` of the Modelfile, `model_id` to the checkpoint file stem or the
+/// Ollama model name.
+fn load_served_model(
+    entry: &ModelEntry,
+    cpu: bool,
+    kv_quantization: Option<KvQuantization>,
+) -> Result<(ServedModel, u64)> {
+    let tokenizer = load_tokenizer_from(
+        entry
+            .tokenizer
+            .as_deref()
+            .unwrap_or(PRETRAIN_TOKENIZER_BERT_BASE_CASED),
+    )?;
+    let modelfile = entry
+        .modelfile
+        .as_deref()
+        .map(|path| anyhow::Ok((Modelfile::from_file(path)?, path)))
+        .transpose()?;
+    let model = match (entry.model.as_str(), modelfile.as_ref()) {
+        ("", Some((modelfile, path))) => modelfile_model(&modelfile.from, Path::new(path)),
+        ("", None) => anyhow::bail!("no model for `{}`", entry.id),
+        (model, _) => model.to_string(),
+    };
+    let model_size = entry.model_size.unwrap_or(ModelSize::Tiny15m);
+    let (engine, local) = load_engine(&model, model_size, cpu)?;
+    let engine = engine.with_kv_quantization(kv_quantization);
+    let memory = match local.as_ref() {
+        Some(local) => std::fs::metadata(&local.model)?.len(),
+        None => std::fs::metadata(&model)?.len(),
+    };
+    // The Modelfile of an Ollama model provides what the given one leaves unset.
+    let modelfile = match (modelfile, local.as_ref()) {
+        (Some((modelfile, _)), Some(local)) => Some(modelfile.inherit(&local.modelfile())),
+        (Some((modelfile, _)), None) => Some(modelfile),
+        (None, Some(local)) => Some(local.modelfile()),
+        (None, None) => None,
+    };
+    let model_id = match (entry.id.as_str(), local) {
+        ("", Some(local)) => local.name.short(),
+        ("", None) => Path::new(&model)
+            .file_stem()
+            .map_or(model.clone(), |stem| stem.to_string_lossy().into_owned()),
+        (id, _) => id.to_string(),
+    };
+    let mut served = ServedModel::new(engine, tokenizer, model_id);
+    if let Some(modelfile) = modelfile {
+        served = served.with_modelfile(modelfile)?;
+    }
+    Ok((served, memory))
+}
+
+fn serve(args: ServeArgs) -> Result<()> {
+    println!("{:?}", args);
+
+    let cpu = args.cpu;
+    let kv_quantization = args.kv_cache_quantization;
+    let mut models = match args.models.as_deref() {
+        Some(path) => {
+            let config = ModelsConfig::from_file(path)?;
+            let budget = config.memory_budget();
+            let loader = Box::new(move |entry: &ModelEntry| {
+                println!("loading {}", entry.id);
+                load_served_model(entry, cpu, kv_quantization)
+            });
+            ModelRegistry::new(config.models, loader)?.with_budget(budget)
+        }
+        None => ModelRegistry::default(),
+    };
+    if args.model.is_some() || args.modelfile.is_some() {
+        let entry = ModelEntry {
+            id: args.model_id.unwrap_or_default(),
+            model: args.model.unwrap_or_default(),
+            model_size: Some(args.model_size),
+            tokenizer: args.tokenizer,
+            modelfile: args.modelfile,
+        };
+        let (served, memory) = load_served_model(&entry, cpu, kv_quantization)?;
+        models.insert(served.model_id.clone(), served, memory)?;
+    }
+    let mut state = ServerState::with_models(models)
+        .with_scheduler(SchedulerConfig {
+            max_batch_size: args.max_batch_size,
+            max_batch_tokens: args.max_batch_tokens,
+            ..Default::default()
+        })
+        .with_admission(AdmissionConfig {
+            max_queue: args.max_queue,
+            timeout: args
+                .request_timeout
+                .map(Duration::try_from_secs_f64)
+                .transpose()?,
+        });
+    if let Some(path) = args.api_keys.as_deref() {
+        state = state.with_api_keys(ApiKeys::from_file(path)?);
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(server::serve(&args.addr, state))
+}
+
+fn modelfile(args: ModelfileArgs) -> Result<()> {
+    let modelfile = Modelfile::from_file(&args.file)?;
+    if let Some(template) = modelfile.template.as_deref() {
+        Template::parse(template)?;
+    }
+    print!("{modelfile}");
+    Ok(())
+}
+
+fn bench_serve(args: BenchServeArgs) -> Result<()> {
+    println!("{:?}", args);
+
+    let mut requests = bench::read_requests(&args.file)?;
+    if let Some(limit) = args.limit {
+        requests.truncate(limit);
+    }
+    for request in requests.iter_mut() {
+        request.params.max_tokens.get_or_insert(args.max_tokens);
+    }
+    let arrivals = bench::arrivals(&requests, args.rate)?;
+    println!("replaying {} requests from {}", requests.len(), args.file);
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let target = match (args.url, args.model) {
+        (Some(url), _) => runtime.block_on(BenchTarget::http(
+            &url,
+            args.model_id,
+            args.api_key.as_deref(),
+        ))?,
+        (None, Some(model)) => {
+            let tokenizer = load_tokenizer_from(
+                args.tokenizer
+                    .as_deref()
+                    .unwrap_or(PRETRAIN_TOKENIZER_BERT_BASE_CASED),
+            )?;
+            let (engine, _) = load_engine(&model, args.model_size, args.cpu)?;
+            let engine = engine.with_kv_quantization(args.kv_cache_quantization);
+            // The engine thread sends its events through tokio channels, it needs no runtime.
+            BenchTarget::InProcess(Box::new(BatchEngine::spawn(
+                Arc::new(engine),
+                tokenizer,
+                SchedulerConfig {
+                    max_batch_size: args.max_batch_size,
+                    max_batch_tokens: args.max_batch_tokens,
+                    ..Default::default()
+                },
+                AdmissionConfig {
+                    max_queue: args.max_queue,
+                    timeout: None,
+                },
+                Arc::new(Metrics::default()),
+            )))
+        }
+        (None, None) => unreachable!("clap requires --model without --url"),
+    };
+    let report = runtime.block_on(bench::run(target, requests, arrivals));
+    println!("{report}");
+
+    Ok(())
+}
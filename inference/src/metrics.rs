@@ -0,0 +1,329 @@
+//! 服务指标
+//!
+//! Counters, gauges and histograms of the server, rendered in the Prometheus text exposition
+//! format by [`Metrics::render`]. The batch engine records the token counts and latencies, the
+//! HTTP layer the requests by endpoint and status.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Content type of [`Metrics::render`].
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Buckets in seconds of the time to first token and of the request latency.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Buckets in seconds of the time between two tokens.
+const INTER_TOKEN_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// 计数器
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 仪表
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn add(&self, delta: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + delta).to_bits())
+            });
+    }
+}
+
+/// 仪表份额, the part of a gauge one of its sources set
+///
+/// Several batch engines add up to the same gauges, each sets its own part, which is removed
+/// when it is dropped.
+#[derive(Debug)]
+pub struct GaugeShare<'a> {
+    gauge: &'a Gauge,
+    value: f64,
+}
+
+impl<'a> GaugeShare<'a> {
+    pub fn new(gauge: &'a Gauge) -> Self {
+        Self { gauge, value: 0.0 }
+    }
+
+    pub fn set(&mut self, value: f64) {
+        self.gauge.add(value - self.value);
+        self.value = value;
+    }
+}
+
+impl Drop for GaugeShare<'_> {
+    fn drop(&mut self) {
+        self.set(0.0);
+    }
+}
+
+#[derive(Debug)]
+struct HistogramState {
+    // Observations in each bucket, not cumulative, the last one is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+/// 直方图
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+impl Histogram {
+    /// `buckets` are the increasing upper bounds of the buckets, `+Inf` is implied.
+    pub fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            state: Mutex::new(HistogramState {
+                counts: vec![0; buckets.len() + 1],
+                sum: 0.0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.buckets.len());
+        let mut state = self.state.lock().expect("histogram lock poisoned");
+        state.counts[bucket] += 1;
+        state.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        let state = self.state.lock().expect("histogram lock poisoned");
+        state.counts.iter().sum()
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let state = self.state.lock().expect("histogram lock poisoned");
+        let mut cumulative = 0;
+        let bounds = self.buckets.iter().map(|bound| bound.to_string());
+        for (le, count) in bounds
+            .chain(std::iter::once("+Inf".to_string()))
+            .zip(state.counts.iter())
+        {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_sum {}", state.sum);
+        let _ = writeln!(out, "{name}_count {cumulative}");
+    }
+}
+
+/// 服务指标
+#[derive(Debug)]
+pub struct Metrics {
+    // Keyed by endpoint and status code.
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    /// Generations waiting for room in the batch.
+    pub queue_depth: Gauge,
+    /// Generations in the batch.
+    pub running_sequences: Gauge,
+    /// Token slots of the KV blocks held by running generations.
+    pub kv_cache_used: Gauge,
+    /// Token slots of the KV blocks.
+    pub kv_cache_capacity: Gauge,
+    pub prompt_tokens: Counter,
+    pub generation_tokens: Counter,
+    /// Prompts that found KV blocks in the prefix cache.
+    pub prefix_cache_hits: Counter,
+    /// Prompts that found none.
+    pub prefix_cache_misses: Counter,
+    /// Prompt tokens looked up in the prefix cache.
+    pub prefix_cache_query_tokens: Counter,
+    /// Prompt tokens found in the prefix cache, their prefill is skipped.
+    pub prefix_cache_hit_tokens: Counter,
+    /// Seconds from the submission of a generation to its first token.
+    pub time_to_first_token: Histogram,
+    /// Seconds between two tokens of a generation.
+    pub inter_token_latency: Histogram,
+    /// Seconds from the submission of a generation to its end.
+    pub e2e_request_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: Mutex::default(),
+            queue_depth: Gauge::default(),
+            running_sequences: Gauge::default(),
+            kv_cache_used: Gauge::default(),
+            kv_cache_capacity: Gauge::default(),
+            prompt_tokens: Counter::default(),
+            generation_tokens: Counter::default(),
+            prefix_cache_hits: Counter::default(),
+            prefix_cache_misses: Counter::default(),
+            prefix_cache_query_tokens: Counter::default(),
+            prefix_cache_hit_tokens: Counter::default(),
+            time_to_first_token: Histogram::new(LATENCY_BUCKETS),
+            inter_token_latency: Histogram::new(INTER_TOKEN_BUCKETS),
+            e2e_request_latency: Histogram::new(LATENCY_BUCKETS),
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+impl Metrics {
+    /// Count a response of `endpoint`, the route pattern rather than the path so that request ids
+    /// do not create new series.
+    pub fn record_request(&self, endpoint: &str, status: u16) {
+        let mut requests = self.requests.lock().expect("metrics lock poisoned");
+        *requests.entry((endpoint.to_string(), status)).or_default() += 1;
+    }
+
+    /// All the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let name = "llama_requests_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "HTTP requests by endpoint and status.",
+        );
+        for ((endpoint, status), count) in
+            self.requests.lock().expect("metrics lock poisoned").iter()
+        {
+            let endpoint = endpoint.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                out,
+                "{name}{{endpoint=\"{endpoint}\",status=\"{status}\"}} {count}"
+            );
+        }
+        let capacity = self.kv_cache_capacity.get();
+        let utilization = if capacity > 0.0 {
+            self.kv_cache_used.get() / capacity
+        } else {
+            0.0
+        };
+        let query_tokens = self.prefix_cache_query_tokens.get();
+        let hit_rate = if query_tokens > 0 {
+            self.prefix_cache_hit_tokens.get() as f64 / query_tokens as f64
+        } else {
+            0.0
+        };
+        let gauges = [
+            (
+                "llama_queue_depth",
+                "Generations waiting for the batch.",
+                self.queue_depth.get(),
+            ),
+            (
+                "llama_running_sequences",
+                "Generations in the batch.",
+                self.running_sequences.get(),
+            ),
+            (
+                "llama_kv_cache_utilization",
+                "Share of the KV cache slots in use.",
+                utilization,
+            ),
+            (
+                "llama_prefix_cache_hit_rate",
+                "Share of the prompt tokens found in the prefix cache.",
+                hit_rate,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+        let counters = [
+            (
+                "llama_prompt_tokens_total",
+                "Prompt tokens read.",
+                &self.prompt_tokens,
+            ),
+            (
+                "llama_generation_tokens_total",
+                "Tokens generated.",
+                &self.generation_tokens,
+            ),
+            (
+                "llama_prefix_cache_hits_total",
+                "Prompts that found KV blocks in the prefix cache.",
+                &self.prefix_cache_hits,
+            ),
+            (
+                "llama_prefix_cache_misses_total",
+                "Prompts that found no KV block in the prefix cache.",
+                &self.prefix_cache_misses,
+            ),
+            (
+                "llama_prefix_cache_query_tokens_total",
+                "Prompt tokens looked up in the prefix cache.",
+                &self.prefix_cache_query_tokens,
+            ),
+            (
+                "llama_prefix_cache_hit_tokens_total",
+                "Prompt tokens found in the prefix cache.",
+                &self.prefix_cache_hit_tokens,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{name} {}", counter.get());
+        }
+        let histograms = [
+            (
+                "llama_time_to_first_token_seconds",
+                "Time to the first token of a generation.",
+                &self.time_to_first_token,
+            ),
+            (
+                "llama_inter_token_latency_seconds",
+                "Time between two tokens of a generation.",
+                &self.inter_token_latency,
+            ),
+            (
+                "llama_e2e_request_latency_seconds",
+                "Time from the submission to the end of a generation.",
+                &self.e2e_request_latency,
+            ),
+        ];
+        for (name, help, histogram) in histograms {
+            header(&mut out, name, "histogram", help);
+            histogram.render(&mut out, name);
+        }
+        out
+    }
+}
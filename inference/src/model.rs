@@ -0,0 +1,754 @@
+/*
+ * Adapted from
+ * https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/llama2_c.rs
+ * Copyright (c) 2023, The Huggingface team.
+ *
+ * The key/value cache is backed by the `kv-cache` crate so that it can be rewound or quantized,
+ * and the attention mask takes the cached prefix into account so that several tokens can be
+ * processed on top of an existing cache. The same model can also be loaded from a GGUF file, in
+ * which case the projections keep their quantized weights. Many independent sequences can be
+ * run together on top of a `PagedCache`, each at its own position and through its own block
+ * table, so that sequences can share the blocks of a common prompt.
+ */
+
+use {
+    candle_core::{
+        D, DType, Device, Result, Tensor,
+        quantized::{QMatMul, gguf_file},
+    },
+    candle_nn::{
+        Embedding, Linear, Module, RmsNorm, VarBuilder, embedding, linear_no_bias as linear,
+        rms_norm,
+    },
+    kv_cache::{KvCache, KvQuantization, QuantizedKvCache},
+    paged_attention::{
+        BlockCopy, BlockTable, PagedKvCache, paged_attention, paged_prefill_attention,
+    },
+    std::{
+        collections::HashMap,
+        io::{Read, Seek},
+    },
+};
+
+pub use candle_transformers::models::llama2_c::Config;
+
+/// Key/value cache of a layer, quantized or in the dtype of the model.
+#[derive(Debug, Clone)]
+enum LayerCache {
+    Full(KvCache),
+    Quantized(QuantizedKvCache),
+}
+
+impl LayerCache {
+    fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        match self {
+            Self::Full(kv) => kv.append(k, v),
+            Self::Quantized(kv) => kv.append(k, v),
+        }
+    }
+
+    fn current_seq_len(&self) -> usize {
+        match self {
+            Self::Full(kv) => kv.current_seq_len(),
+            Self::Quantized(kv) => kv.current_seq_len(),
+        }
+    }
+
+    fn truncate(&mut self, seq_len: usize) {
+        match self {
+            Self::Full(kv) => kv.truncate(seq_len),
+            Self::Quantized(kv) => kv.truncate(seq_len),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Self::Full(kv) => kv.reset(),
+            Self::Quantized(kv) => kv.reset(),
+        }
+    }
+
+    fn fork(&self) -> Result<Self> {
+        Ok(match self {
+            Self::Full(kv) => Self::Full(kv.fork()?),
+            Self::Quantized(kv) => Self::Quantized(kv.fork()?),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    masks: HashMap<(usize, usize), Tensor>,
+    kvs: Vec<LayerCache>,
+    seq_len: usize,
+    cos: Tensor,
+    sin: Tensor,
+    device: Device,
+}
+
+impl Cache {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Self::with_rope_theta(cfg, 10000., vb)
+    }
+
+    /// Like [`Cache::new`], the rotary tables missing from `vb` are computed with the base
+    /// frequency `rope_theta`.
+    pub fn with_rope_theta(cfg: &Config, rope_theta: f32, vb: VarBuilder) -> Result<Self> {
+        let (cos, sin) = rope_tables(cfg, rope_theta, &vb)?;
+        // k and v are stored as (b_sz, seq_len, n_kv_heads, head_dim).
+        let kvs = (0..cfg.n_layers)
+            .map(|_| LayerCache::Full(KvCache::new(1, cfg.seq_len)))
+            .collect();
+        Ok(Self {
+            masks: HashMap::new(),
+            kvs,
+            seq_len: cfg.seq_len,
+            cos,
+            sin,
+            device: vb.device().clone(),
+        })
+    }
+
+    /// Store the keys and values quantized, the cache must be empty.
+    pub fn with_kv_quantization(mut self, quantization: KvQuantization) -> Self {
+        for kv in self.kvs.iter_mut() {
+            *kv = LayerCache::Quantized(QuantizedKvCache::new(1, self.seq_len, quantization));
+        }
+        self
+    }
+
+    /// Number of positions currently held by the cache.
+    pub fn current_seq_len(&self) -> usize {
+        self.kvs.first().map_or(0, |kv| kv.current_seq_len())
+    }
+
+    /// Rewinds the cache to its first `seq_len` positions.
+    pub fn truncate(&mut self, seq_len: usize) {
+        for kv in self.kvs.iter_mut() {
+            kv.truncate(seq_len);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for kv in self.kvs.iter_mut() {
+            kv.reset();
+        }
+    }
+
+    /// Deep copy of the cache, appending to the fork leaves `self` untouched.
+    pub fn fork(&self) -> Result<Self> {
+        let kvs = self
+            .kvs
+            .iter()
+            .map(|kv| kv.fork())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            masks: self.masks.clone(),
+            kvs,
+            seq_len: self.seq_len,
+            cos: self.cos.clone(),
+            sin: self.sin.clone(),
+            device: self.device.clone(),
+        })
+    }
+
+    /// Mask for `t` new tokens attending to `index_pos` cached positions and to themselves.
+    fn mask(&mut self, t: usize, index_pos: usize) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&(t, index_pos)) {
+            Ok(mask.clone())
+        } else {
+            let mask: Vec<_> = (0..t)
+                .flat_map(|i| (0..index_pos + t).map(move |j| u8::from(j > i + index_pos)))
+                .collect();
+            let mask = Tensor::from_slice(&mask, (t, index_pos + t), &self.device)?;
+            self.masks.insert((t, index_pos), mask.clone());
+            Ok(mask)
+        }
+    }
+}
+
+/// 分页缓存
+///
+/// Key/value cache of many sequences in a pool of blocks of `block_size` tokens, one
+/// [`PagedKvCache`] per layer. The tokens of a sequence go to the slots of its [`BlockTable`],
+/// handed out by a [`paged_attention::BlockManager`], so sequences can share blocks.
+#[derive(Debug, Clone)]
+pub struct PagedCache {
+    kvs: Vec<PagedKvCache>,
+    cos: Tensor,
+    sin: Tensor,
+}
+
+impl PagedCache {
+    /// `num_blocks` empty blocks of `block_size` tokens, their keys and values stored with
+    /// `quantization` when given. The rotary tables missing from `vb` are computed with the base
+    /// frequency `rope_theta`.
+    pub fn new(
+        cfg: &Config,
+        rope_theta: f32,
+        num_blocks: usize,
+        block_size: usize,
+        quantization: Option<KvQuantization>,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let (cos, sin) = rope_tables(cfg, rope_theta, &vb)?;
+        // k and v are stored as (num_blocks * block_size, n_kv_heads, head_dim).
+        let (n_kv_heads, head_size) = (cfg.n_kv_heads, cfg.head_size());
+        let kvs = (0..cfg.n_layers)
+            .map(|_| match quantization {
+                Some(quantization) => PagedKvCache::quantized(
+                    num_blocks,
+                    block_size,
+                    n_kv_heads,
+                    head_size,
+                    quantization,
+                    DType::F32,
+                    vb.device(),
+                ),
+                None => PagedKvCache::new(
+                    num_blocks,
+                    block_size,
+                    n_kv_heads,
+                    head_size,
+                    DType::F32,
+                    vb.device(),
+                ),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { kvs, cos, sin })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.kvs.first().map_or(1, PagedKvCache::block_size)
+    }
+
+    /// Make a copy asked by [`paged_attention::BlockManager::append_slots`] in every layer.
+    pub fn copy_block(&mut self, copy: BlockCopy) -> Result<()> {
+        for kv in self.kvs.iter_mut() {
+            kv.copy_block(copy)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tokens of one sequence run by [`Llama::forward_paged`].
+#[derive(Debug, Clone, Copy)]
+pub struct PagedChunk<'a> {
+    /// Block table of the sequence, with slots for the tokens of the chunk.
+    pub table: &'a BlockTable,
+    /// Position of the first token of the chunk, the positions before it are in the cache.
+    pub start: usize,
+    pub len: usize,
+}
+
+/// Per step state of a paged forward pass, shared by the blocks.
+struct PagedStep<'a> {
+    cos: Tensor,
+    sin: Tensor,
+    slots: Vec<usize>,
+    chunks: &'a [PagedChunk<'a>],
+}
+
+/// Rotary tables of shape `(seq_len, head_dim / 2, 1)`, the ones shipped with the checkpoint in
+/// `vb` take precedence over the ones computed from `rope_theta`.
+fn rope_tables(cfg: &Config, rope_theta: f32, vb: &VarBuilder) -> Result<(Tensor, Tensor)> {
+    let n_elem = cfg.dim / cfg.n_heads;
+    let theta: Vec<_> = (0..n_elem)
+        .step_by(2)
+        .map(|i| 1f32 / rope_theta.powf(i as f32 / n_elem as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), vb.device())?;
+    let idx_theta = Tensor::arange(0, cfg.seq_len as u32, vb.device())?
+        .to_dtype(DType::F32)?
+        .reshape((cfg.seq_len, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let precomputed_cos = idx_theta.cos()?;
+    let precomputed_sin = idx_theta.sin()?;
+
+    let freq_cis_real = if vb.contains_tensor("freq_cis_real") {
+        vb.get((cfg.seq_len, cfg.head_size() / 2), "freq_cis_real")?
+    } else {
+        precomputed_cos
+    };
+    let freq_cis_imag = if vb.contains_tensor("freq_cis_imag") {
+        vb.get((cfg.seq_len, cfg.head_size() / 2), "freq_cis_imag")?
+    } else {
+        precomputed_sin
+    };
+    let cos = freq_cis_real.reshape((cfg.seq_len, cfg.head_size() / 2, 1))?;
+    let sin = freq_cis_imag.reshape((cfg.seq_len, cfg.head_size() / 2, 1))?;
+    Ok((cos, sin))
+}
+
+fn silu(xs: &Tensor) -> Result<Tensor> {
+    xs / (xs.neg()?.exp()? + 1.0)?
+}
+
+/// Weights of a GGUF file, read on demand.
+struct GgufReader<'a, R> {
+    content: &'a gguf_file::Content,
+    reader: &'a mut R,
+    device: &'a Device,
+}
+
+impl<R: Read + Seek> GgufReader<'_, R> {
+    fn contains(&self, name: &str) -> bool {
+        self.content.tensor_infos.contains_key(name)
+    }
+
+    fn proj(&mut self, name: &str) -> Result<Proj> {
+        let qtensor = self.content.tensor(self.reader, name, self.device)?;
+        Ok(Proj::Quantized(QMatMul::from_qtensor(qtensor)?))
+    }
+
+    fn tensor(&mut self, name: &str) -> Result<Tensor> {
+        self.content
+            .tensor(self.reader, name, self.device)?
+            .dequantize(self.device)
+    }
+
+    fn rms_norm(&mut self, name: &str, eps: f64) -> Result<RmsNorm> {
+        Ok(RmsNorm::new(self.tensor(name)?, eps))
+    }
+}
+
+/// Linear layer without bias, dense for safetensors checkpoints and quantized for GGUF files.
+#[derive(Debug, Clone)]
+enum Proj {
+    Dense(Linear),
+    Quantized(QMatMul),
+}
+
+impl Proj {
+    fn load(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Self> {
+        Ok(Self::Dense(linear(in_dim, out_dim, vb)?))
+    }
+}
+
+impl Module for Proj {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Dense(l) => l.forward(xs),
+            Self::Quantized(q) => q.forward(xs),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CausalSelfAttention {
+    q_proj: Proj,
+    k_proj: Proj,
+    v_proj: Proj,
+    o_proj: Proj,
+    n_head: usize,
+    n_key_value_head: usize,
+    head_dim: usize,
+}
+
+impl CausalSelfAttention {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize, cache: &Cache) -> Result<Tensor> {
+        let (b_sz, seq_len, _, n_embd) = x.dims4()?;
+        let cos = cache.cos.narrow(0, index_pos, seq_len)?;
+        let sin = cache.sin.narrow(0, index_pos, seq_len)?;
+        let cos = cos.unsqueeze(1)?;
+        let sin = sin.unsqueeze(1)?;
+        let cos = cos.broadcast_as((b_sz, seq_len, 1, n_embd / 2, 1))?;
+        let sin = sin.broadcast_as((b_sz, seq_len, 1, n_embd / 2, 1))?;
+        Self::rotate(x, &cos, &sin)
+    }
+
+    /// Rotates the interleaved pairs of `x` by the angles of `cos` and `sin`, which have shape
+    /// `(b_sz, seq_len, 1, head_dim / 2, 1)`.
+    fn rotate(x: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+        let (b_sz, seq_len, h, n_embd) = x.dims4()?;
+        let x = x.reshape((b_sz, seq_len, h, n_embd / 2, 2))?;
+        let x0 = x.narrow(D::Minus1, 0, 1)?;
+        let x1 = x.narrow(D::Minus1, 1, 1)?;
+        let dst0 = (x0.broadcast_mul(cos)? - x1.broadcast_mul(sin)?)?;
+        let dst1 = (x0.broadcast_mul(sin)? + x1.broadcast_mul(cos)?)?;
+        let rope = Tensor::cat(&[&dst0, &dst1], D::Minus1)?.reshape((b_sz, seq_len, h, n_embd))?;
+        Ok(rope)
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let q = self.q_proj.forward(x)?;
+        let k = self.k_proj.forward(x)?;
+        let v = self.v_proj.forward(x)?;
+
+        let q = q.reshape((b_sz, seq_len, self.n_head, self.head_dim))?;
+        let k = k.reshape((b_sz, seq_len, self.n_key_value_head, self.head_dim))?;
+        let v = v.reshape((b_sz, seq_len, self.n_key_value_head, self.head_dim))?;
+
+        let q = self.apply_rotary_emb(&q, index_pos, cache)?;
+        let k = self.apply_rotary_emb(&k, index_pos, cache)?;
+
+        let (k, v) = cache.kvs[block_idx].append(&k.contiguous()?, &v.contiguous()?)?;
+
+        let k = self.repeat_kv(k)?;
+        let v = self.repeat_kv(v)?;
+
+        let q = q.transpose(1, 2)?.contiguous()?;
+        let k = k.transpose(1, 2)?.contiguous()?;
+        let v = v.transpose(1, 2)?.contiguous()?;
+
+        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let att = if seq_len <= 1 {
+            att
+        } else {
+            let mask = cache.mask(seq_len, index_pos)?.broadcast_as(att.shape())?;
+            masked_fill(&att, &mask, f32::NEG_INFINITY)?
+        };
+        let att = candle_nn::ops::softmax(&att, D::Minus1)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        let y = att.matmul(&v.contiguous()?)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        let y = self.o_proj.forward(&y)?;
+        Ok(y)
+    }
+
+    /// Attention of the chunks of several sequences, each over its own cached positions. `x`
+    /// holds the tokens of all the chunks one after the other.
+    fn forward_paged(
+        &self,
+        x: &Tensor,
+        step: &PagedStep,
+        cache: &mut PagedKvCache,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let q = self.q_proj.forward(x)?;
+        let k = self.k_proj.forward(x)?;
+        let v = self.v_proj.forward(x)?;
+
+        let q = q.reshape((b_sz, seq_len, self.n_head, self.head_dim))?;
+        let k = k.reshape((b_sz, seq_len, self.n_key_value_head, self.head_dim))?;
+        let v = v.reshape((b_sz, seq_len, self.n_key_value_head, self.head_dim))?;
+
+        let q = Self::rotate(&q, &step.cos, &step.sin)?.squeeze(0)?;
+        let k = Self::rotate(&k, &step.cos, &step.sin)?.squeeze(0)?;
+        cache.write(&step.slots, &k.contiguous()?, &v.squeeze(0)?.contiguous()?)?;
+
+        let decode = step
+            .chunks
+            .iter()
+            .all(|chunk| chunk.len == 1 && chunk.start + 1 == chunk.table.num_tokens());
+        let y = if decode {
+            let tables: Vec<_> = step.chunks.iter().map(|chunk| chunk.table).collect();
+            paged_attention(&q.contiguous()?, cache, &tables)?
+        } else {
+            let mut offset = 0;
+            let mut outputs = Vec::with_capacity(step.chunks.len());
+            for chunk in step.chunks.iter() {
+                let q = q.narrow(0, offset, chunk.len)?;
+                let context = chunk.start + chunk.len;
+                outputs.push(paged_prefill_attention(&q, cache, chunk.table, context)?);
+                offset += chunk.len;
+            }
+            Tensor::cat(&outputs, 0)?
+        };
+        let y = y.reshape((b_sz, seq_len, n_embd))?;
+        self.o_proj.forward(&y)
+    }
+
+    fn repeat_kv(&self, x: Tensor) -> Result<Tensor> {
+        let n_rep = self.n_head / self.n_key_value_head;
+        if n_rep == 1 {
+            Ok(x)
+        } else {
+            let (b_sz, seq_len, n_kv_head, head_dim) = x.dims4()?;
+            let x = x
+                .unsqueeze(3)?
+                .expand((b_sz, seq_len, n_kv_head, n_rep, head_dim))?
+                .reshape((b_sz, seq_len, n_kv_head * n_rep, head_dim))?;
+            Ok(x)
+        }
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        let size_in = cfg.dim;
+        let size_q = (cfg.dim / cfg.n_heads) * cfg.n_heads;
+        let size_kv = (cfg.dim / cfg.n_heads) * cfg.n_kv_heads;
+        let q_proj = Proj::load(size_in, size_q, vb.pp("q_proj"))?;
+        let k_proj = Proj::load(size_in, size_kv, vb.pp("k_proj"))?;
+        let v_proj = Proj::load(size_in, size_kv, vb.pp("v_proj"))?;
+        let o_proj = Proj::load(size_q, size_in, vb.pp("o_proj"))?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            n_head: cfg.n_heads,
+            n_key_value_head: cfg.n_kv_heads,
+            head_dim: cfg.dim / cfg.n_heads,
+        })
+    }
+
+    fn load_gguf<R: Read + Seek>(
+        gguf: &mut GgufReader<R>,
+        prefix: &str,
+        cfg: &Config,
+    ) -> Result<Self> {
+        Ok(Self {
+            q_proj: gguf.proj(&format!("{prefix}.attn_q.weight"))?,
+            k_proj: gguf.proj(&format!("{prefix}.attn_k.weight"))?,
+            v_proj: gguf.proj(&format!("{prefix}.attn_v.weight"))?,
+            o_proj: gguf.proj(&format!("{prefix}.attn_output.weight"))?,
+            n_head: cfg.n_heads,
+            n_key_value_head: cfg.n_kv_heads,
+            head_dim: cfg.dim / cfg.n_heads,
+        })
+    }
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
+    let m = mask.where_cond(&on_true, on_false)?;
+    Ok(m)
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    c_fc1: Proj,
+    c_fc2: Proj,
+    c_proj: Proj,
+}
+
+impl Mlp {
+    fn new(c_fc1: Proj, c_fc2: Proj, c_proj: Proj) -> Self {
+        Self {
+            c_fc1,
+            c_fc2,
+            c_proj,
+        }
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let x = (silu(&self.c_fc1.forward(x)?)? * self.c_fc2.forward(x)?)?;
+        self.c_proj.forward(&x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        let h_size = cfg.dim;
+        let i_size = cfg.hidden_dim;
+        let c_fc1 = Proj::load(h_size, i_size, vb.pp("gate_proj"))?;
+        let c_fc2 = Proj::load(h_size, i_size, vb.pp("up_proj"))?;
+        let c_proj = Proj::load(i_size, h_size, vb.pp("down_proj"))?;
+        Ok(Self::new(c_fc1, c_fc2, c_proj))
+    }
+
+    fn load_gguf<R: Read + Seek>(gguf: &mut GgufReader<R>, prefix: &str) -> Result<Self> {
+        let c_fc1 = gguf.proj(&format!("{prefix}.ffn_gate.weight"))?;
+        let c_fc2 = gguf.proj(&format!("{prefix}.ffn_up.weight"))?;
+        let c_proj = gguf.proj(&format!("{prefix}.ffn_down.weight"))?;
+        Ok(Self::new(c_fc1, c_fc2, c_proj))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    rms_1: RmsNorm,
+    attn: CausalSelfAttention,
+    rms_2: RmsNorm,
+    mlp: Mlp,
+}
+
+impl Block {
+    fn new(rms_1: RmsNorm, attn: CausalSelfAttention, rms_2: RmsNorm, mlp: Mlp) -> Self {
+        Self {
+            rms_1,
+            attn,
+            rms_2,
+            mlp,
+        }
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let residual = x;
+        let x = self.rms_1.forward(x)?;
+        let x = (self.attn.forward(&x, index_pos, block_idx, cache)? + residual)?;
+        let residual = &x;
+        let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
+        Ok(x)
+    }
+
+    fn forward_paged(
+        &self,
+        x: &Tensor,
+        step: &PagedStep,
+        cache: &mut PagedKvCache,
+    ) -> Result<Tensor> {
+        let residual = x;
+        let x = self.rms_1.forward(x)?;
+        let x = (self.attn.forward_paged(&x, step, cache)? + residual)?;
+        let residual = &x;
+        let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
+        Ok(x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        let attn = CausalSelfAttention::load(vb.pp("self_attn"), cfg)?;
+        let mlp = Mlp::load(vb.pp("mlp"), cfg)?;
+        let input_layernorm = rms_norm(cfg.dim, cfg.norm_eps, vb.pp("input_layernorm"))?;
+        let post_attention_layernorm =
+            rms_norm(cfg.dim, cfg.norm_eps, vb.pp("post_attention_layernorm"))?;
+        Ok(Self::new(
+            input_layernorm,
+            attn,
+            post_attention_layernorm,
+            mlp,
+        ))
+    }
+
+    fn load_gguf<R: Read + Seek>(gguf: &mut GgufReader<R>, i: usize, cfg: &Config) -> Result<Self> {
+        let prefix = format!("blk.{i}");
+        let attn = CausalSelfAttention::load_gguf(gguf, &prefix, cfg)?;
+        let mlp = Mlp::load_gguf(gguf, &prefix)?;
+        let attn_norm = gguf.rms_norm(&format!("{prefix}.attn_norm.weight"), cfg.norm_eps)?;
+        let ffn_norm = gguf.rms_norm(&format!("{prefix}.ffn_norm.weight"), cfg.norm_eps)?;
+        Ok(Self::new(attn_norm, attn, ffn_norm, mlp))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Llama {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: RmsNorm,
+    lm_head: Proj,
+    pub config: Config,
+}
+
+impl Llama {
+    /// Logits for every position of `x`, the tokens are placed at `index_pos` and following.
+    pub fn forward(&self, x: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let x = self.hidden_states(x, index_pos, cache)?;
+        let logits = self.lm_head.forward(&x)?;
+        logits.to_dtype(DType::F32)
+    }
+
+    /// Normalized output of the last block for every position of `x`, with shape
+    /// `(b, seq_len, dim)`.
+    pub fn hidden_states(&self, x: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let (_b_sz, _seq_len) = x.dims2()?;
+        let mut x = self.wte.forward(x)?;
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            x = block.forward(&x, index_pos, block_idx, cache)?;
+        }
+        self.ln_f.forward(&x)
+    }
+
+    /// Logits of the last token of each chunk, with shape `(chunks.len(), vocab_size)`. `x`
+    /// holds the tokens of all the chunks one after the other, their keys and values are written
+    /// to the slots of the chunk positions in the block tables, which must have room for them.
+    pub fn forward_paged(
+        &self,
+        x: &Tensor,
+        chunks: &[PagedChunk],
+        cache: &mut PagedCache,
+    ) -> Result<Tensor> {
+        let block_size = cache.block_size();
+        let context = cache.cos.dim(0)?;
+        let mut positions = Vec::with_capacity(x.dim(0)?);
+        let mut slots = Vec::with_capacity(x.dim(0)?);
+        let mut last = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            let end = chunk.start + chunk.len;
+            if chunk.len == 0 || end > chunk.table.num_tokens() || end > context {
+                candle_core::bail!(
+                    "a chunk of {} tokens at {} does not fit a sequence of {} slots",
+                    chunk.len,
+                    chunk.start,
+                    chunk.table.num_tokens().min(context)
+                );
+            }
+            positions.extend((chunk.start..end).map(|pos| pos as u32));
+            slots.extend((chunk.start..end).map(|pos| chunk.table.slot(pos, block_size)));
+            last.push(positions.len() as u32 - 1);
+        }
+        if positions.len() != x.dim(0)? {
+            candle_core::bail!("{} tokens for chunks of {}", x.dim(0)?, positions.len());
+        }
+        let num_tokens = positions.len();
+        let positions = Tensor::new(positions, cache.cos.device())?;
+        let (_, half, _) = cache.cos.dims3()?;
+        let shape = (1, num_tokens, 1, half, 1);
+        let cos = cache.cos.index_select(&positions, 0)?.reshape(shape)?;
+        let sin = cache.sin.index_select(&positions, 0)?.reshape(shape)?;
+        let step = PagedStep {
+            cos,
+            sin,
+            slots,
+            chunks,
+        };
+        let mut x = self.wte.forward(&x.unsqueeze(0)?)?;
+        for (block, kv) in self.blocks.iter().zip(cache.kvs.iter_mut()) {
+            x = block.forward_paged(&x, &step, kv)?;
+        }
+        let x = self.ln_f.forward(&x.squeeze(0)?)?;
+        let last = Tensor::new(last, x.device())?;
+        let logits = self.lm_head.forward(&x.index_select(&last, 0)?)?;
+        logits.to_dtype(DType::F32)
+    }
+
+    pub fn load(vb: VarBuilder, cfg: Config) -> Result<Self> {
+        let wte = embedding(cfg.vocab_size, cfg.dim, vb.pp("model.embed_tokens"))?;
+        let lm_head = Proj::load(cfg.dim, cfg.vocab_size, vb.pp("lm_head"))?;
+        let ln_f = rms_norm(cfg.dim, cfg.norm_eps, vb.pp("model.norm"))?;
+        let blocks = (0..cfg.n_layers)
+            .map(|i| Block::load(vb.pp(format!("model.layers.{i}")), &cfg))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            config: cfg,
+        })
+    }
+
+    /// Load the weights of a GGUF file following the llama.cpp tensor names, `cfg` is usually
+    /// read from the metadata of the same file. The output projection falls back to the token
+    /// embeddings when the file ties them.
+    pub fn load_gguf<R: Read + Seek>(
+        content: &gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        cfg: Config,
+    ) -> Result<Self> {
+        let mut gguf = GgufReader {
+            content,
+            reader,
+            device,
+        };
+        let wte = Embedding::new(gguf.tensor("token_embd.weight")?, cfg.dim);
+        let lm_head = if gguf.contains("output.weight") {
+            gguf.proj("output.weight")?
+        } else {
+            gguf.proj("token_embd.weight")?
+        };
+        let ln_f = gguf.rms_norm("output_norm.weight", cfg.norm_eps)?;
+        let blocks = (0..cfg.n_layers)
+            .map(|i| Block::load_gguf(&mut gguf, i, &cfg))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            config: cfg,
+        })
+    }
+}
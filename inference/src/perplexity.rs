@@ -0,0 +1,145 @@
+use {
+    crate::inference::InferenceEngine,
+    anyhow::{Result, bail},
+    candle_core::{D, DType, Tensor},
+    std::fmt,
+};
+
+/// 单个评估窗口的困惑度
+#[derive(Debug, Clone)]
+pub struct ChunkPerplexity {
+    /// Index of the first token of the window in the corpus.
+    pub start: usize,
+    /// Number of tokens whose log-likelihood was accumulated for this window.
+    pub scored_tokens: usize,
+    /// Sum of the negative log-likelihoods of the scored tokens.
+    pub nll: f64,
+}
+
+impl ChunkPerplexity {
+    pub fn perplexity(&self) -> f64 {
+        (self.nll / self.scored_tokens as f64).exp()
+    }
+}
+
+/// 困惑度评估结果
+#[derive(Debug, Clone)]
+pub struct PerplexityReport {
+    pub chunks: Vec<ChunkPerplexity>,
+    /// Number of tokens fed through the model, overlapping context included.
+    pub evaluated_tokens: usize,
+    /// Wall clock time spent in the forward passes, in seconds.
+    pub elapsed: f64,
+}
+
+impl PerplexityReport {
+    pub fn scored_tokens(&self) -> usize {
+        self.chunks.iter().map(|c| c.scored_tokens).sum()
+    }
+
+    pub fn nll(&self) -> f64 {
+        self.chunks.iter().map(|c| c.nll).sum()
+    }
+
+    pub fn perplexity(&self) -> f64 {
+        (self.nll() / self.scored_tokens() as f64).exp()
+    }
+
+    pub fn tokens_per_second(&self) -> f64 {
+        self.evaluated_tokens as f64 / self.elapsed
+    }
+}
+
+impl fmt::Display for PerplexityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            writeln!(
+                f,
+                "[{index}] start {} scored {} ppl {:.4}",
+                chunk.start,
+                chunk.scored_tokens,
+                chunk.perplexity()
+            )?;
+        }
+        write!(
+            f,
+            "perplexity {:.4} over {} tokens ({} evaluated, {:.2} token/s)",
+            self.perplexity(),
+            self.scored_tokens(),
+            self.evaluated_tokens,
+            self.tokens_per_second()
+        )
+    }
+}
+
+impl InferenceEngine {
+    /// Sliding window perplexity over `tokens`.
+    ///
+    /// Each window holds at most `ctx` tokens and starts `stride` tokens after the previous one.
+    /// Only the tokens that were not scored by an earlier window contribute to the result, the
+    /// overlap is used as context so that every token is predicted with `ctx - stride` tokens of
+    /// history at least. `stride` must therefore be smaller than `ctx`.
+    pub fn perplexity(
+        &self,
+        tokens: &[u32],
+        ctx: usize,
+        stride: usize,
+    ) -> Result<PerplexityReport> {
+        if ctx > self.config().seq_len {
+            bail!(
+                "context size {ctx} exceeds the model maximum of {}",
+                self.config().seq_len
+            );
+        }
+        if ctx < 2 {
+            bail!("context size must hold at least two tokens, got {ctx}");
+        }
+        if stride == 0 || stride >= ctx {
+            bail!("stride must be in 1..{ctx}, got {stride}");
+        }
+        if tokens.len() < 2 {
+            bail!("at least two tokens are required to compute the perplexity");
+        }
+
+        let mut chunks = Vec::new();
+        let mut evaluated_tokens = 0;
+        let mut prev_end = 0;
+        let start_eval = std::time::Instant::now();
+        for start in (0..tokens.len()).step_by(stride) {
+            let end = (start + ctx).min(tokens.len());
+            let window = &tokens[start..end];
+            // The first token of the corpus has no prediction.
+            let first = (prev_end - start).max(1);
+
+            let mut cache = self.new_cache()?;
+            let logits = self.forward(window, 0, &mut cache)?;
+            let log_probs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+            // Logits at position `i` predict the token at position `i + 1`.
+            let targets = Tensor::new(&window[first..], self.device())?.unsqueeze(1)?;
+            let log_probs = log_probs
+                .narrow(0, first - 1, window.len() - first)?
+                .gather(&targets, 1)?;
+            let nll = -log_probs
+                .to_dtype(DType::F64)?
+                .sum_all()?
+                .to_scalar::<f64>()?;
+            evaluated_tokens += window.len();
+            chunks.push(ChunkPerplexity {
+                start,
+                scored_tokens: window.len() - first,
+                nll,
+            });
+
+            prev_end = end;
+            if end == tokens.len() {
+                break;
+            }
+        }
+
+        Ok(PerplexityReport {
+            chunks,
+            evaluated_tokens,
+            elapsed: start_eval.elapsed().as_secs_f64(),
+        })
+    }
+}
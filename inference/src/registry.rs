@@ -0,0 +1,288 @@
+//! 多模型注册表
+//!
+//! The models a server may serve are registered from a JSON file and loaded on their first
+//! request. When the estimated memory of the loaded models exceeds the budget, the least recently
+//! used ones are unloaded, they are loaded again on their next request:
+//!
+//! ```json
+//! {
+//!     "memory_budget_mb": 4096,
+//!     "models": [
+//!         {"id": "stories", "model": "stories15M.safetensors", "model_size": "tiny15m"},
+//!         {"id": "llama3", "model": "ollama:llama3:8b", "tokenizer": "meta-llama/Meta-Llama-3-8B"}
+//!     ]
+//! }
+//! ```
+//!
+//! An unloaded model stays in memory until the requests using it are done.
+
+use {
+    crate::args::ModelSize,
+    anyhow::{Context, Result, bail},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        path::Path,
+        sync::{Arc, Mutex, MutexGuard},
+    },
+};
+
+/// Prefix of the models read from the local Ollama store.
+pub const OLLAMA_PREFIX: &str = "ollama:";
+
+/// 模型条目
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelEntry {
+    /// Name of the model in the API.
+    pub id: String,
+    /// Checkpoint path, a GGUF file or `ollama:<name>`, the `FROM` of the Modelfile by default.
+    #[serde(default)]
+    pub model: String,
+    /// llama2.c 模型规格, for checkpoints without their configuration.
+    #[serde(default)]
+    pub model_size: Option<ModelSize>,
+    /// `tokenizer.json` file or Hugging Face model id.
+    #[serde(default)]
+    pub tokenizer: Option<String>,
+    /// Ollama Modelfile applied to the model.
+    #[serde(default)]
+    pub modelfile: Option<String>,
+}
+
+/// 模型配置文件
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModelsConfig {
+    /// Estimated memory of the loaded models beyond which the least recently used are unloaded,
+    /// no limit by default.
+    #[serde(default)]
+    pub memory_budget_mb: Option<u64>,
+    pub models: Vec<ModelEntry>,
+}
+
+impl ModelsConfig {
+    /// Read a models file, relative paths that exist next to it are resolved against its
+    /// directory.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let mut config: Self = serde_json::from_str(&text)
+            .with_context(|| format!("{}: invalid models file", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let resolve = |name: &mut String| {
+            let path = dir.join(&*name);
+            if !name.is_empty() && path.is_file() {
+                *name = path.to_string_lossy().into_owned();
+            }
+        };
+        for entry in config.models.iter_mut() {
+            if !entry.model.starts_with(OLLAMA_PREFIX) {
+                resolve(&mut entry.model);
+            }
+            if let Some(tokenizer) = entry.tokenizer.as_mut() {
+                resolve(tokenizer);
+            }
+            if let Some(modelfile) = entry.modelfile.as_mut() {
+                resolve(modelfile);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn memory_budget(&self) -> Option<u64> {
+        self.memory_budget_mb.map(|mb| mb * 1024 * 1024)
+    }
+}
+
+/// Load the model of an entry along with its estimated memory in bytes.
+pub type Loader<T> = Box<dyn Fn(&ModelEntry) -> Result<(T, u64)> + Send + Sync>;
+
+struct Loaded<T> {
+    model: Arc<T>,
+    memory: u64,
+    // Tick of the last use, the lowest is the least recently used.
+    used: u64,
+    // Given loaded, it cannot be loaded again.
+    pinned: bool,
+}
+
+struct Models<T> {
+    loaded: HashMap<String, Loaded<T>>,
+    tick: u64,
+}
+
+/// 模型注册表
+pub struct ModelRegistry<T> {
+    // In registration order.
+    ids: Vec<String>,
+    entries: HashMap<String, ModelEntry>,
+    budget: Option<u64>,
+    loader: Option<Loader<T>>,
+    models: Mutex<Models<T>>,
+    // Held while loading, models are loaded one at a time and concurrent requests for a model
+    // load it once.
+    loading: Mutex<()>,
+}
+
+impl<T> Default for ModelRegistry<T> {
+    fn default() -> Self {
+        Self {
+            ids: Vec::new(),
+            entries: HashMap::new(),
+            budget: None,
+            loader: None,
+            models: Mutex::new(Models {
+                loaded: HashMap::new(),
+                tick: 0,
+            }),
+            loading: Mutex::new(()),
+        }
+    }
+}
+
+impl<T> ModelRegistry<T> {
+    /// Registry of the models of `entries`, loaded by `loader`.
+    pub fn new(entries: Vec<ModelEntry>, loader: Loader<T>) -> Result<Self> {
+        let mut registry = Self {
+            loader: Some(loader),
+            ..Default::default()
+        };
+        for entry in entries {
+            if entry.id.is_empty() {
+                bail!("the models must have an id");
+            }
+            registry.add_id(&entry.id)?;
+            registry.entries.insert(entry.id.clone(), entry);
+        }
+        Ok(registry)
+    }
+
+    /// Unload the least recently used models beyond `budget` bytes.
+    pub fn with_budget(mut self, budget: Option<u64>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Add a model already loaded, which is never unloaded.
+    pub fn insert(&mut self, id: impl Into<String>, model: T, memory: u64) -> Result<()> {
+        let id = id.into();
+        self.add_id(&id)?;
+        let models = self.models.get_mut().expect("registry lock poisoned");
+        let loaded = Loaded {
+            model: Arc::new(model),
+            memory,
+            used: 0,
+            pinned: true,
+        };
+        models.loaded.insert(id, loaded);
+        Ok(())
+    }
+
+    fn add_id(&mut self, id: &str) -> Result<()> {
+        if self.ids.iter().any(|other| other == id) {
+            bail!("the model `{id}` is registered twice");
+        }
+        self.ids.push(id.to_string());
+        Ok(())
+    }
+
+    fn models(&self) -> MutexGuard<'_, Models<T>> {
+        self.models.lock().expect("registry lock poisoned")
+    }
+
+    /// Ids of the registered models, loaded or not.
+    pub fn ids(&self) -> &[String] {
+        &self.ids
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.iter().any(|other| other == id)
+    }
+
+    /// The model `id` if it is loaded, which counts as a use.
+    pub fn loaded(&self, id: &str) -> Option<Arc<T>> {
+        let mut models = self.models();
+        models.tick += 1;
+        let tick = models.tick;
+        let loaded = models.loaded.get_mut(id)?;
+        loaded.used = tick;
+        Some(loaded.model.clone())
+    }
+
+    /// The loaded models, in registration order.
+    pub fn all_loaded(&self) -> Vec<(String, Arc<T>)> {
+        let models = self.models();
+        self.ids
+            .iter()
+            .filter_map(|id| Some((id.clone(), models.loaded.get(id)?.model.clone())))
+            .collect()
+    }
+
+    /// Estimated memory of the loaded models in bytes.
+    pub fn memory(&self) -> u64 {
+        self.models()
+            .loaded
+            .values()
+            .map(|loaded| loaded.memory)
+            .sum()
+    }
+
+    /// Mutable access to a loaded model that is not shared yet.
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut T> {
+        let models = self.models.get_mut().expect("registry lock poisoned");
+        Arc::get_mut(&mut models.loaded.get_mut(id)?.model)
+    }
+
+    /// The model `id`, loaded first if needed, which blocks for the time of the loading.
+    pub fn get(&self, id: &str) -> Result<Arc<T>> {
+        if let Some(model) = self.loaded(id) {
+            return Ok(model);
+        }
+        let entry = self
+            .entries
+            .get(id)
+            .with_context(|| format!("the model `{id}` is not registered"))?;
+        let _loading = self.loading.lock().expect("registry lock poisoned");
+        // Loaded by another request while this one waited.
+        if let Some(model) = self.loaded(id) {
+            return Ok(model);
+        }
+        let loader = self.loader.as_ref().context("no model loader")?;
+        let (model, memory) =
+            loader(entry).with_context(|| format!("failed to load the model `{id}`"))?;
+        let model = Arc::new(model);
+        let mut models = self.models();
+        models.tick += 1;
+        let loaded = Loaded {
+            model: model.clone(),
+            memory,
+            used: models.tick,
+            pinned: false,
+        };
+        models.loaded.insert(id.to_string(), loaded);
+        if let Some(budget) = self.budget {
+            evict(&mut models, budget, id);
+        }
+        Ok(model)
+    }
+}
+
+/// Unload the least recently used models but `keep` until the loaded ones fit in `budget`.
+fn evict<T>(models: &mut Models<T>, budget: u64, keep: &str) {
+    loop {
+        let memory: u64 = models.loaded.values().map(|loaded| loaded.memory).sum();
+        if memory <= budget {
+            return;
+        }
+        let lru = models
+            .loaded
+            .iter()
+            .filter(|(id, loaded)| !loaded.pinned && id.as_str() != keep)
+            .min_by_key(|(_, loaded)| loaded.used)
+            .map(|(id, _)| id.clone());
+        match lru {
+            Some(id) => models.loaded.remove(&id),
+            None => return,
+        };
+    }
+}
@@ -0,0 +1,121 @@
+use {
+    crate::args::Args,
+    anyhow::Result,
+    candle_core::{D, DType, Tensor},
+    rand::{Rng, SeedableRng, distributions::Distribution, rngs::StdRng},
+};
+
+/// 采样参数
+#[derive(Debug, Clone)]
+pub struct SamplingParams {
+    /// Softmax temperature, values below 1e-7 select the most likely token.
+    pub temperature: f64,
+    /// Nucleus sampling probability cutoff.
+    pub top_p: Option<f64>,
+    /// Penalty to be applied for repeating tokens, 1. means no penalty.
+    pub repeat_penalty: f32,
+    /// The context size to consider for the repeat penalty.
+    pub repeat_last_n: usize,
+    pub seed: u64,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: 0.7,
+            top_p: None,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            seed: 299792458,
+        }
+    }
+}
+
+impl From<&Args> for SamplingParams {
+    fn from(args: &Args) -> Self {
+        Self {
+            temperature: args.temperature,
+            top_p: args.top_p,
+            repeat_penalty: args.repeat_penalty,
+            repeat_last_n: args.repeat_last_n,
+            ..Default::default()
+        }
+    }
+}
+
+impl SamplingParams {
+    pub fn is_greedy(&self) -> bool {
+        self.temperature < 1e-7
+    }
+
+    /// Distribution of the next token given the `logits` of the last position and the tokens
+    /// seen so far, after the repeat penalty, temperature and top-p filtering have been applied.
+    ///
+    /// Greedy sampling yields a distribution with all the mass on the most likely token.
+    pub fn probabilities(&self, logits: &Tensor, history: &[u32]) -> Result<Vec<f32>> {
+        let logits = logits.to_dtype(DType::F32)?;
+        let logits = if self.repeat_penalty == 1. || history.is_empty() {
+            logits
+        } else {
+            let start_at = history.len().saturating_sub(self.repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(
+                &logits,
+                self.repeat_penalty,
+                &history[start_at..],
+            )?
+        };
+
+        if self.is_greedy() {
+            let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()? as usize;
+            let mut probs = vec![0f32; logits.dim(D::Minus1)?];
+            probs[next_token] = 1.0;
+            return Ok(probs);
+        }
+
+        let logits = (logits / self.temperature)?;
+        let mut probs = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1::<f32>()?;
+        if let Some(top_p) = self.top_p
+            && top_p > 0.0
+            && top_p < 1.0
+        {
+            let mut order: Vec<usize> = (0..probs.len()).collect();
+            order.sort_by(|&i, &j| probs[j].total_cmp(&probs[i]));
+            // Keep the most likely tokens until their cumulative mass reaches top_p.
+            let mut cumsum = 0f64;
+            for index in order {
+                if cumsum >= top_p {
+                    probs[index] = 0.0;
+                } else {
+                    cumsum += probs[index] as f64;
+                }
+            }
+            let total: f32 = probs.iter().sum();
+            probs.iter_mut().for_each(|p| *p /= total);
+        }
+        Ok(probs)
+    }
+}
+
+/// 随机采样器
+pub struct Sampler {
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Draws a token from `probs`, the weights do not need to be normalized.
+    pub fn sample(&mut self, probs: &[f32]) -> Result<u32> {
+        let distr = rand::distributions::WeightedIndex::new(probs)?;
+        Ok(distr.sample(&mut self.rng) as u32)
+    }
+
+    /// Uniform sample in `[0, 1)`.
+    pub fn uniform(&mut self) -> f32 {
+        self.rng.r#gen::<f32>()
+    }
+}
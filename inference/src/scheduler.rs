@@ -0,0 +1,487 @@
+//! 连续批处理调度
+//!
+//! [`Scheduler`] runs many sequences together over one [`PagedCache`]. Waiting sequences are
+//! admitted before every step while the batch has room and finished sequences leave it at once,
+//! so a long generation does not hold back the requests queued behind it.
+//!
+//! A step is either a prefill, which runs a chunk of the prompt of the oldest sequence still
+//! reading its prompt, or a decode, which runs the last sampled token of every other sequence.
+//! The two kinds alternate while both have work. Only the tokens of the sequences run by a step
+//! go through the model.
+//!
+//! The keys and values are stored in blocks handed out by a [`BlockManager`]. The sequences added
+//! together with [`Scheduler::add_parallel`], such as the choices of a completion, read their
+//! prompt once: the first one runs the prefill, the others then fork its blocks and only copy the
+//! last, partly filled one once they write to it.
+//!
+//! With prefix caching the full blocks of the prompts stay cached after their prefill, and a
+//! prompt starting with cached blocks, such as a system prompt sent with every request, only
+//! runs the prefill of the rest.
+
+use {
+    crate::{
+        completion::FinishReason,
+        inference::InferenceEngine,
+        model::{PagedCache, PagedChunk},
+        sampling::{Sampler, SamplingParams},
+    },
+    anyhow::{Result, bail},
+    candle_core::{IndexOp, Tensor},
+    paged_attention::{BlockManager, PrefixCacheStats},
+    std::collections::VecDeque,
+};
+
+/// 调度参数
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    /// Number of sequences generating at once.
+    pub max_batch_size: usize,
+    /// Number of tokens a step may run: the prompt chunk of a prefill, the number of sequences of
+    /// a decode.
+    pub max_batch_tokens: usize,
+    /// Number of tokens of a block of the paged KV cache.
+    pub block_size: usize,
+    /// Keep the KV blocks of the prompts for the prompts starting with the same tokens.
+    pub prefix_caching: bool,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 4,
+            max_batch_tokens: 512,
+            block_size: 16,
+            prefix_caching: true,
+        }
+    }
+}
+
+/// 待调度的生成请求
+#[derive(Debug, Clone)]
+pub struct SequenceRequest {
+    pub prompt: Vec<u32>,
+    pub sampling: SamplingParams,
+    pub max_tokens: usize,
+    pub eos_token_id: Option<u32>,
+}
+
+/// 调度步骤类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepKind {
+    Prefill,
+    Decode,
+}
+
+/// 序列事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum SequenceEvent {
+    /// A token was sampled, including the end of sequence token. `logprob` is its
+    /// log-probability under the model, before the sampling parameters apply.
+    Token { id: u64, token: u32, logprob: f32 },
+    /// The sequence left the batch.
+    Finished { id: u64, reason: FinishReason },
+}
+
+/// 单步调度结果
+#[derive(Debug, Clone)]
+pub struct StepOutput {
+    pub kind: StepKind,
+    /// Number of sequences run by the step.
+    pub sequences: usize,
+    /// Number of tokens run by the step.
+    pub tokens: usize,
+    pub events: Vec<SequenceEvent>,
+}
+
+struct Sequence {
+    id: u64,
+    // Prompt followed by the sampled tokens.
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    // Number of tokens already in the cache.
+    processed: usize,
+    sampling: SamplingParams,
+    sampler: Sampler,
+    max_tokens: usize,
+    eos_token_id: Option<u32>,
+    // Sequences of the same prompt waiting for its prefill to fork the blocks of this one.
+    forks: Vec<Sequence>,
+}
+
+impl Sequence {
+    /// Tokens that still have to go through the model before the next one can be sampled.
+    fn pending(&self) -> usize {
+        self.tokens.len() - self.processed
+    }
+
+    fn generated(&self) -> usize {
+        self.tokens.len() - self.prompt_len
+    }
+
+    /// The sequence and its forks.
+    fn size(&self) -> usize {
+        1 + self.forks.len()
+    }
+
+    /// Sample the next token from the `logits` of the last one. Returns whether the sequence is
+    /// done.
+    fn sample(
+        &mut self,
+        logits: &Tensor,
+        seq_len: usize,
+        events: &mut Vec<SequenceEvent>,
+    ) -> Result<bool> {
+        let probs = self.sampling.probabilities(logits, &self.tokens)?;
+        let token = self.sampler.sample(&probs)?;
+        let logprob = candle_nn::ops::log_softmax(logits, 0)?
+            .i(token as usize)?
+            .to_scalar::<f32>()?;
+        self.tokens.push(token);
+        let id = self.id;
+        events.push(SequenceEvent::Token { id, token, logprob });
+        let reason = if self.eos_token_id == Some(token) {
+            Some(FinishReason::Stop)
+        } else if self.generated() >= self.max_tokens || self.tokens.len() >= seq_len {
+            Some(FinishReason::Length)
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            events.push(SequenceEvent::Finished { id, reason });
+        }
+        Ok(reason.is_some())
+    }
+
+    /// The first fork, which takes over the prompt of the sequence and the other forks.
+    fn heir(self) -> Option<Sequence> {
+        let mut forks = self.forks.into_iter();
+        let mut heir = forks.next()?;
+        heir.processed = self.processed;
+        heir.forks = forks.collect();
+        Some(heir)
+    }
+}
+
+/// 连续批处理调度器
+pub struct Scheduler<'a> {
+    engine: &'a InferenceEngine,
+    config: SchedulerConfig,
+    cache: PagedCache,
+    blocks: BlockManager,
+    waiting: VecDeque<Sequence>,
+    running: Vec<Sequence>,
+    next_id: u64,
+    last_kind: Option<StepKind>,
+}
+
+impl<'a> Scheduler<'a> {
+    pub fn new(engine: &'a InferenceEngine, config: SchedulerConfig) -> Result<Self> {
+        if config.max_batch_size == 0 || config.max_batch_tokens == 0 || config.block_size == 0 {
+            bail!("the batch size, the batch tokens and the block size must be at least 1");
+        }
+        // Room for the whole context of every sequence of a full batch, forks only take less.
+        let num_blocks =
+            config.max_batch_size * engine.config().seq_len.div_ceil(config.block_size);
+        let cache = engine.new_paged_cache(num_blocks, config.block_size)?;
+        let mut blocks = BlockManager::new(num_blocks, config.block_size);
+        if config.prefix_caching {
+            blocks = blocks.with_prefix_cache();
+        }
+        Ok(Self {
+            engine,
+            config,
+            cache,
+            blocks,
+            waiting: VecDeque::new(),
+            running: Vec::new(),
+            next_id: 0,
+            last_kind: None,
+        })
+    }
+
+    fn sequence(&mut self, request: SequenceRequest) -> Result<Sequence> {
+        let seq_len = self.engine.config().seq_len;
+        if request.prompt.is_empty() {
+            bail!("the prompt must contain at least one token");
+        }
+        if request.prompt.len() >= seq_len {
+            bail!(
+                "the prompt has {} tokens but the context length of the model is {seq_len}",
+                request.prompt.len()
+            );
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        Ok(Sequence {
+            id,
+            prompt_len: request.prompt.len(),
+            tokens: request.prompt,
+            processed: 0,
+            sampler: Sampler::new(request.sampling.seed),
+            sampling: request.sampling,
+            max_tokens: request.max_tokens,
+            eos_token_id: request.eos_token_id,
+            forks: Vec::new(),
+        })
+    }
+
+    /// Queue a sequence, it enters the batch at the next step with room for it.
+    pub fn add(&mut self, request: SequenceRequest) -> Result<u64> {
+        let seq = self.sequence(request)?;
+        let id = seq.id;
+        self.waiting.push_back(seq);
+        Ok(id)
+    }
+
+    /// Queue sequences of the same prompt, which only differ by their sampling, such as the
+    /// choices of a completion. They enter the batch together and the prompt goes through the
+    /// model once, the sequences then share its KV blocks. Returns their ids in order.
+    pub fn add_parallel(&mut self, requests: Vec<SequenceRequest>) -> Result<Vec<u64>> {
+        if requests.len() > self.config.max_batch_size {
+            bail!(
+                "{} parallel sequences do not fit a batch of {}",
+                requests.len(),
+                self.config.max_batch_size
+            );
+        }
+        if requests
+            .windows(2)
+            .any(|pair| pair[0].prompt != pair[1].prompt)
+        {
+            bail!("parallel sequences must share their prompt");
+        }
+        let mut sequences = requests
+            .into_iter()
+            .map(|request| self.sequence(request))
+            .collect::<Result<VecDeque<_>>>()?;
+        let ids = sequences.iter().map(|seq| seq.id).collect();
+        if let Some(mut seq) = sequences.pop_front() {
+            seq.forks = sequences.into();
+            self.waiting.push_back(seq);
+        }
+        Ok(ids)
+    }
+
+    /// Drop a waiting or running sequence, its blocks are freed unless other sequences share
+    /// them. Returns whether the sequence was known.
+    pub fn abort(&mut self, id: u64) -> bool {
+        if let Some(pos) = self.waiting.iter().position(|seq| seq.id == id) {
+            let seq = self
+                .waiting
+                .remove(pos)
+                .expect("the position was just found");
+            if let Some(heir) = seq.heir() {
+                self.waiting.insert(pos, heir);
+            }
+            return true;
+        }
+        if let Some(pos) = self.running.iter().position(|seq| seq.id == id) {
+            let seq = self.running.remove(pos);
+            if let Some(heir) = seq.heir() {
+                self.blocks
+                    .fork(id, heir.id)
+                    .expect("running sequences have blocks");
+                self.running.insert(pos, heir);
+            }
+            self.blocks.free(id).expect("running sequences have blocks");
+            return true;
+        }
+        for seq in self.waiting.iter_mut().chain(self.running.iter_mut()) {
+            if let Some(pos) = seq.forks.iter().position(|fork| fork.id == id) {
+                seq.forks.remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.iter().map(Sequence::size).sum()
+    }
+
+    /// Number of sequences in the batch, including the forks waiting for their prompt.
+    pub fn num_running(&self) -> usize {
+        self.running.iter().map(Sequence::size).sum()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty() && self.running.is_empty()
+    }
+
+    /// Share of the token slots of the KV blocks held by running sequences.
+    pub fn cache_utilization(&self) -> f64 {
+        self.cached_positions() as f64 / self.cache_capacity() as f64
+    }
+
+    /// Token slots of the KV blocks held by running sequences, a shared block counts once.
+    pub fn cached_positions(&self) -> usize {
+        (self.blocks.num_blocks() - self.blocks.num_available_blocks()) * self.blocks.block_size()
+    }
+
+    /// Token slots of the KV blocks.
+    pub fn cache_capacity(&self) -> usize {
+        self.blocks.num_blocks() * self.blocks.block_size()
+    }
+
+    /// Lookups and hits of the prefix cache since the scheduler started, `None` without prefix
+    /// caching.
+    pub fn prefix_cache_stats(&self) -> Option<PrefixCacheStats> {
+        self.blocks.prefix_cache_stats()
+    }
+
+    /// Move waiting sequences into the batch while it has room for them and their forks, the
+    /// ones without a token budget finish at once.
+    fn admit(&mut self, events: &mut Vec<SequenceEvent>) -> Result<()> {
+        while let Some(seq) = self.waiting.front() {
+            if self.num_running() + seq.size() > self.config.max_batch_size
+                || !self.blocks.can_allocate(seq.prompt_len)
+            {
+                break;
+            }
+            let mut seq = self.waiting.pop_front().expect("the front was just seen");
+            let forks = std::mem::take(&mut seq.forks);
+            let mut group = std::iter::once(seq).chain(forks).filter(|seq| {
+                if seq.max_tokens == 0 {
+                    let reason = FinishReason::Length;
+                    events.push(SequenceEvent::Finished { id: seq.id, reason });
+                }
+                seq.max_tokens > 0
+            });
+            let Some(mut seq) = group.next() else {
+                continue;
+            };
+            seq.forks = group.collect();
+            // The keys and values of the cached prefix are already stored.
+            seq.processed = self.blocks.allocate_prompt(seq.id, &seq.tokens)?;
+            self.running.push(seq);
+        }
+        Ok(())
+    }
+
+    /// Sequences run by the next step, as indices in `running`, and the number of tokens each
+    /// of them runs.
+    fn plan(&self) -> Option<(StepKind, Vec<usize>, usize)> {
+        let prefill = self
+            .running
+            .iter()
+            .enumerate()
+            .filter(|(_, seq)| seq.pending() > 1)
+            .min_by_key(|(_, seq)| seq.id);
+        let mut decode: Vec<_> = self
+            .running
+            .iter()
+            .enumerate()
+            .filter(|(_, seq)| seq.pending() == 1)
+            .collect();
+        let prefill_turn = decode.is_empty() || self.last_kind != Some(StepKind::Prefill);
+        match prefill {
+            Some((index, seq)) if prefill_turn => {
+                let len = seq.pending().min(self.config.max_batch_tokens);
+                Some((StepKind::Prefill, vec![index], len))
+            }
+            _ if !decode.is_empty() => {
+                decode.sort_by_key(|(_, seq)| seq.id);
+                let indices = decode
+                    .into_iter()
+                    .take(self.config.max_batch_tokens)
+                    .map(|(index, _)| index)
+                    .collect();
+                Some((StepKind::Decode, indices, 1))
+            }
+            _ => None,
+        }
+    }
+
+    /// Admit waiting sequences, run one step and sample the sequences whose pending tokens all
+    /// went through the model. Returns `None` when there is nothing to run.
+    pub fn step(&mut self) -> Result<Option<StepOutput>> {
+        let mut events = Vec::new();
+        self.admit(&mut events)?;
+        let Some((kind, indices, len)) = self.plan() else {
+            return Ok((!events.is_empty()).then_some(StepOutput {
+                kind: StepKind::Decode,
+                sequences: 0,
+                tokens: 0,
+                events,
+            }));
+        };
+
+        // Slots for the sampled tokens, a shared block is copied before it is written.
+        let mut input = Vec::with_capacity(indices.len() * len);
+        for &index in indices.iter() {
+            let seq = &self.running[index];
+            let end = seq.processed + len;
+            let table = self
+                .blocks
+                .block_table(seq.id)
+                .expect("running sequences have blocks");
+            if end > table.num_tokens() {
+                let appended = self.blocks.append_slots(seq.id, end - table.num_tokens())?;
+                if let Some(copy) = appended.copy {
+                    self.cache.copy_block(copy)?;
+                }
+            }
+            input.extend_from_slice(&seq.tokens[seq.processed..end]);
+        }
+        let chunks: Vec<_> = indices
+            .iter()
+            .map(|&index| {
+                let seq = &self.running[index];
+                PagedChunk {
+                    table: self
+                        .blocks
+                        .block_table(seq.id)
+                        .expect("running sequences have blocks"),
+                    start: seq.processed,
+                    len,
+                }
+            })
+            .collect();
+        let logits = self
+            .engine
+            .forward_paged(&input, &chunks, &mut self.cache)?;
+
+        let seq_len = self.engine.config().seq_len;
+        let mut finished = Vec::new();
+        let mut forked = Vec::new();
+        for (i, &index) in indices.iter().enumerate() {
+            let seq = &mut self.running[index];
+            seq.processed += len;
+            if seq.processed <= seq.prompt_len {
+                self.blocks
+                    .cache_prefix(seq.id, &seq.tokens[..seq.processed])?;
+            }
+            if seq.pending() > 0 {
+                continue;
+            }
+            let logits = logits.i(i)?;
+            // The prompt is read, the forks share its blocks and sample from the same logits.
+            let forks = std::mem::take(&mut seq.forks);
+            if seq.sample(&logits, seq_len, &mut events)? {
+                finished.push(seq.id);
+            }
+            let (id, processed) = (seq.id, seq.processed);
+            for mut fork in forks {
+                self.blocks.fork(id, fork.id)?;
+                fork.processed = processed;
+                if fork.sample(&logits, seq_len, &mut events)? {
+                    self.blocks.free(fork.id)?;
+                } else {
+                    forked.push(fork);
+                }
+            }
+        }
+        for id in finished {
+            self.blocks.free(id)?;
+            self.running.retain(|seq| seq.id != id);
+        }
+        self.running.extend(forked);
+        self.last_kind = Some(kind);
+        Ok(Some(StepOutput {
+            kind,
+            sequences: indices.len(),
+            tokens: indices.len() * len,
+            events,
+        }))
+    }
+}
@@ -0,0 +1,81 @@
+use {
+    crate::inference::InferenceEngine,
+    anyhow::{Result, bail},
+    candle_core::{D, DType, Tensor},
+};
+
+/// 候选续写的对数似然
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContinuationScore {
+    /// Sum of the log-probabilities of the continuation tokens given the context.
+    pub log_likelihood: f64,
+    pub num_tokens: usize,
+    /// Whether greedy decoding from the context would have produced exactly this continuation.
+    pub is_greedy: bool,
+}
+
+impl ContinuationScore {
+    /// Log-likelihood averaged over the continuation tokens, so that candidates of different
+    /// lengths can be compared.
+    pub fn normalized_log_likelihood(&self) -> f64 {
+        self.log_likelihood / self.num_tokens as f64
+    }
+}
+
+impl InferenceEngine {
+    /// Scores each of `continuations` as a follow-up of `context`.
+    ///
+    /// The context is run through the model once, every candidate is then evaluated on top of the
+    /// cached context which is rewound before moving to the next candidate.
+    pub fn score_continuations(
+        &self,
+        context: &[u32],
+        continuations: &[Vec<u32>],
+    ) -> Result<Vec<ContinuationScore>> {
+        if context.is_empty() {
+            bail!("the context must contain at least one token");
+        }
+        let seq_len = self.config().seq_len;
+
+        let mut cache = self.new_cache()?;
+        let context_logits = self.forward(context, 0, &mut cache)?;
+        let last_logits = context_logits.narrow(0, context.len() - 1, 1)?;
+
+        let mut scores = Vec::with_capacity(continuations.len());
+        for continuation in continuations {
+            if continuation.is_empty() {
+                bail!("continuations must contain at least one token");
+            }
+            if context.len() + continuation.len() > seq_len {
+                bail!(
+                    "context and continuation hold {} tokens, the model supports {seq_len}",
+                    context.len() + continuation.len()
+                );
+            }
+
+            cache.truncate(context.len());
+            // The logits predicting the first continuation token come from the context.
+            let logits = if continuation.len() > 1 {
+                let len = continuation.len() - 1;
+                let logits = self.forward(&continuation[..len], context.len(), &mut cache)?;
+                Tensor::cat(&[&last_logits, &logits], 0)?
+            } else {
+                last_logits.clone()
+            };
+
+            let targets = Tensor::new(continuation.as_slice(), self.device())?;
+            let greedy = logits.argmax(D::Minus1)?.to_vec1::<u32>()?;
+            let log_likelihood = candle_nn::ops::log_softmax(&logits, D::Minus1)?
+                .gather(&targets.unsqueeze(1)?, 1)?
+                .to_dtype(DType::F64)?
+                .sum_all()?
+                .to_scalar::<f64>()?;
+            scores.push(ContinuationScore {
+                log_likelihood,
+                num_tokens: continuation.len(),
+                is_greedy: greedy == *continuation,
+            });
+        }
+        Ok(scores)
+    }
+}
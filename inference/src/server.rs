@@ -0,0 +1,1268 @@
+//! OpenAI 兼容的 HTTP 服务
+//!
+//! Serves `/v1/models`, `/v1/completions`, `/v1/chat/completions` and `/v1/embeddings` on top of
+//! long-lived [`InferenceEngine`]s, one [`ServedModel`] per model of a [`ModelRegistry`], requests
+//! are routed by their `model`. Completions are generated by a [`BatchEngine`] per model, which
+//! batches the requests in flight on its own thread. With `stream: true` the text is sent as
+//! server-sent events in the OpenAI chunk format, terminated by `data: [DONE]`.
+//!
+//! The Ollama API is served by the [`ollama`] submodule, `/tokenize` and `/detokenize` by
+//! [`tokenize`].
+//!
+//! A Modelfile applied with [`ServedModel::with_modelfile`] provides the sampling defaults, the
+//! stop strings, the system prompt and the chat template of its model in both APIs.
+//!
+//! Every generation has a request id, the `x-request-id` header of the request when given, which
+//! `DELETE /v1/requests/{id}` cancels. A full queue answers 429, a request past its deadline 408
+//! and a cancelled one 499.
+//!
+//! `GET /metrics` reports the requests, the state of the batch and the latencies in the
+//! Prometheus text format.
+//!
+//! With [`ServerState::with_api_keys`] every other endpoint requires a bearer key, within the
+//! limits of the key, and `GET /admin/usage` reports the usage of every key to admin keys.
+
+mod ollama;
+pub mod tokenize;
+
+use {
+    crate::{
+        admission::{Admission, AdmissionConfig, AdmissionError},
+        auth::{Account, ApiKeys, AuthError},
+        batching::{BatchEngine, CompletionEvent},
+        chat::{CHAT_TURN_STOP, ChatMessage, render_chat_prompt},
+        completion::{Completion, CompletionParams, FinishReason},
+        embedding::Pooling,
+        inference::InferenceEngine,
+        metrics::{self, Metrics},
+        openai::{
+            ChatCompletionChoice, ChatCompletionChunk, ChatCompletionChunkChoice,
+            ChatCompletionRequest, ChatCompletionResponse, ChatDelta, CompletionChoice,
+            CompletionChunk, CompletionRequest, CompletionResponse, EmbeddingData, EmbeddingInput,
+            EmbeddingRequest, EmbeddingResponse, EmbeddingUsage, ErrorDetail, ErrorResponse,
+            ModelCard, ModelList, StringOrArray, Usage,
+        },
+        registry::ModelRegistry,
+        sampling::SamplingParams,
+        scheduler::SchedulerConfig,
+        tokenizer::Tokenizer,
+    },
+    ::ollama::{Modelfile, Options, Template},
+    anyhow::bail,
+    axum::{
+        Json, Router,
+        extract::{MatchedPath, Path, Request, State},
+        http::{HeaderMap, HeaderValue, StatusCode, header},
+        middleware::{self, Next},
+        response::{
+            IntoResponse, Response,
+            sse::{Event, Sse},
+        },
+        routing::{delete, get, post},
+    },
+    futures::StreamExt,
+    serde_json::{Value, json},
+    std::{
+        collections::{HashMap, HashSet},
+        sync::{
+            Arc, OnceLock,
+            atomic::{AtomicU64, Ordering},
+        },
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+    tokio::sync::mpsc,
+};
+
+/// Completions requested without `max_tokens` stop after this many tokens, like the OpenAI API.
+const DEFAULT_COMPLETION_MAX_TOKENS: usize = 16;
+
+/// Header naming the request id, sent back with the response.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// 服务中的模型
+pub struct ServedModel {
+    pub engine: Arc<InferenceEngine>,
+    pub tokenizer: Tokenizer,
+    /// Name of the model in the API.
+    pub model_id: String,
+    modelfile: Modelfile,
+    template: Option<Template>,
+    // Started by the first completion.
+    batch: OnceLock<BatchEngine>,
+}
+
+impl ServedModel {
+    pub fn new(engine: InferenceEngine, tokenizer: Tokenizer, model_id: impl Into<String>) -> Self {
+        Self {
+            engine: Arc::new(engine),
+            tokenizer,
+            model_id: model_id.into(),
+            modelfile: Modelfile::default(),
+            template: None,
+            batch: OnceLock::new(),
+        }
+    }
+
+    /// Apply the parameters, system prompt, messages and template of `modelfile`. Its `FROM` is
+    /// not read, the engine is already loaded.
+    pub fn with_modelfile(mut self, modelfile: Modelfile) -> anyhow::Result<Self> {
+        self.apply_modelfile(modelfile)?;
+        Ok(self)
+    }
+
+    fn apply_modelfile(&mut self, modelfile: Modelfile) -> anyhow::Result<()> {
+        if !modelfile.adapters.is_empty() {
+            bail!("ADAPTER is not supported");
+        }
+        self.template = modelfile
+            .template
+            .as_deref()
+            .map(Template::parse)
+            .transpose()?;
+        self.modelfile = modelfile;
+        Ok(())
+    }
+
+    fn batch(&self, state: &ServerState) -> &BatchEngine {
+        self.batch.get_or_init(|| {
+            BatchEngine::spawn(
+                self.engine.clone(),
+                self.tokenizer.clone(),
+                state.scheduler.clone(),
+                state.admission.clone(),
+                state.metrics.clone(),
+            )
+        })
+    }
+
+    /// Sampling defaults of the Modelfile.
+    fn default_options(&self) -> &Options {
+        &self.modelfile.parameters
+    }
+
+    /// Prompt of a conversation and the stop strings ending the assistant turn.
+    ///
+    /// The system prompt of the Modelfile applies unless the conversation has its own, followed
+    /// by the messages of the Modelfile. Without a template the conversation is rendered with
+    /// [`render_chat_prompt`].
+    fn chat_prompt(
+        &self,
+        messages: &[ChatMessage],
+    ) -> Result<(String, &'static [&'static str]), ApiError> {
+        let mut conversation = Vec::new();
+        if let Some(system) = &self.modelfile.system
+            && !messages.iter().any(|message| message.role == "system")
+        {
+            conversation.push(ChatMessage::new("system", system));
+        }
+        conversation.extend(
+            self.modelfile
+                .messages
+                .iter()
+                .map(|message| ChatMessage::new(&message.role, &message.content)),
+        );
+        conversation.extend_from_slice(messages);
+        let Some(template) = &self.template else {
+            return Ok((render_chat_prompt(&conversation), &[CHAT_TURN_STOP]));
+        };
+        let conversation: Vec<_> = conversation
+            .into_iter()
+            .map(|message| ::ollama::ChatMessage {
+                role: message.role,
+                content: message.content,
+            })
+            .collect();
+        let prompt = template
+            .chat_prompt(&conversation)
+            .map_err(ApiError::internal)?;
+        Ok((prompt, &[]))
+    }
+
+    /// Number of prompt tokens, rejecting prompts that leave no room for generation.
+    fn check_prompt(&self, prompt: &str) -> Result<usize, ApiError> {
+        let tokens = self.tokenizer.encode(prompt).map_err(ApiError::internal)?;
+        let seq_len = self.engine.config().seq_len;
+        if tokens.is_empty() {
+            return Err(ApiError::invalid_request("the prompt is empty", "prompt"));
+        }
+        if tokens.len() >= seq_len {
+            return Err(ApiError::invalid_request(
+                format!(
+                    "the prompt has {} tokens but the context length of the model is {seq_len}",
+                    tokens.len()
+                ),
+                "prompt",
+            ));
+        }
+        Ok(tokens.len())
+    }
+}
+
+/// 服务共享状态
+pub struct ServerState {
+    models: ModelRegistry<ServedModel>,
+    scheduler: SchedulerConfig,
+    admission: AdmissionConfig,
+    metrics: Arc<Metrics>,
+    keys: Option<Arc<ApiKeys>>,
+    created: u64,
+    next_id: AtomicU64,
+}
+
+impl ServerState {
+    /// Serve a single model.
+    pub fn new(engine: InferenceEngine, tokenizer: Tokenizer, model_id: impl Into<String>) -> Self {
+        let model = ServedModel::new(engine, tokenizer, model_id);
+        let mut models = ModelRegistry::default();
+        models
+            .insert(model.model_id.clone(), model, 0)
+            .expect("a single model is registered once");
+        Self::with_models(models)
+    }
+
+    /// Serve the models of a registry, loaded on their first request.
+    pub fn with_models(models: ModelRegistry<ServedModel>) -> Self {
+        Self {
+            models,
+            scheduler: SchedulerConfig::default(),
+            admission: AdmissionConfig::default(),
+            metrics: Arc::default(),
+            keys: None,
+            created: unix_time(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Apply `modelfile` to the model given to [`ServerState::new`], see
+    /// [`ServedModel::with_modelfile`].
+    pub fn with_modelfile(mut self, modelfile: Modelfile) -> anyhow::Result<Self> {
+        let id = self.models.ids().first().cloned().unwrap_or_default();
+        self.models
+            .get_mut(&id)
+            .ok_or_else(|| anyhow::anyhow!("no model is loaded"))?
+            .apply_modelfile(modelfile)?;
+        Ok(self)
+    }
+
+    /// Batch size and batch tokens of the engines generating the completions.
+    pub fn with_scheduler(mut self, config: SchedulerConfig) -> Self {
+        self.scheduler = config;
+        self
+    }
+
+    /// Queue size and default timeout of the requests waiting for the engines.
+    pub fn with_admission(mut self, config: AdmissionConfig) -> Self {
+        self.admission = config;
+        self
+    }
+
+    /// Require one of `keys` on every endpoint but `/metrics`.
+    pub fn with_api_keys(mut self, keys: ApiKeys) -> Self {
+        self.keys = Some(Arc::new(keys));
+        self
+    }
+
+    /// The model `id`, loaded first when it is registered but not loaded.
+    async fn model(self: &Arc<Self>, id: &str) -> Result<Arc<ServedModel>, ApiError> {
+        if let Some(model) = self.models.loaded(id) {
+            return Ok(model);
+        }
+        if !self.models.contains(id) {
+            return Err(ApiError::model_not_found(id));
+        }
+        // Loading reads the weights from disk.
+        let state = self.clone();
+        let id = id.to_string();
+        tokio::task::spawn_blocking(move || state.models.get(&id))
+            .await
+            .map_err(ApiError::internal)?
+            .map_err(|err| ApiError::internal(format!("{err:#}")))
+    }
+
+    /// Admission of a request: its id is the `x-request-id` header when given, a new id starting
+    /// with `prefix` otherwise. `timeout` is in seconds. The request is owned by its API key.
+    fn admission(
+        &self,
+        headers: &HeaderMap,
+        prefix: &str,
+        priority: Option<i32>,
+        timeout: Option<f64>,
+    ) -> Result<Admission, ApiError> {
+        let request_id = match headers.get(REQUEST_ID_HEADER) {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .filter(|id| !id.is_empty())
+                .ok_or_else(|| {
+                    ApiError::invalid_request("x-request-id must be a visible ASCII string", "id")
+                })?
+                .to_string(),
+            None => self.next_id(prefix),
+        };
+        let deadline = match timeout {
+            Some(timeout) if !(timeout > 0.0 && timeout.is_finite()) => {
+                return Err(ApiError::invalid_request(
+                    "timeout must be a positive number of seconds",
+                    "timeout",
+                ));
+            }
+            Some(timeout) => Some(Instant::now() + Duration::from_secs_f64(timeout)),
+            None => None,
+        };
+        Ok(Admission {
+            request_id,
+            priority: priority.unwrap_or(0),
+            deadline,
+            owner: self.owner(headers),
+        })
+    }
+
+    /// Take the `prompt_tokens` of a request whose cost is known before it runs from the budget
+    /// of its API key, when keys are enabled.
+    fn charge(&self, headers: &HeaderMap, prompt_tokens: usize) -> Result<(), ApiError> {
+        if let Some(keys) = &self.keys {
+            keys.authenticate(authorization(headers))?
+                .charge(prompt_tokens, Instant::now())?;
+        }
+        Ok(())
+    }
+
+    /// Name of the API key of a request, `None` without authentication.
+    fn owner(&self, headers: &HeaderMap) -> Option<String> {
+        self.keys
+            .as_ref()
+            .and_then(|keys| keys.authenticate(authorization(headers)).ok())
+            .map(|account| account.key.name.clone())
+    }
+
+    fn next_id(&self, prefix: &str) -> String {
+        format!("{prefix}-{}", self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+fn authorization(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// 接口错误，以 OpenAI 的错误格式返回
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    kind: &'static str,
+    message: String,
+    param: Option<&'static str>,
+    code: Option<&'static str>,
+    /// Sent as the `Retry-After` header.
+    retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    fn invalid_request(message: impl Into<String>, param: &'static str) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            kind: "invalid_request_error",
+            message: message.into(),
+            param: Some(param),
+            code: None,
+            retry_after: None,
+        }
+    }
+
+    fn model_not_found(model: &str) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            kind: "invalid_request_error",
+            message: format!("The model `{model}` does not exist"),
+            param: Some("model"),
+            code: Some("model_not_found"),
+            retry_after: None,
+        }
+    }
+
+    fn internal(err: impl std::fmt::Display) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            kind: "server_error",
+            message: err.to_string(),
+            param: None,
+            code: None,
+            retry_after: None,
+        }
+    }
+
+    fn request_not_found(request_id: &str) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            kind: "invalid_request_error",
+            message: format!("No request `{request_id}` is in flight"),
+            param: None,
+            code: Some("request_not_found"),
+            retry_after: None,
+        }
+    }
+}
+
+impl From<AdmissionError> for ApiError {
+    fn from(err: AdmissionError) -> Self {
+        let (status, kind, code) = match err {
+            AdmissionError::QueueFull { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limit_error",
+                "queue_full",
+            ),
+            AdmissionError::TimedOut => (StatusCode::REQUEST_TIMEOUT, "timeout_error", "timeout"),
+            // The status nginx uses for a request the client closed.
+            AdmissionError::Cancelled => (
+                StatusCode::from_u16(499).expect("499 is a valid status code"),
+                "cancelled_error",
+                "cancelled",
+            ),
+        };
+        Self {
+            status,
+            kind,
+            message: err.to_string(),
+            param: None,
+            code: Some(code),
+            retry_after: None,
+        }
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(err: AuthError) -> Self {
+        let (status, kind, code) = match err {
+            AuthError::MissingKey | AuthError::InvalidKey => (
+                StatusCode::UNAUTHORIZED,
+                "invalid_request_error",
+                "invalid_api_key",
+            ),
+            AuthError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "permission_error",
+                "insufficient_permissions",
+            ),
+            AuthError::RateLimited { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limit_error",
+                "rate_limit_exceeded",
+            ),
+        };
+        Self {
+            status,
+            kind,
+            message: err.to_string(),
+            param: None,
+            code: Some(code),
+            retry_after: match err {
+                AuthError::RateLimited { retry_after, .. } => Some(retry_after),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Admission errors keep their status, other errors are internal.
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<AdmissionError>() {
+            Some(&err) => err.into(),
+            None => Self::internal(err),
+        }
+    }
+}
+
+impl ApiError {
+    fn body(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorDetail {
+                message: self.message.clone(),
+                kind: self.kind.to_string(),
+                param: self.param.map(str::to_string),
+                code: self.code.map(str::to_string),
+            },
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(self.body())).into_response();
+        if let Some(retry_after) = self.retry_after {
+            set_retry_after(&mut response, retry_after);
+        }
+        response
+    }
+}
+
+/// Tell the client to retry after `retry_after`, rounded up to a whole second.
+fn set_retry_after(response: &mut Response, retry_after: Duration) {
+    let seconds = retry_after.as_secs_f64().ceil() as u64;
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+}
+
+/// Sampling options shared by the completion and chat completion requests.
+struct RequestOptions {
+    max_tokens: Option<usize>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    n: Option<usize>,
+    best_of: Option<usize>,
+    stop: Option<StringOrArray>,
+    seed: Option<u64>,
+}
+
+impl RequestOptions {
+    /// Completion parameters of each of the `best_of` candidates, `n` by default, they only
+    /// differ by their seed.
+    ///
+    /// Unset options take the `defaults` of the Modelfile, whose stop strings always apply.
+    fn into_params(
+        self,
+        defaults: &Options,
+        default_max_tokens: usize,
+        default_stop: &[&str],
+    ) -> Result<Vec<CompletionParams>, ApiError> {
+        let temperature = self.temperature.or(defaults.temperature).unwrap_or(1.0);
+        if !(0.0..=2.0).contains(&temperature) {
+            return Err(ApiError::invalid_request(
+                "temperature must be between 0 and 2",
+                "temperature",
+            ));
+        }
+        let top_p = self.top_p.or(defaults.top_p);
+        if let Some(top_p) = top_p
+            && !(0.0..=1.0).contains(&top_p)
+        {
+            return Err(ApiError::invalid_request(
+                "top_p must be between 0 and 1",
+                "top_p",
+            ));
+        }
+        let n = self.n.unwrap_or(1);
+        if n == 0 {
+            return Err(ApiError::invalid_request("n must be at least 1", "n"));
+        }
+        let best_of = self.best_of.unwrap_or(n);
+        if best_of < n {
+            return Err(ApiError::invalid_request(
+                "best_of must be at least n",
+                "best_of",
+            ));
+        }
+        let mut stop = self.stop.map(StringOrArray::into_vec).unwrap_or_default();
+        stop.extend(defaults.stop.iter().flatten().cloned());
+        stop.extend(default_stop.iter().map(|s| s.to_string()));
+
+        let seed = self.seed.or(defaults.seed).unwrap_or_else(rand::random);
+        let mut sampling = SamplingParams {
+            temperature,
+            top_p,
+            // The OpenAI API has no repeat penalty, only a Modelfile sets one.
+            repeat_penalty: defaults.repeat_penalty.unwrap_or(1.0),
+            seed,
+            ..Default::default()
+        };
+        if let Some(repeat_last_n) = defaults.repeat_last_n {
+            sampling.repeat_last_n = repeat_last_n;
+        }
+        let max_tokens = match (self.max_tokens, defaults.num_predict) {
+            (Some(max_tokens), _) => max_tokens,
+            (None, Some(n)) if n >= 0 => n as usize,
+            _ => default_max_tokens,
+        };
+        let params = CompletionParams {
+            sampling,
+            max_tokens,
+            stop,
+        };
+        Ok((0..best_of as u64)
+            .map(|i| {
+                let mut params = params.clone();
+                params.sampling.seed = seed.wrapping_add(i);
+                params
+            })
+            .collect())
+    }
+}
+
+/// Forward the events of a request, accounting the tokens of its completions to `account` as
+/// they are generated, so that a cancelled or disconnected request pays for its tokens too.
+fn account_tokens(
+    account: Arc<Account>,
+    mut events: mpsc::UnboundedReceiver<CompletionEvent>,
+) -> mpsc::UnboundedReceiver<CompletionEvent> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        // Tokens accounted for each choice, a choice finishing without a token pays for its
+        // prompt when it finishes.
+        let mut accounted: HashMap<usize, (usize, usize)> = HashMap::new();
+        while let Some(event) = events.recv().await {
+            let tokens = match &event {
+                CompletionEvent::Tokens {
+                    index,
+                    prompt_tokens,
+                    completion_tokens,
+                } => Some((*index, *prompt_tokens, *completion_tokens)),
+                CompletionEvent::Finished { index, completion } => {
+                    let (prompt, generated) = accounted.get(index).copied().unwrap_or_default();
+                    let stats = &completion.stats;
+                    Some((
+                        *index,
+                        stats.prompt_tokens.saturating_sub(prompt),
+                        stats.generated_tokens.saturating_sub(generated),
+                    ))
+                }
+                _ => None,
+            };
+            if let Some((index, prompt_tokens, completion_tokens)) = tokens {
+                let entry = accounted.entry(index).or_default();
+                entry.0 += prompt_tokens;
+                entry.1 += completion_tokens;
+                account.record(prompt_tokens, completion_tokens, Instant::now());
+            }
+            // The client is gone, dropping `events` aborts the generations.
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Submit every prompt with every set of parameters to the batch engine, choices are indexed by
+/// prompt first. Dropping the receiver, which happens when the client disconnects, aborts the
+/// generations.
+fn submit_completions(
+    state: &ServerState,
+    model: &ServedModel,
+    admission: Admission,
+    prompts: Vec<String>,
+    params: Vec<CompletionParams>,
+) -> Result<mpsc::UnboundedReceiver<CompletionEvent>, ApiError> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let choices = prompts
+        .into_iter()
+        .flat_map(|prompt| {
+            params
+                .iter()
+                .map(move |params| (prompt.clone(), params.clone()))
+        })
+        .collect();
+    let account = admission
+        .owner
+        .as_deref()
+        .and_then(|owner| state.keys.as_ref()?.account(owner));
+    model.batch(state).submit(admission, choices, tx)?;
+    Ok(match account {
+        Some(account) => account_tokens(account, rx),
+        None => rx,
+    })
+}
+
+/// Same as [`submit_completions`] but waits for all the choices.
+async fn run_completions(
+    state: Arc<ServerState>,
+    model: &ServedModel,
+    admission: Admission,
+    prompts: Vec<String>,
+    params: Vec<CompletionParams>,
+) -> Result<Vec<Completion>, ApiError> {
+    let mut completions = vec![None; prompts.len() * params.len()];
+    let mut rx = submit_completions(&state, model, admission, prompts, params)?;
+    while let Some(event) = rx.recv().await {
+        match event {
+            CompletionEvent::Text { .. } | CompletionEvent::Tokens { .. } => {}
+            CompletionEvent::Finished { index, completion } => {
+                completions[index] = Some(completion)
+            }
+            CompletionEvent::Failed(message) => return Err(ApiError::internal(message)),
+            CompletionEvent::Aborted(err) => return Err(err.into()),
+        }
+    }
+    completions
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| ApiError::internal("the batch engine has stopped"))
+}
+
+/// The `n` completions of each prompt with the highest log-probability per token out of its
+/// `candidates`, best first.
+fn best_completions(completions: Vec<Completion>, candidates: usize, n: usize) -> Vec<Completion> {
+    let per_token = |completion: &Completion| {
+        let logprob = completion.logprob.unwrap_or(0.0);
+        logprob / completion.stats.generated_tokens.max(1) as f64
+    };
+    let mut completions = completions.into_iter();
+    let mut best = Vec::new();
+    loop {
+        let mut prompt: Vec<_> = completions.by_ref().take(candidates).collect();
+        if prompt.is_empty() {
+            return best;
+        }
+        prompt.sort_by(|a, b| per_token(b).total_cmp(&per_token(a)));
+        best.extend(prompt.into_iter().take(n));
+    }
+}
+
+fn usage(prompt_tokens: usize, completions: &[Completion]) -> Usage {
+    let completion_tokens = completions.iter().map(|c| c.stats.generated_tokens).sum();
+    Usage::new(prompt_tokens, completion_tokens)
+}
+
+/// Builds the server-sent events of a streamed completion or chat completion.
+struct ChunkBuilder {
+    id: String,
+    created: u64,
+    model: String,
+    chat: bool,
+    include_usage: bool,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    // Chat choices that already sent their role, and some content.
+    role_sent: HashSet<usize>,
+    content_sent: HashSet<usize>,
+}
+
+type SseEvent = Result<Event, axum::Error>;
+
+impl ChunkBuilder {
+    fn completion_chunk(&self, choices: Vec<CompletionChoice>, usage: Option<Usage>) -> SseEvent {
+        Event::default().json_data(CompletionChunk {
+            id: self.id.clone(),
+            object: "text_completion".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices,
+            usage,
+        })
+    }
+
+    fn chat_chunk(
+        &self,
+        choices: Vec<ChatCompletionChunkChoice>,
+        usage: Option<Usage>,
+    ) -> SseEvent {
+        Event::default().json_data(ChatCompletionChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices,
+            usage,
+        })
+    }
+
+    fn chat_delta(
+        &self,
+        index: usize,
+        delta: ChatDelta,
+        finish_reason: Option<FinishReason>,
+    ) -> SseEvent {
+        let choice = ChatCompletionChunkChoice {
+            index,
+            delta,
+            finish_reason,
+        };
+        self.chat_chunk(vec![choice], None)
+    }
+
+    fn text(&mut self, index: usize, text: String) -> Vec<SseEvent> {
+        if !self.chat {
+            let choice = CompletionChoice {
+                text,
+                index,
+                logprobs: None,
+                finish_reason: None,
+            };
+            return vec![self.completion_chunk(vec![choice], None)];
+        }
+        let mut events = Vec::new();
+        if !self.role_sent.contains(&index) {
+            events.push(self.role(index));
+        }
+        // Like the non-streamed message, the content starts at its first non-blank character.
+        let text = if self.content_sent.contains(&index) {
+            text.as_str()
+        } else {
+            text.trim_start()
+        };
+        if !text.is_empty() {
+            self.content_sent.insert(index);
+            let delta = ChatDelta {
+                content: Some(text.to_string()),
+                ..Default::default()
+            };
+            events.push(self.chat_delta(index, delta, None));
+        }
+        events
+    }
+
+    fn role(&mut self, index: usize) -> SseEvent {
+        self.role_sent.insert(index);
+        let delta = ChatDelta {
+            role: Some("assistant".to_string()),
+            content: Some(String::new()),
+        };
+        self.chat_delta(index, delta, None)
+    }
+
+    fn finished(&mut self, index: usize, completion: Completion) -> Vec<SseEvent> {
+        self.completion_tokens += completion.stats.generated_tokens;
+        let finish_reason = Some(completion.finish_reason);
+        if !self.chat {
+            let choice = CompletionChoice {
+                text: String::new(),
+                index,
+                logprobs: None,
+                finish_reason,
+            };
+            return vec![self.completion_chunk(vec![choice], None)];
+        }
+        let mut events = Vec::new();
+        if !self.role_sent.contains(&index) {
+            events.push(self.role(index));
+        }
+        events.push(self.chat_delta(index, ChatDelta::default(), finish_reason));
+        events
+    }
+
+    fn events(&mut self, event: CompletionEvent) -> Vec<SseEvent> {
+        match event {
+            CompletionEvent::Text { index, text } => self.text(index, text),
+            CompletionEvent::Tokens { .. } => Vec::new(),
+            CompletionEvent::Finished { index, completion } => self.finished(index, completion),
+            CompletionEvent::Failed(message) => {
+                vec![Event::default().json_data(ApiError::internal(message).body())]
+            }
+            CompletionEvent::Aborted(err) => {
+                vec![Event::default().json_data(ApiError::from(err).body())]
+            }
+        }
+    }
+
+    /// The usage chunk when requested, then the `[DONE]` marker.
+    fn end(&self) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        if self.include_usage {
+            let usage = Some(Usage::new(self.prompt_tokens, self.completion_tokens));
+            events.push(if self.chat {
+                self.chat_chunk(Vec::new(), usage)
+            } else {
+                self.completion_chunk(Vec::new(), usage)
+            });
+        }
+        events.push(Ok(Event::default().data("[DONE]")));
+        events
+    }
+
+    fn into_sse(self, rx: mpsc::UnboundedReceiver<CompletionEvent>) -> Response {
+        let stream = futures::stream::unfold(
+            (rx, self, false),
+            |(mut rx, mut builder, done)| async move {
+                if done {
+                    return None;
+                }
+                match rx.recv().await {
+                    Some(message) => {
+                        let events = builder.events(message);
+                        Some((events, (rx, builder, false)))
+                    }
+                    None => Some((builder.end(), (rx, builder, true))),
+                }
+            },
+        )
+        .flat_map(futures::stream::iter);
+        Sse::new(stream).into_response()
+    }
+}
+
+async fn list_models(State(state): State<Arc<ServerState>>) -> Json<ModelList> {
+    Json(ModelList {
+        object: "list".to_string(),
+        data: state
+            .models
+            .ids()
+            .iter()
+            .map(|id| ModelCard {
+                id: id.clone(),
+                object: "model".to_string(),
+                created: state.created,
+                owned_by: "llama.rust".to_string(),
+            })
+            .collect(),
+    })
+}
+
+/// Send the request id back in the `x-request-id` header.
+fn with_request_id(mut response: Response, request_id: &str) -> Response {
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+async fn completions(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<CompletionRequest>,
+) -> Result<Response, ApiError> {
+    let model = state.model(&request.model).await?;
+    let admission = state.admission(&headers, "cmpl", request.priority, request.timeout)?;
+    let id = admission.request_id.clone();
+    let params = RequestOptions {
+        max_tokens: request.max_tokens,
+        temperature: request.temperature,
+        top_p: request.top_p,
+        n: request.n,
+        best_of: request.best_of,
+        stop: request.stop,
+        seed: request.seed,
+    }
+    .into_params(model.default_options(), DEFAULT_COMPLETION_MAX_TOKENS, &[])?;
+    let n = request.n.unwrap_or(1);
+    let prompts = request.prompt.into_vec();
+    if prompts.is_empty() {
+        return Err(ApiError::invalid_request("the prompt is empty", "prompt"));
+    }
+    let mut prompt_tokens = 0;
+    for prompt in prompts.iter() {
+        prompt_tokens += model.check_prompt(prompt)?;
+    }
+
+    if request.stream == Some(true) {
+        if params.len() > n {
+            return Err(ApiError::invalid_request(
+                "best_of larger than n cannot be streamed",
+                "best_of",
+            ));
+        }
+        let builder = ChunkBuilder {
+            id: id.clone(),
+            created: unix_time(),
+            model: model.model_id.clone(),
+            chat: false,
+            include_usage: request.stream_options.is_some_and(|o| o.include_usage),
+            prompt_tokens,
+            completion_tokens: 0,
+            role_sent: HashSet::new(),
+            content_sent: HashSet::new(),
+        };
+        let rx = submit_completions(&state, &model, admission, prompts, params)?;
+        return Ok(with_request_id(builder.into_sse(rx), &id));
+    }
+
+    let candidates = params.len();
+    let completions = run_completions(state.clone(), &model, admission, prompts, params).await?;
+    // The candidates left out are generated, and billed, all the same.
+    let usage = usage(prompt_tokens, &completions);
+    let completions = best_completions(completions, candidates, n);
+    let response = Json(CompletionResponse {
+        id: id.clone(),
+        object: "text_completion".to_string(),
+        created: unix_time(),
+        model: model.model_id.clone(),
+        choices: completions
+            .into_iter()
+            .enumerate()
+            .map(|(index, completion)| CompletionChoice {
+                text: completion.text,
+                index,
+                logprobs: None,
+                finish_reason: Some(completion.finish_reason),
+            })
+            .collect(),
+        usage,
+    });
+    Ok(with_request_id(response.into_response(), &id))
+}
+
+async fn chat_completions(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, ApiError> {
+    let model = state.model(&request.model).await?;
+    let admission = state.admission(&headers, "chatcmpl", request.priority, request.timeout)?;
+    let id = admission.request_id.clone();
+    if request.messages.is_empty() {
+        return Err(ApiError::invalid_request(
+            "messages must not be empty",
+            "messages",
+        ));
+    }
+    let (prompt, turn_stop) = model.chat_prompt(&request.messages)?;
+    let params = RequestOptions {
+        max_tokens: request.max_completion_tokens.or(request.max_tokens),
+        temperature: request.temperature,
+        top_p: request.top_p,
+        n: request.n,
+        best_of: None,
+        stop: request.stop,
+        seed: request.seed,
+    }
+    .into_params(model.default_options(), usize::MAX, turn_stop)?;
+    let prompt_tokens = model.check_prompt(&prompt)?;
+
+    if request.stream == Some(true) {
+        let builder = ChunkBuilder {
+            id: id.clone(),
+            created: unix_time(),
+            model: model.model_id.clone(),
+            chat: true,
+            include_usage: request.stream_options.is_some_and(|o| o.include_usage),
+            prompt_tokens,
+            completion_tokens: 0,
+            role_sent: HashSet::new(),
+            content_sent: HashSet::new(),
+        };
+        let rx = submit_completions(&state, &model, admission, vec![prompt], params)?;
+        return Ok(with_request_id(builder.into_sse(rx), &id));
+    }
+
+    let completions =
+        run_completions(state.clone(), &model, admission, vec![prompt], params).await?;
+    let usage = usage(prompt_tokens, &completions);
+    let response = Json(ChatCompletionResponse {
+        id: id.clone(),
+        object: "chat.completion".to_string(),
+        created: unix_time(),
+        model: model.model_id.clone(),
+        choices: completions
+            .into_iter()
+            .enumerate()
+            .map(|(index, completion)| ChatCompletionChoice {
+                index,
+                message: ChatMessage::new("assistant", completion.text.trim_start()),
+                finish_reason: Some(completion.finish_reason),
+            })
+            .collect(),
+        usage,
+    });
+    Ok(with_request_id(response.into_response(), &id))
+}
+
+async fn embeddings(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, ApiError> {
+    let model = state.model(&request.model).await?;
+    if let Some(format) = request.encoding_format.as_deref()
+        && format != "float"
+    {
+        return Err(ApiError::invalid_request(
+            format!("the encoding format `{format}` is not supported, only `float` is"),
+            "encoding_format",
+        ));
+    }
+    // The inputs of a request count like the choices of a completion, they may not exceed the
+    // queue.
+    let num_inputs = match &request.input {
+        EmbeddingInput::String(_) | EmbeddingInput::Tokens(_) => 1,
+        EmbeddingInput::Array(texts) => texts.len(),
+        EmbeddingInput::TokenArrays(inputs) => inputs.len(),
+    };
+    if num_inputs > state.admission.max_queue {
+        return Err(ApiError::invalid_request(
+            format!(
+                "{num_inputs} inputs were given, at most {} are accepted",
+                state.admission.max_queue
+            ),
+            "input",
+        ));
+    }
+    let inputs = match request.input {
+        EmbeddingInput::String(text) => vec![model.tokenizer.encode(&text)],
+        EmbeddingInput::Array(texts) => texts
+            .iter()
+            .map(|text| model.tokenizer.encode(text))
+            .collect(),
+        EmbeddingInput::Tokens(tokens) => vec![Ok(tokens)],
+        EmbeddingInput::TokenArrays(inputs) => inputs.into_iter().map(Ok).collect(),
+    };
+    let inputs = inputs
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(ApiError::internal)?;
+    if inputs.is_empty() {
+        return Err(ApiError::invalid_request("the input is empty", "input"));
+    }
+    let config = model.engine.config();
+    for tokens in inputs.iter() {
+        if tokens.is_empty() {
+            return Err(ApiError::invalid_request("an input is empty", "input"));
+        }
+        if tokens.len() > config.seq_len {
+            return Err(ApiError::invalid_request(
+                format!(
+                    "an input has {} tokens but the context length of the model is {}",
+                    tokens.len(),
+                    config.seq_len
+                ),
+                "input",
+            ));
+        }
+        if tokens
+            .iter()
+            .any(|&token| token as usize >= config.vocab_size)
+        {
+            return Err(ApiError::invalid_request(
+                "an input has tokens outside the vocabulary",
+                "input",
+            ));
+        }
+    }
+    let prompt_tokens = inputs.iter().map(Vec::len).sum();
+    state.charge(&headers, prompt_tokens)?;
+    let pooling = request.pooling.unwrap_or(Pooling::Mean);
+    let normalize = request.normalize.unwrap_or(true);
+    let engine = model.engine.clone();
+    let embeddings =
+        tokio::task::spawn_blocking(move || engine.embed_batch(&inputs, pooling, normalize))
+            .await
+            .map_err(ApiError::internal)?
+            .map_err(ApiError::internal)?;
+    Ok(Json(EmbeddingResponse {
+        object: "list".to_string(),
+        data: embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| EmbeddingData {
+                object: "embedding".to_string(),
+                embedding,
+                index,
+            })
+            .collect(),
+        model: model.model_id.clone(),
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    }))
+}
+
+/// Cancel a request in flight. With API keys, a key only cancels its own requests, those of
+/// the other keys are not found.
+async fn cancel_request(
+    State(state): State<Arc<ServerState>>,
+    Path(request_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let owner = state.owner(&headers);
+    for (_, model) in state.models.all_loaded() {
+        if let Some(batch) = model.batch.get()
+            && batch.cancel(&request_id, owner.as_deref())?
+        {
+            return Ok(StatusCode::NO_CONTENT);
+        }
+    }
+    Err(ApiError::request_not_found(&request_id))
+}
+
+async fn render_metrics(State(state): State<Arc<ServerState>>) -> Response {
+    (
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        state.metrics.render(),
+    )
+        .into_response()
+}
+
+/// Usage of every API key, for admin keys.
+async fn key_usage(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    let keys = state
+        .keys
+        .as_ref()
+        .ok_or_else(|| ApiError::internal("API keys are not enabled"))?;
+    if !keys.authenticate(authorization(&headers))?.key.admin {
+        return Err(AuthError::Forbidden.into());
+    }
+    Ok(Json(json!({"object": "list", "data": keys.usage()})))
+}
+
+/// Reject the requests without a valid API key or beyond its limits, when keys are enabled.
+async fn authenticate(
+    State(state): State<Arc<ServerState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(keys) = &state.keys else {
+        return next.run(request).await;
+    };
+    let admitted = keys
+        .authenticate(authorization(request.headers()))
+        .and_then(|account| account.admit(Instant::now()));
+    let err = match admitted {
+        Ok(()) => return next.run(request).await,
+        Err(err) => err,
+    };
+    let mut response = ApiError::from(err).into_response();
+    if let AuthError::MissingKey | AuthError::InvalidKey = err {
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    response
+}
+
+/// Count the responses of every route by status.
+async fn track_requests(
+    State(state): State<Arc<ServerState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let endpoint = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(String::new, |path| path.as_str().to_string());
+    let response = next.run(request).await;
+    state
+        .metrics
+        .record_request(&endpoint, response.status().as_u16());
+    response
+}
+
+/// Routes of the OpenAI and Ollama compatible APIs.
+pub fn router(state: ServerState) -> Router {
+    let state = Arc::new(state);
+    let mut routes = Router::new()
+        .route("/v1/models", get(list_models))
+        .route("/v1/completions", post(completions))
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/requests/{id}", delete(cancel_request))
+        .merge(ollama::routes())
+        .merge(tokenize::routes());
+    if state.keys.is_some() {
+        routes = routes.route("/admin/usage", get(key_usage));
+    }
+    routes
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .route("/metrics", get(render_metrics))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            track_requests,
+        ))
+        .with_state(state)
+}
+
+/// Listen on `addr` until the process is stopped.
+pub async fn serve(addr: &str, state: ServerState) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
@@ -0,0 +1,508 @@
+//! Ollama 兼容接口
+//!
+//! `/api/generate`, `/api/chat`, `/api/tags`, `/api/show` and `/api/embeddings` with the request
+//! and response types of the `ollama` crate. Generations are streamed as newline delimited JSON
+//! unless the request sets `stream: false`.
+
+use {
+    super::{
+        ApiError, ServedModel, ServerState, run_completions, set_retry_after, submit_completions,
+        with_request_id,
+    },
+    crate::{
+        batching::CompletionEvent,
+        chat,
+        completion::{Completion, CompletionParams},
+        model::Config,
+        sampling::SamplingParams,
+    },
+    ::ollama::{
+        ChatMessage, ChatRequest, ChatResponse, EmbeddingsRequest, EmbeddingsResponse,
+        ErrorResponse, GenerateRequest, GenerateResponse, GenerationMetrics, Model, ModelDetails,
+        ModelList, Modelfile, Options, ShowRequest, ShowResponse, parameter_size,
+    },
+    axum::{
+        Json, Router,
+        body::{Body, Bytes},
+        extract::State,
+        http::{HeaderMap, StatusCode, header},
+        response::{IntoResponse, Response},
+        routing::{get, post},
+    },
+    chrono::{DateTime, Utc},
+    futures::StreamExt,
+    serde_json::json,
+    std::{
+        collections::{HashMap, HashSet},
+        hash::{DefaultHasher, Hash, Hasher},
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    tokio::sync::mpsc,
+};
+
+/// 接口错误，以 Ollama 的 `{"error": ...}` 格式返回
+#[derive(Debug)]
+pub struct OllamaError {
+    status: StatusCode,
+    message: String,
+    retry_after: Option<Duration>,
+}
+
+impl From<ApiError> for OllamaError {
+    fn from(err: ApiError) -> Self {
+        Self {
+            status: err.status,
+            message: err.message,
+            retry_after: err.retry_after,
+        }
+    }
+}
+
+impl IntoResponse for OllamaError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            error: self.message,
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            set_retry_after(&mut response, retry_after);
+        }
+        response
+    }
+}
+
+/// Name of a model in the Ollama API, which always carries a tag.
+fn ollama_name(model_id: &str) -> String {
+    if model_id.contains(':') {
+        model_id.to_string()
+    } else {
+        format!("{model_id}:latest")
+    }
+}
+
+/// Details of a model, the parameter size is only known once it is loaded.
+fn details(config: Option<&Config>) -> ModelDetails {
+    ModelDetails {
+        parent_model: None,
+        format: "safetensors".to_string(),
+        family: "llama".to_string(),
+        families: HashSet::from(["llama".to_string()]),
+        parameter_size: config.map(|config| parameter_size(parameter_count(config) as u64)),
+        quantization_level: Some("F32".to_string()),
+    }
+}
+
+impl ServerState {
+    /// The model named `name` in the Ollama API, with or without its `latest` tag.
+    async fn ollama_model(self: &Arc<Self>, name: &str) -> Result<Arc<ServedModel>, OllamaError> {
+        let id = self
+            .models
+            .ids()
+            .iter()
+            .find(|id| *id == name || ollama_name(id) == name)
+            .cloned()
+            .ok_or_else(|| OllamaError {
+                status: StatusCode::NOT_FOUND,
+                message: format!("model '{name}' not found"),
+                retry_after: None,
+            })?;
+        Ok(self.model(&id).await?)
+    }
+}
+
+impl ServedModel {
+    /// Prompt of `/api/generate`, the template of the Modelfile applied to the system prompt and
+    /// the prompt. Without a template, a system prompt is separated from the prompt by a blank
+    /// line.
+    fn generate_prompt(&self, system: Option<String>, prompt: String) -> Result<String, ApiError> {
+        let system = system.or_else(|| self.modelfile.system.clone());
+        let Some(template) = &self.template else {
+            return Ok(match system {
+                Some(system) => format!("{system}\n\n{prompt}"),
+                None => prompt,
+            });
+        };
+        let mut messages = Vec::new();
+        if let Some(system) = system {
+            messages.push(ChatMessage {
+                role: "system".to_string(),
+                content: system,
+            });
+        }
+        messages.push(ChatMessage {
+            role: "user".to_string(),
+            content: prompt,
+        });
+        template.chat_prompt(&messages).map_err(ApiError::internal)
+    }
+}
+
+/// Number of weights of a llama2.c model with the given configuration.
+fn parameter_count(config: &Config) -> usize {
+    let head_dim = config.dim / config.n_heads;
+    let kv_dim = config.n_kv_heads * head_dim;
+    let attention = 2 * config.dim * config.dim + 2 * config.dim * kv_dim;
+    let feed_forward = 3 * config.dim * config.hidden_dim;
+    let norms = 2 * config.dim;
+    let layers = config.n_layers * (attention + feed_forward + norms);
+    2 * config.vocab_size * config.dim + layers + config.dim
+}
+
+/// Defaults of the Modelfile, then of Ollama, for the options a request leaves unset. The stop
+/// strings of the Modelfile apply along with the ones of the request.
+fn completion_params(
+    options: Option<Options>,
+    defaults: &Options,
+    default_stop: &[&str],
+) -> CompletionParams {
+    let mut options = options.unwrap_or_default();
+    let mut stop = options.stop.take().unwrap_or_default();
+    let options = options.or(defaults);
+    stop.extend(options.stop.into_iter().flatten());
+    stop.extend(default_stop.iter().map(|s| s.to_string()));
+    CompletionParams {
+        sampling: SamplingParams {
+            temperature: options.temperature.unwrap_or(0.8),
+            top_p: Some(options.top_p.unwrap_or(0.9)),
+            repeat_penalty: options.repeat_penalty.unwrap_or(1.1),
+            repeat_last_n: options.repeat_last_n.unwrap_or(64),
+            seed: options.seed.unwrap_or_else(rand::random),
+        },
+        // A negative number of tokens means no limit, the context length still applies.
+        max_tokens: match options.num_predict {
+            Some(n) if n >= 0 => n as usize,
+            _ => usize::MAX,
+        },
+        stop,
+    }
+}
+
+fn nanos(seconds: f64) -> u64 {
+    (seconds * 1e9) as u64
+}
+
+fn metrics(completion: &Completion, start: Instant) -> GenerationMetrics {
+    let stats = &completion.stats;
+    GenerationMetrics {
+        total_duration: Some(start.elapsed().as_nanos() as u64),
+        load_duration: Some(0),
+        prompt_eval_count: Some(stats.prompt_tokens),
+        prompt_eval_duration: Some(nanos(stats.time_to_first_token)),
+        eval_count: Some(stats.generated_tokens),
+        eval_duration: Some(nanos(stats.elapsed - stats.time_to_first_token)),
+    }
+}
+
+fn done_reason(completion: &Completion) -> String {
+    serde_json::to_value(completion.finish_reason)
+        .ok()
+        .and_then(|reason| reason.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Builds the lines of a streamed generate or chat response.
+struct LineBuilder {
+    model: String,
+    chat: bool,
+    start: Instant,
+    // Whether some chat content was sent, leading blanks of the answer are dropped.
+    content_sent: bool,
+}
+
+impl LineBuilder {
+    fn line(value: impl serde::Serialize) -> Result<Bytes, serde_json::Error> {
+        let mut line = serde_json::to_vec(&value)?;
+        line.push(b'\n');
+        Ok(line.into())
+    }
+
+    fn response(
+        &self,
+        text: String,
+        done: Option<&Completion>,
+    ) -> Result<Bytes, serde_json::Error> {
+        let done_reason = done.map(done_reason);
+        let metrics = done
+            .map(|completion| metrics(completion, self.start))
+            .unwrap_or_default();
+        if self.chat {
+            Self::line(ChatResponse {
+                model: self.model.clone(),
+                created_at: Utc::now(),
+                message: ChatMessage {
+                    role: "assistant".to_string(),
+                    content: text,
+                },
+                done: done.is_some(),
+                done_reason,
+                metrics,
+            })
+        } else {
+            Self::line(GenerateResponse {
+                model: self.model.clone(),
+                created_at: Utc::now(),
+                response: text,
+                done: done.is_some(),
+                done_reason,
+                metrics,
+            })
+        }
+    }
+
+    fn lines(&mut self, message: CompletionEvent) -> Option<Result<Bytes, serde_json::Error>> {
+        match message {
+            CompletionEvent::Text { text, .. } => {
+                let text = if self.chat && !self.content_sent {
+                    text.trim_start().to_string()
+                } else {
+                    text
+                };
+                if text.is_empty() {
+                    return None;
+                }
+                self.content_sent = true;
+                Some(self.response(text, None))
+            }
+            CompletionEvent::Tokens { .. } => None,
+            CompletionEvent::Finished { completion, .. } => {
+                Some(self.response(String::new(), Some(&completion)))
+            }
+            CompletionEvent::Failed(error) => Some(Self::line(ErrorResponse { error })),
+            CompletionEvent::Aborted(err) => Some(Self::line(ErrorResponse {
+                error: err.to_string(),
+            })),
+        }
+    }
+
+    fn into_response(mut self, rx: mpsc::UnboundedReceiver<CompletionEvent>) -> Response {
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|message| (message, rx))
+        })
+        .filter_map(move |message| std::future::ready(self.lines(message)));
+        (
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            Body::from_stream(stream),
+        )
+            .into_response()
+    }
+}
+
+async fn generate(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<GenerateRequest>,
+) -> Result<Response, OllamaError> {
+    let start = Instant::now();
+    let served = state.ollama_model(&request.model).await?;
+    let model = request.model.clone();
+    // An empty prompt only loads the model.
+    if request.prompt.is_empty() {
+        return Ok(Json(GenerateResponse {
+            model,
+            created_at: Utc::now(),
+            response: String::new(),
+            done: true,
+            done_reason: Some("load".to_string()),
+            metrics: GenerationMetrics::default(),
+        })
+        .into_response());
+    }
+    let prompt = if request.raw == Some(true) {
+        request.prompt
+    } else {
+        served.generate_prompt(request.system, request.prompt)?
+    };
+    served.check_prompt(&prompt)?;
+    let params = completion_params(request.options, served.default_options(), &[]);
+    let admission = state.admission(&headers, "generate", None, None)?;
+    let id = admission.request_id.clone();
+
+    let builder = LineBuilder {
+        model,
+        chat: false,
+        start,
+        content_sent: false,
+    };
+    if request.stream != Some(false) {
+        let rx = submit_completions(&state, &served, admission, vec![prompt], vec![params])?;
+        return Ok(with_request_id(builder.into_response(rx), &id));
+    }
+
+    let completion = run_completions(state, &served, admission, vec![prompt], vec![params])
+        .await?
+        .remove(0);
+    let response = Json(GenerateResponse {
+        model: builder.model,
+        created_at: Utc::now(),
+        done_reason: Some(done_reason(&completion)),
+        metrics: metrics(&completion, start),
+        response: completion.text,
+        done: true,
+    });
+    Ok(with_request_id(response.into_response(), &id))
+}
+
+async fn chat(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<ChatRequest>,
+) -> Result<Response, OllamaError> {
+    let start = Instant::now();
+    let served = state.ollama_model(&request.model).await?;
+    if request.messages.is_empty() {
+        return Err(ApiError::invalid_request("messages must not be empty", "messages").into());
+    }
+    let messages: Vec<chat::ChatMessage> = request
+        .messages
+        .iter()
+        .map(|message| chat::ChatMessage::new(&message.role, &message.content))
+        .collect();
+    let (prompt, turn_stop) = served.chat_prompt(&messages)?;
+    served.check_prompt(&prompt)?;
+    let params = completion_params(request.options, served.default_options(), turn_stop);
+    let admission = state.admission(&headers, "chat", None, None)?;
+    let id = admission.request_id.clone();
+
+    let builder = LineBuilder {
+        model: request.model,
+        chat: true,
+        start,
+        content_sent: false,
+    };
+    if request.stream != Some(false) {
+        let rx = submit_completions(&state, &served, admission, vec![prompt], vec![params])?;
+        return Ok(with_request_id(builder.into_response(rx), &id));
+    }
+
+    let completion = run_completions(state, &served, admission, vec![prompt], vec![params])
+        .await?
+        .remove(0);
+    let response = Json(ChatResponse {
+        model: builder.model,
+        created_at: Utc::now(),
+        message: ChatMessage {
+            role: "assistant".to_string(),
+            content: completion.text.trim_start().to_string(),
+        },
+        done: true,
+        done_reason: Some(done_reason(&completion)),
+        metrics: metrics(&completion, start),
+    });
+    Ok(with_request_id(response.into_response(), &id))
+}
+
+async fn tags(State(state): State<Arc<ServerState>>) -> Json<ModelList> {
+    let loaded: HashMap<_, _> = state.models.all_loaded().into_iter().collect();
+    let models = state
+        .models
+        .ids()
+        .iter()
+        .map(|id| {
+            let name = ollama_name(id);
+            let config = loaded.get(id).map(|model| model.engine.config());
+            // Not the sha256 of a manifest as models are not pulled from a registry, but stable
+            // for a given name and configuration.
+            let mut hasher = DefaultHasher::new();
+            (&name, format!("{config:?}")).hash(&mut hasher);
+            Model {
+                model: name.clone(),
+                name,
+                modified_at: DateTime::from_timestamp(state.created as i64, 0).unwrap_or_default(),
+                size: config.map_or(0, |config| (parameter_count(config) * 4) as u64),
+                digest: format!("{:016x}", hasher.finish()),
+                details: details(config),
+            }
+        })
+        .collect();
+    Json(ModelList { models })
+}
+
+async fn show(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<ShowRequest>,
+) -> Result<Json<ShowResponse>, OllamaError> {
+    let served = state.ollama_model(&request.model).await?;
+    let config = served.engine.config();
+    let model_info = json!({
+        "general.architecture": "llama",
+        "general.parameter_count": parameter_count(config),
+        "llama.context_length": config.seq_len,
+        "llama.embedding_length": config.dim,
+        "llama.feed_forward_length": config.hidden_dim,
+        "llama.block_count": config.n_layers,
+        "llama.attention.head_count": config.n_heads,
+        "llama.attention.head_count_kv": config.n_kv_heads,
+        "llama.attention.layer_norm_rms_epsilon": config.norm_eps,
+        "llama.vocab_size": config.vocab_size,
+    });
+    let modelfile = &served.modelfile;
+    let parameters: Vec<String> = modelfile
+        .parameters
+        .parameters()
+        .into_iter()
+        .map(|(name, value)| match name {
+            "stop" => format!("{name} {value:?}"),
+            _ => format!("{name} {value}"),
+        })
+        .collect();
+    Ok(Json(ShowResponse {
+        license: modelfile.license.join("\n\n"),
+        modelfile: Modelfile {
+            from: served.model_id.clone(),
+            ..modelfile.clone()
+        }
+        .to_string(),
+        parameters: parameters.join("\n"),
+        template: match &served.template {
+            Some(template) => template.source().to_string(),
+            None => "{{ .Prompt }}".to_string(),
+        },
+        system: modelfile.system.clone().unwrap_or_default(),
+        details: details(Some(config)),
+        model_info: match model_info {
+            serde_json::Value::Object(map) => map,
+            _ => unreachable!("json! object literal"),
+        },
+    }))
+}
+
+async fn embeddings(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<EmbeddingsRequest>,
+) -> Result<Json<EmbeddingsResponse>, OllamaError> {
+    let served = state.ollama_model(&request.model).await?;
+    if request.prompt.is_empty() {
+        return Ok(Json(EmbeddingsResponse {
+            embedding: Vec::new(),
+        }));
+    }
+    let tokens = served
+        .tokenizer
+        .encode(&request.prompt)
+        .map_err(ApiError::internal)?;
+    if tokens.len() > served.engine.config().seq_len {
+        return Err(ApiError::invalid_request(
+            "the input is longer than the context length of the model",
+            "prompt",
+        )
+        .into());
+    }
+    state.charge(&headers, tokens.len())?;
+    let embedding = tokio::task::spawn_blocking(move || served.engine.embed(&tokens))
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)?;
+    Ok(Json(EmbeddingsResponse { embedding }))
+}
+
+/// Routes of the Ollama compatible API.
+pub(super) fn routes() -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/api/generate", post(generate))
+        .route("/api/chat", post(chat))
+        .route("/api/tags", get(tags))
+        .route("/api/show", post(show))
+        .route("/api/embeddings", post(embeddings))
+}
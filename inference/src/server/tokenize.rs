@@ -0,0 +1,134 @@
+//! 分词接口
+//!
+//! `POST /tokenize` and `POST /detokenize` with the tokenizer of a served model, so that clients
+//! can budget their prompts without a copy of it. `/tokenize` takes a `prompt` or chat `messages`,
+//! rendered like `/v1/chat/completions` renders them, and returns the tokens with their byte
+//! offsets in the text, or only their count with `count_only: true`.
+
+use {
+    super::{ApiError, ServerState},
+    crate::{chat::ChatMessage, tokenizer::TokenInfo},
+    axum::{Json, Router, extract::State, routing::post},
+    serde::{Deserialize, Serialize},
+    std::sync::Arc,
+};
+
+/// `POST /tokenize` 请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenizeRequest {
+    pub model: String,
+    #[serde(default)]
+    pub prompt: Option<String>,
+    /// Conversation tokenized as the prompt of a chat completion, instead of `prompt`.
+    #[serde(default)]
+    pub messages: Option<Vec<ChatMessage>>,
+    /// Add the special tokens of the tokenizer, such as a beginning of sequence, `true` by
+    /// default.
+    #[serde(default)]
+    pub add_special_tokens: Option<bool>,
+    /// Only return the number of tokens.
+    #[serde(default)]
+    pub count_only: bool,
+}
+
+/// `POST /tokenize` 响应
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenizeResponse {
+    pub model: String,
+    pub count: usize,
+    /// Context length of the model.
+    pub max_model_len: usize,
+    /// Offsets are in bytes of the prompt, or of the rendered conversation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Vec<TokenInfo>>,
+    /// The rendered conversation, for `messages`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+}
+
+/// `POST /detokenize` 请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetokenizeRequest {
+    pub model: String,
+    pub tokens: Vec<u32>,
+    #[serde(default)]
+    pub skip_special_tokens: bool,
+}
+
+/// `POST /detokenize` 响应
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetokenizeResponse {
+    pub model: String,
+    pub text: String,
+}
+
+async fn tokenize(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<TokenizeRequest>,
+) -> Result<Json<TokenizeResponse>, ApiError> {
+    let model = state.model(&request.model).await?;
+    let (text, rendered) = match (request.prompt, request.messages) {
+        (Some(prompt), None) => (prompt, false),
+        (None, Some(messages)) => (model.chat_prompt(&messages)?.0, true),
+        _ => {
+            return Err(ApiError::invalid_request(
+                "exactly one of prompt and messages must be given",
+                "prompt",
+            ));
+        }
+    };
+    let add_special_tokens = request.add_special_tokens.unwrap_or(true);
+    let (count, tokens) = if request.count_only {
+        let count = model
+            .tokenizer
+            .count(&text, add_special_tokens)
+            .map_err(ApiError::internal)?;
+        (count, None)
+    } else {
+        let tokens = model
+            .tokenizer
+            .tokenize(&text, add_special_tokens)
+            .map_err(ApiError::internal)?;
+        (tokens.len(), Some(tokens))
+    };
+    Ok(Json(TokenizeResponse {
+        model: model.model_id.clone(),
+        count,
+        max_model_len: model.engine.config().seq_len,
+        tokens,
+        prompt: rendered.then_some(text),
+    }))
+}
+
+async fn detokenize(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<DetokenizeRequest>,
+) -> Result<Json<DetokenizeResponse>, ApiError> {
+    let model = state.model(&request.model).await?;
+    let vocab_size = model.tokenizer.vocab_size();
+    if let Some(token) = request
+        .tokens
+        .iter()
+        .find(|&&token| token as usize >= vocab_size)
+    {
+        return Err(ApiError::invalid_request(
+            format!("the token {token} is outside the vocabulary of {vocab_size} tokens"),
+            "tokens",
+        ));
+    }
+    let text = model
+        .tokenizer
+        .decode_with(&request.tokens, request.skip_special_tokens)
+        .map_err(ApiError::internal)?;
+    Ok(Json(DetokenizeResponse {
+        model: model.model_id.clone(),
+        text,
+    }))
+}
+
+/// Routes of the tokenizer API.
+pub(super) fn routes() -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
+}
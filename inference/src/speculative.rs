@@ -0,0 +1,219 @@
+use {
+    crate::{
+        inference::{GenerationStats, InferenceEngine},
+        model::Cache,
+        sampling::{Sampler, SamplingParams},
+    },
+    anyhow::{Result, bail},
+};
+
+/// 推测解码的草稿来源
+#[derive(Clone, Copy)]
+pub enum Draft<'a> {
+    /// A smaller model sharing the vocabulary of the target model.
+    Model(&'a InferenceEngine),
+    /// Continuations copied from earlier occurrences of the last `ngram_size` tokens in the
+    /// prompt or in the generated text.
+    PromptLookup { ngram_size: usize },
+}
+
+/// Finds the most recent earlier occurrence of the trailing n-gram of `tokens` and returns up to
+/// `max_tokens` tokens that followed it. N-grams from `ngram_size` down to a single token are
+/// tried in turn, the result is empty when none of them appeared before.
+pub fn prompt_lookup(tokens: &[u32], ngram_size: usize, max_tokens: usize) -> &[u32] {
+    for n in (1..=ngram_size.min(tokens.len().saturating_sub(1))).rev() {
+        let ngram = &tokens[tokens.len() - n..];
+        let found = (0..tokens.len() - n)
+            .rev()
+            .find(|&start| &tokens[start..start + n] == ngram);
+        if let Some(start) = found {
+            let from = start + n;
+            return &tokens[from..(from + max_tokens).min(tokens.len())];
+        }
+    }
+    &[]
+}
+
+enum Drafter<'a> {
+    Model {
+        engine: &'a InferenceEngine,
+        cache: Cache,
+    },
+    PromptLookup {
+        ngram_size: usize,
+    },
+}
+
+impl Drafter<'_> {
+    /// Appends up to `k` draft tokens to `history` and returns the draft distribution for each
+    /// of them, `None` standing for a draft that puts all its mass on the proposed token.
+    fn propose(
+        &mut self,
+        history: &mut Vec<u32>,
+        k: usize,
+        params: &SamplingParams,
+        sampler: &mut Sampler,
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        match self {
+            Self::Model { engine, cache } => {
+                let mut draft_probs = Vec::with_capacity(k);
+                for _ in 0..k {
+                    let pos = cache.current_seq_len();
+                    let logits = engine.forward(&history[pos..], pos, cache)?;
+                    let logits = logits.get(history.len() - pos - 1)?;
+                    let probs = params.probabilities(&logits, history)?;
+                    history.push(sampler.sample(&probs)?);
+                    draft_probs.push(Some(probs));
+                }
+                Ok(draft_probs)
+            }
+            Self::PromptLookup { ngram_size } => {
+                let proposal = prompt_lookup(history, *ngram_size, k).to_vec();
+                history.extend_from_slice(&proposal);
+                Ok(vec![None; proposal.len()])
+            }
+        }
+    }
+
+    /// Drops the draft state past the first `seq_len` tokens.
+    fn truncate(&mut self, seq_len: usize) {
+        if let Self::Model { cache, .. } = self {
+            cache.truncate(seq_len)
+        }
+    }
+}
+
+impl InferenceEngine {
+    /// Speculative decoding: `draft` proposes up to `draft_tokens` tokens which are then checked
+    /// by a single forward pass of `self`.
+    ///
+    /// Draft tokens are accepted with probability `min(1, p / q)` where `p` and `q` are the target
+    /// and draft probabilities, on the first rejection a replacement token is drawn from the
+    /// normalized `max(0, p - q)`. The generated tokens thus follow the target distribution, the
+    /// draft only affects the speed.
+    pub fn generate_speculative(
+        &self,
+        draft: Draft,
+        prompt: &[u32],
+        params: &SamplingParams,
+        max_tokens: usize,
+        draft_tokens: usize,
+        mut on_token: impl FnMut(u32) -> Result<bool>,
+    ) -> Result<GenerationStats> {
+        if prompt.is_empty() {
+            bail!("the prompt must contain at least one token");
+        }
+        if draft_tokens == 0 {
+            bail!("the draft must propose at least one token");
+        }
+        let (mut drafter, seq_len) = match draft {
+            Draft::Model(engine) => {
+                if engine.config().vocab_size != self.config().vocab_size {
+                    bail!(
+                        "the draft vocabulary has {} tokens, the target one {}",
+                        engine.config().vocab_size,
+                        self.config().vocab_size
+                    );
+                }
+                let cache = engine.new_cache()?;
+                let seq_len = self.config().seq_len.min(engine.config().seq_len);
+                (Drafter::Model { engine, cache }, seq_len)
+            }
+            Draft::PromptLookup { ngram_size } => {
+                if ngram_size == 0 {
+                    bail!("the prompt lookup n-gram size must be positive");
+                }
+                (Drafter::PromptLookup { ngram_size }, self.config().seq_len)
+            }
+        };
+
+        let mut sampler = Sampler::new(params.seed);
+        let mut tokens = prompt.to_vec();
+        let mut stats = GenerationStats {
+            prompt_tokens: prompt.len(),
+            ..Default::default()
+        };
+
+        // Between rounds the target cache holds every token but the last one, which is fed again
+        // together with the draft tokens. The draft model cache may lag behind and catches up
+        // when drafting.
+        let mut target_cache = self.new_cache()?;
+        if prompt.len() > 1 {
+            self.forward(&prompt[..prompt.len() - 1], 0, &mut target_cache)?;
+        }
+
+        let start_gen = std::time::Instant::now();
+        'generation: while stats.generated_tokens < max_tokens && tokens.len() < seq_len {
+            // Leave room for the token sampled from the target after the draft ones.
+            let k = draft_tokens
+                .min(max_tokens - stats.generated_tokens - 1)
+                .min(seq_len - tokens.len() - 1);
+
+            let mut history = tokens.clone();
+            let draft_probs = drafter.propose(&mut history, k, params, &mut sampler)?;
+            let k = draft_probs.len();
+
+            let pos = tokens.len() - 1;
+            let logits = self.forward(&history[pos..], pos, &mut target_cache)?;
+            let mut accepted = 0;
+            let mut next_token = None;
+            for (i, q) in draft_probs.iter().enumerate() {
+                let context = &history[..tokens.len() + i];
+                let p = params.probabilities(&logits.get(i)?, context)?;
+                let token = history[tokens.len() + i] as usize;
+                let q_token = q.as_ref().map_or(1.0, |q| q[token]);
+                if sampler.uniform() * q_token < p[token] {
+                    accepted += 1;
+                    continue;
+                }
+                let mut residual: Vec<f32> = match q {
+                    Some(q) => p
+                        .iter()
+                        .zip(q.iter())
+                        .map(|(p, q)| (p - q).max(0.0))
+                        .collect(),
+                    None => {
+                        let mut residual = p.clone();
+                        residual[token] = 0.0;
+                        residual
+                    }
+                };
+                if residual.iter().sum::<f32>() <= 0.0 {
+                    residual = p;
+                }
+                next_token = Some(sampler.sample(&residual)?);
+                break;
+            }
+            let next_token = match next_token {
+                Some(token) => token,
+                None => {
+                    let p = params.probabilities(&logits.get(k)?, &history)?;
+                    sampler.sample(&p)?
+                }
+            };
+            stats.draft_tokens += k;
+            stats.accepted_tokens += accepted;
+
+            history.truncate(tokens.len() + accepted);
+            history.push(next_token);
+            if stats.generated_tokens == 0 {
+                stats.time_to_first_token = start_gen.elapsed().as_secs_f64();
+            }
+            for &token in history[tokens.len()..].iter() {
+                stats.generated_tokens += 1;
+                if !on_token(token)? {
+                    break 'generation;
+                }
+            }
+            tokens = history;
+            target_cache.truncate(tokens.len() - 1);
+            drafter.truncate(tokens.len() - 1);
+        }
+
+        stats.elapsed = start_gen.elapsed().as_secs_f64();
+        if stats.generated_tokens == 0 {
+            stats.time_to_first_token = stats.elapsed;
+        }
+        Ok(stats)
+    }
+}
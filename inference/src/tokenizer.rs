@@ -1,4 +1,21 @@
-use tokenizers::Tokenizer as HFTokenizer;
+use {
+    serde::{Deserialize, Serialize},
+    tokenizers::Tokenizer as HFTokenizer,
+};
+
+/// 词元信息
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub id: u32,
+    /// Token string of the vocabulary.
+    pub token: String,
+    /// Byte offset of the first byte of the token in the text.
+    pub start: usize,
+    /// Byte offset past the last byte of the token, `start` for tokens added to the text.
+    pub end: usize,
+    /// The token is a special token, such as the beginning or end of sequence.
+    pub special: bool,
+}
 
 #[derive(Debug, Clone)]
 pub struct Tokenizer {
@@ -18,11 +35,79 @@ impl Tokenizer {
         })
     }
 
+    /// Load a `tokenizer.json` file, the end of sequence token is looked up among the usual
+    /// special tokens.
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let tokenizer = HFTokenizer::from_file(path)
+            .map_err(|e| anyhow::anyhow!("Failed to load tokenizer from {}: {}", path, e))?;
+        let eos_token_id = ["</s>", "<|endoftext|>", "<|eot_id|>", "[SEP]"]
+            .iter()
+            .find_map(|token| tokenizer.token_to_id(token))
+            .ok_or_else(|| anyhow::anyhow!("No end of sequence token in {}", path))?;
+        Ok(Self {
+            tokenizer,
+            eos_token_id,
+        })
+    }
+
+    pub fn from_tokenizer(tokenizer: HFTokenizer, eos_token_id: u32) -> Self {
+        Self {
+            tokenizer,
+            eos_token_id,
+        }
+    }
+
     pub fn encode(&self, text: &str) -> anyhow::Result<Vec<u32>> {
         let encoding = self.tokenizer.encode(text, true).unwrap();
         Ok(encoding.get_ids().to_vec())
     }
 
+    /// Tokens of `text` with their strings and byte offsets, with the special tokens of the
+    /// tokenizer's post-processor when `add_special_tokens` is set.
+    pub fn tokenize(&self, text: &str, add_special_tokens: bool) -> anyhow::Result<Vec<TokenInfo>> {
+        let encoding = self
+            .tokenizer
+            .encode(text, add_special_tokens)
+            .map_err(|e| anyhow::anyhow!("Failed to tokenize: {}", e))?;
+        let added = self.tokenizer.get_added_tokens_decoder();
+        let tokens = encoding
+            .get_ids()
+            .iter()
+            .zip(encoding.get_tokens())
+            .zip(encoding.get_offsets())
+            .zip(encoding.get_special_tokens_mask())
+            .map(|(((&id, token), &(start, end)), &mask)| TokenInfo {
+                id,
+                token: token.clone(),
+                start,
+                end,
+                special: mask == 1 || added.get(&id).is_some_and(|token| token.special),
+            })
+            .collect();
+        Ok(tokens)
+    }
+
+    /// Number of tokens of `text`, see [`Self::tokenize`].
+    pub fn count(&self, text: &str, add_special_tokens: bool) -> anyhow::Result<usize> {
+        let encoding = self
+            .tokenizer
+            .encode(text, add_special_tokens)
+            .map_err(|e| anyhow::anyhow!("Failed to tokenize: {}", e))?;
+        Ok(encoding.len())
+    }
+
+    /// Number of tokens of the vocabulary, added tokens included.
+    pub fn vocab_size(&self) -> usize {
+        self.tokenizer.get_vocab_size(true)
+    }
+
+    /// Text of `tokens`, leaving the special tokens out when `skip_special_tokens` is set.
+    pub fn decode_with(&self, tokens: &[u32], skip_special_tokens: bool) -> anyhow::Result<String> {
+        self.tokenizer
+            .decode(tokens, skip_special_tokens)
+            .map_err(|e| anyhow::anyhow!("Failed to decode: {}", e))
+    }
+
     pub fn decode(&self, tokens: &[u32]) -> anyhow::Result<String> {
         let text = self.tokenizer.decode(tokens, true).unwrap();
         Ok(text)
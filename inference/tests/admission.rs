@@ -0,0 +1,90 @@
+use {
+    llama_rust::admission::{Admission, AdmissionError, AdmissionQueue},
+    std::time::{Duration, Instant},
+};
+
+fn admission(request_id: &str, priority: i32) -> Admission {
+    Admission {
+        priority,
+        ..Admission::new(request_id)
+    }
+}
+
+fn drain(queue: &mut AdmissionQueue<u32>) -> Vec<u32> {
+    std::iter::from_fn(|| queue.pop().map(|(_, item)| item)).collect()
+}
+
+#[test]
+fn lower_priorities_are_served_first_then_by_arrival() {
+    let mut queue = AdmissionQueue::new(8);
+    queue.push(&admission("a", 0), vec![1, 2]).unwrap();
+    queue.push(&admission("b", 5), vec![3]).unwrap();
+    queue.push(&admission("c", -1), vec![4]).unwrap();
+    queue.push(&admission("d", 0), vec![5]).unwrap();
+    assert_eq!(queue.len(), 5);
+    assert_eq!(drain(&mut queue), [4, 1, 2, 5, 3]);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn requests_that_do_not_fit_are_rejected_whole() {
+    let mut queue = AdmissionQueue::new(3);
+    queue.push(&admission("a", 0), vec![1, 2]).unwrap();
+    assert_eq!(
+        queue.push(&admission("b", 0), vec![3, 4]),
+        Err(AdmissionError::QueueFull { capacity: 3 })
+    );
+    assert_eq!(queue.len(), 2);
+    queue.push(&admission("c", 0), vec![5]).unwrap();
+    assert_eq!(drain(&mut queue), [1, 2, 5]);
+}
+
+#[test]
+fn expired_and_cancelled_requests_leave_the_queue() {
+    let now = Instant::now();
+    let mut queue = AdmissionQueue::new(8);
+    let expired = Admission {
+        deadline: Some(now),
+        ..admission("expired", 0)
+    };
+    let later = Admission {
+        deadline: Some(now + Duration::from_secs(60)),
+        ..admission("later", 0)
+    };
+    queue.push(&expired, vec![1, 2]).unwrap();
+    queue.push(&later, vec![3]).unwrap();
+    queue.push(&admission("cancelled", 0), vec![4, 5]).unwrap();
+
+    let items = |removed: Vec<(Admission, u32)>| -> Vec<u32> {
+        removed.into_iter().map(|(_, item)| item).collect()
+    };
+    assert_eq!(items(queue.expire(now)), [1, 2]);
+    assert!(queue.contains("cancelled", None));
+    assert_eq!(items(queue.cancel("cancelled", None)), [4, 5]);
+    assert!(!queue.contains("cancelled", None));
+    assert!(queue.cancel("unknown", None).is_empty());
+    assert_eq!(drain(&mut queue), [3]);
+}
+
+#[test]
+fn requests_are_only_cancelled_by_their_owner() {
+    let mut queue = AdmissionQueue::new(8);
+    let owned = |owner: &str| Admission {
+        owner: Some(owner.to_string()),
+        ..admission("shared", 0)
+    };
+    queue.push(&owned("team-a"), vec![1]).unwrap();
+    queue.push(&owned("team-b"), vec![2]).unwrap();
+    queue.push(&admission("shared", 0), vec![3]).unwrap();
+
+    let cancelled: Vec<u32> = queue
+        .cancel("shared", Some("team-b"))
+        .into_iter()
+        .map(|(_, item)| item)
+        .collect();
+    // The request without an owner is anyone's.
+    assert_eq!(cancelled, [2, 3]);
+    assert!(!queue.contains("shared", Some("team-b")));
+    assert!(queue.contains("shared", Some("team-a")));
+    assert_eq!(drain(&mut queue), [1]);
+}
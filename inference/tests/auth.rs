@@ -0,0 +1,114 @@
+use {
+    llama_rust::auth::{ApiKey, ApiKeys, AuthError, Limit},
+    std::time::{Duration, Instant},
+};
+
+fn key(key: &str, name: &str) -> ApiKey {
+    ApiKey {
+        key: key.to_string(),
+        name: name.to_string(),
+        requests_per_minute: None,
+        tokens_per_minute: None,
+        admin: false,
+    }
+}
+
+#[test]
+fn bearer_keys_are_authenticated() {
+    let keys = ApiKeys::new(vec![key("sk-a", "team-a"), key("sk-b", "team-b")]).unwrap();
+    let account = keys.authenticate(Some("Bearer sk-b")).unwrap();
+    assert_eq!(account.key.name, "team-b");
+    assert!(keys.authenticate(Some("bearer sk-a")).is_ok());
+    assert_eq!(
+        keys.authenticate(Some("Bearer sk-c")).unwrap_err(),
+        AuthError::InvalidKey
+    );
+    assert_eq!(
+        keys.authenticate(Some("sk-a")).unwrap_err(),
+        AuthError::MissingKey
+    );
+    assert_eq!(keys.authenticate(None).unwrap_err(), AuthError::MissingKey);
+    assert_eq!(keys.account("team-a").unwrap().key.key, "sk-a");
+
+    assert!(ApiKeys::new(vec![key("sk-a", "team"), key("sk-b", "team")]).is_err());
+    assert!(ApiKeys::new(vec![key("sk-a", "team-a"), key("sk-a", "team-b")]).is_err());
+    let unlimited = ApiKey {
+        requests_per_minute: Some(0),
+        ..key("sk-a", "team-a")
+    };
+    assert!(ApiKeys::new(vec![unlimited]).is_err());
+}
+
+#[test]
+fn requests_per_minute_refill_over_the_minute() {
+    let keys = ApiKeys::new(vec![ApiKey {
+        requests_per_minute: Some(2),
+        ..key("sk-a", "team-a")
+    }])
+    .unwrap();
+    let account = keys.account("team-a").unwrap();
+    let now = Instant::now() + Duration::from_secs(1);
+    account.admit(now).unwrap();
+    account.admit(now).unwrap();
+    let Err(AuthError::RateLimited { limit, retry_after }) = account.admit(now) else {
+        panic!("the third request is admitted");
+    };
+    assert_eq!(limit, Limit::RequestsPerMinute);
+    assert!((retry_after.as_secs_f64() - 30.0).abs() < 1e-6);
+    account.admit(now + Duration::from_secs(30)).unwrap();
+
+    let usage = account.usage();
+    assert_eq!(usage.requests, 3);
+    assert_eq!(usage.rate_limited, 1);
+}
+
+#[test]
+fn tokens_beyond_the_limit_block_the_key_until_they_refill() {
+    let keys = ApiKeys::new(vec![ApiKey {
+        tokens_per_minute: Some(600),
+        ..key("sk-a", "team-a")
+    }])
+    .unwrap();
+    let account = keys.account("team-a").unwrap();
+    let now = Instant::now() + Duration::from_secs(1);
+    account.admit(now).unwrap();
+    account.record(500, 200, now);
+    // 100 tokens of debt, refilled at 10 tokens a second, then one token for the next request.
+    let Err(AuthError::RateLimited { limit, retry_after }) = account.admit(now) else {
+        panic!("the key is in debt");
+    };
+    assert_eq!(limit, Limit::TokensPerMinute);
+    assert!((retry_after.as_secs_f64() - 10.1).abs() < 1e-6);
+    account.admit(now + Duration::from_secs(11)).unwrap();
+
+    let usage = keys.usage();
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].name, "team-a");
+    assert_eq!(usage[0].prompt_tokens, 500);
+    assert_eq!(usage[0].completion_tokens, 200);
+    assert_eq!(usage[0].tokens_per_minute, Some(600));
+}
+
+#[test]
+fn known_costs_are_charged_before_running() {
+    let keys = ApiKeys::new(vec![ApiKey {
+        tokens_per_minute: Some(600),
+        ..key("sk-a", "team-a")
+    }])
+    .unwrap();
+    let account = keys.account("team-a").unwrap();
+    let now = Instant::now() + Duration::from_secs(1);
+    account.charge(400, now).unwrap();
+    let Err(AuthError::RateLimited { limit, retry_after }) = account.charge(300, now) else {
+        panic!("the bucket only holds 200 tokens");
+    };
+    assert_eq!(limit, Limit::TokensPerMinute);
+    assert!((retry_after.as_secs_f64() - 10.0).abs() < 1e-6);
+    // A request beyond the limit runs on a full bucket and leaves it in debt.
+    account.charge(1000, now + Duration::from_secs(40)).unwrap();
+    assert!(account.admit(now + Duration::from_secs(40)).is_err());
+
+    let usage = account.usage();
+    assert_eq!(usage.prompt_tokens, 1400);
+    assert_eq!(usage.rate_limited, 2);
+}
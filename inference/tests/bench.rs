@@ -0,0 +1,156 @@
+mod common;
+
+use {
+    anyhow::Result,
+    candle_nn::VarMap,
+    common::{random_engine, random_engine_from, word_tokenizer},
+    llama_rust::{
+        admission::AdmissionConfig,
+        batching::BatchEngine,
+        bench::{
+            self, BenchParams, BenchReport, BenchRequest, BenchTarget, Percentiles, RequestResult,
+        },
+        metrics::Metrics,
+        scheduler::SchedulerConfig,
+        server::{ServerState, router},
+    },
+    std::sync::Arc,
+};
+
+fn requests(n: usize, max_tokens: usize) -> Vec<BenchRequest> {
+    (0..n)
+        .map(|i| BenchRequest {
+            prompt: "the cat sat on the mat".to_string(),
+            params: BenchParams {
+                max_tokens: Some(max_tokens),
+                seed: Some(i as u64),
+                ..Default::default()
+            },
+            offset: None,
+        })
+        .collect()
+}
+
+#[test]
+fn requests_are_read_from_jsonl() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("bench-{}.jsonl", std::process::id()));
+    std::fs::write(
+        &path,
+        concat!(
+            "{\"prompt\": \"the cat\", \"params\": {\"max_tokens\": 4, \"temperature\": 0}}\n",
+            "\n",
+            "{\"body\": \"a dog\", \"offset\": 1.5}\n",
+        ),
+    )?;
+    let requests = bench::read_requests(&path)?;
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].prompt, "the cat");
+    assert_eq!(requests[0].params.max_tokens, Some(4));
+    assert_eq!(requests[0].params.temperature, Some(0.0));
+    assert_eq!(requests[1].prompt, "a dog");
+    assert_eq!(requests[1].offset, Some(1.5));
+
+    std::fs::write(&path, "{\"prompt\": \"the cat\"}\nnot json\n")?;
+    let err = bench::read_requests(&path).unwrap_err();
+    assert!(format!("{err}").ends_with(":2: invalid request"), "{err}");
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn arrivals_follow_the_rate_or_the_offsets() -> Result<()> {
+    let mut requests = requests(3, 1);
+    requests[1].offset = Some(0.5);
+    requests[2].offset = Some(0.25);
+    assert_eq!(bench::arrivals(&requests, None)?, [0.0, 0.5, 0.25]);
+    assert_eq!(bench::arrivals(&requests, Some(4.0))?, [0.0, 0.25, 0.5]);
+    assert!(bench::arrivals(&requests, Some(0.0)).is_err());
+    requests[0].offset = Some(-1.0);
+    assert!(bench::arrivals(&requests, None).is_err());
+    Ok(())
+}
+
+#[test]
+fn report_takes_percentiles_of_the_completed_requests() {
+    let samples = (1..=100).map(|i| i as f64).collect();
+    let percentiles = Percentiles::new(samples);
+    assert_eq!(percentiles.mean, 50.5);
+    assert_eq!(percentiles.p50, 50.0);
+    assert_eq!(percentiles.p90, 90.0);
+    assert_eq!(percentiles.p99, 99.0);
+    assert_eq!(Percentiles::new(Vec::new()), Percentiles::default());
+
+    let completed = |ttft: f64, itl: Vec<f64>| RequestResult {
+        ttft: Some(ttft),
+        latency: ttft + itl.iter().sum::<f64>(),
+        generated_tokens: itl.len() + 1,
+        itl,
+        error: None,
+    };
+    let failed = |error: &str| RequestResult {
+        latency: 10.0,
+        error: Some(error.to_string()),
+        ..Default::default()
+    };
+    let results = [
+        completed(0.1, vec![0.01, 0.03]),
+        completed(0.3, vec![0.02]),
+        failed("queue full"),
+        failed("queue full"),
+        failed("http 500"),
+    ];
+    let report = BenchReport::new(&results, 2.0);
+    assert_eq!(report.completed, 2);
+    assert_eq!(report.failed(), 3);
+    assert_eq!(report.errors["queue full"], 2);
+    assert_eq!(report.errors["http 500"], 1);
+    assert_eq!(report.generated_tokens, 5);
+    assert_eq!(report.request_throughput(), 1.0);
+    assert_eq!(report.token_throughput(), 2.5);
+    assert_eq!(report.ttft.p99, 0.3);
+    assert_eq!(report.itl.p50, 0.02);
+    assert!(report.latency.p99 < 1.0);
+    let text = report.to_string();
+    assert!(text.contains("2 completed, 3 failed"), "{text}");
+    assert!(text.contains("error queue full: 2"), "{text}");
+}
+
+#[tokio::test]
+async fn in_process_run_completes_every_request() -> Result<()> {
+    let batch = BatchEngine::spawn(
+        Arc::new(random_engine()?),
+        word_tokenizer(),
+        SchedulerConfig::default(),
+        AdmissionConfig::default(),
+        Arc::new(Metrics::default()),
+    );
+    let requests = requests(6, 4);
+    let arrivals = bench::arrivals(&requests, Some(200.0))?;
+    let report = bench::run(BenchTarget::InProcess(Box::new(batch)), requests, arrivals).await;
+    assert_eq!(report.completed, 6, "{report}");
+    assert!(report.errors.is_empty(), "{report}");
+    assert!(report.generated_tokens <= 6 * 4);
+    assert!(report.duration > 0.0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_run_streams_from_the_server_and_counts_errors() -> Result<()> {
+    let engine = random_engine_from(&VarMap::new())?;
+    let app = router(ServerState::new(engine, word_tokenizer(), "tiny"));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let target = BenchTarget::http(&url, None, None).await?;
+    let report = bench::run(target, requests(4, 4), vec![0.0; 4]).await;
+    assert_eq!(report.completed, 4, "{report}");
+    assert!(report.errors.is_empty(), "{report}");
+    assert!(report.generated_tokens <= 4 * 4);
+
+    let target = BenchTarget::http(&url, Some("missing".to_string()), None).await?;
+    let report = bench::run(target, requests(2, 1), vec![0.0; 2]).await;
+    assert_eq!(report.completed, 0);
+    assert_eq!(report.errors["http 404"], 2);
+    Ok(())
+}
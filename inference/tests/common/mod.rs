@@ -0,0 +1,76 @@
+// Shared by several test binaries, each of them only uses part of it.
+#![allow(dead_code)]
+
+use {
+    anyhow::Result,
+    candle_core::{DType, Device},
+    candle_nn::{VarBuilder, VarMap},
+    candle_transformers::models::llama2_c::Config,
+    llama_rust::{inference::InferenceEngine, tokenizer::Tokenizer},
+    tokenizers::{models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace},
+};
+
+pub fn tiny_config() -> Config {
+    Config {
+        dim: 16,
+        hidden_dim: 32,
+        n_layers: 2,
+        n_heads: 2,
+        n_kv_heads: 1,
+        vocab_size: 32,
+        seq_len: 64,
+        norm_eps: 1e-5,
+    }
+}
+
+/// All weights are zero so every position predicts the uniform distribution over the vocabulary.
+pub fn uniform_engine() -> Result<InferenceEngine> {
+    let vb = VarBuilder::zeros(DType::F32, &Device::Cpu);
+    InferenceEngine::from_var_builder(vb, tiny_config())
+}
+
+pub fn random_engine() -> Result<InferenceEngine> {
+    random_engine_from(&VarMap::new())
+}
+
+/// The weights are drawn when first loaded, engines built from the same `varmap` share them.
+pub fn random_engine_from(varmap: &VarMap) -> Result<InferenceEngine> {
+    let vb = VarBuilder::from_varmap(varmap, DType::F32, &Device::Cpu);
+    InferenceEngine::from_var_builder(vb, tiny_config())
+}
+
+/// Words of the test vocabulary, one token each.
+pub const WORDS: [&str; 32] = [
+    "<unk>", "</s>", "the", "a", "cat", "dog", "bird", "fish", "sat", "ran", "flew", "swam", "on",
+    "in", "under", "over", "mat", "tree", "sky", "sea", "big", "small", "red", "blue", "and",
+    "then", "it", "was", "happy", "sad", "today", "again",
+];
+
+/// Whitespace separated word level tokenizer over [`WORDS`], matching the vocabulary size of
+/// [`tiny_config`].
+pub fn word_tokenizer() -> Tokenizer {
+    Tokenizer::from_tokenizer(word_level(), 1)
+}
+
+/// Like [`word_tokenizer`] with an end of text token outside the vocabulary, so that
+/// generations only stop at their token budget.
+pub fn endless_word_tokenizer() -> Tokenizer {
+    Tokenizer::from_tokenizer(word_level(), WORDS.len() as u32)
+}
+
+fn word_level() -> tokenizers::Tokenizer {
+    let model = WordLevel::builder()
+        .vocab(
+            WORDS
+                .iter()
+                .enumerate()
+                .map(|(id, word)| (word.to_string(), id as u32))
+                .collect(),
+        )
+        .unk_token("<unk>".to_string())
+        .build()
+        .expect("valid word level vocabulary");
+    let mut tokenizer = tokenizers::Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+    tokenizer
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use {
+    anyhow::Result,
+    candle_core::quantized::{GgmlDType, QTensor, gguf_file},
+    candle_nn::VarMap,
+    common::{random_engine_from, tiny_config},
+    llama_rust::{gguf, inference::InferenceEngine, sampling::SamplingParams},
+    std::{fs::File, path::PathBuf},
+};
+
+/// llama.cpp name of a tensor loaded by [`llama_rust::model::Llama::load`].
+fn gguf_name(name: &str) -> String {
+    let name = name
+        .replace("model.embed_tokens", "token_embd")
+        .replace("model.norm", "output_norm")
+        .replace("lm_head", "output")
+        .replace("model.layers.", "blk.");
+    [
+        ("self_attn.q_proj", "attn_q"),
+        ("self_attn.k_proj", "attn_k"),
+        ("self_attn.v_proj", "attn_v"),
+        ("self_attn.o_proj", "attn_output"),
+        ("mlp.gate_proj", "ffn_gate"),
+        ("mlp.up_proj", "ffn_up"),
+        ("mlp.down_proj", "ffn_down"),
+        ("post_attention_layernorm", "ffn_norm"),
+        ("input_layernorm", "attn_norm"),
+    ]
+    .iter()
+    .fold(name, |name, (from, to)| name.replace(from, to))
+}
+
+/// Write the weights of `varmap` to a GGUF file of the given architecture and data type.
+fn write_gguf(varmap: &VarMap, test: &str, arch: &str, dtype: GgmlDType) -> Result<PathBuf> {
+    let path = std::env::temp_dir().join(format!("llama-gguf-{}-{test}.gguf", std::process::id()));
+    let cfg = tiny_config();
+    let u32_value = |v: usize| gguf_file::Value::U32(v as u32);
+    let metadata = [
+        (
+            "general.architecture",
+            gguf_file::Value::String(arch.into()),
+        ),
+        ("llama.embedding_length", u32_value(cfg.dim)),
+        ("llama.feed_forward_length", u32_value(cfg.hidden_dim)),
+        ("llama.block_count", u32_value(cfg.n_layers)),
+        ("llama.attention.head_count", u32_value(cfg.n_heads)),
+        ("llama.attention.head_count_kv", u32_value(cfg.n_kv_heads)),
+        ("llama.context_length", u32_value(cfg.seq_len)),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(cfg.norm_eps as f32),
+        ),
+        ("llama.rope.freq_base", gguf_file::Value::F32(10000.)),
+    ];
+    let tensors = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| {
+            // Norms stay in F32, as in the files written by llama.cpp.
+            let dtype = if var.rank() == 1 {
+                GgmlDType::F32
+            } else {
+                dtype
+            };
+            Ok((gguf_name(name), QTensor::quantize(var.as_tensor(), dtype)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let metadata: Vec<_> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+    let tensors: Vec<_> = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    gguf_file::write(&mut File::create(&path)?, &metadata, &tensors)?;
+    Ok(path)
+}
+
+fn greedy_tokens(engine: &InferenceEngine, prompt: &[u32]) -> Result<Vec<u32>> {
+    let params = SamplingParams {
+        temperature: 0.0,
+        repeat_penalty: 1.0,
+        ..Default::default()
+    };
+    let mut tokens = Vec::new();
+    engine.generate_tokens(prompt, &params, 20, |token| {
+        tokens.push(token);
+        Ok(true)
+    })?;
+    Ok(tokens)
+}
+
+#[test]
+fn gguf_model_matches_the_safetensors_weights() -> Result<()> {
+    let varmap = VarMap::new();
+    let engine = random_engine_from(&varmap)?;
+    let path = write_gguf(&varmap, "f32", "llama", GgmlDType::F32)?;
+    assert!(gguf::is_gguf(&path));
+
+    let gguf_engine = InferenceEngine::load_model(path.to_str().unwrap(), tiny_config(), true)?;
+    let config = gguf_engine.config();
+    assert_eq!(
+        (config.dim, config.n_kv_heads, config.vocab_size),
+        (16, 1, 32)
+    );
+
+    let prompt = [1, 4, 9, 4, 2];
+    assert_eq!(
+        greedy_tokens(&gguf_engine, &prompt)?,
+        greedy_tokens(&engine, &prompt)?
+    );
+    let expected = engine.embed(&prompt)?;
+    let embedding = gguf_engine.embed(&prompt)?;
+    for (a, b) in expected.iter().zip(&embedding) {
+        assert!((a - b).abs() < 1e-5, "{a} != {b}");
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[test]
+fn f16_gguf_model_is_close_to_the_f32_weights() -> Result<()> {
+    let varmap = VarMap::new();
+    let engine = random_engine_from(&varmap)?;
+    let path = write_gguf(&varmap, "f16", "llama", GgmlDType::F16)?;
+    let gguf_engine = InferenceEngine::load_gguf(&path, true)?;
+
+    let tokens: Vec<u32> = (0..40).map(|i| (i * 7 % 32) as u32).collect();
+    let expected = engine.perplexity(&tokens, 16, 8)?.perplexity();
+    let perplexity = gguf_engine.perplexity(&tokens, 16, 8)?.perplexity();
+    assert!(
+        (perplexity - expected).abs() / expected < 1e-2,
+        "{perplexity} != {expected}"
+    );
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[test]
+fn gguf_rejects_other_architectures() -> Result<()> {
+    let varmap = VarMap::new();
+    random_engine_from(&varmap)?;
+    let path = write_gguf(&varmap, "arch", "gemma", GgmlDType::F32)?;
+    let error = InferenceEngine::load_gguf(&path, true)
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        error.contains("unsupported model architecture 'gemma'"),
+        "{error}"
+    );
+    std::fs::remove_file(path)?;
+    Ok(())
+}
@@ -1 +1,321 @@
-// TODO: add more tests here
+mod common;
+
+use {
+    anyhow::Result,
+    candle_nn::VarMap,
+    common::{random_engine, random_engine_from, uniform_engine, word_tokenizer},
+    kv_cache::KvQuantization,
+    llama_rust::{
+        beam_search::BeamSearchParams,
+        completion::{CompletionParams, FinishReason},
+        embedding::Pooling,
+        sampling::SamplingParams,
+        speculative::{Draft, prompt_lookup},
+    },
+};
+
+#[test]
+fn perplexity_of_uniform_model_is_vocab_size() -> Result<()> {
+    let engine = uniform_engine()?;
+    let tokens: Vec<u32> = (0..100).map(|i| (i * 7 % 32) as u32).collect();
+
+    for (ctx, stride) in [(16, 15), (16, 8), (32, 5), (64, 63)] {
+        let report = engine.perplexity(&tokens, ctx, stride)?;
+        assert_eq!(report.scored_tokens(), tokens.len() - 1);
+        assert!((report.perplexity() - 32.0).abs() < 1e-3);
+        for chunk in report.chunks.iter() {
+            assert!(chunk.scored_tokens < ctx);
+            assert!((chunk.perplexity() - 32.0).abs() < 1e-3);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn perplexity_rejects_invalid_windows() -> Result<()> {
+    let engine = uniform_engine()?;
+    let tokens = [1, 2, 3, 4];
+    assert!(engine.perplexity(&tokens, 128, 64).is_err());
+    assert!(engine.perplexity(&tokens, 16, 0).is_err());
+    assert!(engine.perplexity(&tokens, 16, 16).is_err());
+    Ok(())
+}
+
+#[test]
+fn score_continuations_matches_token_by_token_scoring() -> Result<()> {
+    let engine = random_engine()?;
+    let context = [3, 1, 4, 1, 5];
+    let continuations = vec![vec![9, 2, 6], vec![5], vec![3, 5, 8, 9]];
+
+    let scores = engine.score_continuations(&context, &continuations)?;
+    assert_eq!(scores.len(), continuations.len());
+    for (score, continuation) in scores.iter().zip(continuations.iter()) {
+        // Score each token separately, running the whole prefix through a fresh cache.
+        let mut expected = 0.0;
+        let mut prefix = context.to_vec();
+        for &token in continuation {
+            expected += engine.score_continuations(&prefix, &[vec![token]])?[0].log_likelihood;
+            prefix.push(token);
+        }
+        assert_eq!(score.num_tokens, continuation.len());
+        assert!((score.log_likelihood - expected).abs() < 1e-3);
+        assert!(
+            (score.normalized_log_likelihood() - expected / continuation.len() as f64).abs() < 1e-3
+        );
+    }
+
+    assert!(engine.score_continuations(&[], &continuations).is_err());
+    assert!(engine.score_continuations(&context, &[vec![]]).is_err());
+    Ok(())
+}
+
+#[test]
+fn beam_search_width_one_is_greedy() -> Result<()> {
+    let engine = random_engine()?;
+    let prompt = [2, 7, 1, 8];
+    let params = BeamSearchParams {
+        beam_width: 1,
+        max_tokens: 6,
+        ..Default::default()
+    };
+    let hypotheses = engine.beam_search(&prompt, &params)?;
+    assert_eq!(hypotheses.len(), 1);
+    assert_eq!(hypotheses[0].tokens.len(), 6);
+
+    let score = &engine.score_continuations(&prompt, &[hypotheses[0].tokens.clone()])?[0];
+    assert!(score.is_greedy);
+    assert!((score.log_likelihood - hypotheses[0].log_prob).abs() < 1e-3);
+    Ok(())
+}
+
+#[test]
+fn beam_search_returns_ranked_distinct_hypotheses() -> Result<()> {
+    let engine = random_engine()?;
+    let prompt = [2, 7, 1, 8];
+    let params = BeamSearchParams {
+        beam_width: 4,
+        num_return: 3,
+        max_tokens: 5,
+        eos_token_id: Some(0),
+        ..Default::default()
+    };
+    let hypotheses = engine.beam_search(&prompt, &params)?;
+    assert_eq!(hypotheses.len(), 3);
+    for pair in hypotheses.windows(2) {
+        assert!(pair[0].score >= pair[1].score);
+        assert_ne!(pair[0].tokens, pair[1].tokens);
+    }
+    // Forked caches must not leak into each other: rescoring from scratch gives the same values.
+    for hypothesis in hypotheses.iter() {
+        let mut continuation = hypothesis.tokens.clone();
+        if hypothesis.eos {
+            continuation.push(0);
+        }
+        let score = &engine.score_continuations(&prompt, &[continuation])?[0];
+        assert!((score.log_likelihood - hypothesis.log_prob).abs() < 1e-3);
+    }
+    Ok(())
+}
+
+fn greedy() -> SamplingParams {
+    SamplingParams {
+        temperature: 0.0,
+        repeat_penalty: 1.0,
+        ..Default::default()
+    }
+}
+
+fn collect_tokens(
+    generate: impl FnOnce(&mut dyn FnMut(u32) -> Result<bool>) -> Result<()>,
+) -> Result<Vec<u32>> {
+    let mut tokens = Vec::new();
+    generate(&mut |token| {
+        tokens.push(token);
+        Ok(true)
+    })?;
+    Ok(tokens)
+}
+
+#[test]
+fn speculative_decoding_matches_greedy_target() -> Result<()> {
+    let target = random_engine()?;
+    let draft = random_engine()?;
+    let prompt = [1, 4, 1, 4, 2];
+    let params = greedy();
+
+    let expected = collect_tokens(|on_token| {
+        target.generate_tokens(&prompt, &params, 20, on_token)?;
+        Ok(())
+    })?;
+    let mut stats = None;
+    let tokens = collect_tokens(|on_token| {
+        stats = Some(target.generate_speculative(
+            Draft::Model(&draft),
+            &prompt,
+            &params,
+            20,
+            3,
+            on_token,
+        )?);
+        Ok(())
+    })?;
+    assert_eq!(tokens, expected);
+    let stats = stats.unwrap();
+    assert_eq!(stats.generated_tokens, 20);
+    assert!(stats.draft_tokens > 0);
+    assert!(stats.accepted_tokens <= stats.draft_tokens);
+    Ok(())
+}
+
+#[test]
+fn speculative_decoding_with_identical_draft_accepts_everything() -> Result<()> {
+    let engine = random_engine()?;
+    let params = SamplingParams {
+        temperature: 0.8,
+        top_p: Some(0.9),
+        ..Default::default()
+    };
+    let stats =
+        engine.generate_speculative(Draft::Model(&engine), &[3, 3, 7], &params, 16, 4, |_| {
+            Ok(true)
+        })?;
+    assert_eq!(stats.generated_tokens, 16);
+    assert_eq!(stats.acceptance_rate(), Some(1.0));
+    Ok(())
+}
+
+#[test]
+fn prompt_lookup_copies_the_latest_match() {
+    let tokens = [5, 6, 7, 8, 1, 5, 6, 9, 2, 5, 6];
+    // The bigram (5, 6) was last seen followed by 9, 2.
+    assert_eq!(prompt_lookup(&tokens, 2, 2), &[9, 2]);
+    assert_eq!(prompt_lookup(&tokens, 2, 10), &[9, 2, 5, 6]);
+    // Falls back to shorter n-grams when the longer ones never occurred.
+    assert_eq!(prompt_lookup(&[1, 2, 3, 9, 3], 3, 2), &[9, 3]);
+    assert!(prompt_lookup(&[1, 2, 3], 3, 2).is_empty());
+    assert!(prompt_lookup(&[1], 3, 2).is_empty());
+}
+
+#[test]
+fn prompt_lookup_decoding_matches_greedy_target() -> Result<()> {
+    let engine = random_engine()?;
+    let prompt = [1, 2, 3, 4, 5, 1, 2, 3, 4, 5, 1, 2];
+    let params = greedy();
+
+    let expected = collect_tokens(|on_token| {
+        engine.generate_tokens(&prompt, &params, 24, on_token)?;
+        Ok(())
+    })?;
+    let mut stats = None;
+    let tokens = collect_tokens(|on_token| {
+        let draft = Draft::PromptLookup { ngram_size: 3 };
+        stats = Some(engine.generate_speculative(draft, &prompt, &params, 24, 5, on_token)?);
+        Ok(())
+    })?;
+    assert_eq!(tokens, expected);
+    let stats = stats.unwrap();
+    assert_eq!(stats.generated_tokens, 24);
+    assert!(stats.draft_tokens > 0);
+    Ok(())
+}
+
+#[test]
+fn completion_stops_before_stop_string() -> Result<()> {
+    let engine = uniform_engine()?;
+    let tokenizer = word_tokenizer();
+    let mut params = CompletionParams {
+        sampling: SamplingParams {
+            temperature: 1.0,
+            seed: 7,
+            ..Default::default()
+        },
+        max_tokens: 30,
+        stop: Vec::new(),
+    };
+
+    let full = engine.complete(&tokenizer, "the cat sat", &params, |_| true)?;
+    let words: Vec<&str> = full.text.split_whitespace().collect();
+    assert!(
+        words.len() > 3,
+        "too short to pick a stop string: {:?}",
+        full.text
+    );
+
+    let stop = format!(" {}", words[2]);
+    params.stop = vec!["never produced".to_string(), stop.clone()];
+    let mut streamed = String::new();
+    let completion = engine.complete(&tokenizer, "the cat sat", &params, |text| {
+        streamed.push_str(text);
+        true
+    })?;
+    assert_eq!(completion.text, full.text[..full.text.find(&stop).unwrap()]);
+    assert_eq!(completion.finish_reason, FinishReason::Stop);
+    assert_eq!(streamed, completion.text);
+    Ok(())
+}
+
+#[test]
+fn completion_aborts_when_the_callback_refuses_text() -> Result<()> {
+    let engine = uniform_engine()?;
+    let params = CompletionParams {
+        max_tokens: 30,
+        ..Default::default()
+    };
+    let mut calls = 0;
+    let completion = engine.complete(&word_tokenizer(), "the cat sat", &params, |_| {
+        calls += 1;
+        false
+    })?;
+    assert_eq!(calls, 1);
+    assert!(completion.stats.generated_tokens <= 1);
+    Ok(())
+}
+
+#[test]
+fn embeddings_pool_the_hidden_states() -> Result<()> {
+    let engine = random_engine()?;
+    let tokens = [4, 8, 12, 16, 20];
+    let mean = engine.embed_with(&tokens, Pooling::Mean, false)?;
+    assert_eq!(mean, engine.embed(&tokens)?);
+    assert_eq!(mean.len(), 16);
+
+    // The model is causal, the first position only sees the first token.
+    let cls = engine.embed_with(&tokens, Pooling::Cls, false)?;
+    let first = engine.embed_with(&tokens[..1], Pooling::Last, false)?;
+    for (a, b) in cls.iter().zip(&first) {
+        assert!((a - b).abs() < 1e-5);
+    }
+
+    let normalized = engine.embed_with(&tokens, Pooling::Last, true)?;
+    let norm = normalized.iter().map(|x| x * x).sum::<f32>().sqrt();
+    assert!((norm - 1.0).abs() < 1e-5);
+
+    let inputs = vec![tokens.to_vec(), tokens[..2].to_vec()];
+    let batch = engine.embed_batch(&inputs, Pooling::Last, true)?;
+    assert_eq!(batch.len(), 2);
+    assert_eq!(batch[0], normalized);
+    assert!(engine.embed_with(&[], Pooling::Cls, false).is_err());
+    Ok(())
+}
+
+#[test]
+fn quantized_kv_cache_keeps_the_hidden_states_close() -> Result<()> {
+    let varmap = VarMap::new();
+    let engine = random_engine_from(&varmap)?;
+    let tokens: Vec<u32> = (0..40).map(|i| (i * 11 % 32) as u32).collect();
+    let expected = engine.embed_with(&tokens, Pooling::Last, false)?;
+    let cosine = |a: &[f32], b: &[f32]| {
+        let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+        let norm = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>().sqrt();
+        dot / (norm(a) * norm(b))
+    };
+    for (quantization, min_similarity) in
+        [(KvQuantization::Int8, 0.999), (KvQuantization::Int4, 0.9)]
+    {
+        let quantized = random_engine_from(&varmap)?.with_kv_quantization(Some(quantization));
+        let embedding = quantized.embed_with(&tokens, Pooling::Last, false)?;
+        let similarity = cosine(&expected, &embedding);
+        assert!(similarity > min_similarity, "{quantization}: {similarity}");
+    }
+    Ok(())
+}
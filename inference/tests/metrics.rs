@@ -0,0 +1,51 @@
+use llama_rust::metrics::Metrics;
+
+/// Value of the sample named `series` in the text format.
+fn sample(text: &str, series: &str) -> Option<f64> {
+    text.lines()
+        .filter_map(|line| line.rsplit_once(' '))
+        .find(|(name, _)| *name == series)
+        .map(|(_, value)| value.parse().unwrap())
+}
+
+#[test]
+fn histograms_render_cumulative_buckets() {
+    let metrics = Metrics::default();
+    for seconds in [0.003, 0.02, 0.02, 120.0] {
+        metrics.time_to_first_token.observe(seconds);
+    }
+    assert_eq!(metrics.time_to_first_token.count(), 4);
+    let text = metrics.render();
+    let name = "llama_time_to_first_token_seconds";
+    assert!(text.contains(&format!("# TYPE {name} histogram")));
+    let bucket = |le: &str| sample(&text, &format!("{name}_bucket{{le=\"{le}\"}}"));
+    assert_eq!(bucket("0.005"), Some(1.0));
+    assert_eq!(bucket("0.01"), Some(1.0));
+    assert_eq!(bucket("0.025"), Some(3.0));
+    assert_eq!(bucket("60"), Some(3.0));
+    assert_eq!(bucket("+Inf"), Some(4.0));
+    assert_eq!(sample(&text, &format!("{name}_count")), Some(4.0));
+    assert!((sample(&text, &format!("{name}_sum")).unwrap() - 120.043).abs() < 1e-9);
+}
+
+#[test]
+fn requests_are_counted_by_endpoint_and_status() {
+    let metrics = Metrics::default();
+    metrics.record_request("/v1/completions", 200);
+    metrics.record_request("/v1/completions", 200);
+    metrics.record_request("/v1/completions", 429);
+    metrics.queue_depth.set(3.0);
+    metrics.generation_tokens.add(7);
+    let text = metrics.render();
+    let requests = |status: u16| {
+        sample(
+            &text,
+            &format!("llama_requests_total{{endpoint=\"/v1/completions\",status=\"{status}\"}}"),
+        )
+    };
+    assert_eq!(requests(200), Some(2.0));
+    assert_eq!(requests(429), Some(1.0));
+    assert_eq!(sample(&text, "llama_queue_depth"), Some(3.0));
+    assert_eq!(sample(&text, "llama_generation_tokens_total"), Some(7.0));
+    assert_eq!(sample(&text, "llama_running_sequences"), Some(0.0));
+}
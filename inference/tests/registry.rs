@@ -0,0 +1,116 @@
+use {
+    anyhow::Result,
+    llama_rust::{
+        args::ModelSize,
+        registry::{Loader, ModelEntry, ModelRegistry, ModelsConfig},
+    },
+    std::sync::{Arc, Mutex},
+};
+
+fn entry(id: &str) -> ModelEntry {
+    ModelEntry {
+        id: id.to_string(),
+        model: format!("{id}.safetensors"),
+        model_size: None,
+        tokenizer: None,
+        modelfile: None,
+    }
+}
+
+/// Ids of the loaded models, in the order of the loads.
+type Loads = Arc<Mutex<Vec<String>>>;
+
+/// Registry of models `a`, `b` and `c` of 100 bytes each.
+fn registry(budget: Option<u64>) -> Result<(ModelRegistry<String>, Loads)> {
+    let loads = Arc::new(Mutex::new(Vec::new()));
+    let log = loads.clone();
+    let loader: Loader<String> = Box::new(move |entry: &ModelEntry| {
+        log.lock().unwrap().push(entry.id.clone());
+        Ok((entry.model.clone(), 100))
+    });
+    let registry =
+        ModelRegistry::new(vec![entry("a"), entry("b"), entry("c")], loader)?.with_budget(budget);
+    Ok((registry, loads))
+}
+
+fn loaded_ids(registry: &ModelRegistry<String>) -> Vec<String> {
+    registry
+        .all_loaded()
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect()
+}
+
+#[test]
+fn models_are_loaded_once_on_first_use() -> Result<()> {
+    let (registry, loads) = registry(None)?;
+    assert_eq!(registry.ids(), ["a", "b", "c"]);
+    assert!(registry.loaded("a").is_none());
+    assert_eq!(*registry.get("a")?, "a.safetensors");
+    assert_eq!(*registry.get("a")?, "a.safetensors");
+    registry.get("c")?;
+    assert_eq!(*loads.lock().unwrap(), ["a", "c"]);
+    assert_eq!(loaded_ids(&registry), ["a", "c"]);
+    assert_eq!(registry.memory(), 200);
+    assert!(registry.get("d").is_err());
+    Ok(())
+}
+
+#[test]
+fn least_recently_used_models_are_unloaded_beyond_the_budget() -> Result<()> {
+    let (registry, loads) = registry(Some(250))?;
+    registry.get("a")?;
+    registry.get("b")?;
+    // `a` was used last, loading `c` unloads `b`.
+    registry.get("a")?;
+    registry.get("c")?;
+    assert_eq!(loaded_ids(&registry), ["a", "c"]);
+    registry.get("b")?;
+    assert_eq!(loaded_ids(&registry), ["b", "c"]);
+    assert_eq!(*loads.lock().unwrap(), ["a", "b", "c", "b"]);
+    assert_eq!(registry.memory(), 200);
+    Ok(())
+}
+
+#[test]
+fn given_models_are_never_unloaded() -> Result<()> {
+    let (mut registry, _) = registry(Some(150))?;
+    registry.insert("pinned", "pinned".to_string(), 100)?;
+    assert!(registry.insert("a", String::new(), 0).is_err());
+    assert_eq!(
+        registry.get_mut("pinned").map(|model| model.as_str()),
+        Some("pinned")
+    );
+    registry.get("a")?;
+    registry.get("b")?;
+    assert_eq!(loaded_ids(&registry), ["b", "pinned"]);
+    Ok(())
+}
+
+#[test]
+fn models_file_paths_are_relative_to_it() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("models-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("stories.safetensors"), b"")?;
+    std::fs::write(
+        dir.join("models.json"),
+        r#"{
+            "memory_budget_mb": 2,
+            "models": [
+                {"id": "stories", "model": "stories.safetensors", "model_size": "tiny260k"},
+                {"id": "llama3", "model": "ollama:llama3:8b", "tokenizer": "gpt2"}
+            ]
+        }"#,
+    )?;
+    let config = ModelsConfig::from_file(dir.join("models.json"))?;
+    assert_eq!(config.memory_budget(), Some(2 * 1024 * 1024));
+    assert_eq!(
+        config.models[0].model,
+        dir.join("stories.safetensors").to_string_lossy()
+    );
+    assert_eq!(config.models[0].model_size, Some(ModelSize::Tiny260k));
+    assert_eq!(config.models[1].model, "ollama:llama3:8b");
+    assert_eq!(config.models[1].tokenizer.as_deref(), Some("gpt2"));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
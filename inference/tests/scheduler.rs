@@ -0,0 +1,356 @@
+mod common;
+
+use {
+    anyhow::Result,
+    common::random_engine,
+    kv_cache::KvQuantization,
+    llama_rust::{
+        completion::FinishReason,
+        inference::InferenceEngine,
+        sampling::SamplingParams,
+        scheduler::{
+            Scheduler, SchedulerConfig, SequenceEvent, SequenceRequest, StepKind, StepOutput,
+        },
+    },
+    std::collections::HashMap,
+};
+
+fn greedy() -> SamplingParams {
+    SamplingParams {
+        temperature: 0.0,
+        repeat_penalty: 1.0,
+        ..Default::default()
+    }
+}
+
+fn request(prompt: &[u32], max_tokens: usize) -> SequenceRequest {
+    SequenceRequest {
+        prompt: prompt.to_vec(),
+        sampling: greedy(),
+        max_tokens,
+        eos_token_id: None,
+    }
+}
+
+/// Sampled with temperature 1, so that sequences with different seeds diverge.
+fn seeded(seed: u64) -> SamplingParams {
+    SamplingParams {
+        temperature: 1.0,
+        repeat_penalty: 1.0,
+        seed,
+        ..Default::default()
+    }
+}
+
+fn sequential_tokens(
+    engine: &InferenceEngine,
+    prompt: &[u32],
+    max_tokens: usize,
+) -> Result<Vec<u32>> {
+    sampled_tokens(engine, prompt, &greedy(), max_tokens)
+}
+
+fn sampled_tokens(
+    engine: &InferenceEngine,
+    prompt: &[u32],
+    sampling: &SamplingParams,
+    max_tokens: usize,
+) -> Result<Vec<u32>> {
+    let mut tokens = Vec::new();
+    engine.generate_tokens(prompt, sampling, max_tokens, |token| {
+        tokens.push(token);
+        Ok(true)
+    })?;
+    Ok(tokens)
+}
+
+/// Tokens and finish reason of every sequence.
+#[derive(Default)]
+struct Outputs {
+    tokens: HashMap<u64, Vec<u32>>,
+    finished: HashMap<u64, FinishReason>,
+}
+
+impl Outputs {
+    fn record(&mut self, output: &StepOutput) {
+        for event in output.events.iter() {
+            match *event {
+                SequenceEvent::Token { id, token, .. } => {
+                    self.tokens.entry(id).or_default().push(token)
+                }
+                SequenceEvent::Finished { id, reason } => {
+                    assert!(self.finished.insert(id, reason).is_none());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn batched_generation_matches_sequential_generation() -> Result<()> {
+    let engine = random_engine()?;
+    let config = SchedulerConfig {
+        max_batch_size: 2,
+        max_batch_tokens: 3,
+        block_size: 2,
+        ..Default::default()
+    };
+    let mut scheduler = Scheduler::new(&engine, config)?;
+    let prompts: [&[u32]; 3] = [&[1, 4, 9, 4, 2, 7, 3], &[5], &[8, 8, 2, 11]];
+    let ids = prompts
+        .iter()
+        .map(|prompt| scheduler.add(request(prompt, 6)))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(scheduler.num_waiting(), 3);
+
+    let mut outputs = Outputs::default();
+    while let Some(output) = scheduler.step()? {
+        assert!(scheduler.num_running() <= 2);
+        match output.kind {
+            StepKind::Prefill => {
+                assert_eq!(output.sequences, 1);
+                assert!(output.tokens <= 3);
+            }
+            StepKind::Decode => assert_eq!(output.tokens, output.sequences),
+        }
+        outputs.record(&output);
+    }
+    assert!(scheduler.is_idle());
+
+    for (id, prompt) in ids.iter().zip(prompts) {
+        assert_eq!(outputs.tokens[id], sequential_tokens(&engine, prompt, 6)?);
+        assert_eq!(outputs.finished[id], FinishReason::Length);
+    }
+    Ok(())
+}
+
+#[test]
+fn quantized_batches_match_quantized_sequential_generation() -> Result<()> {
+    for quantization in [KvQuantization::Int8, KvQuantization::Int4] {
+        let engine = random_engine()?.with_kv_quantization(Some(quantization));
+        let config = SchedulerConfig {
+            max_batch_size: 2,
+            block_size: 2,
+            ..Default::default()
+        };
+        let mut scheduler = Scheduler::new(&engine, config)?;
+        let prompts: [&[u32]; 2] = [&[1, 4, 9, 4, 2], &[8, 8, 2]];
+        let ids = prompts
+            .iter()
+            .map(|prompt| scheduler.add(request(prompt, 6)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut outputs = Outputs::default();
+        while let Some(output) = scheduler.step()? {
+            outputs.record(&output);
+        }
+        // Both caches store the same quantized entries.
+        for (id, prompt) in ids.iter().zip(prompts) {
+            assert_eq!(outputs.tokens[id], sequential_tokens(&engine, prompt, 6)?);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn sequences_join_and_leave_a_running_batch() -> Result<()> {
+    let engine = random_engine()?;
+    let mut scheduler = Scheduler::new(&engine, SchedulerConfig::default())?;
+    let long = scheduler.add(request(&[3, 1, 4, 1, 5], 12))?;
+    let mut outputs = Outputs::default();
+    for _ in 0..4 {
+        outputs.record(&scheduler.step()?.unwrap());
+    }
+
+    // Admitted at the next step while the first one is still decoding, and done first.
+    let short = scheduler.add(request(&[2, 7, 1], 2))?;
+    outputs.record(&scheduler.step()?.unwrap());
+    assert_eq!(scheduler.num_running(), 2);
+    while !outputs.finished.contains_key(&short) {
+        outputs.record(&scheduler.step()?.unwrap());
+    }
+    assert_eq!(scheduler.num_running(), 1);
+    while let Some(output) = scheduler.step()? {
+        outputs.record(&output);
+    }
+
+    assert_eq!(
+        outputs.tokens[&short],
+        sequential_tokens(&engine, &[2, 7, 1], 2)?
+    );
+    assert_eq!(
+        outputs.tokens[&long],
+        sequential_tokens(&engine, &[3, 1, 4, 1, 5], 12)?
+    );
+    Ok(())
+}
+
+#[test]
+fn aborted_sequences_free_their_blocks() -> Result<()> {
+    let engine = random_engine()?;
+    let config = SchedulerConfig {
+        max_batch_size: 1,
+        ..Default::default()
+    };
+    let mut scheduler = Scheduler::new(&engine, config)?;
+    let first = scheduler.add(request(&[1, 2, 3], 20))?;
+    let second = scheduler.add(request(&[4, 5], 3))?;
+    scheduler.step()?;
+    assert_eq!((scheduler.num_running(), scheduler.num_waiting()), (1, 1));
+
+    assert!(scheduler.abort(first));
+    assert!(!scheduler.abort(first));
+    let mut outputs = Outputs::default();
+    while let Some(output) = scheduler.step()? {
+        outputs.record(&output);
+    }
+    assert!(!outputs.tokens.contains_key(&first));
+    assert_eq!(
+        outputs.tokens[&second],
+        sequential_tokens(&engine, &[4, 5], 3)?
+    );
+    assert_eq!(scheduler.cached_positions(), 0);
+    Ok(())
+}
+
+#[test]
+fn parallel_sequences_read_their_prompt_once() -> Result<()> {
+    let engine = random_engine()?;
+    let config = SchedulerConfig {
+        block_size: 4,
+        ..Default::default()
+    };
+    let mut scheduler = Scheduler::new(&engine, config)?;
+    let prompt = [3, 9, 4, 1, 7, 7, 2];
+    let requests = (0..3)
+        .map(|seed| SequenceRequest {
+            sampling: seeded(seed),
+            ..request(&prompt, 5)
+        })
+        .collect();
+    let ids = scheduler.add_parallel(requests)?;
+    assert_eq!((scheduler.num_waiting(), scheduler.num_running()), (3, 0));
+
+    let mut outputs = Outputs::default();
+    let output = scheduler.step()?.unwrap();
+    assert_eq!((output.kind, output.tokens), (StepKind::Prefill, 7));
+    outputs.record(&output);
+    assert_eq!(scheduler.num_running(), 3);
+    // The two blocks of the prompt are shared until the sequences write to the second one.
+    assert_eq!(scheduler.cached_positions(), 8);
+    while let Some(output) = scheduler.step()? {
+        assert_eq!(output.kind, StepKind::Decode);
+        outputs.record(&output);
+    }
+    for (seed, id) in ids.iter().enumerate() {
+        let expected = sampled_tokens(&engine, &prompt, &seeded(seed as u64), 5)?;
+        assert_eq!(outputs.tokens[id], expected);
+    }
+    assert_ne!(outputs.tokens[&ids[0]], outputs.tokens[&ids[1]]);
+
+    let requests = vec![request(&prompt, 1); 5];
+    let error = scheduler.add_parallel(requests).unwrap_err().to_string();
+    assert!(error.contains("do not fit"), "{error}");
+    let requests = vec![request(&prompt, 1), request(&[1], 1)];
+    assert!(scheduler.add_parallel(requests).is_err());
+    Ok(())
+}
+
+#[test]
+fn forks_take_over_the_prompt_of_an_aborted_sequence() -> Result<()> {
+    let engine = random_engine()?;
+    let config = SchedulerConfig {
+        max_batch_tokens: 3,
+        block_size: 2,
+        ..Default::default()
+    };
+    let mut scheduler = Scheduler::new(&engine, config)?;
+    let prompt = [5, 1, 8, 2, 6, 4, 4, 9];
+    let requests = (0..3)
+        .map(|seed| SequenceRequest {
+            sampling: seeded(seed),
+            ..request(&prompt, 4)
+        })
+        .collect();
+    let ids = scheduler.add_parallel(requests)?;
+    // Dropped while waiting, then while reading the prompt: the last one reads the rest of it.
+    assert!(scheduler.abort(ids[0]));
+    scheduler.step()?;
+    assert!(scheduler.abort(ids[1]));
+    assert_eq!(scheduler.num_running(), 1);
+    let mut outputs = Outputs::default();
+    while let Some(output) = scheduler.step()? {
+        outputs.record(&output);
+    }
+    assert_eq!(outputs.tokens.len(), 1);
+    assert_eq!(
+        outputs.tokens[&ids[2]],
+        sampled_tokens(&engine, &prompt, &seeded(2), 4)?
+    );
+    assert_eq!(scheduler.cached_positions(), 0);
+    Ok(())
+}
+
+#[test]
+fn sequences_stop_at_the_end_of_sequence_token() -> Result<()> {
+    let engine = random_engine()?;
+    let expected = sequential_tokens(&engine, &[6, 2, 9], 8)?;
+    let mut scheduler = Scheduler::new(&engine, SchedulerConfig::default())?;
+    let eos = scheduler.add(SequenceRequest {
+        eos_token_id: Some(expected[2]),
+        ..request(&[6, 2, 9], 8)
+    })?;
+    let empty = scheduler.add(request(&[6], 0))?;
+    let mut outputs = Outputs::default();
+    while let Some(output) = scheduler.step()? {
+        outputs.record(&output);
+    }
+    let stop = expected.iter().position(|&t| t == expected[2]).unwrap();
+    assert_eq!(outputs.tokens[&eos], expected[..=stop]);
+    assert_eq!(outputs.finished[&eos], FinishReason::Stop);
+    assert!(!outputs.tokens.contains_key(&empty));
+    assert_eq!(outputs.finished[&empty], FinishReason::Length);
+
+    let error = scheduler.add(request(&[1; 64], 1)).unwrap_err().to_string();
+    assert!(error.contains("context length"), "{error}");
+    Ok(())
+}
+
+#[test]
+fn cached_prefixes_skip_their_prefill() -> Result<()> {
+    let engine = random_engine()?;
+    let system = [7, 3, 3, 8, 1, 5, 9, 2];
+    let first = [&system[..], &[4, 6]].concat();
+    let second = [&system[..], &[11, 2, 5]].concat();
+    for prefix_caching in [true, false] {
+        let config = SchedulerConfig {
+            block_size: 4,
+            prefix_caching,
+            ..Default::default()
+        };
+        let mut scheduler = Scheduler::new(&engine, config)?;
+        scheduler.add(request(&first, 3))?;
+        while scheduler.step()?.is_some() {}
+
+        // The blocks of the shared prefix are cached, only the rest of the prompt is read.
+        let id = scheduler.add(request(&second, 3))?;
+        let output = scheduler.step()?.unwrap();
+        assert_eq!(output.kind, StepKind::Prefill);
+        assert_eq!(output.tokens, if prefix_caching { 3 } else { 11 });
+        let mut outputs = Outputs::default();
+        outputs.record(&output);
+        while let Some(output) = scheduler.step()? {
+            outputs.record(&output);
+        }
+        assert_eq!(outputs.tokens[&id], sequential_tokens(&engine, &second, 3)?);
+
+        let stats = scheduler.prefix_cache_stats();
+        assert_eq!(stats.is_some(), prefix_caching);
+        if let Some(stats) = stats {
+            assert_eq!((stats.lookups, stats.hits), (2, 1));
+            assert_eq!((stats.query_tokens, stats.hit_tokens), (21, 8));
+        }
+        // Cached blocks are not held by a sequence.
+        assert_eq!(scheduler.cached_positions(), 0);
+    }
+    Ok(())
+}
@@ -0,0 +1,1209 @@
+mod common;
+
+use {
+    anyhow::Result,
+    axum::{
+        Router,
+        body::Body,
+        http::{HeaderMap, Request, StatusCode},
+    },
+    candle_nn::VarMap,
+    common::{endless_word_tokenizer, random_engine_from, word_tokenizer},
+    http_body_util::BodyExt,
+    llama_rust::{
+        admission::AdmissionConfig,
+        auth::{ApiKey, ApiKeys},
+        completion::{Completion, CompletionParams},
+        registry::{Loader, ModelEntry, ModelRegistry},
+        sampling::SamplingParams,
+        scheduler::SchedulerConfig,
+        server::{ServedModel, ServerState, router},
+    },
+    ollama::Modelfile,
+    serde_json::{Value, json},
+    std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    },
+    tokenizers::AddedToken,
+    tower::ServiceExt,
+};
+
+fn app(varmap: &VarMap) -> Result<Router> {
+    let engine = random_engine_from(varmap)?;
+    Ok(router(ServerState::new(engine, word_tokenizer(), "tiny")))
+}
+
+async fn send_raw(
+    app: Router,
+    method: &str,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, String) {
+    let request = Request::builder().method(method).uri(uri);
+    let request = match body {
+        Some(body) => request
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    }
+    .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (status, String::from_utf8(bytes.to_vec()).unwrap())
+}
+
+async fn send(app: Router, method: &str, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let (status, body) = send_raw(app, method, uri, body).await;
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+/// Payloads of the `data:` lines of a server-sent events body.
+fn sse_data(body: &str) -> Vec<&str> {
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .collect()
+}
+
+#[tokio::test]
+async fn models_lists_the_served_model() -> Result<()> {
+    let (status, body) = send(app(&VarMap::new())?, "GET", "/v1/models", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["object"], "list");
+    assert_eq!(body["data"][0]["id"], "tiny");
+    assert_eq!(body["data"][0]["object"], "model");
+    Ok(())
+}
+
+#[tokio::test]
+async fn completion_matches_the_engine() -> Result<()> {
+    let varmap = VarMap::new();
+    let app = app(&varmap)?;
+    let request = json!({
+        "model": "tiny",
+        "prompt": "the cat sat on",
+        "max_tokens": 8,
+        "temperature": 0.0,
+    });
+    let (status, body) = send(app, "POST", "/v1/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["object"], "text_completion");
+    assert_eq!(body["model"], "tiny");
+
+    let params = CompletionParams {
+        sampling: SamplingParams {
+            temperature: 0.0,
+            repeat_penalty: 1.0,
+            ..Default::default()
+        },
+        max_tokens: 8,
+        stop: Vec::new(),
+    };
+    let expected = random_engine_from(&varmap)?.complete(
+        &word_tokenizer(),
+        "the cat sat on",
+        &params,
+        |_| true,
+    )?;
+    let choice = &body["choices"][0];
+    assert_eq!(choice["index"], 0);
+    assert_eq!(choice["text"], expected.text);
+    assert_eq!(
+        choice["finish_reason"],
+        serde_json::to_value(expected.finish_reason)?
+    );
+
+    let usage = &body["usage"];
+    assert_eq!(usage["prompt_tokens"], 4);
+    assert_eq!(usage["completion_tokens"], expected.stats.generated_tokens);
+    assert_eq!(usage["total_tokens"], 4 + expected.stats.generated_tokens);
+    Ok(())
+}
+
+#[tokio::test]
+async fn completion_returns_n_choices_per_prompt() -> Result<()> {
+    let request = json!({
+        "model": "tiny",
+        "prompt": ["the cat", "a dog ran"],
+        "max_tokens": 4,
+        "n": 2,
+        "seed": 3,
+    });
+    let (status, body) = send(
+        app(&VarMap::new())?,
+        "POST",
+        "/v1/completions",
+        Some(request),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let choices = body["choices"].as_array().unwrap();
+    assert_eq!(choices.len(), 4);
+    for (index, choice) in choices.iter().enumerate() {
+        assert_eq!(choice["index"], index);
+    }
+    assert_eq!(body["usage"]["prompt_tokens"], 5);
+    Ok(())
+}
+
+#[tokio::test]
+async fn best_of_returns_the_best_candidates_of_each_prompt() -> Result<()> {
+    let app = app(&VarMap::new())?;
+    let mut request = json!({
+        "model": "tiny",
+        "prompt": ["the cat", "a dog ran"],
+        "max_tokens": 4,
+        "n": 3,
+        "seed": 3,
+    });
+    let (status, all) = send(
+        app.clone(),
+        "POST",
+        "/v1/completions",
+        Some(request.clone()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    request["n"] = json!(1);
+    request["best_of"] = json!(3);
+    let (status, best) = send(app, "POST", "/v1/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // The candidates are the choices generated with `n`, and they are all billed.
+    let choices = best["choices"].as_array().unwrap();
+    assert_eq!(choices.len(), 2);
+    for (index, choice) in choices.iter().enumerate() {
+        assert_eq!(choice["index"], index);
+        let candidates = &all["choices"].as_array().unwrap()[3 * index..3 * (index + 1)];
+        assert!(candidates.iter().any(|c| c["text"] == choice["text"]));
+    }
+    assert_eq!(best["usage"], all["usage"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn chat_completion_returns_an_assistant_message() -> Result<()> {
+    let request = json!({
+        "model": "tiny",
+        "messages": [
+            {"role": "system", "content": "it was a big day"},
+            {"role": "user", "content": "the cat sat on the mat"},
+        ],
+        "max_tokens": 6,
+        "seed": 1,
+    });
+    let (status, body) = send(
+        app(&VarMap::new())?,
+        "POST",
+        "/v1/chat/completions",
+        Some(request),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["object"], "chat.completion");
+    assert!(body["id"].as_str().unwrap().starts_with("chatcmpl-"));
+    let choice = &body["choices"][0];
+    assert_eq!(choice["message"]["role"], "assistant");
+    assert!(choice["message"]["content"].is_string());
+    assert!(body["usage"]["completion_tokens"].as_u64().unwrap() <= 6);
+    Ok(())
+}
+
+#[tokio::test]
+async fn unknown_model_is_not_found() -> Result<()> {
+    let request = json!({"model": "gpt-4", "prompt": "the cat"});
+    let (status, body) = send(
+        app(&VarMap::new())?,
+        "POST",
+        "/v1/completions",
+        Some(request),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"]["code"], "model_not_found");
+    assert_eq!(body["error"]["param"], "model");
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_parameters_are_rejected() -> Result<()> {
+    let app = app(&VarMap::new())?;
+    for (request, param) in [
+        (
+            json!({"model": "tiny", "prompt": "the cat", "temperature": 3.0}),
+            "temperature",
+        ),
+        (json!({"model": "tiny", "prompt": "the cat", "n": 0}), "n"),
+        (
+            json!({"model": "tiny", "prompt": "the cat", "n": 2, "best_of": 1}),
+            "best_of",
+        ),
+        (
+            json!({"model": "tiny", "prompt": "the cat", "best_of": 2, "stream": true}),
+            "best_of",
+        ),
+        (json!({"model": "tiny", "prompt": ""}), "prompt"),
+    ] {
+        let (status, body) = send(app.clone(), "POST", "/v1/completions", Some(request)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(body["error"]["param"], param);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn streamed_completion_matches_the_full_response() -> Result<()> {
+    let app = app(&VarMap::new())?;
+    let mut request = json!({
+        "model": "tiny",
+        "prompt": "the dog ran under the tree",
+        "max_tokens": 12,
+        "temperature": 0.0,
+    });
+    let (_, full) = send(
+        app.clone(),
+        "POST",
+        "/v1/completions",
+        Some(request.clone()),
+    )
+    .await;
+
+    request["stream"] = json!(true);
+    request["stream_options"] = json!({"include_usage": true});
+    let (status, body) = send_raw(app, "POST", "/v1/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    let data = sse_data(&body);
+    assert_eq!(data.last(), Some(&"[DONE]"));
+
+    let chunks: Vec<Value> = data[..data.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    let (usage, chunks) = chunks.split_last().unwrap();
+    assert_eq!(usage["choices"], json!([]));
+    assert_eq!(usage["usage"], full["usage"]);
+
+    let (last, text_chunks) = chunks.split_last().unwrap();
+    assert_eq!(
+        last["choices"][0]["finish_reason"],
+        full["choices"][0]["finish_reason"]
+    );
+    let mut text = String::new();
+    for chunk in text_chunks {
+        assert_eq!(chunk["object"], "text_completion");
+        assert_eq!(chunk["id"], last["id"]);
+        assert!(chunk["choices"][0]["finish_reason"].is_null());
+        assert!(chunk.get("usage").is_none());
+        text.push_str(chunk["choices"][0]["text"].as_str().unwrap());
+    }
+    assert_eq!(text, full["choices"][0]["text"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn streamed_chat_completion_sends_deltas() -> Result<()> {
+    let app = app(&VarMap::new())?;
+    let mut request = json!({
+        "model": "tiny",
+        "messages": [{"role": "user", "content": "a red bird flew over the sea"}],
+        "max_tokens": 10,
+        "temperature": 0.0,
+    });
+    let (_, full) = send(
+        app.clone(),
+        "POST",
+        "/v1/chat/completions",
+        Some(request.clone()),
+    )
+    .await;
+
+    request["stream"] = json!(true);
+    let (status, body) = send_raw(app, "POST", "/v1/chat/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    let data = sse_data(&body);
+    assert_eq!(data.last(), Some(&"[DONE]"));
+    let chunks: Vec<Value> = data[..data.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+
+    assert_eq!(chunks[0]["object"], "chat.completion.chunk");
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    let last = chunks.last().unwrap();
+    assert_eq!(last["choices"][0]["delta"], json!({}));
+    assert_eq!(
+        last["choices"][0]["finish_reason"],
+        full["choices"][0]["finish_reason"]
+    );
+    let content: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(content, full["choices"][0]["message"]["content"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn ollama_tags_lists_the_model() -> Result<()> {
+    let (status, body) = send(app(&VarMap::new())?, "GET", "/api/tags", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let list: ollama::ModelList = serde_json::from_value(body)?;
+    assert_eq!(list.models.len(), 1);
+    assert_eq!(list.models[0].name, "tiny:latest");
+    assert_eq!(list.models[0].details.family, "llama");
+    Ok(())
+}
+
+#[tokio::test]
+async fn ollama_generate_streams_ndjson() -> Result<()> {
+    let app = app(&VarMap::new())?;
+    let mut request = json!({
+        "model": "tiny:latest",
+        "prompt": "the big cat sat on the mat",
+        "options": {"temperature": 0.0, "num_predict": 10},
+    });
+    let (status, body) =
+        send_raw(app.clone(), "POST", "/api/generate", Some(request.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+    let lines: Vec<ollama::GenerateResponse> = body
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    let (last, partial) = lines.split_last().unwrap();
+    assert!(last.done);
+    assert!(partial.iter().all(|line| !line.done));
+    assert_eq!(last.metrics.prompt_eval_count, Some(7));
+    assert!(last.metrics.eval_count.unwrap() <= 10);
+
+    request["stream"] = json!(false);
+    let (status, full) = send(app, "POST", "/api/generate", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    let full: ollama::GenerateResponse = serde_json::from_value(full)?;
+    let streamed: String = lines.iter().map(|line| line.response.as_str()).collect();
+    assert_eq!(streamed, full.response);
+    assert_eq!(last.done_reason, full.done_reason);
+    assert_eq!(last.metrics.eval_count, full.metrics.eval_count);
+    Ok(())
+}
+
+#[tokio::test]
+async fn ollama_chat_returns_an_assistant_message() -> Result<()> {
+    let request = json!({
+        "model": "tiny",
+        "messages": [{"role": "user", "content": "the fish swam in the sea"}],
+        "stream": false,
+        "options": {"num_predict": 5, "seed": 4},
+    });
+    let (status, body) = send(app(&VarMap::new())?, "POST", "/api/chat", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    let response: ollama::ChatResponse = serde_json::from_value(body)?;
+    assert!(response.done);
+    assert_eq!(response.message.role, "assistant");
+    assert!(response.metrics.eval_count.unwrap() <= 5);
+    Ok(())
+}
+
+#[tokio::test]
+async fn ollama_show_and_embeddings_describe_the_model() -> Result<()> {
+    let app = app(&VarMap::new())?;
+    let (status, body) = send(
+        app.clone(),
+        "POST",
+        "/api/show",
+        Some(json!({"name": "tiny"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let show: ollama::ShowResponse = serde_json::from_value(body)?;
+    assert_eq!(show.model_info["llama.context_length"], 64);
+    assert_eq!(show.details.family, "llama");
+
+    let request = json!({"model": "tiny", "prompt": "a blue sky"});
+    let (status, body) = send(app, "POST", "/api/embeddings", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    let embeddings: ollama::EmbeddingsResponse = serde_json::from_value(body)?;
+    assert_eq!(embeddings.embedding.len(), 16);
+    Ok(())
+}
+
+#[tokio::test]
+async fn embeddings_are_returned_for_every_input() -> Result<()> {
+    let app = app(&VarMap::new())?;
+    let request = json!({"model": "tiny", "input": ["a blue sky", "the cat sat"]});
+    let (status, body) = send(app.clone(), "POST", "/v1/embeddings", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["object"], "list");
+    assert_eq!(body["usage"]["prompt_tokens"], 6);
+    let data = body["data"].as_array().unwrap();
+    assert_eq!(data.len(), 2);
+    assert_eq!(data[1]["index"], 1);
+    let embedding: Vec<f32> = serde_json::from_value(data[0]["embedding"].clone())?;
+    assert_eq!(embedding.len(), 16);
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    assert!((norm - 1.0).abs() < 1e-4);
+
+    // Token ids are embedded as given, like their text.
+    let request = json!({
+        "model": "tiny",
+        "input": [3, 23, 18],
+        "pooling": "last",
+        "normalize": false,
+    });
+    let (status, by_tokens) = send(app.clone(), "POST", "/v1/embeddings", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    let request = json!({
+        "model": "tiny",
+        "input": "a blue sky",
+        "pooling": "last",
+        "normalize": false,
+    });
+    let (_, by_text) = send(app.clone(), "POST", "/v1/embeddings", Some(request)).await;
+    assert_eq!(by_tokens["data"], by_text["data"]);
+
+    for input in [json!([]), json!([""]), json!([[40]])] {
+        let request = json!({"model": "tiny", "input": input});
+        let (status, body) = send(app.clone(), "POST", "/v1/embeddings", Some(request)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["param"], "input");
+    }
+    let request = json!({"model": "tiny", "input": "a", "encoding_format": "base64"});
+    let (status, _) = send(app.clone(), "POST", "/v1/embeddings", Some(request)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // No more inputs than the queue holds generations.
+    let request = json!({"model": "tiny", "input": vec!["the cat"; 65]});
+    let (status, body) = send(app, "POST", "/v1/embeddings", Some(request)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["param"], "input");
+    Ok(())
+}
+
+#[tokio::test]
+async fn prompts_are_tokenized_with_the_model_tokenizer() -> Result<()> {
+    let mut tokenizer = word_tokenizer();
+    tokenizer
+        .tokenizer
+        .add_special_tokens(&[AddedToken::from("</s>", true)]);
+    let engine = random_engine_from(&VarMap::new())?;
+    let app = router(ServerState::new(engine, tokenizer, "tiny"));
+
+    let request = json!({"model": "tiny", "prompt": "the  cat </s>"});
+    let (status, body) = send(app.clone(), "POST", "/tokenize", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["count"], 3);
+    assert_eq!(body["max_model_len"], 64);
+    assert_eq!(
+        body["tokens"],
+        json!([
+            {"id": 2, "token": "the", "start": 0, "end": 3, "special": false},
+            {"id": 4, "token": "cat", "start": 5, "end": 8, "special": false},
+            {"id": 1, "token": "</s>", "start": 9, "end": 13, "special": true},
+        ])
+    );
+
+    let request = json!({"model": "tiny", "prompt": "the cat sat", "count_only": true});
+    let (status, body) = send(app.clone(), "POST", "/tokenize", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["count"], 3);
+    assert!(body.get("tokens").is_none());
+
+    let request = json!({
+        "model": "tiny",
+        "messages": [{"role": "user", "content": "a red dog"}],
+        "count_only": true,
+    });
+    let (status, body) = send(app.clone(), "POST", "/tokenize", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["prompt"].as_str().unwrap().contains("a red dog"));
+    assert!(body["count"].as_u64().unwrap() >= 3);
+
+    let request = json!({"model": "tiny"});
+    let (status, _) = send(app.clone(), "POST", "/tokenize", Some(request)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let request = json!({"model": "tiny", "tokens": [2, 4, 1]});
+    let (status, body) = send(app.clone(), "POST", "/detokenize", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["text"], "the cat </s>");
+    let request = json!({"model": "tiny", "tokens": [2, 4, 1], "skip_special_tokens": true});
+    let (_, body) = send(app.clone(), "POST", "/detokenize", Some(request)).await;
+    assert_eq!(body["text"], "the cat");
+    let request = json!({"model": "tiny", "tokens": [2, 99]});
+    let (status, body) = send(app, "POST", "/detokenize", Some(request)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["param"], "tokens");
+    Ok(())
+}
+
+const MODELFILE: &str = r#"
+FROM ./tiny.bin
+SYSTEM it was a big sky
+TEMPLATE """{{ if .System }}{{ .System }} then {{ end }}{{ .Prompt }}"""
+PARAMETER temperature 0
+PARAMETER repeat_penalty 1
+PARAMETER stop mat
+"#;
+
+fn modelfile_app(varmap: &VarMap) -> Result<Router> {
+    let engine = random_engine_from(varmap)?;
+    let state = ServerState::new(engine, word_tokenizer(), "tiny")
+        .with_modelfile(MODELFILE.parse::<Modelfile>()?)?;
+    Ok(router(state))
+}
+
+/// Greedy completion of `prompt` with the stop string of [`MODELFILE`].
+fn modelfile_completion(varmap: &VarMap, prompt: &str, max_tokens: usize) -> Result<Completion> {
+    let params = CompletionParams {
+        sampling: SamplingParams {
+            temperature: 0.0,
+            repeat_penalty: 1.0,
+            ..Default::default()
+        },
+        max_tokens,
+        stop: vec!["mat".to_string()],
+    };
+    random_engine_from(varmap)?.complete(&word_tokenizer(), prompt, &params, |_| true)
+}
+
+#[tokio::test]
+async fn modelfile_applies_its_system_prompt_template_and_parameters() -> Result<()> {
+    let varmap = VarMap::new();
+    let request = json!({
+        "model": "tiny",
+        "messages": [{"role": "user", "content": "the cat sat on"}],
+        "max_tokens": 8,
+    });
+    let (status, body) = send(
+        modelfile_app(&varmap)?,
+        "POST",
+        "/v1/chat/completions",
+        Some(request),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let expected = modelfile_completion(&varmap, "it was a big sky then the cat sat on", 8)?;
+    assert_eq!(body["usage"]["prompt_tokens"], 10);
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        expected.text.trim_start()
+    );
+
+    // The system prompt of a request replaces the one of the Modelfile.
+    let request = json!({
+        "model": "tiny",
+        "prompt": "the dog ran",
+        "system": "it was sad",
+        "stream": false,
+        "options": {"num_predict": 6},
+    });
+    let (status, body) = send(
+        modelfile_app(&varmap)?,
+        "POST",
+        "/api/generate",
+        Some(request),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let response: ollama::GenerateResponse = serde_json::from_value(body)?;
+    let expected = modelfile_completion(&varmap, "it was sad then the dog ran", 6)?;
+    assert_eq!(response.metrics.prompt_eval_count, Some(7));
+    assert_eq!(response.response, expected.text);
+    Ok(())
+}
+
+#[tokio::test]
+async fn ollama_show_reports_the_modelfile() -> Result<()> {
+    let (status, body) = send(
+        modelfile_app(&VarMap::new())?,
+        "POST",
+        "/api/show",
+        Some(json!({"name": "tiny"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let show: ollama::ShowResponse = serde_json::from_value(body)?;
+    assert_eq!(show.system, "it was a big sky");
+    assert_eq!(
+        show.template,
+        "{{ if .System }}{{ .System }} then {{ end }}{{ .Prompt }}"
+    );
+    assert_eq!(
+        show.parameters,
+        "temperature 0\nrepeat_penalty 1\nstop \"mat\""
+    );
+    let modelfile: Modelfile = show.modelfile.parse()?;
+    assert_eq!(modelfile.from, "tiny");
+    assert_eq!(modelfile.parameters.stop, Some(vec!["mat".to_string()]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn ollama_unknown_model_is_not_found() -> Result<()> {
+    let request = json!({"model": "llama3:8b", "prompt": "the cat"});
+    let (status, body) = send(app(&VarMap::new())?, "POST", "/api/generate", Some(request)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "model 'llama3:8b' not found");
+    Ok(())
+}
+
+fn admission_app(varmap: &VarMap, config: AdmissionConfig) -> Result<Router> {
+    let engine = random_engine_from(varmap)?;
+    let state = ServerState::new(engine, word_tokenizer(), "tiny")
+        .with_scheduler(SchedulerConfig {
+            max_batch_size: 1,
+            ..Default::default()
+        })
+        .with_admission(config);
+    Ok(router(state))
+}
+
+async fn send_with_request_id(
+    app: Router,
+    uri: &str,
+    request_id: &str,
+    body: Value,
+) -> (StatusCode, Option<String>, Value) {
+    let request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header("x-request-id", request_id)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .map(|value| value.to_str().unwrap().to_string());
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (status, request_id, serde_json::from_slice(&bytes).unwrap())
+}
+
+#[tokio::test]
+async fn responses_carry_the_request_id() -> Result<()> {
+    let app = app(&VarMap::new())?;
+    let request = json!({"model": "tiny", "prompt": "the cat", "max_tokens": 2});
+    let (status, request_id, body) =
+        send_with_request_id(app.clone(), "/v1/completions", "req-1", request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(request_id.as_deref(), Some("req-1"));
+    assert_eq!(body["id"], "req-1");
+
+    let request = json!({"model": "tiny", "prompt": "the cat", "stream": false});
+    let (status, request_id, _) =
+        send_with_request_id(app, "/api/generate", "req-2", request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(request_id.as_deref(), Some("req-2"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn requests_beyond_the_queue_are_rejected() -> Result<()> {
+    let config = AdmissionConfig {
+        max_queue: 1,
+        ..Default::default()
+    };
+    let app = admission_app(&VarMap::new(), config)?;
+    let request = json!({"model": "tiny", "prompt": "the cat", "max_tokens": 2, "n": 2});
+    let (status, body) = send(app.clone(), "POST", "/v1/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["error"]["code"], "queue_full");
+
+    let request = json!({"model": "tiny", "prompt": "the cat", "max_tokens": 2});
+    let (status, _) = send(app, "POST", "/v1/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    Ok(())
+}
+
+#[tokio::test]
+async fn requests_past_their_deadline_time_out() -> Result<()> {
+    let app = admission_app(&VarMap::new(), AdmissionConfig::default())?;
+    let request = json!({
+        "model": "tiny",
+        "prompt": "the cat",
+        "max_tokens": 50,
+        "timeout": 1e-6,
+    });
+    let (status, body) = send(app.clone(), "POST", "/v1/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+    assert_eq!(body["error"]["code"], "timeout");
+
+    let request = json!({"model": "tiny", "prompt": "the cat", "timeout": -1.0});
+    let (status, body) = send(app, "POST", "/v1/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["param"], "timeout");
+
+    // The default timeout applies to the Ollama API as well.
+    let config = AdmissionConfig {
+        timeout: Some(Duration::from_nanos(1)),
+        ..Default::default()
+    };
+    let app = admission_app(&VarMap::new(), config)?;
+    let request = json!({"model": "tiny", "prompt": "the cat", "stream": false});
+    let (status, body) = send(app, "POST", "/api/generate", Some(request)).await;
+    assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+    assert_eq!(body["error"], "the request timed out");
+    Ok(())
+}
+
+#[tokio::test]
+async fn requests_can_be_cancelled_by_id() -> Result<()> {
+    let app = admission_app(&VarMap::new(), AdmissionConfig::default())?;
+    let (status, body) = send(app.clone(), "DELETE", "/v1/requests/unknown", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"]["code"], "request_not_found");
+
+    // Four long generations run one after the other, the request is still in flight when it is
+    // cancelled.
+    let request = json!({"model": "tiny", "prompt": "the cat", "max_tokens": 100, "n": 4});
+    let completion = tokio::spawn(send_with_request_id(
+        app.clone(),
+        "/v1/completions",
+        "slow",
+        request,
+    ));
+    let mut cancelled = false;
+    for _ in 0..1000 {
+        let (status, _) = send_raw(app.clone(), "DELETE", "/v1/requests/slow", None).await;
+        if status == StatusCode::NO_CONTENT {
+            cancelled = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    assert!(cancelled);
+    let (status, _, body) = completion.await?;
+    assert_eq!(status.as_u16(), 499);
+    assert_eq!(body["error"]["code"], "cancelled");
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_report_requests_tokens_and_latencies() -> Result<()> {
+    let app = app(&VarMap::new())?;
+    let request = json!({"model": "tiny", "prompt": "the cat sat", "max_tokens": 4});
+    let (status, body) = send(app.clone(), "POST", "/v1/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    let request = json!({"model": "gpt-4", "prompt": "the cat"});
+    send(app.clone(), "POST", "/v1/completions", Some(request)).await;
+
+    let (status, text) = send_raw(app, "GET", "/metrics", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let sample = |series: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .map(|value| value.parse::<f64>().unwrap())
+    };
+    let completions = "llama_requests_total{endpoint=\"/v1/completions\"";
+    assert_eq!(
+        sample(&format!("{completions},status=\"200\"}}")),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(&format!("{completions},status=\"404\"}}")),
+        Some(1.0)
+    );
+    let usage = &body["usage"];
+    assert_eq!(
+        sample("llama_prompt_tokens_total"),
+        usage["prompt_tokens"].as_f64()
+    );
+    assert_eq!(
+        sample("llama_generation_tokens_total"),
+        usage["completion_tokens"].as_f64()
+    );
+    assert_eq!(sample("llama_time_to_first_token_seconds_count"), Some(1.0));
+    assert_eq!(sample("llama_e2e_request_latency_seconds_count"), Some(1.0));
+    // A token between each two, fewer than 4 when the end of text is sampled.
+    assert_eq!(
+        sample("llama_inter_token_latency_seconds_count"),
+        usage["completion_tokens"]
+            .as_f64()
+            .map(|tokens| tokens - 1.0)
+    );
+    assert_eq!(sample("llama_queue_depth"), Some(0.0));
+    assert_eq!(sample("llama_running_sequences"), Some(0.0));
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_report_prefix_cache_hits_and_misses() -> Result<()> {
+    let engine = random_engine_from(&VarMap::new())?;
+    let state = ServerState::new(engine, word_tokenizer(), "tiny")
+        .with_scheduler(SchedulerConfig::default());
+    let app = router(state);
+    // 20 tokens, of which the last is always computed: the second request finds its first
+    // block of 16 in the cache.
+    let prompt =
+        "the cat sat on the mat and the dog ran under the tree then it was happy again today";
+    for _ in 0..2 {
+        let request = json!({"model": "tiny", "prompt": prompt, "max_tokens": 1});
+        let (status, _) = send(app.clone(), "POST", "/v1/completions", Some(request)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (status, text) = send_raw(app, "GET", "/metrics", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let sample = |series: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .map(|value| value.parse::<f64>().unwrap())
+    };
+    assert_eq!(sample("llama_prefix_cache_hits_total"), Some(1.0));
+    assert_eq!(sample("llama_prefix_cache_misses_total"), Some(1.0));
+    assert_eq!(sample("llama_prefix_cache_query_tokens_total"), Some(38.0));
+    assert_eq!(sample("llama_prefix_cache_hit_tokens_total"), Some(16.0));
+    assert_eq!(sample("llama_prefix_cache_hit_rate"), Some(16.0 / 38.0));
+    Ok(())
+}
+
+fn keys_app(varmap: &VarMap) -> Result<Router> {
+    let key = |key: &str, name: &str, requests_per_minute, admin| ApiKey {
+        key: key.to_string(),
+        name: name.to_string(),
+        requests_per_minute,
+        tokens_per_minute: None,
+        admin,
+    };
+    let keys = ApiKeys::new(vec![
+        key("sk-a", "team-a", Some(2), false),
+        key("sk-b", "team-b", None, false),
+        key("sk-ops", "ops", None, true),
+    ])?;
+    let engine = random_engine_from(varmap)?;
+    let state = ServerState::new(engine, word_tokenizer(), "tiny").with_api_keys(keys);
+    Ok(router(state))
+}
+
+async fn send_with_key(
+    app: Router,
+    method: &str,
+    uri: &str,
+    key: Option<&str>,
+    body: Option<Value>,
+) -> (StatusCode, HeaderMap, String) {
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(key) = key {
+        request = request.header("authorization", format!("Bearer {key}"));
+    }
+    let request = match body {
+        Some(body) => request
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    }
+    .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (status, headers, String::from_utf8(bytes.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn api_keys_are_required_except_for_metrics() -> Result<()> {
+    let app = keys_app(&VarMap::new())?;
+    let (status, headers, body) = send_with_key(app.clone(), "GET", "/v1/models", None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(headers["www-authenticate"], "Bearer");
+    let body: Value = serde_json::from_str(&body)?;
+    assert_eq!(body["error"]["code"], "invalid_api_key");
+
+    let (status, _, _) = send_with_key(app.clone(), "GET", "/api/tags", Some("sk-x"), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, _) = send_with_key(app.clone(), "GET", "/v1/models", Some("sk-b"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, _) = send_with_key(app, "GET", "/metrics", None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    Ok(())
+}
+
+#[tokio::test]
+async fn keys_are_rate_limited_and_their_usage_reported() -> Result<()> {
+    let app = keys_app(&VarMap::new())?;
+    let request = json!({"model": "tiny", "prompt": "the cat sat", "max_tokens": 4});
+    let (status, _, body) = send_with_key(
+        app.clone(),
+        "POST",
+        "/v1/completions",
+        Some("sk-a"),
+        Some(request.clone()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let completion: Value = serde_json::from_str(&body)?;
+    let (status, _, _) = send_with_key(app.clone(), "GET", "/v1/models", Some("sk-a"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, headers, body) = send_with_key(
+        app.clone(),
+        "POST",
+        "/v1/completions",
+        Some("sk-a"),
+        Some(request),
+    )
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(headers["retry-after"].to_str()?.parse::<u64>()? >= 1);
+    let body: Value = serde_json::from_str(&body)?;
+    assert_eq!(body["error"]["code"], "rate_limit_exceeded");
+
+    let (status, _, _) =
+        send_with_key(app.clone(), "GET", "/admin/usage", Some("sk-b"), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, body) = send_with_key(app, "GET", "/admin/usage", Some("sk-ops"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_str(&body)?;
+    let usage = body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|usage| usage["name"] == "team-a")
+        .unwrap();
+    assert_eq!(usage["requests"], 2);
+    assert_eq!(usage["rate_limited"], 1);
+    assert_eq!(usage["requests_per_minute"], 2);
+    assert_eq!(usage["prompt_tokens"], completion["usage"]["prompt_tokens"]);
+    assert_eq!(
+        usage["completion_tokens"],
+        completion["usage"]["completion_tokens"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn keys_only_cancel_their_own_requests() -> Result<()> {
+    let app = keys_app(&VarMap::new())?;
+    let completion = |key: &str| {
+        let request = json!({"model": "tiny", "prompt": "the cat", "max_tokens": 100, "n": 8});
+        let request = Request::builder()
+            .method("POST")
+            .uri("/v1/completions")
+            .header("authorization", format!("Bearer {key}"))
+            .header("content-type", "application/json")
+            .header("x-request-id", "shared-id")
+            .body(Body::from(request.to_string()))
+            .unwrap();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let bytes = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<Value>(&bytes).unwrap())
+        })
+    };
+
+    // Team b, and even an admin, cannot cancel the request of team a while it runs.
+    let owned = completion("sk-a");
+    while !owned.is_finished() {
+        for key in ["sk-b", "sk-ops"] {
+            let (status, _, _) = send_with_key(
+                app.clone(),
+                "DELETE",
+                "/v1/requests/shared-id",
+                Some(key),
+                None,
+            )
+            .await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+        tokio::task::yield_now().await;
+    }
+    let (status, body) = owned.await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["choices"].as_array().unwrap().len(), 8);
+
+    // A key cancels its own request.
+    let own = completion("sk-b");
+    let mut cancelled = false;
+    for _ in 0..1000 {
+        let (status, _, _) = send_with_key(
+            app.clone(),
+            "DELETE",
+            "/v1/requests/shared-id",
+            Some("sk-b"),
+            None,
+        )
+        .await;
+        if status == StatusCode::NO_CONTENT {
+            cancelled = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    assert!(cancelled);
+    let (status, body) = own.await?;
+    assert_eq!(status.as_u16(), 499);
+    assert_eq!(body["error"]["code"], "cancelled");
+    Ok(())
+}
+
+#[tokio::test]
+async fn dropped_streams_pay_for_the_tokens_they_generated() -> Result<()> {
+    let key = |key: &str, name: &str, tokens_per_minute, admin| ApiKey {
+        key: key.to_string(),
+        name: name.to_string(),
+        requests_per_minute: None,
+        tokens_per_minute,
+        admin,
+    };
+    let keys = ApiKeys::new(vec![
+        key("sk-a", "team-a", Some(8), false),
+        key("sk-ops", "ops", None, true),
+    ])?;
+    let engine = random_engine_from(&VarMap::new())?;
+    let app =
+        router(ServerState::new(engine, endless_word_tokenizer(), "tiny").with_api_keys(keys));
+    let request = json!({
+        "model": "tiny",
+        "prompt": "the cat",
+        "max_tokens": 50,
+        "temperature": 0.0,
+        "stream": true,
+    });
+    let request = Request::builder()
+        .method("POST")
+        .uri("/v1/completions")
+        .header("authorization", "Bearer sk-a")
+        .header("content-type", "application/json")
+        .body(Body::from(request.to_string()))?;
+    let response = app.clone().oneshot(request).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The client goes away after 6 chunks, each of them at least one token.
+    let mut body = response.into_body();
+    let mut chunks = 0;
+    while chunks < 6 {
+        let frame = body.frame().await.unwrap()?;
+        let data = String::from_utf8(frame.into_data().unwrap().to_vec())?;
+        for data in sse_data(&data) {
+            let chunk: Value = serde_json::from_str(data)?;
+            assert!(chunk["choices"][0]["finish_reason"].is_null());
+            chunks += 1;
+        }
+    }
+    drop(body);
+
+    let (status, _, body) =
+        send_with_key(app.clone(), "GET", "/admin/usage", Some("sk-ops"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: Value = serde_json::from_str(&body)?;
+    let usage = body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|usage| usage["name"] == "team-a")
+        .unwrap();
+    assert_eq!(usage["prompt_tokens"], 2);
+    let completion_tokens = usage["completion_tokens"].as_u64().unwrap();
+    assert!((6..50).contains(&completion_tokens));
+
+    // The 8 tokens a minute of the key are spent.
+    let request = json!({"model": "tiny", "prompt": "the cat", "max_tokens": 1});
+    let (status, _, body) =
+        send_with_key(app, "POST", "/v1/completions", Some("sk-a"), Some(request)).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let body: Value = serde_json::from_str(&body)?;
+    assert_eq!(body["error"]["code"], "rate_limit_exceeded");
+    Ok(())
+}
+
+#[tokio::test]
+async fn embeddings_are_charged_to_the_key_before_running() -> Result<()> {
+    let keys = ApiKeys::new(vec![ApiKey {
+        key: "sk-a".to_string(),
+        name: "team-a".to_string(),
+        requests_per_minute: None,
+        tokens_per_minute: Some(10),
+        admin: false,
+    }])?;
+    let engine = random_engine_from(&VarMap::new())?;
+    let app = router(ServerState::new(engine, word_tokenizer(), "tiny").with_api_keys(keys));
+    let request = json!({"model": "tiny", "input": ["a blue sky", "the cat sat"]});
+    let (status, _, _) = send_with_key(
+        app.clone(),
+        "POST",
+        "/v1/embeddings",
+        Some("sk-a"),
+        Some(request.clone()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    // 4 tokens are left, the 6 of the inputs are refused before they are embedded.
+    let (status, headers, body) = send_with_key(
+        app.clone(),
+        "POST",
+        "/v1/embeddings",
+        Some("sk-a"),
+        Some(request),
+    )
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(headers["retry-after"].to_str()?.parse::<u64>()? >= 1);
+    let body: Value = serde_json::from_str(&body)?;
+    assert_eq!(body["error"]["code"], "rate_limit_exceeded");
+    let request = json!({"model": "tiny", "prompt": "the cat sat on the mat"});
+    let (status, headers, _) =
+        send_with_key(app, "POST", "/api/embeddings", Some("sk-a"), Some(request)).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(headers.contains_key("retry-after"));
+    Ok(())
+}
+
+/// Models `a` and `b` sharing the weights of `varmap`, only one of them fits in memory.
+fn models_app(varmap: &VarMap, loads: Arc<AtomicUsize>) -> Result<Router> {
+    let varmap = varmap.clone();
+    let loader: Loader<ServedModel> = Box::new(move |entry: &ModelEntry| {
+        loads.fetch_add(1, Ordering::SeqCst);
+        let engine = random_engine_from(&varmap)?;
+        Ok((ServedModel::new(engine, word_tokenizer(), &entry.id), 100))
+    });
+    let entry = |id: &str| ModelEntry {
+        id: id.to_string(),
+        model: String::new(),
+        model_size: None,
+        tokenizer: None,
+        modelfile: None,
+    };
+    let models = ModelRegistry::new(vec![entry("a"), entry("b")], loader)?.with_budget(Some(150));
+    Ok(router(ServerState::with_models(models)))
+}
+
+#[tokio::test]
+async fn requests_are_routed_to_lazily_loaded_models() -> Result<()> {
+    let loads = Arc::new(AtomicUsize::new(0));
+    let app = models_app(&VarMap::new(), loads.clone())?;
+    let (status, body) = send(app.clone(), "GET", "/v1/models", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["data"][0]["id"], "a");
+    assert_eq!(body["data"][1]["id"], "b");
+    assert_eq!(loads.load(Ordering::SeqCst), 0);
+
+    for (model, loaded) in [("a", 1), ("a", 1), ("b", 2), ("a", 3)] {
+        let request = json!({"model": model, "prompt": "the cat", "max_tokens": 2});
+        let (status, body) = send(app.clone(), "POST", "/v1/completions", Some(request)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["model"], model);
+        assert_eq!(loads.load(Ordering::SeqCst), loaded);
+    }
+
+    let (status, body) = send(app.clone(), "GET", "/api/tags", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["models"][0]["name"], "a:latest");
+    assert_eq!(body["models"][1]["name"], "b:latest");
+    assert!(body["models"][1]["details"]["parameter_size"].is_null());
+    let request = json!({"model": "b:latest", "prompt": "the cat", "stream": false});
+    let (status, body) = send(app.clone(), "POST", "/api/generate", Some(request)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["model"], "b:latest");
+    assert_eq!(loads.load(Ordering::SeqCst), 4);
+
+    let request = json!({"model": "c", "prompt": "the cat"});
+    let (status, _) = send(app, "POST", "/v1/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
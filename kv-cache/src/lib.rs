@@ -4,7 +4,10 @@
  *
  */
 
-use candle_core::{DType, Device, Result, Tensor};
+use {
+    candle_core::{D, DType, Device, Result, Tensor, bail},
+    std::{fmt, str::FromStr},
+};
 
 #[derive(Debug, Clone)]
 pub struct Cache {
@@ -59,6 +62,27 @@ impl Cache {
         self.all_data = None;
     }
 
+    /// Drops everything past the first `seq_len` elements, the underlying buffer is kept so that
+    /// the next append overwrites the discarded entries in place.
+    pub fn truncate(&mut self, seq_len: usize) {
+        self.current_seq_len = self.current_seq_len.min(seq_len);
+    }
+
+    /// Returns a copy of the cache that does not share its storage with `self`.
+    ///
+    /// `Clone` only bumps the reference count of the underlying tensor and `append` writes to it
+    /// in place, so two clones would overwrite each other's entries as soon as they diverge.
+    pub fn fork(&self) -> Result<Self> {
+        let all_data = match self.all_data.as_ref() {
+            None => None,
+            Some(d) => Some(d.copy()?),
+        };
+        Ok(Self {
+            all_data,
+            ..self.clone()
+        })
+    }
+
     pub fn append(&mut self, src: &Tensor) -> Result<()> {
         let seq_len = src.dim(self.dim)?;
         // This doesn't seem very idiomatic but because the creation can fail, it's tricky to use
@@ -152,19 +176,281 @@ impl KvCache {
         self.k.reset();
         self.v.reset();
     }
+
+    pub fn truncate(&mut self, seq_len: usize) {
+        self.k.truncate(seq_len);
+        self.v.truncate(seq_len);
+    }
+
+    pub fn fork(&self) -> Result<Self> {
+        Ok(Self {
+            k: self.k.fork()?,
+            v: self.v.fork()?,
+        })
+    }
+}
+
+/// KV 量化方式
+///
+/// Entries are stored as signed integers with one scale per token and head, the absolute maximum
+/// of the head mapped to the largest integer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvQuantization {
+    /// 8 bits per entry, in `-127..=127`.
+    Int8,
+    /// 4 bits per entry in `-7..=7`, two entries packed in a byte, the head dimension must be
+    /// even.
+    Int4,
+}
+
+impl KvQuantization {
+    fn max(self) -> f64 {
+        match self {
+            Self::Int8 => 127.,
+            Self::Int4 => 7.,
+        }
+    }
+
+    /// Quantized entries as `u8`, offset to be non-negative, and their scales, with a last
+    /// dimension of 1.
+    pub fn quantize(self, src: &Tensor) -> Result<(Tensor, Tensor)> {
+        let max = self.max();
+        let src = src.to_dtype(DType::F32)?;
+        let scales = (src.abs()?.max_keepdim(D::Minus1)? / max)?;
+        let q = src
+            .broadcast_div(&scales.maximum(1e-12)?)?
+            .round()?
+            .clamp(-max, max)?;
+        let q = match self {
+            Self::Int8 => (q + 128.)?,
+            Self::Int4 => {
+                let mut dims = q.dims().to_vec();
+                let head_dim = dims.pop().unwrap_or(0);
+                if head_dim % 2 != 0 {
+                    bail!("int4 KV entries need an even head dimension, got {head_dim}")
+                }
+                dims.extend([head_dim / 2, 2]);
+                let pairs = (q + 8.)?.reshape(dims)?;
+                let low = pairs.narrow(D::Minus1, 0, 1)?;
+                let high = pairs.narrow(D::Minus1, 1, 1)?;
+                (low + (high * 16.)?)?.squeeze(D::Minus1)?
+            }
+        };
+        Ok((q.to_dtype(DType::U8)?, scales))
+    }
+
+    /// Entries of `dtype` back from the output of [`KvQuantization::quantize`].
+    pub fn dequantize(self, data: &Tensor, scales: &Tensor, dtype: DType) -> Result<Tensor> {
+        let data = data.to_dtype(DType::F32)?;
+        let q = match self {
+            Self::Int8 => (data - 128.)?,
+            Self::Int4 => {
+                let high = (&data / 16.)?.floor()?;
+                let low = (&data - (&high * 16.)?)?;
+                let pairs = Tensor::stack(&[low, high], D::Minus1)?;
+                (pairs.flatten_from(D::Minus2)? - 8.)?
+            }
+        };
+        q.broadcast_mul(scales)?.to_dtype(dtype)
+    }
+}
+
+impl fmt::Display for KvQuantization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int8 => write!(f, "int8"),
+            Self::Int4 => write!(f, "int4"),
+        }
+    }
+}
+
+impl FromStr for KvQuantization {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "int8" | "q8" => Ok(Self::Int8),
+            "int4" | "q4" => Ok(Self::Int4),
+            _ => Err(format!(
+                "unknown KV quantization `{s}`, expected int8 or int4"
+            )),
+        }
+    }
+}
+
+/// Like [`Cache`] with its entries quantized, they are dequantized to the dtype they were
+/// appended with on read. Only the quantized entries and their scales are stored.
+#[derive(Debug, Clone)]
+pub struct QuantizedCache {
+    data: Cache,
+    scales: Cache,
+    quantization: KvQuantization,
+    dtype: DType,
+}
+
+impl QuantizedCache {
+    /// `dim` must not be the last dimension, along which the entries are quantized.
+    pub fn new(dim: usize, max_seq_len: usize, quantization: KvQuantization) -> Self {
+        Self {
+            data: Cache::new(dim, max_seq_len),
+            scales: Cache::new(dim, max_seq_len),
+            quantization,
+            dtype: DType::F32,
+        }
+    }
+
+    pub fn quantization(&self) -> KvQuantization {
+        self.quantization
+    }
+
+    pub fn dim(&self) -> usize {
+        self.data.dim()
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.data.current_seq_len()
+    }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.data.max_seq_len()
+    }
+
+    /// The entries quantized and dequantized again.
+    pub fn current_data(&self) -> Result<Option<Tensor>> {
+        let Some((data, scales)) = self.quantized_data()? else {
+            return Ok(None);
+        };
+        Ok(Some(
+            self.quantization.dequantize(&data, &scales, self.dtype)?,
+        ))
+    }
+
+    /// The quantized entries and their scales, as returned by [`KvQuantization::quantize`].
+    pub fn quantized_data(&self) -> Result<Option<(Tensor, Tensor)>> {
+        let (Some(data), Some(scales)) = (self.data.current_data()?, self.scales.current_data()?)
+        else {
+            return Ok(None);
+        };
+        Ok(Some((data, scales)))
+    }
+
+    pub fn reset(&mut self) {
+        self.data.reset();
+        self.scales.reset();
+    }
+
+    pub fn truncate(&mut self, seq_len: usize) {
+        self.data.truncate(seq_len);
+        self.scales.truncate(seq_len);
+    }
+
+    pub fn fork(&self) -> Result<Self> {
+        Ok(Self {
+            data: self.data.fork()?,
+            scales: self.scales.fork()?,
+            ..*self
+        })
+    }
+
+    pub fn append(&mut self, src: &Tensor) -> Result<()> {
+        if self.dim() + 1 >= src.rank() {
+            bail!("cannot quantize along the cache dimension {}", self.dim())
+        }
+        let (data, scales) = self.quantization.quantize(src)?;
+        self.data.append(&data)?;
+        self.scales.append(&scales)?;
+        self.dtype = src.dtype();
+        Ok(())
+    }
 }
 
+/// Like [`KvCache`] with its keys and values quantized.
+#[derive(Debug, Clone)]
+pub struct QuantizedKvCache {
+    k: QuantizedCache,
+    v: QuantizedCache,
+}
+
+impl QuantizedKvCache {
+    pub fn new(dim: usize, max_seq_len: usize, quantization: KvQuantization) -> Self {
+        let k = QuantizedCache::new(dim, max_seq_len, quantization);
+        let v = QuantizedCache::new(dim, max_seq_len, quantization);
+        Self { k, v }
+    }
+
+    pub fn k_cache(&self) -> &QuantizedCache {
+        &self.k
+    }
+
+    pub fn v_cache(&self) -> &QuantizedCache {
+        &self.v
+    }
+
+    pub fn k(&self) -> Result<Option<Tensor>> {
+        self.k.current_data()
+    }
+
+    pub fn v(&self) -> Result<Option<Tensor>> {
+        self.v.current_data()
+    }
+
+    /// Append `k` and `v` and return all the keys and values, dequantized.
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        self.k.append(k)?;
+        self.v.append(v)?;
+        let k = self
+            .k
+            .current_data()?
+            .expect("the cache was just appended to");
+        let v = self
+            .v
+            .current_data()?
+            .expect("the cache was just appended to");
+        Ok((k, v))
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.k.current_seq_len()
+    }
+
+    pub fn reset(&mut self) {
+        self.k.reset();
+        self.v.reset();
+    }
+
+    pub fn truncate(&mut self, seq_len: usize) {
+        self.k.truncate(seq_len);
+        self.v.truncate(seq_len);
+    }
+
+    pub fn fork(&self) -> Result<Self> {
+        Ok(Self {
+            k: self.k.fork()?,
+            v: self.v.fork()?,
+        })
+    }
+}
+
+/// Cache of the last `max_seq_len` positions of a sequence, overwritten in a ring.
+///
+/// With [`RotatingCache::with_sinks`], the first `sinks` positions of the sequence are kept for
+/// good, the attention sinks of StreamingLLM (<https://arxiv.org/abs/2309.17453>), and only the
+/// other `max_seq_len - sinks` slots rotate. Models not trained with a sliding window put a lot
+/// of attention on the first tokens and degrade quickly once those leave the window.
 #[derive(Debug, Clone)]
 pub struct RotatingCache {
     all_data: Option<Tensor>,
     dim: usize,
-    // `offset` is the current write index in the buffer
+    // `offset` is the current write index in the rotating part of the buffer
     offset: usize,
     // The total size of the sequence seen so far.
     current_seq_len: usize,
     // max_seq_len is the size of the rotating buffer, it is actually allowed for the full
     // sequence to grow past this limit.
     max_seq_len: usize,
+    // The first `sinks` entries of the buffer hold the first positions of the sequence and are
+    // never overwritten.
+    sinks: usize,
 }
 
 impl RotatingCache {
@@ -175,9 +461,21 @@ impl RotatingCache {
             offset: 0,
             current_seq_len: 0,
             max_seq_len,
+            sinks: 0,
         }
     }
 
+    /// Keep the first `sinks` positions, `sinks` must be less than `max_seq_len`.
+    pub fn with_sinks(dim: usize, max_seq_len: usize, sinks: usize) -> Result<Self> {
+        if sinks >= max_seq_len {
+            bail!("{sinks} sinks leave no room for a window of {max_seq_len} positions")
+        }
+        Ok(Self {
+            sinks,
+            ..Self::new(dim, max_seq_len)
+        })
+    }
+
     pub fn offset(&self) -> usize {
         self.offset
     }
@@ -194,6 +492,15 @@ impl RotatingCache {
         self.max_seq_len
     }
 
+    pub fn sinks(&self) -> usize {
+        self.sinks
+    }
+
+    /// Number of slots that rotate.
+    fn window(&self) -> usize {
+        self.max_seq_len - self.sinks
+    }
+
     pub fn all_data(&self) -> &Option<Tensor> {
         &self.all_data
     }
@@ -228,33 +535,51 @@ impl RotatingCache {
             let ad = Tensor::zeros(shape, src.dtype(), src.device())?;
             self.all_data = Some(ad)
         };
+        let window = self.window();
         let ad = self.all_data.as_mut().unwrap();
 
+        // The first positions of the sequence go to the sinks, the rest to the window.
+        let past = self.current_seq_len;
+        let num_sinks = seq_len.min(self.sinks.saturating_sub(past));
+        if num_sinks > 0 {
+            let sinks = src.narrow(self.dim, 0, num_sinks)?.contiguous()?;
+            ad.slice_set(&sinks, self.dim, past)?;
+        }
+        let rest_len = seq_len - num_sinks;
         self.current_seq_len += seq_len;
-        if seq_len >= self.max_seq_len {
+        if rest_len >= window {
             let to_copy = src
-                .narrow(self.dim, seq_len - self.max_seq_len, self.max_seq_len)?
+                .narrow(self.dim, seq_len - window, window)?
                 .contiguous()?;
-            ad.slice_set(&to_copy, self.dim, 0)?;
+            ad.slice_set(&to_copy, self.dim, self.sinks)?;
             self.offset = 0;
-            // Here we return `src` rather than `ad` so that all the past can be used.
-            Ok(src.clone())
+            // Here we return `src` rather than `ad` so that all the past can be used, along with
+            // the sinks it does not hold.
+            let past_sinks = past.min(self.sinks);
+            if past_sinks == 0 {
+                Ok(src.clone())
+            } else {
+                Tensor::cat(&[&ad.narrow(self.dim, 0, past_sinks)?, src], self.dim)
+            }
         } else {
-            let rem_len = self.max_seq_len - self.offset;
-            if seq_len <= rem_len {
-                ad.slice_set(&src.contiguous()?, self.dim, self.offset)?;
-                self.offset = (self.offset + seq_len) % self.max_seq_len;
+            let src = src.narrow(self.dim, num_sinks, rest_len)?;
+            let rem_len = window - self.offset;
+            if rest_len <= rem_len {
+                if rest_len > 0 {
+                    ad.slice_set(&src.contiguous()?, self.dim, self.sinks + self.offset)?;
+                }
+                self.offset = (self.offset + rest_len) % window;
             } else {
                 // We have to make two copies here as we go over the boundary of the cache.
                 if rem_len > 0 {
                     let src1 = src.narrow(self.dim, 0, rem_len)?.contiguous()?;
-                    ad.slice_set(&src1, self.dim, self.offset)?;
+                    ad.slice_set(&src1, self.dim, self.sinks + self.offset)?;
                 }
                 let src2 = src
-                    .narrow(self.dim, rem_len, seq_len - rem_len)?
+                    .narrow(self.dim, rem_len, rest_len - rem_len)?
                     .contiguous()?;
-                ad.slice_set(&src2, self.dim, 0)?;
-                self.offset = seq_len - rem_len;
+                ad.slice_set(&src2, self.dim, self.sinks)?;
+                self.offset = rest_len - rem_len;
             }
             if self.current_seq_len >= self.max_seq_len {
                 Ok(ad.clone())
@@ -264,75 +589,57 @@ impl RotatingCache {
         }
     }
 
-    fn get_mask_abs(&self, size1: usize, size2: usize, device: &Device) -> Result<Tensor> {
-        let context = self.max_seq_len;
-        let mask: Vec<_> = (0..size1)
-            .flat_map(|i| {
-                (0..size2).map(move |j| {
-                    u8::from(size1 + j > size2 + i || size1 + j + context < size2 + i)
-                })
-            })
-            .collect();
-        Tensor::from_slice(&mask, (size1, size2), device)
-    }
-
-    fn get_mask_rel(&self, size1: usize, size2: usize, device: &Device) -> Result<Tensor> {
-        let context = self.max_seq_len;
-        let upd_offset = (self.offset + size1) % self.max_seq_len;
-        let mask: Vec<_> = (0..size1)
-            .flat_map(|pos_src| {
-                // The absolute position of the elements that will get added to the cache.
-                let pos_src = self.current_seq_len + pos_src;
-                (0..size2).map(move |pos_cache_rel| {
-                    // The absolute position of the cache elements after the addition.
-                    let pos_cache = self.current_seq_len + size1 + pos_cache_rel - upd_offset;
-                    let pos_cache = if pos_cache_rel < upd_offset {
-                        pos_cache
-                    } else {
-                        pos_cache - self.max_seq_len
-                    };
-                    u8::from(pos_cache > pos_src || pos_cache + context < pos_src)
-                })
-            })
-            .collect();
-        Tensor::from_slice(&mask, (size1, size2), device)
-    }
-
     /// Returns the positions corresponding to all the elements that will be retured
     /// *after* adding `seq_len` to the cache.
     pub fn positions(&self, seq_len: usize) -> Vec<usize> {
-        if seq_len <= self.max_seq_len {
-            let upd_offset = (self.offset + seq_len) % self.max_seq_len;
-            let cache_out_len = (self.current_seq_len + seq_len).min(self.max_seq_len);
-            (0..cache_out_len)
-                .map(|i| {
-                    let pos_cache = self.current_seq_len + seq_len + i - upd_offset;
-                    if i < upd_offset {
-                        pos_cache
-                    } else {
-                        pos_cache - self.max_seq_len
-                    }
-                })
-                .collect()
+        let past = self.current_seq_len;
+        let total = past + seq_len;
+        let window = self.window();
+        let rest_len = seq_len - seq_len.min(self.sinks.saturating_sub(past));
+        if rest_len >= window {
+            (0..past.min(self.sinks)).chain(past..total).collect()
         } else {
-            (self.current_seq_len..(self.current_seq_len + seq_len)).collect()
+            let sinks = total.min(self.sinks);
+            let upd_offset = (self.offset + rest_len) % window;
+            let window_len = (total - sinks).min(window);
+            let window_positions = (0..window_len).map(move |i| {
+                let pos_cache = total + i - upd_offset;
+                if i < upd_offset {
+                    pos_cache
+                } else {
+                    pos_cache - window
+                }
+            });
+            (0..sinks).chain(window_positions).collect()
         }
     }
 
     /// Returns the attn_mask to be applied *after* adding `seq_len` to the cache.
+    ///
+    /// A new element attends to the sinks and to the elements at most `max_seq_len - sinks`
+    /// positions before it, not to the ones after it.
     pub fn attn_mask(&self, seq_len: usize, device: &Device) -> Result<Option<Tensor>> {
-        let mask = if seq_len == 1 {
-            None
-        } else {
-            let mask = if seq_len < self.max_seq_len {
-                let cache_out_len = (self.current_seq_len + seq_len).min(self.max_seq_len);
-                self.get_mask_rel(seq_len, cache_out_len, device)?
-            } else {
-                self.get_mask_abs(seq_len, seq_len, device)?
-            };
-            Some(mask)
-        };
-        Ok(mask)
+        if seq_len == 1 {
+            return Ok(None);
+        }
+        let positions = self.positions(seq_len);
+        let (past, sinks, window) = (self.current_seq_len, self.sinks, self.window());
+        let mask: Vec<_> = (0..seq_len)
+            .flat_map(|i| {
+                // The absolute position of the elements that will get added to the cache.
+                let pos_src = past + i;
+                positions.iter().map(move |&pos_cache| {
+                    u8::from(
+                        pos_cache > pos_src || (pos_cache >= sinks && pos_cache + window < pos_src),
+                    )
+                })
+            })
+            .collect();
+        Ok(Some(Tensor::from_slice(
+            &mask,
+            (seq_len, positions.len()),
+            device,
+        )?))
     }
 }
 
@@ -349,6 +656,13 @@ impl RotatingKvCache {
         Self { k, v }
     }
 
+    /// Keep the keys and values of the first `sinks` positions, see [`RotatingCache::with_sinks`].
+    pub fn with_sinks(dim: usize, max_seq_len: usize, sinks: usize) -> Result<Self> {
+        let k = RotatingCache::with_sinks(dim, max_seq_len, sinks)?;
+        let v = RotatingCache::with_sinks(dim, max_seq_len, sinks)?;
+        Ok(Self { k, v })
+    }
+
     pub fn k_cache(&self) -> &RotatingCache {
         &self.k
     }
@@ -638,8 +952,194 @@ impl ScatteredCacheBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use all_close::TensorAllClose;
     use candle_core::IndexOp;
 
+    #[test]
+    fn test_kv_cache_truncate() -> Result<()> {
+        let device = Device::Cpu;
+        let mut cache = KvCache::new(1, 4);
+        let kv = Tensor::arange(0f32, 3., &device)?.reshape((1, 3))?;
+        cache.append(&kv, &kv)?;
+        cache.truncate(1);
+        assert_eq!(cache.current_seq_len(), 1);
+
+        let kv = Tensor::new(&[[7f32, 8.]], &device)?;
+        let (k, v) = cache.append(&kv, &kv)?;
+        assert_eq!(k.to_vec2::<f32>()?, [[0.0, 7.0, 8.0]]);
+        assert_eq!(v.to_vec2::<f32>()?, [[0.0, 7.0, 8.0]]);
+
+        // Truncating past the current length is a no-op.
+        cache.truncate(8);
+        assert_eq!(cache.current_seq_len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kv_cache_fork() -> Result<()> {
+        let device = Device::Cpu;
+        let mut cache = KvCache::new(1, 4);
+        let kv = Tensor::new(&[[1f32, 2.]], &device)?;
+        cache.append(&kv, &kv)?;
+
+        let mut forked = cache.fork()?;
+        let (k, _) = cache.append(&Tensor::new(&[[3f32]], &device)?, &kv.narrow(1, 0, 1)?)?;
+        let (forked_k, _) =
+            forked.append(&Tensor::new(&[[4f32]], &device)?, &kv.narrow(1, 0, 1)?)?;
+        assert_eq!(k.to_vec2::<f32>()?, [[1.0, 2.0, 3.0]]);
+        assert_eq!(forked_k.to_vec2::<f32>()?, [[1.0, 2.0, 4.0]]);
+        Ok(())
+    }
+
+    /// Keys and values of shape `(1, seq_len, 2, 8)`, like the model stores them.
+    fn randn_kv(seq_len: usize, device: &Device) -> Result<Tensor> {
+        Tensor::randn(0f32, 1., (1, seq_len, 2, 8), device)
+    }
+
+    #[test]
+    fn test_quantized_kv_cache_is_close_to_f32() -> Result<()> {
+        let device = Device::Cpu;
+        for (quantization, tolerance) in
+            [(KvQuantization::Int8, 2e-2), (KvQuantization::Int4, 3e-1)]
+        {
+            let mut cache = QuantizedKvCache::new(1, 16, quantization);
+            let mut full = KvCache::new(1, 16);
+            for seq_len in [5, 1, 3] {
+                let k = randn_kv(seq_len, &device)?;
+                let v = randn_kv(seq_len, &device)?;
+                let (quantized_k, quantized_v) = cache.append(&k, &v)?;
+                let (k, v) = full.append(&k, &v)?;
+                assert_eq!(quantized_k.dims(), k.dims());
+                assert!(quantized_k.all_close(&k, tolerance)?);
+                assert!(quantized_v.all_close(&v, tolerance)?);
+            }
+            assert_eq!(cache.current_seq_len(), 9);
+            // Only the quantized entries and their scales are stored, in a fraction of the
+            // memory of the f32 entries.
+            let bytes = |cache: &Cache| {
+                let data = cache.all_data().as_ref().unwrap();
+                data.elem_count() * data.dtype().size_in_bytes()
+            };
+            let quantized = bytes(&cache.k.data) + bytes(&cache.k.scales);
+            let expected = match quantization {
+                KvQuantization::Int8 => (8 + 4) * 2 * 16,
+                KvQuantization::Int4 => (4 + 4) * 2 * 16,
+            };
+            assert_eq!(quantized, expected);
+            assert!(quantized * 2 < bytes(full.k_cache()));
+
+            // Each head keeps its own scale, a small head is not drowned by a large one.
+            let small = Tensor::new(&[1e-3f32, -2e-3, 0., 4e-3], &device)?;
+            let large = Tensor::new(&[100f32, -50., 25., 0.], &device)?;
+            let kv = Tensor::stack(&[&small, &large], 0)?.reshape((1, 1, 2, 4))?;
+            let mut cache = QuantizedCache::new(1, 4, quantization);
+            cache.append(&kv)?;
+            let heads = Tensor::new(&[4e-3f32, 100.], &device)?.reshape((1, 1, 2, 1))?;
+            let out = cache.current_data()?.unwrap().broadcast_div(&heads)?;
+            let expected = kv.broadcast_div(&heads)?;
+            assert!(out.all_close(&expected, 0.5 / quantization.max())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantized_kv_cache_truncate_and_fork() -> Result<()> {
+        let device = Device::Cpu;
+        let mut cache = QuantizedKvCache::new(1, 8, KvQuantization::Int8);
+        let kv = randn_kv(4, &device)?;
+        cache.append(&kv, &kv)?;
+        cache.truncate(2);
+        let mut forked = cache.fork()?;
+        let (k, _) = cache.append(&randn_kv(1, &device)?, &randn_kv(1, &device)?)?;
+        let (forked_k, _) = forked.append(&kv.narrow(1, 0, 1)?, &kv.narrow(1, 0, 1)?)?;
+        assert_eq!(k.dims(), [1, 3, 2, 8]);
+        assert!(
+            forked_k
+                .narrow(1, 0, 2)?
+                .all_close(&k.narrow(1, 0, 2)?, 0.0)?
+        );
+        assert!(forked_k.i((.., 2))?.all_close(&kv.i((.., 0))?, 2e-2)?);
+
+        let odd = Tensor::zeros((1, 1, 2, 3), DType::F32, &device)?;
+        assert!(
+            QuantizedCache::new(1, 8, KvQuantization::Int4)
+                .append(&odd)
+                .is_err()
+        );
+        assert_eq!("q4".parse::<KvQuantization>(), Ok(KvQuantization::Int4));
+        assert!("fp8".parse::<KvQuantization>().is_err());
+        Ok(())
+    }
+
+    /// Appends chunks of positions, stored as their value, and checks that the cache returns
+    /// the positions it reports: the sinks and at least the last window.
+    fn check_rotating_positions(max_seq_len: usize, sinks: usize) -> Result<()> {
+        let device = Device::Cpu;
+        let mut cache = RotatingCache::with_sinks(0, max_seq_len, sinks)?;
+        let window = max_seq_len - sinks;
+        let mut total = 0;
+        for seq_len in [3, 1, 1, 4, 1, 7, 2, 1, 1, 6] {
+            let positions = cache.positions(seq_len);
+            let src = Tensor::arange(total as f32, (total + seq_len) as f32, &device)?;
+            let out = cache.append(&src)?.to_vec1::<f32>()?;
+            let out: Vec<usize> = out.into_iter().map(|x| x as usize).collect();
+            assert_eq!(out, positions);
+            total += seq_len;
+            assert_eq!(cache.current_seq_len(), total);
+            for pos in (0..total.min(sinks)).chain(total.saturating_sub(window).max(sinks)..total) {
+                assert!(out.contains(&pos), "{pos} missing from {out:?}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotating_cache_positions() -> Result<()> {
+        check_rotating_positions(6, 0)?;
+        check_rotating_positions(6, 2)?;
+        check_rotating_positions(6, 5)?;
+        // A bad configuration is an error, not a panic.
+        assert!(RotatingCache::with_sinks(0, 6, 6).is_err());
+        assert!(RotatingKvCache::with_sinks(1, 4, 7).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotating_kv_cache_keeps_the_sinks() -> Result<()> {
+        let device = Device::Cpu;
+        let mut cache = RotatingKvCache::with_sinks(1, 5, 2)?;
+        let kv = Tensor::arange(0f32, 6., &device)?.reshape((1, 6))?;
+        let (k, _) = cache.append(&kv, &kv)?;
+        assert_eq!(k.to_vec2::<f32>()?, [[0., 1., 2., 3., 4., 5.]]);
+        let kv = Tensor::new(&[[6f32]], &device)?;
+        let (k, v) = cache.append(&kv, &kv)?;
+        // The sinks stay in front, the window holds 6, 4 and 5.
+        assert_eq!(k.to_vec2::<f32>()?, [[0., 1., 6., 4., 5.]]);
+        assert_eq!(v.to_vec2::<f32>()?, [[0., 1., 6., 4., 5.]]);
+        assert_eq!(cache.positions(2), [0, 1, 6, 7, 8]);
+
+        // Two new elements at positions 7 and 8 see the sinks and the window, not each other's
+        // future.
+        let mask = cache.attn_mask(2, &device)?.unwrap();
+        assert_eq!(mask.to_vec2::<u8>()?, [[0, 0, 0, 0, 1], [0, 0, 0, 0, 0]]);
+        // Past the window, a new element still sees the sinks.
+        let cache = RotatingCache::with_sinks(0, 3, 1)?;
+        assert_eq!(cache.positions(5), [0, 1, 2, 3, 4]);
+        let mask = cache.attn_mask(5, &device)?.unwrap();
+        assert_eq!(
+            mask.to_vec2::<u8>()?,
+            [
+                [0, 1, 1, 1, 1],
+                [0, 0, 1, 1, 1],
+                [0, 0, 0, 1, 1],
+                [0, 0, 0, 0, 1],
+                [0, 1, 0, 0, 0]
+            ]
+        );
+        assert!(cache.attn_mask(1, &device)?.is_none());
+        Ok(())
+    }
+
     #[test]
     fn test_scattered_kv_cache() -> Result<()> {
         let device = Device::Cpu;
@@ -0,0 +1,495 @@
+//! Ollama 客户端
+//!
+//! Thin wrappers over `ollama-rs` that map its requests and responses to the types of this crate.
+
+use crate::{
+    ChatMessage, ChatRequest, ChatResponse, GenerateRequest, GenerateResponse, GenerationMetrics,
+    Model, ModelDetails, ModelList, Modelfile, Options, PullStatus, ShowResponse, file_type_name,
+    parameter_size,
+};
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use ollama_rs::{
+    Ollama,
+    generation::{
+        chat::{ChatMessage as OllamaChatMessage, MessageRole, request::ChatMessageRequest},
+        completion::request::GenerationRequest,
+    },
+    models::{ModelInfo, ModelOptions},
+};
+use std::collections::HashSet;
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc))
+}
+
+/// Convert the option `name` to the narrower type `ollama-rs` takes, failing rather than wrapping
+/// around when it does not fit.
+fn narrow<T: TryFrom<U>, U: Copy + std::fmt::Display>(name: &str, value: U) -> Result<T> {
+    T::try_from(value).map_err(|_| anyhow!("`{name}` {value} is out of range"))
+}
+
+fn model_options(options: &Options) -> Result<ModelOptions> {
+    let mut model_options = ModelOptions::default();
+    if let Some(temperature) = options.temperature {
+        model_options = model_options.temperature(temperature as f32);
+    }
+    if let Some(top_p) = options.top_p {
+        model_options = model_options.top_p(top_p as f32);
+    }
+    if let Some(top_k) = options.top_k {
+        model_options = model_options.top_k(narrow("top_k", top_k)?);
+    }
+    if let Some(seed) = options.seed {
+        model_options = model_options.seed(narrow("seed", seed)?);
+    }
+    if let Some(num_predict) = options.num_predict {
+        model_options = model_options.num_predict(narrow("num_predict", num_predict)?);
+    }
+    if let Some(num_ctx) = options.num_ctx {
+        model_options = model_options.num_ctx(num_ctx as u64);
+    }
+    if let Some(repeat_penalty) = options.repeat_penalty {
+        model_options = model_options.repeat_penalty(repeat_penalty);
+    }
+    if let Some(repeat_last_n) = options.repeat_last_n {
+        model_options = model_options.repeat_last_n(narrow("repeat_last_n", repeat_last_n)?);
+    }
+    if let Some(stop) = options.stop.as_ref() {
+        model_options = model_options.stop(stop.clone());
+    }
+    if let Some(mirostat) = options.mirostat {
+        model_options = model_options.mirostat(narrow("mirostat", mirostat)?);
+    }
+    if let Some(mirostat_tau) = options.mirostat_tau {
+        model_options = model_options.mirostat_tau(mirostat_tau);
+    }
+    if let Some(mirostat_eta) = options.mirostat_eta {
+        model_options = model_options.mirostat_eta(mirostat_eta);
+    }
+    Ok(model_options)
+}
+
+/// Details of a model as far as they can be told from `/api/show`.
+fn details(info: &ModelInfo) -> ModelDetails {
+    let family = info
+        .model_info
+        .get("general.architecture")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+    ModelDetails {
+        parent_model: None,
+        format: "gguf".to_string(),
+        families: HashSet::from([family.clone()]),
+        family,
+        parameter_size: info
+            .model_info
+            .get("general.parameter_count")
+            .and_then(|v| v.as_u64())
+            .map(parameter_size),
+        quantization_level: info
+            .model_info
+            .get("general.file_type")
+            .and_then(|v| v.as_u64())
+            .and_then(file_type_name)
+            .map(str::to_string),
+    }
+}
+
+/// List the local models, the details of each of them are filled from `/api/show`.
+///
+/// `ollama-rs` does not expose the digests of the models, they are left empty.
+pub async fn tags(ollama: &Ollama) -> Result<ModelList> {
+    let mut models = Vec::new();
+    for local in ollama.list_local_models().await? {
+        let info = ollama.show_model_info(local.name.clone()).await?;
+        models.push(Model {
+            model: local.name.clone(),
+            name: local.name,
+            modified_at: parse_time(&local.modified_at)?,
+            size: local.size,
+            digest: String::new(),
+            details: details(&info),
+        });
+    }
+    Ok(ModelList { models })
+}
+
+/// Modelfile, template, parameters and architecture of `model`.
+pub async fn show(ollama: &Ollama, model: &str) -> Result<ShowResponse> {
+    let info = ollama.show_model_info(model.to_string()).await?;
+    // `ollama-rs` leaves out the system prompt, it is read back from the Modelfile.
+    let system = info
+        .modelfile
+        .parse::<Modelfile>()
+        .ok()
+        .and_then(|modelfile| modelfile.system)
+        .unwrap_or_default();
+    Ok(ShowResponse {
+        details: details(&info),
+        license: info.license,
+        modelfile: info.modelfile,
+        parameters: info.parameters,
+        template: info.template,
+        system,
+        model_info: info.model_info,
+    })
+}
+
+/// Generate a completion without streaming.
+///
+/// `ollama-rs` does not report why the generation ended, so `done_reason` is always `None`.
+pub async fn generate(ollama: &Ollama, request: &GenerateRequest) -> Result<GenerateResponse> {
+    let mut generation = GenerationRequest::new(request.model.clone(), request.prompt.as_str());
+    if let Some(system) = request.system.as_deref() {
+        generation = generation.system(system);
+    }
+    if let Some(raw) = request.raw {
+        generation = generation.raw(raw);
+    }
+    if let Some(options) = request.options.as_ref() {
+        generation = generation.options(model_options(options)?);
+    }
+    let response = ollama.generate(generation).await?;
+    Ok(GenerateResponse {
+        model: response.model,
+        created_at: parse_time(&response.created_at)?,
+        response: response.response,
+        done: response.done,
+        done_reason: None,
+        metrics: GenerationMetrics {
+            total_duration: response.total_duration,
+            load_duration: response.load_duration,
+            prompt_eval_count: response.prompt_eval_count.map(|n| n as usize),
+            prompt_eval_duration: response.prompt_eval_duration,
+            eval_count: response.eval_count.map(|n| n as usize),
+            eval_duration: response.eval_duration,
+        },
+    })
+}
+
+fn message_role(role: &str) -> Result<MessageRole> {
+    Ok(match role {
+        "user" => MessageRole::User,
+        "assistant" => MessageRole::Assistant,
+        "system" => MessageRole::System,
+        "tool" => MessageRole::Tool,
+        _ => bail!("unknown message role `{role}`"),
+    })
+}
+
+/// Send a conversation and wait for the whole answer.
+pub async fn chat(ollama: &Ollama, request: &ChatRequest) -> Result<ChatResponse> {
+    let messages = request
+        .messages
+        .iter()
+        .map(|message| {
+            Ok(OllamaChatMessage::new(
+                message_role(&message.role)?,
+                message.content.clone(),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut chat_request = ChatMessageRequest::new(request.model.clone(), messages);
+    if let Some(options) = request.options.as_ref() {
+        chat_request = chat_request.options(model_options(options)?);
+    }
+    let response = ollama.send_chat_messages(chat_request).await?;
+    let role = match serde_json::to_value(&response.message.role)? {
+        serde_json::Value::String(role) => role,
+        role => bail!("unexpected message role {role}"),
+    };
+    let metrics = response
+        .final_data
+        .map(|data| GenerationMetrics {
+            total_duration: Some(data.total_duration),
+            load_duration: Some(data.load_duration),
+            prompt_eval_count: Some(data.prompt_eval_count as usize),
+            prompt_eval_duration: Some(data.prompt_eval_duration),
+            eval_count: Some(data.eval_count as usize),
+            eval_duration: Some(data.eval_duration),
+        })
+        .unwrap_or_default();
+    Ok(ChatResponse {
+        model: response.model,
+        created_at: parse_time(&response.created_at)?,
+        message: ChatMessage {
+            role,
+            content: response.message.content,
+        },
+        done: response.done,
+        done_reason: None,
+        metrics,
+    })
+}
+
+/// Download `model` from the registry and return the final status.
+pub async fn pull(ollama: &Ollama, model: &str) -> Result<PullStatus> {
+    let status = ollama.pull_model(model.to_string(), false).await?;
+    Ok(PullStatus {
+        status: status.message,
+        digest: status.digest,
+        total: status.total,
+        completed: status.completed,
+    })
+}
+
+/// Remove `model` and its data.
+pub async fn delete(ollama: &Ollama, model: &str) -> Result<()> {
+    ollama.delete_model(model.to_string()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Json, Router,
+        extract::State,
+        http::StatusCode,
+        routing::{delete as delete_route, get, post},
+    };
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    const CREATED_AT: &str = "2024-05-01T10:00:00.123456789-07:00";
+
+    /// Names of the models still present on the mock server.
+    type Models = Arc<Mutex<Vec<String>>>;
+
+    fn not_found(model: &str) -> (StatusCode, Json<Value>) {
+        let error = json!({"error": format!("model '{model}' not found")});
+        (StatusCode::NOT_FOUND, Json(error))
+    }
+
+    async fn list(State(models): State<Models>) -> Json<Value> {
+        let models: Vec<Value> = models
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|name| json!({"name": name, "modified_at": CREATED_AT, "size": 4661224676u64}))
+            .collect();
+        Json(json!({ "models": models }))
+    }
+
+    async fn show_info(
+        State(models): State<Models>,
+        Json(body): Json<Value>,
+    ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+        let name = body["name"].as_str().unwrap_or_default();
+        if !models.lock().unwrap().iter().any(|m| m == name) {
+            return Err(not_found(name));
+        }
+        Ok(Json(json!({
+            "license": "LLAMA 3 COMMUNITY LICENSE",
+            "modelfile": format!("FROM {name}\n"),
+            "parameters": "stop \"<|eot_id|>\"",
+            "template": "{{ .Prompt }}",
+            "model_info": {
+                "general.architecture": "llama",
+                "general.parameter_count": 8030261248u64,
+                "general.file_type": 15,
+            },
+        })))
+    }
+
+    /// Echoes the prompt, system prompt and options it received.
+    async fn generation(Json(body): Json<Value>) -> Json<Value> {
+        let response =
+            json!({"prompt": body["prompt"], "system": body["system"], "options": body["options"]});
+        Json(json!({
+            "model": body["model"],
+            "created_at": CREATED_AT,
+            "response": response.to_string(),
+            "done": true,
+            "total_duration": 5000,
+            "load_duration": 1000,
+            "prompt_eval_count": 3,
+            "prompt_eval_duration": 1500,
+            "eval_count": 7,
+            "eval_duration": 2500,
+        }))
+    }
+
+    /// Answers with the roles of the messages it received.
+    async fn chat_messages(Json(body): Json<Value>) -> Json<Value> {
+        let roles: Vec<&str> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        Json(json!({
+            "model": body["model"],
+            "created_at": CREATED_AT,
+            "message": {"role": "assistant", "content": roles.join(",")},
+            "done": true,
+            "total_duration": 5000,
+            "load_duration": 1000,
+            "prompt_eval_count": 3,
+            "prompt_eval_duration": 1500,
+            "eval_count": 7,
+            "eval_duration": 2500,
+        }))
+    }
+
+    async fn pull_model(State(models): State<Models>, Json(body): Json<Value>) -> Json<Value> {
+        models
+            .lock()
+            .unwrap()
+            .push(body["name"].as_str().unwrap().to_string());
+        Json(json!({"status": "success"}))
+    }
+
+    async fn delete_model(
+        State(models): State<Models>,
+        Json(body): Json<Value>,
+    ) -> Result<(), (StatusCode, Json<Value>)> {
+        let name = body["name"].as_str().unwrap_or_default();
+        let mut models = models.lock().unwrap();
+        match models.iter().position(|m| m == name) {
+            Some(index) => {
+                models.remove(index);
+                Ok(())
+            }
+            None => Err(not_found(name)),
+        }
+    }
+
+    /// Start a mock Ollama server with `llama3:8b` installed.
+    async fn mock_server() -> Ollama {
+        let models: Models = Arc::new(Mutex::new(vec!["llama3:8b".to_string()]));
+        let app = Router::new()
+            .route("/api/tags", get(list))
+            .route("/api/show", post(show_info))
+            .route("/api/generate", post(generation))
+            .route("/api/chat", post(chat_messages))
+            .route("/api/pull", post(pull_model))
+            .route("/api/delete", delete_route(delete_model))
+            .with_state(models);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Ollama::new("http://127.0.0.1", port)
+    }
+
+    #[tokio::test]
+    async fn test_tags() -> Result<()> {
+        let ollama = mock_server().await;
+        let list = tags(&ollama).await?;
+        assert_eq!(list.models.len(), 1);
+        let model = &list.models[0];
+        assert_eq!(model.name, "llama3:8b");
+        assert_eq!(model.size, 4661224676);
+        assert_eq!(
+            model.modified_at,
+            parse_time("2024-05-01T17:00:00.123456789Z")?
+        );
+        assert_eq!(model.details.family, "llama");
+        assert!(model.details.families.contains("llama"));
+        assert_eq!(model.details.parameter_size.as_deref(), Some("8.0B"));
+        assert_eq!(model.details.quantization_level.as_deref(), Some("Q4_K_M"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_show() -> Result<()> {
+        let ollama = mock_server().await;
+        let info = show(&ollama, "llama3:8b").await?;
+        assert_eq!(info.template, "{{ .Prompt }}");
+        assert_eq!(info.parameters, "stop \"<|eot_id|>\"");
+        assert_eq!(info.model_info["general.architecture"], "llama");
+
+        let err = show(&ollama, "mistral").await.unwrap_err();
+        assert!(err.to_string().contains("model 'mistral' not found"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate() -> Result<()> {
+        let ollama = mock_server().await;
+        let request = GenerateRequest {
+            model: "llama3:8b".to_string(),
+            prompt: "Why is the sky blue?".to_string(),
+            system: Some("Be brief.".to_string()),
+            raw: None,
+            stream: None,
+            options: Some(Options {
+                temperature: Some(0.5),
+                num_predict: Some(32),
+                ..Default::default()
+            }),
+        };
+        let response = generate(&ollama, &request).await?;
+        assert!(response.done);
+        let echo: Value = serde_json::from_str(&response.response)?;
+        assert_eq!(echo["prompt"], "Why is the sky blue?");
+        assert_eq!(echo["system"], "Be brief.");
+        assert_eq!(
+            echo["options"],
+            json!({"num_predict": 32, "temperature": 0.5})
+        );
+        assert_eq!(response.metrics.eval_count, Some(7));
+        assert_eq!(response.metrics.prompt_eval_duration, Some(1500));
+
+        // Ollama takes 32-bit seeds, larger ones are refused rather than wrapped around.
+        let mut request = request;
+        request.options = Some(Options {
+            seed: Some(u64::from(u32::MAX)),
+            ..Default::default()
+        });
+        let err = generate(&ollama, &request).await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("`seed` 4294967295 is out of range")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat() -> Result<()> {
+        let ollama = mock_server().await;
+        let mut request = ChatRequest {
+            model: "llama3:8b".to_string(),
+            messages: vec![
+                ChatMessage {
+                    role: "system".to_string(),
+                    content: "Be brief.".to_string(),
+                },
+                ChatMessage {
+                    role: "user".to_string(),
+                    content: "Hello".to_string(),
+                },
+            ],
+            stream: None,
+            options: None,
+        };
+        let response = chat(&ollama, &request).await?;
+        assert_eq!(response.message.role, "assistant");
+        assert_eq!(response.message.content, "system,user");
+        assert_eq!(response.metrics.total_duration, Some(5000));
+
+        request.messages[0].role = "narrator".to_string();
+        assert!(chat(&ollama, &request).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pull_and_delete() -> Result<()> {
+        let ollama = mock_server().await;
+        assert_eq!(pull(&ollama, "mistral:7b").await?.status, "success");
+        assert_eq!(tags(&ollama).await?.models.len(), 2);
+
+        delete(&ollama, "llama3:8b").await?;
+        let names: Vec<String> = tags(&ollama)
+            .await?
+            .models
+            .into_iter()
+            .map(|m| m.name)
+            .collect();
+        assert_eq!(names, vec!["mistral:7b"]);
+        assert!(delete(&ollama, "llama3:8b").await.is_err());
+        Ok(())
+    }
+}
@@ -1,15 +1,23 @@
-use ollama_rs::{models::LocalModel, Ollama};
-use serde::{Deserialize, Serialize};
+mod client;
+pub mod modelfile;
+pub mod store;
+pub mod template;
+
+pub use client::{chat, delete, generate, pull, show, tags};
+pub use modelfile::Modelfile;
+pub use store::{LocalModel, ModelName, ModelStore};
+pub use template::Template;
+
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
-use anyhow::Result;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelList {
     pub models: Vec<Model>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Model {
     pub name: String,
     pub model: String,
@@ -19,7 +27,7 @@ pub struct Model {
     pub details: ModelDetails,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelDetails {
     pub parent_model: Option<String>,
     pub format: String,
@@ -29,6 +37,250 @@ pub struct ModelDetails {
     pub quantization_level: Option<String>,
 }
 
-pub async fn tags(ollama: &Ollama) -> Result<LocalModel> {
-    todo!("Implement the tags function to fetch local models from Ollama");
-}
\ No newline at end of file
+/// Runtime options of a request, unset options take the server defaults.
+///
+/// The engine of `llama-serve` applies the sampling options, the stop strings and
+/// `num_predict`. The other options are accepted so that Modelfiles and requests written for
+/// Ollama stay valid.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Options {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Maximum number of tokens to generate, -1 for no limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_last_n: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typical_p: Option<f64>,
+    /// Number of prompt tokens kept when the context overflows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_keep: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    /// Mirostat sampling, 0 disabled, 1 Mirostat, 2 Mirostat 2.0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirostat: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirostat_tau: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirostat_eta: Option<f32>,
+}
+
+impl Options {
+    /// Options of `self`, falling back to `defaults` for the unset ones.
+    pub fn or(self, defaults: &Options) -> Options {
+        Options {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            top_k: self.top_k.or(defaults.top_k),
+            seed: self.seed.or(defaults.seed),
+            num_predict: self.num_predict.or(defaults.num_predict),
+            num_ctx: self.num_ctx.or(defaults.num_ctx),
+            repeat_penalty: self.repeat_penalty.or(defaults.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.or(defaults.repeat_last_n),
+            stop: self.stop.or_else(|| defaults.stop.clone()),
+            min_p: self.min_p.or(defaults.min_p),
+            typical_p: self.typical_p.or(defaults.typical_p),
+            num_keep: self.num_keep.or(defaults.num_keep),
+            presence_penalty: self.presence_penalty.or(defaults.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(defaults.frequency_penalty),
+            mirostat: self.mirostat.or(defaults.mirostat),
+            mirostat_tau: self.mirostat_tau.or(defaults.mirostat_tau),
+            mirostat_eta: self.mirostat_eta.or(defaults.mirostat_eta),
+        }
+    }
+}
+
+/// `POST /api/generate`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerateRequest {
+    pub model: String,
+    #[serde(default)]
+    pub prompt: String,
+    /// Overrides the system prompt of the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// Send the prompt as is, without applying the prompt template.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<bool>,
+    /// Responses are streamed unless this is `false`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<Options>,
+}
+
+/// Timings and token counts sent with the last response of a generation, durations are in
+/// nanoseconds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GenerationMetrics {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_duration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_duration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_eval_count: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_eval_duration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval_count: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval_duration: Option<u64>,
+}
+
+/// `POST /api/generate` response, one per line when streaming.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerateResponse {
+    pub model: String,
+    pub created_at: DateTime<Utc>,
+    pub response: String,
+    pub done: bool,
+    /// `stop` or `length`, only set when `done`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<String>,
+    #[serde(flatten)]
+    pub metrics: GenerationMetrics,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+/// `POST /api/chat`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    /// Responses are streamed unless this is `false`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<Options>,
+}
+
+/// `POST /api/chat` response, one per line when streaming.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatResponse {
+    pub model: String,
+    pub created_at: DateTime<Utc>,
+    pub message: ChatMessage,
+    pub done: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<String>,
+    #[serde(flatten)]
+    pub metrics: GenerationMetrics,
+}
+
+/// `POST /api/show`, older clients send the model as `name`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShowRequest {
+    #[serde(default, alias = "name")]
+    pub model: String,
+}
+
+/// `POST /api/show` response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShowResponse {
+    #[serde(default)]
+    pub license: String,
+    #[serde(default)]
+    pub modelfile: String,
+    /// One `name value` pair per line.
+    #[serde(default)]
+    pub parameters: String,
+    #[serde(default)]
+    pub template: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub system: String,
+    pub details: ModelDetails,
+    #[serde(default)]
+    pub model_info: serde_json::Map<String, serde_json::Value>,
+}
+
+/// `POST /api/embeddings`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingsRequest {
+    pub model: String,
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<Options>,
+}
+
+/// `POST /api/embeddings` response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingsResponse {
+    pub embedding: Vec<f32>,
+}
+
+/// Body of every error response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+/// `POST /api/pull` final status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PullStatus {
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed: Option<u64>,
+}
+
+/// Human readable parameter count as shown by Ollama, e.g. `137M` or `8.0B`.
+pub fn parameter_size(count: u64) -> String {
+    let count = count as f64;
+    if count >= 1e9 {
+        format!("{:.1}B", count / 1e9)
+    } else if count >= 1e6 {
+        format!("{:.0}M", count / 1e6)
+    } else {
+        format!("{:.0}K", count / 1e3)
+    }
+}
+
+/// Name of a GGUF `general.file_type`, the quantization of most of the weights.
+pub fn file_type_name(file_type: u64) -> Option<&'static str> {
+    Some(match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        32 => "BF16",
+        _ => return None,
+    })
+}
@@ -0,0 +1,491 @@
+//! Modelfiles, the recipes Ollama builds models from, see
+//! <https://github.com/ollama/ollama/blob/main/docs/modelfile.md>.
+//!
+//! A Modelfile is a list of directives, one per line, with `#` starting a comment line.
+//! Arguments run to the end of the line, or are quoted with `"` or `"""` to hold quotes, leading
+//! spaces or several lines.
+
+use {
+    crate::{ChatMessage, Options},
+    anyhow::{Context, Result, bail},
+    std::{fmt, path::Path, str::FromStr},
+};
+
+/// Roles accepted by `MESSAGE`.
+pub const MESSAGE_ROLES: [&str; 4] = ["system", "user", "assistant", "tool"];
+
+/// Modelfile 模型描述
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Modelfile {
+    /// Base model, a model name or a path to the weights relative to the Modelfile.
+    pub from: String,
+    /// LoRA adapters applied to the base model.
+    pub adapters: Vec<String>,
+    /// Prompt template, in the Go template syntax.
+    pub template: Option<String>,
+    pub system: Option<String>,
+    /// Default options of requests.
+    pub parameters: Options,
+    /// Conversation the chat history starts with.
+    pub messages: Vec<ChatMessage>,
+    pub license: Vec<String>,
+}
+
+impl Modelfile {
+    pub fn new(from: impl Into<String>) -> Self {
+        Self {
+            from: from.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        text.parse()
+            .with_context(|| format!("invalid Modelfile {}", path.display()))
+    }
+
+    pub fn with_adapter(mut self, adapter: impl Into<String>) -> Self {
+        self.adapters.push(adapter.into());
+        self
+    }
+
+    pub fn with_template(mut self, template: impl Into<String>) -> Self {
+        self.template = Some(template.into());
+        self
+    }
+
+    pub fn with_system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    /// Set a parameter as `PARAMETER name value` would.
+    pub fn with_parameter(mut self, name: &str, value: &str) -> Result<Self> {
+        self.parameters.set(name, value)?;
+        Ok(self)
+    }
+
+    pub fn with_message(mut self, role: &str, content: impl Into<String>) -> Result<Self> {
+        if !MESSAGE_ROLES.contains(&role) {
+            bail!("invalid message role '{role}'");
+        }
+        self.messages.push(ChatMessage {
+            role: role.to_string(),
+            content: content.into(),
+        });
+        Ok(self)
+    }
+
+    pub fn with_license(mut self, license: impl Into<String>) -> Self {
+        self.license.push(license.into());
+        self
+    }
+
+    /// Complete this Modelfile with `base`, the Modelfile of the model named by `FROM`: the
+    /// weights and adapters come from `base`, and every directive this Modelfile leaves unset
+    /// keeps the value of `base`.
+    pub fn inherit(self, base: &Modelfile) -> Self {
+        let mut adapters = base.adapters.clone();
+        adapters.extend(self.adapters);
+        Self {
+            from: base.from.clone(),
+            adapters,
+            template: self.template.or_else(|| base.template.clone()),
+            system: self.system.or_else(|| base.system.clone()),
+            parameters: self.parameters.or(&base.parameters),
+            messages: if self.messages.is_empty() {
+                base.messages.clone()
+            } else {
+                self.messages
+            },
+            license: if self.license.is_empty() {
+                base.license.clone()
+            } else {
+                self.license
+            },
+        }
+    }
+}
+
+impl Options {
+    /// Set the option `name` from its textual value, `stop` strings accumulate.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        fn parse<T: FromStr>(name: &str, value: &str) -> Result<Option<T>> {
+            match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => bail!("invalid value '{value}' of parameter {name}"),
+            }
+        }
+        match name {
+            "temperature" => self.temperature = parse(name, value)?,
+            "top_p" => self.top_p = parse(name, value)?,
+            "top_k" => self.top_k = parse(name, value)?,
+            "seed" => self.seed = parse(name, value)?,
+            "num_predict" => self.num_predict = parse(name, value)?,
+            "num_ctx" => self.num_ctx = parse(name, value)?,
+            "repeat_penalty" => self.repeat_penalty = parse(name, value)?,
+            "repeat_last_n" => self.repeat_last_n = parse(name, value)?,
+            "stop" => self.stop.get_or_insert_default().push(value.to_string()),
+            "min_p" => self.min_p = parse(name, value)?,
+            "typical_p" => self.typical_p = parse(name, value)?,
+            "num_keep" => self.num_keep = parse(name, value)?,
+            "presence_penalty" => self.presence_penalty = parse(name, value)?,
+            "frequency_penalty" => self.frequency_penalty = parse(name, value)?,
+            "mirostat" => self.mirostat = parse(name, value)?,
+            "mirostat_tau" => self.mirostat_tau = parse(name, value)?,
+            "mirostat_eta" => self.mirostat_eta = parse(name, value)?,
+            _ => bail!("unknown parameter '{name}'"),
+        }
+        Ok(())
+    }
+
+    /// `(name, value)` pairs of the options that are set, one per stop string.
+    pub fn parameters(&self) -> Vec<(&'static str, String)> {
+        let mut parameters = Vec::new();
+        macro_rules! push {
+            ($($field:ident),*) => {
+                $(
+                    if let Some(value) = &self.$field {
+                        parameters.push((stringify!($field), value.to_string()));
+                    }
+                )*
+            };
+        }
+        push!(
+            temperature,
+            top_p,
+            top_k,
+            seed,
+            num_predict,
+            num_ctx,
+            repeat_penalty,
+            repeat_last_n
+        );
+        for stop in self.stop.iter().flatten() {
+            parameters.push(("stop", stop.clone()));
+        }
+        push!(
+            min_p,
+            typical_p,
+            num_keep,
+            presence_penalty,
+            frequency_penalty,
+            mirostat,
+            mirostat_tau,
+            mirostat_eta
+        );
+        parameters
+    }
+}
+
+/// Reads the directives of a Modelfile, keeping track of the line for error messages.
+struct Parser<'a> {
+    rest: &'a str,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn advance(&mut self, len: usize) -> &'a str {
+        let (taken, rest) = self.rest.split_at(len);
+        self.line += taken.matches('\n').count();
+        self.rest = rest;
+        taken
+    }
+
+    fn skip_blanks(&mut self) {
+        let len = self.rest.len() - self.rest.trim_start_matches([' ', '\t', '\r']).len();
+        self.advance(len);
+    }
+
+    fn skip_line(&mut self) {
+        let len = self.rest.find('\n').map_or(self.rest.len(), |i| i + 1);
+        self.advance(len);
+    }
+
+    fn word(&mut self) -> &'a str {
+        self.skip_blanks();
+        let len = self
+            .rest
+            .find(char::is_whitespace)
+            .unwrap_or(self.rest.len());
+        self.advance(len)
+    }
+
+    /// Argument running to the end of the line, or quoted.
+    fn argument(&mut self) -> Result<String> {
+        self.skip_blanks();
+        let line = self.line;
+        let argument = if let Some(rest) = self.rest.strip_prefix(r#"""""#) {
+            let Some(len) = rest.find(r#"""""#) else {
+                bail!("line {line}: unterminated \"\"\"");
+            };
+            self.advance(3);
+            let argument = self.advance(len).to_string();
+            self.advance(3);
+            argument
+        } else if self.rest.starts_with('"') {
+            self.advance(1);
+            let mut argument = String::new();
+            let mut chars = self.rest.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => argument.push(c),
+                        None => bail!("line {line}: unterminated \""),
+                    },
+                    Some((_, c)) => argument.push(c),
+                    None => bail!("line {line}: unterminated \""),
+                }
+            };
+            self.advance(end + 1);
+            argument
+        } else {
+            let len = self.rest.find('\n').unwrap_or(self.rest.len());
+            return Ok(self.advance(len).trim_end().to_string());
+        };
+        self.skip_blanks();
+        if !(self.rest.is_empty() || self.rest.starts_with('\n')) {
+            bail!(
+                "line {}: unexpected text after the quoted argument",
+                self.line
+            );
+        }
+        Ok(argument)
+    }
+}
+
+impl FromStr for Modelfile {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            rest: text,
+            line: 1,
+        };
+        let mut from = None;
+        let mut modelfile = Modelfile::default();
+        loop {
+            let len = parser.rest.len() - parser.rest.trim_start().len();
+            parser.advance(len);
+            if parser.rest.is_empty() {
+                break;
+            }
+            if parser.rest.starts_with('#') {
+                parser.skip_line();
+                continue;
+            }
+            let line = parser.line;
+            let command = parser.word().to_ascii_uppercase();
+            let (name, argument) = match command.as_str() {
+                "PARAMETER" | "MESSAGE" => (parser.word().to_string(), parser.argument()?),
+                _ => (String::new(), parser.argument()?),
+            };
+            if argument.is_empty() {
+                bail!("line {line}: missing argument of {command}");
+            }
+            match command.as_str() {
+                "FROM" if from.is_some() => bail!("line {line}: FROM is given twice"),
+                "FROM" => from = Some(argument),
+                "ADAPTER" => modelfile.adapters.push(argument),
+                "TEMPLATE" => modelfile.template = Some(argument),
+                "SYSTEM" => modelfile.system = Some(argument),
+                "LICENSE" => modelfile.license.push(argument),
+                "PARAMETER" => modelfile
+                    .parameters
+                    .set(&name.to_ascii_lowercase(), &argument)
+                    .with_context(|| format!("line {line}"))?,
+                "MESSAGE" => {
+                    modelfile = modelfile
+                        .with_message(&name.to_ascii_lowercase(), argument)
+                        .with_context(|| format!("line {line}"))?
+                }
+                _ => bail!("line {line}: unknown command '{command}'"),
+            }
+        }
+        let Some(from) = from else {
+            bail!("missing FROM");
+        };
+        modelfile.from = from;
+        Ok(modelfile)
+    }
+}
+
+/// `text` as a Modelfile argument, quoted when it cannot be written as is.
+fn quote(text: &str, multiline: bool) -> String {
+    if multiline && !text.contains(r#"""""#) && !text.ends_with('"') {
+        format!(r#""""{text}""""#)
+    } else if !multiline
+        && !text.is_empty()
+        && !text.starts_with('"')
+        && !text.contains(char::is_whitespace)
+    {
+        text.to_string()
+    } else {
+        format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+impl fmt::Display for Modelfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "FROM {}", quote(&self.from, false))?;
+        for adapter in self.adapters.iter() {
+            writeln!(f, "ADAPTER {}", quote(adapter, false))?;
+        }
+        if let Some(template) = &self.template {
+            writeln!(f, "TEMPLATE {}", quote(template, true))?;
+        }
+        if let Some(system) = &self.system {
+            writeln!(f, "SYSTEM {}", quote(system, true))?;
+        }
+        for (name, value) in self.parameters.parameters() {
+            let value = if name == "stop" {
+                format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+            } else {
+                quote(&value, false)
+            };
+            writeln!(f, "PARAMETER {name} {value}")?;
+        }
+        for message in self.messages.iter() {
+            writeln!(
+                f,
+                "MESSAGE {} {}",
+                message.role,
+                quote(&message.content, true)
+            )?;
+        }
+        for license in self.license.iter() {
+            writeln!(f, "LICENSE {}", quote(license, true))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LLAMA3: &str = r#"
+# Modelfile generated by "ollama show"
+FROM llama3:8b
+template """{{ if .System }}<|start_header_id|>system<|end_header_id|>
+
+{{ .System }}<|eot_id|>{{ end }}{{ .Prompt }}"""
+SYSTEM You are a concise assistant.
+PARAMETER temperature 0.6
+PARAMETER stop "<|start_header_id|>"
+PARAMETER stop <|eot_id|>
+PARAMETER num_keep 24
+MESSAGE user "Who are \"you\"?"
+MESSAGE assistant """A llama."""
+LICENSE """META LLAMA 3 COMMUNITY LICENSE"""
+"#;
+
+    #[test]
+    fn parses_every_directive() {
+        let modelfile: Modelfile = LLAMA3.parse().unwrap();
+        assert_eq!(modelfile.from, "llama3:8b");
+        assert_eq!(
+            modelfile.template.as_deref(),
+            Some(
+                "{{ if .System }}<|start_header_id|>system<|end_header_id|>\n\n\
+                 {{ .System }}<|eot_id|>{{ end }}{{ .Prompt }}"
+            )
+        );
+        assert_eq!(
+            modelfile.system.as_deref(),
+            Some("You are a concise assistant.")
+        );
+        assert_eq!(modelfile.parameters.temperature, Some(0.6));
+        assert_eq!(modelfile.parameters.num_keep, Some(24));
+        assert_eq!(
+            modelfile.parameters.stop,
+            Some(vec![
+                "<|start_header_id|>".to_string(),
+                "<|eot_id|>".to_string()
+            ])
+        );
+        assert_eq!(modelfile.messages.len(), 2);
+        assert_eq!(modelfile.messages[0].content, r#"Who are "you"?"#);
+        assert_eq!(modelfile.messages[1].role, "assistant");
+        assert_eq!(modelfile.license, ["META LLAMA 3 COMMUNITY LICENSE"]);
+    }
+
+    #[test]
+    fn renders_a_modelfile_that_parses_back() {
+        let modelfile: Modelfile = LLAMA3.parse().unwrap();
+        let rendered = modelfile.to_string();
+        assert!(
+            rendered.contains("PARAMETER stop \"<|eot_id|>\"\n"),
+            "{rendered}"
+        );
+        assert_eq!(rendered.parse::<Modelfile>().unwrap(), modelfile);
+
+        let built = Modelfile::new("./model.gguf")
+            .with_system("Say \"hi\"")
+            .with_parameter("top_k", "40")
+            .unwrap()
+            .with_message("user", "  leading spaces")
+            .unwrap()
+            .with_adapter("lora dir/adapter.gguf");
+        assert_eq!(built.to_string().parse::<Modelfile>().unwrap(), built);
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        let error = |text: &str| format!("{:#}", text.parse::<Modelfile>().unwrap_err());
+        assert_eq!(error("SYSTEM hi"), "missing FROM");
+        assert_eq!(error("FROM a\nFROM b"), "line 2: FROM is given twice");
+        assert_eq!(
+            error("FROM a\n\nPARAMETER top_q 1"),
+            "line 3: unknown parameter 'top_q'"
+        );
+        assert_eq!(
+            error("FROM a\nPARAMETER temperature warm"),
+            "line 2: invalid value 'warm' of parameter temperature"
+        );
+        assert_eq!(
+            error("FROM a\nMESSAGE robot hi"),
+            "line 2: invalid message role 'robot'"
+        );
+        assert_eq!(
+            error("FROM a\nTEMPLATE \"\"\"{{ .Prompt }}"),
+            "line 2: unterminated \"\"\""
+        );
+        assert_eq!(
+            error("FROM a\nSYSTEM \"hi\" there"),
+            "line 2: unexpected text after the quoted argument"
+        );
+        assert_eq!(
+            error("FROM a\nQUANTIZE q4_0"),
+            "line 2: unknown command 'QUANTIZE'"
+        );
+        assert_eq!(
+            error("FROM a\nSYSTEM"),
+            "line 2: missing argument of SYSTEM"
+        );
+    }
+
+    #[test]
+    fn inherits_unset_directives_from_the_base_model() {
+        let base: Modelfile = LLAMA3.parse().unwrap();
+        let base = Modelfile {
+            from: "/blobs/sha256-1".to_string(),
+            ..base
+        };
+        let modelfile = Modelfile::new("llama3:8b")
+            .with_system("Answer in French.")
+            .with_parameter("temperature", "0.2")
+            .unwrap()
+            .inherit(&base);
+        assert_eq!(modelfile.from, "/blobs/sha256-1");
+        assert_eq!(modelfile.system.as_deref(), Some("Answer in French."));
+        assert_eq!(modelfile.template, base.template);
+        assert_eq!(modelfile.parameters.temperature, Some(0.2));
+        assert_eq!(modelfile.parameters.stop, base.parameters.stop);
+        assert_eq!(modelfile.messages, base.messages);
+    }
+}
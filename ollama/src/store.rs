@@ -0,0 +1,393 @@
+//! Models pulled by Ollama, read from its local blob store.
+//!
+//! The store holds one manifest per model under
+//! `manifests/<host>/<namespace>/<model>/<tag>`. The manifest lists the layers of the model by
+//! media type and digest, and each layer is kept in `blobs/sha256-<hex>`.
+
+use {
+    crate::{ChatMessage, Modelfile, Options},
+    anyhow::{Context, Result, bail},
+    serde::{Deserialize, Serialize},
+    std::{
+        fmt, fs,
+        path::{Path, PathBuf},
+        str::FromStr,
+    },
+};
+
+pub const DEFAULT_HOST: &str = "registry.ollama.ai";
+pub const DEFAULT_NAMESPACE: &str = "library";
+pub const DEFAULT_TAG: &str = "latest";
+
+/// Media types of the manifest layers.
+pub mod media_type {
+    pub const MODEL: &str = "application/vnd.ollama.image.model";
+    pub const ADAPTER: &str = "application/vnd.ollama.image.adapter";
+    pub const TEMPLATE: &str = "application/vnd.ollama.image.template";
+    pub const SYSTEM: &str = "application/vnd.ollama.image.system";
+    pub const PARAMS: &str = "application/vnd.ollama.image.params";
+    pub const MESSAGES: &str = "application/vnd.ollama.image.messages";
+    pub const LICENSE: &str = "application/vnd.ollama.image.license";
+}
+
+/// 模型名称, `[host/][namespace/]model[:tag]`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ModelName {
+    pub host: String,
+    pub namespace: String,
+    pub model: String,
+    pub tag: String,
+}
+
+impl ModelName {
+    /// Name as shown by `ollama list`, the default host and namespace are left out.
+    pub fn short(&self) -> String {
+        if self.host == DEFAULT_HOST && self.namespace == DEFAULT_NAMESPACE {
+            format!("{}:{}", self.model, self.tag)
+        } else if self.host == DEFAULT_HOST {
+            format!("{}/{}:{}", self.namespace, self.model, self.tag)
+        } else {
+            self.to_string()
+        }
+    }
+}
+
+impl FromStr for ModelName {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        let (path, tag) = match name.rsplit_once(':') {
+            // A colon before the last slash is the port of the host.
+            Some((path, tag)) if !tag.contains('/') => (path, tag),
+            _ => (name, DEFAULT_TAG),
+        };
+        let parts: Vec<_> = path.split('/').collect();
+        let (host, namespace, model) = match parts.as_slice() {
+            [model] => (DEFAULT_HOST, DEFAULT_NAMESPACE, *model),
+            [namespace, model] => (DEFAULT_HOST, *namespace, *model),
+            [host, namespace, model] => (*host, *namespace, *model),
+            _ => bail!("invalid model name '{name}'"),
+        };
+        let valid = |part: &str| {
+            !part.is_empty()
+                && part != "."
+                && part != ".."
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+        };
+        if ![host, namespace, model, tag].into_iter().all(valid) {
+            bail!("invalid model name '{name}'");
+        }
+        Ok(Self {
+            host: host.to_string(),
+            namespace: namespace.to_string(),
+            model: model.to_string(),
+            tag: tag.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ModelName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}:{}",
+            self.host, self.namespace, self.model, self.tag
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Layer {
+    pub media_type: String,
+    /// `sha256:<hex>`
+    pub digest: String,
+    pub size: u64,
+}
+
+/// 模型清单
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub schema_version: u32,
+    #[serde(default)]
+    pub media_type: String,
+    pub config: Layer,
+    pub layers: Vec<Layer>,
+}
+
+impl Manifest {
+    /// Layers of the given media type, in manifest order.
+    pub fn layers<'a>(&'a self, media_type: &'a str) -> impl Iterator<Item = &'a Layer> + 'a {
+        self.layers
+            .iter()
+            .filter(move |layer| layer.media_type == media_type)
+    }
+}
+
+/// A model resolved in the store, with the contents of its text layers.
+#[derive(Debug, Clone)]
+pub struct LocalModel {
+    pub name: ModelName,
+    pub manifest: Manifest,
+    /// GGUF file of the weights.
+    pub model: PathBuf,
+    /// GGUF files of the LoRA adapters.
+    pub adapters: Vec<PathBuf>,
+    /// Prompt template, in the Go template syntax.
+    pub template: Option<String>,
+    pub system: Option<String>,
+    /// Default options of requests.
+    pub params: Options,
+    /// Conversation the chat history starts with.
+    pub messages: Vec<ChatMessage>,
+    pub license: Vec<String>,
+}
+
+impl LocalModel {
+    /// Modelfile equivalent to the layers of the model, `FROM` is the path of the weights.
+    pub fn modelfile(&self) -> Modelfile {
+        let path = |path: &PathBuf| path.display().to_string();
+        Modelfile {
+            from: path(&self.model),
+            adapters: self.adapters.iter().map(path).collect(),
+            template: self.template.clone(),
+            system: self.system.clone(),
+            parameters: self.params.clone(),
+            messages: self.messages.clone(),
+            license: self.license.clone(),
+        }
+    }
+}
+
+/// Ollama 本地模型仓库
+#[derive(Debug, Clone)]
+pub struct ModelStore {
+    root: PathBuf,
+}
+
+impl ModelStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The store used by the Ollama server, `$OLLAMA_MODELS` or `~/.ollama/models`.
+    pub fn locate() -> Result<Self> {
+        if let Some(root) = std::env::var_os("OLLAMA_MODELS") {
+            return Ok(Self::new(root));
+        }
+        let home = std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .context("cannot find the home directory, set OLLAMA_MODELS")?;
+        Ok(Self::new(Path::new(&home).join(".ollama").join("models")))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn manifest_path(&self, name: &ModelName) -> PathBuf {
+        self.root
+            .join("manifests")
+            .join(&name.host)
+            .join(&name.namespace)
+            .join(&name.model)
+            .join(&name.tag)
+    }
+
+    /// Path of the blob with the given `sha256:<hex>` digest.
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        match digest.split_once(':') {
+            Some(("sha256", hex))
+                if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                Ok(self.root.join("blobs").join(format!("sha256-{hex}")))
+            }
+            _ => bail!("invalid digest '{digest}'"),
+        }
+    }
+
+    pub fn manifest(&self, name: &ModelName) -> Result<Manifest> {
+        let path = self.manifest_path(name);
+        let manifest = fs::read_to_string(&path)
+            .with_context(|| format!("model '{}' not found in {}", name.short(), path.display()))?;
+        serde_json::from_str(&manifest)
+            .with_context(|| format!("invalid manifest {}", path.display()))
+    }
+
+    /// Resolve `name`, e.g. `llama3:8b`, to its weights and read its template, system prompt,
+    /// parameters, messages and license layers.
+    pub fn load(&self, name: &str) -> Result<LocalModel> {
+        let name: ModelName = name.parse()?;
+        let manifest = self.manifest(&name)?;
+
+        let mut model = None;
+        let mut adapters = Vec::new();
+        let mut template = None;
+        let mut system = None;
+        let mut params = Options::default();
+        let mut messages = Vec::new();
+        let mut license = Vec::new();
+        for layer in &manifest.layers {
+            let path = self.layer_path(layer)?;
+            match layer.media_type.as_str() {
+                media_type::MODEL => model = Some(path),
+                media_type::ADAPTER => adapters.push(path),
+                media_type::TEMPLATE => template = Some(fs::read_to_string(path)?),
+                media_type::SYSTEM => system = Some(fs::read_to_string(path)?),
+                media_type::PARAMS => {
+                    params = serde_json::from_slice(&fs::read(path)?)
+                        .with_context(|| format!("invalid parameters layer {}", layer.digest))?;
+                }
+                media_type::MESSAGES => {
+                    messages = serde_json::from_slice(&fs::read(path)?)
+                        .with_context(|| format!("invalid messages layer {}", layer.digest))?;
+                }
+                media_type::LICENSE => license.push(fs::read_to_string(path)?),
+                _ => {}
+            }
+        }
+        let Some(model) = model else {
+            bail!("model '{}' has no weights layer", name.short());
+        };
+        Ok(LocalModel {
+            name,
+            manifest,
+            model,
+            adapters,
+            template,
+            system,
+            params,
+            messages,
+            license,
+        })
+    }
+
+    /// Path of the blob of `layer`, checking that it was fully downloaded.
+    fn layer_path(&self, layer: &Layer) -> Result<PathBuf> {
+        let path = self.blob_path(&layer.digest)?;
+        let size = fs::metadata(&path)
+            .with_context(|| format!("missing blob {}", path.display()))?
+            .len();
+        if size != layer.size {
+            bail!(
+                "blob {} has {size} bytes, the manifest expects {}",
+                path.display(),
+                layer.size
+            );
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(n: u8) -> String {
+        format!("sha256:{}", format!("{n:02x}").repeat(32))
+    }
+
+    /// Store in a fresh temporary directory holding `llama3:8b` with the given layers.
+    fn fake_store(test: &str, layers: &[(&str, &[u8])]) -> ModelStore {
+        let root = std::env::temp_dir().join(format!("ollama-store-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let store = ModelStore::new(&root);
+        fs::create_dir_all(root.join("blobs")).unwrap();
+        let layers: Vec<_> = layers
+            .iter()
+            .enumerate()
+            .map(|(i, (media_type, data))| {
+                let digest = digest(i as u8 + 1);
+                fs::write(store.blob_path(&digest).unwrap(), data).unwrap();
+                Layer {
+                    media_type: media_type.to_string(),
+                    digest,
+                    size: data.len() as u64,
+                }
+            })
+            .collect();
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: "application/vnd.docker.distribution.manifest.v2+json".to_string(),
+            config: Layer {
+                media_type: "application/vnd.docker.container.image.v1+json".to_string(),
+                digest: digest(0),
+                size: 0,
+            },
+            layers,
+        };
+        let path = store.manifest_path(&"llama3:8b".parse().unwrap());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, serde_json::to_string(&manifest).unwrap()).unwrap();
+        store
+    }
+
+    #[test]
+    fn parses_model_names() {
+        let name: ModelName = "llama3".parse().unwrap();
+        assert_eq!(name.to_string(), "registry.ollama.ai/library/llama3:latest");
+        assert_eq!(name.short(), "llama3:latest");
+
+        let name: ModelName = "user/model:q4".parse().unwrap();
+        assert_eq!(name.short(), "user/model:q4");
+
+        let name: ModelName = "localhost:5000/user/model".parse().unwrap();
+        assert_eq!(name.host, "localhost:5000");
+        assert_eq!(name.tag, "latest");
+
+        for invalid in ["", "a/b/c/d", "../model", "model:", "a b"] {
+            assert!(invalid.parse::<ModelName>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn loads_the_layers_of_a_model() {
+        let store = fake_store(
+            "layers",
+            &[
+                (media_type::MODEL, b"GGUF"),
+                (media_type::TEMPLATE, b"{{ .Prompt }}"),
+                (media_type::SYSTEM, b"Be brief."),
+                (
+                    media_type::PARAMS,
+                    br#"{"stop":["<|eot_id|>"],"temperature":0.6,"num_keep":24}"#,
+                ),
+                (media_type::MESSAGES, br#"[{"role":"user","content":"hi"}]"#),
+                (media_type::LICENSE, b"MIT"),
+            ],
+        );
+        let model = store.load("llama3:8b").unwrap();
+        assert_eq!(model.name.short(), "llama3:8b");
+        assert_eq!(model.model, store.blob_path(&digest(1)).unwrap());
+        assert_eq!(fs::read(&model.model).unwrap(), b"GGUF");
+        assert_eq!(model.template.as_deref(), Some("{{ .Prompt }}"));
+        assert_eq!(model.system.as_deref(), Some("Be brief."));
+        assert_eq!(model.params.stop, Some(vec!["<|eot_id|>".to_string()]));
+        assert_eq!(model.params.temperature, Some(0.6));
+        assert_eq!(model.messages.len(), 1);
+        assert_eq!(model.license, ["MIT"]);
+        assert!(model.adapters.is_empty());
+
+        let modelfile = model.modelfile();
+        assert_eq!(modelfile.from, model.model.display().to_string());
+        assert_eq!(modelfile.system, model.system);
+    }
+
+    #[test]
+    fn rejects_missing_models_and_partial_blobs() {
+        let store = fake_store("partial", &[(media_type::MODEL, b"GGUF")]);
+        let error = store.load("llama3:70b").unwrap_err().to_string();
+        assert!(error.contains("'llama3:70b' not found"), "{error}");
+
+        fs::write(store.blob_path(&digest(1)).unwrap(), b"GG").unwrap();
+        let error = store.load("llama3:8b").unwrap_err().to_string();
+        assert!(error.contains("expects 4"), "{error}");
+
+        let store = fake_store("no-weights", &[(media_type::SYSTEM, b"Be brief.")]);
+        assert!(store.load("llama3:8b").is_err());
+        assert!(store.blob_path("md5:abc").is_err());
+    }
+}
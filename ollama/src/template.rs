@@ -0,0 +1,1158 @@
+//! Prompt templates of Ollama models, written in a subset of the Go `text/template` language,
+//! see <https://pkg.go.dev/text/template>.
+//!
+//! Supported are text with `{{- ... -}}` whitespace trimming and comments, fields (`.System`,
+//! `$.Messages`), variables (`$last := ...`, `range $i, $m := ...`), `if`/`else if`/`else`,
+//! `range`, `with`, pipes, parenthesized calls and the functions `eq`, `ne`, `lt`, `le`, `gt`,
+//! `ge`, `and`, `or`, `not`, `len`, `index`, `slice`, `print`, `json` and `currentDate`. The data
+//! is a JSON value, missing fields render as empty and are false in conditions.
+
+use {
+    crate::ChatMessage,
+    anyhow::{Result, anyhow, bail},
+    serde_json::{Value, json},
+    std::{
+        cmp::Ordering,
+        collections::HashSet,
+        fmt::Write,
+        iter::Peekable,
+        str::{CharIndices, FromStr},
+    },
+};
+
+/// 提示模板
+#[derive(Debug, Clone)]
+pub struct Template {
+    source: String,
+    nodes: Vec<Node>,
+    // Names of all the fields the template reads.
+    fields: HashSet<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    /// Prints the value of the pipeline, unless it declares or assigns variables.
+    Action(Pipeline),
+    If {
+        branches: Vec<(Pipeline, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    Range {
+        pipeline: Pipeline,
+        body: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    With {
+        pipeline: Pipeline,
+        body: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Pipeline {
+    /// Variables declared (`:=`) or assigned (`=`) with the value of the pipeline.
+    vars: Vec<String>,
+    declare: bool,
+    commands: Vec<Vec<Operand>>,
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    /// Field path from the dot, empty for the dot itself.
+    Field(Vec<String>),
+    /// Variable and field path from it, `$` is the data the template runs on.
+    Var(String, Vec<String>),
+    Literal(Value),
+    Function(String),
+    Pipeline(Box<Pipeline>),
+}
+
+/// Control actions, the other actions are pipelines.
+enum Action {
+    Pipeline(Pipeline),
+    If(Pipeline),
+    ElseIf(Pipeline),
+    Else,
+    End,
+    Range(Pipeline),
+    With(Pipeline),
+}
+
+enum Item {
+    Text(String),
+    Action(Action),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Field(Vec<String>),
+    Var(String, Vec<String>),
+    Ident(String),
+    Literal(Value),
+    LeftParen,
+    RightParen,
+    Pipe,
+    Comma,
+    Declare,
+    Assign,
+}
+
+fn field_path(path: &str) -> Vec<String> {
+    path.split('.')
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Consumes the characters matching `pred` and returns the offset of the first other one.
+fn take_while(chars: &mut Peekable<CharIndices>, len: usize, pred: impl Fn(char) -> bool) -> usize {
+    while let Some(&(i, c)) = chars.peek() {
+        if !pred(c) {
+            return i;
+        }
+        chars.next();
+    }
+    len
+}
+
+fn tokenize(action: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = action.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '|' | ',' | '=' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LeftParen,
+                    ')' => Token::RightParen,
+                    '|' => Token::Pipe,
+                    ',' => Token::Comma,
+                    _ => Token::Assign,
+                });
+            }
+            ':' => {
+                chars.next();
+                if chars.next().map(|(_, c)| c) != Some('=') {
+                    bail!("expected := in '{action}'");
+                }
+                tokens.push(Token::Declare);
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next().map(|(_, c)| c) {
+                        Some('"') => break,
+                        Some('\\') => text.push(match chars.next().map(|(_, c)| c) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some(c @ ('\\' | '"' | '\'')) => c,
+                            _ => bail!("invalid escape in '{action}'"),
+                        }),
+                        Some(c) => text.push(c),
+                        None => bail!("unterminated string in '{action}'"),
+                    }
+                }
+                tokens.push(Token::Literal(Value::String(text)));
+            }
+            '`' => {
+                chars.next();
+                let end = take_while(&mut chars, action.len(), |c| c != '`');
+                if chars.next().is_none() {
+                    bail!("unterminated raw string in '{action}'");
+                }
+                tokens.push(Token::Literal(Value::String(
+                    action[start + 1..end].to_string(),
+                )));
+            }
+            '.' => {
+                chars.next();
+                let end = take_while(&mut chars, action.len(), |c| is_ident_char(c) || c == '.');
+                tokens.push(Token::Field(field_path(&action[start..end])));
+            }
+            '$' => {
+                chars.next();
+                let name_end = take_while(&mut chars, action.len(), is_ident_char);
+                let end = take_while(&mut chars, action.len(), |c| is_ident_char(c) || c == '.');
+                let name = action[start + 1..name_end].to_string();
+                tokens.push(Token::Var(name, field_path(&action[name_end..end])));
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '+' => {
+                chars.next();
+                let end = take_while(&mut chars, action.len(), |c| {
+                    c.is_ascii_alphanumeric() || c == '.'
+                });
+                let number = &action[start..end];
+                let value = match (number.parse::<i64>(), number.parse::<f64>()) {
+                    (Ok(n), _) => json!(n),
+                    (_, Ok(n)) => json!(n),
+                    _ => bail!("invalid number '{number}'"),
+                };
+                tokens.push(Token::Literal(value));
+            }
+            c if is_ident_char(c) => {
+                let end = take_while(&mut chars, action.len(), is_ident_char);
+                tokens.push(match &action[start..end] {
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "nil" => Token::Literal(Value::Null),
+                    ident => Token::Ident(ident.to_string()),
+                });
+            }
+            c => bail!("unexpected '{c}' in '{action}'"),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses the tokens of one action.
+struct ActionParser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    fields: &'a mut HashSet<String>,
+}
+
+impl ActionParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn record(&mut self, path: &[String]) {
+        self.fields.extend(path.iter().cloned());
+    }
+
+    fn pipeline(&mut self) -> Result<Pipeline> {
+        // Declarations are `$a :=`, `$a, $b :=` or `$a =`.
+        let mut vars = Vec::new();
+        let mut declare = false;
+        let mut pos = self.pos;
+        while let Some(Token::Var(name, path)) = self.tokens.get(pos)
+            && path.is_empty()
+        {
+            match self.tokens.get(pos + 1) {
+                Some(Token::Comma) => {
+                    vars.push(name.clone());
+                    pos += 2;
+                }
+                Some(token @ (Token::Declare | Token::Assign)) => {
+                    declare = *token == Token::Declare;
+                    vars.push(name.clone());
+                    self.pos = pos + 2;
+                    break;
+                }
+                _ => break,
+            }
+        }
+        if pos < self.pos {
+            if vars.len() > 2 {
+                bail!("too many variables in a declaration");
+            }
+        } else {
+            vars.clear();
+        }
+
+        let mut commands = vec![self.command()?];
+        while self.peek() == Some(&Token::Pipe) {
+            self.next();
+            commands.push(self.command()?);
+        }
+        Ok(Pipeline {
+            vars,
+            declare,
+            commands,
+        })
+    }
+
+    fn command(&mut self) -> Result<Vec<Operand>> {
+        let mut operands = Vec::new();
+        while let Some(token) = self.peek() {
+            if matches!(token, Token::Pipe | Token::RightParen) {
+                break;
+            }
+            let operand = match self.next().unwrap() {
+                Token::Field(path) => {
+                    self.record(&path);
+                    Operand::Field(path)
+                }
+                Token::Var(name, path) => {
+                    self.record(&path);
+                    Operand::Var(name, path)
+                }
+                Token::Literal(value) => Operand::Literal(value),
+                Token::Ident(name) => Operand::Function(name),
+                Token::LeftParen => {
+                    let pipeline = self.pipeline()?;
+                    if self.next() != Some(Token::RightParen) {
+                        bail!("unclosed parenthesis");
+                    }
+                    Operand::Pipeline(Box::new(pipeline))
+                }
+                token => bail!("unexpected {token:?}"),
+            };
+            operands.push(operand);
+        }
+        if operands.is_empty() {
+            bail!("missing value");
+        }
+        if operands.len() > 1 && !matches!(operands[0], Operand::Function(_)) {
+            bail!("only functions take arguments");
+        }
+        Ok(operands)
+    }
+
+    fn action(mut self) -> Result<Action> {
+        let keyword = match self.peek() {
+            Some(Token::Ident(ident)) => ident.clone(),
+            _ => String::new(),
+        };
+        let action = match keyword.as_str() {
+            "if" | "range" | "with" => {
+                self.next();
+                let pipeline = self.pipeline()?;
+                match keyword.as_str() {
+                    "if" => Action::If(pipeline),
+                    "range" => Action::Range(pipeline),
+                    _ => Action::With(pipeline),
+                }
+            }
+            "else" => {
+                self.next();
+                match self.peek() {
+                    Some(Token::Ident(ident)) if ident == "if" => {
+                        self.next();
+                        Action::ElseIf(self.pipeline()?)
+                    }
+                    _ => Action::Else,
+                }
+            }
+            "end" => {
+                self.next();
+                Action::End
+            }
+            "template" | "define" | "block" | "break" | "continue" => {
+                bail!("{keyword} actions are not supported")
+            }
+            _ => Action::Pipeline(self.pipeline()?),
+        };
+        if let Some(token) = self.peek() {
+            bail!("unexpected {token:?}");
+        }
+        Ok(action)
+    }
+}
+
+/// Splits the template into text and actions, applying the whitespace trimming markers.
+fn lex(source: &str, fields: &mut HashSet<String>) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut rest = source;
+    let mut trim_next = false;
+    while !rest.is_empty() {
+        let (text, action) = match rest.find("{{") {
+            Some(start) => (&rest[..start], Some(&rest[start + 2..])),
+            None => (rest, None),
+        };
+        let mut text = if trim_next { text.trim_start() } else { text };
+        let Some(action) = action else {
+            items.push(Item::Text(text.to_string()));
+            break;
+        };
+        let (action, trim_before) = match action.strip_prefix("- ") {
+            Some(action) => (action, true),
+            None => (action, false),
+        };
+        if trim_before {
+            text = text.trim_end();
+        }
+        if !text.is_empty() {
+            items.push(Item::Text(text.to_string()));
+        }
+        let end = action_end(action)?;
+        let (body, trim_after) = match action[..end].strip_suffix(" -") {
+            Some(body) => (body, true),
+            None => (&action[..end], false),
+        };
+        rest = &action[end + 2..];
+        trim_next = trim_after;
+        let body = body.trim();
+        if body.starts_with("/*") {
+            if !body.ends_with("*/") {
+                bail!("unterminated comment");
+            }
+            continue;
+        }
+        let parser = ActionParser {
+            tokens: tokenize(body)?,
+            pos: 0,
+            fields,
+        };
+        items.push(Item::Action(parser.action()?));
+    }
+    Ok(items)
+}
+
+/// Offset of the `}}` closing an action, skipping the ones in strings and comments.
+fn action_end(action: &str) -> Result<usize> {
+    if action.trim_start().starts_with("/*") {
+        return match action.find("*/") {
+            Some(i) => match action[i..].find("}}") {
+                Some(j) => Ok(i + j),
+                None => bail!("unclosed action"),
+            },
+            None => bail!("unterminated comment"),
+        };
+    }
+    let mut quote = None;
+    let mut chars = action.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '`') => quote = Some(c),
+            (None, '}') if action[i..].starts_with("}}") => return Ok(i),
+            _ => {}
+        }
+    }
+    bail!("unclosed action")
+}
+
+/// Builds the nodes up to the next `else`, `end` or the end of the template, returned as well.
+fn parse_list(items: &mut std::vec::IntoIter<Item>) -> Result<(Vec<Node>, Option<Action>)> {
+    let mut nodes = Vec::new();
+    while let Some(item) = items.next() {
+        let action = match item {
+            Item::Text(text) => {
+                nodes.push(Node::Text(text));
+                continue;
+            }
+            Item::Action(action) => action,
+        };
+        match action {
+            Action::Pipeline(pipeline) => nodes.push(Node::Action(pipeline)),
+            Action::If(pipeline) => {
+                let mut branches = Vec::new();
+                let mut condition = pipeline;
+                let otherwise = loop {
+                    let (body, end) = parse_list(items)?;
+                    branches.push((condition, body));
+                    match end {
+                        Some(Action::ElseIf(pipeline)) => condition = pipeline,
+                        Some(Action::Else) => break parse_end(items)?,
+                        Some(Action::End) => break Vec::new(),
+                        _ => bail!("missing {{{{ end }}}} of if"),
+                    }
+                };
+                nodes.push(Node::If {
+                    branches,
+                    otherwise,
+                });
+            }
+            Action::Range(pipeline) => {
+                let (body, otherwise) = parse_body(items, "range")?;
+                nodes.push(Node::Range {
+                    pipeline,
+                    body,
+                    otherwise,
+                });
+            }
+            Action::With(pipeline) => {
+                let (body, otherwise) = parse_body(items, "with")?;
+                nodes.push(Node::With {
+                    pipeline,
+                    body,
+                    otherwise,
+                });
+            }
+            end => return Ok((nodes, Some(end))),
+        }
+    }
+    Ok((nodes, None))
+}
+
+/// Nodes up to the `end` closing a block.
+fn parse_end(items: &mut std::vec::IntoIter<Item>) -> Result<Vec<Node>> {
+    match parse_list(items)? {
+        (nodes, Some(Action::End)) => Ok(nodes),
+        _ => bail!("missing {{{{ end }}}} after else"),
+    }
+}
+
+/// Body and `else` branch of a `range` or `with` block.
+fn parse_body(items: &mut std::vec::IntoIter<Item>, block: &str) -> Result<(Vec<Node>, Vec<Node>)> {
+    match parse_list(items)? {
+        (body, Some(Action::End)) => Ok((body, Vec::new())),
+        (body, Some(Action::Else)) => Ok((body, parse_end(items)?)),
+        _ => bail!("missing {{{{ end }}}} of {block}"),
+    }
+}
+
+fn truth(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+    }
+}
+
+fn get_path(mut value: &Value, path: &[String]) -> Value {
+    for field in path {
+        match value.get(field) {
+            Some(v) => value = v,
+            None => return Value::Null,
+        }
+    }
+    value.clone()
+}
+
+fn print_value(value: &Value, out: &mut String) {
+    match value {
+        Value::Null => {}
+        Value::String(s) => out.push_str(s),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                print_value(item, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            out.push_str("map[");
+            for (i, (key, item)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                let _ = write!(out, "{key}:");
+                print_value(item, out);
+            }
+            out.push(']');
+        }
+        Value::Bool(_) | Value::Number(_) => {
+            let _ = write!(out, "{value}");
+        }
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Result<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => {
+            let (a, b) = (a.as_f64().unwrap_or(0.), b.as_f64().unwrap_or(0.));
+            a.partial_cmp(&b)
+                .ok_or_else(|| anyhow!("cannot compare NaN"))
+        }
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        _ => bail!("incompatible types for comparison: {a} and {b}"),
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64() == y.as_f64(),
+        _ => a == b,
+    }
+}
+
+fn as_index(value: &Value) -> Result<usize> {
+    value
+        .as_u64()
+        .map(|i| i as usize)
+        .ok_or_else(|| anyhow!("invalid index {value}"))
+}
+
+fn call(name: &str, args: Vec<Value>) -> Result<Value> {
+    let arity = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(anyhow!("{name} takes {n} arguments, got {}", args.len()))
+        }
+    };
+    Ok(match name {
+        "eq" => {
+            if args.len() < 2 {
+                bail!("eq takes at least 2 arguments");
+            }
+            Value::Bool(args[1..].iter().any(|arg| equal(&args[0], arg)))
+        }
+        "ne" => {
+            arity(2)?;
+            Value::Bool(!equal(&args[0], &args[1]))
+        }
+        "lt" | "le" | "gt" | "ge" => {
+            arity(2)?;
+            let ordering = compare(&args[0], &args[1])?;
+            Value::Bool(match name {
+                "lt" => ordering.is_lt(),
+                "le" => ordering.is_le(),
+                "gt" => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })
+        }
+        "and" => args
+            .iter()
+            .find(|arg| !truth(arg))
+            .or(args.last())
+            .cloned()
+            .ok_or_else(|| anyhow!("and takes at least 1 argument"))?,
+        "or" => args
+            .iter()
+            .find(|arg| truth(arg))
+            .or(args.last())
+            .cloned()
+            .ok_or_else(|| anyhow!("or takes at least 1 argument"))?,
+        "not" => {
+            arity(1)?;
+            Value::Bool(!truth(&args[0]))
+        }
+        "len" => {
+            arity(1)?;
+            json!(match &args[0] {
+                Value::String(s) => s.len(),
+                Value::Array(a) => a.len(),
+                Value::Object(o) => o.len(),
+                value => bail!("len of {value}"),
+            })
+        }
+        "index" => {
+            let Some((value, keys)) = args.split_first() else {
+                bail!("index takes at least 1 argument");
+            };
+            let mut value = value.clone();
+            for key in keys {
+                value = match (&value, key) {
+                    (Value::Array(a), key) => match a.get(as_index(key)?) {
+                        Some(v) => v.clone(),
+                        None => bail!("index {key} out of range"),
+                    },
+                    (Value::Object(o), Value::String(key)) => {
+                        o.get(key).cloned().unwrap_or(Value::Null)
+                    }
+                    (value, key) => bail!("cannot index {value} with {key}"),
+                };
+            }
+            value
+        }
+        "slice" => {
+            let Some((value, bounds)) = args.split_first() else {
+                bail!("slice takes at least 1 argument");
+            };
+            let len = match value {
+                Value::String(s) => s.len(),
+                Value::Array(a) => a.len(),
+                value => bail!("cannot slice {value}"),
+            };
+            let start = bounds.first().map(as_index).transpose()?.unwrap_or(0);
+            let end = bounds.get(1).map(as_index).transpose()?.unwrap_or(len);
+            if bounds.len() > 2 || start > end || end > len {
+                bail!("invalid slice bounds of {value}");
+            }
+            match value {
+                Value::String(s) => match s.get(start..end) {
+                    Some(s) => Value::String(s.to_string()),
+                    None => bail!("slice bounds not on a character boundary"),
+                },
+                Value::Array(a) => Value::Array(a[start..end].to_vec()),
+                _ => unreachable!("checked above"),
+            }
+        }
+        "print" => {
+            let mut out = String::new();
+            for (i, arg) in args.iter().enumerate() {
+                // Like fmt.Sprint, operands are separated when neither is a string.
+                if i > 0 && !arg.is_string() && !args[i - 1].is_string() {
+                    out.push(' ');
+                }
+                print_value(arg, &mut out);
+            }
+            Value::String(out)
+        }
+        "json" => {
+            arity(1)?;
+            Value::String(args[0].to_string())
+        }
+        "currentDate" => {
+            arity(0)?;
+            Value::String(chrono::Utc::now().format("%Y-%m-%d").to_string())
+        }
+        _ => bail!("function {name} not defined"),
+    })
+}
+
+/// State of a template execution.
+struct Exec<'a> {
+    root: &'a Value,
+    vars: Vec<(String, Value)>,
+    out: String,
+    /// Stop right after printing `.Response`, so that the model writes the response.
+    halt_at_response: bool,
+    halted: bool,
+}
+
+impl Exec<'_> {
+    fn var(&self, name: &str) -> Result<&Value> {
+        if name.is_empty() {
+            return Ok(self.root);
+        }
+        match self.vars.iter().rev().find(|(var, _)| var == name) {
+            Some((_, value)) => Ok(value),
+            None => bail!("undefined variable ${name}"),
+        }
+    }
+
+    fn set_vars(&mut self, pipeline: &Pipeline, values: &[Value]) -> Result<()> {
+        for (name, value) in pipeline.vars.iter().zip(values) {
+            if pipeline.declare {
+                self.vars.push((name.clone(), value.clone()));
+            } else {
+                match self.vars.iter_mut().rev().find(|(var, _)| var == name) {
+                    Some((_, var)) => *var = value.clone(),
+                    None => bail!("undefined variable ${name}"),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn operand(&mut self, operand: &Operand, dot: &Value) -> Result<Value> {
+        Ok(match operand {
+            Operand::Field(path) => get_path(dot, path),
+            Operand::Var(name, path) => get_path(self.var(name)?, path),
+            Operand::Literal(value) => value.clone(),
+            Operand::Function(name) => call(name, Vec::new())?,
+            Operand::Pipeline(pipeline) => self.pipeline(pipeline, dot)?,
+        })
+    }
+
+    /// Value of the pipeline, each command gets the value of the previous one as last argument.
+    fn pipeline(&mut self, pipeline: &Pipeline, dot: &Value) -> Result<Value> {
+        let mut piped = None;
+        for command in pipeline.commands.iter() {
+            let value = match command.split_first() {
+                Some((Operand::Function(name), args)) => {
+                    let mut values = args
+                        .iter()
+                        .map(|arg| self.operand(arg, dot))
+                        .collect::<Result<Vec<_>>>()?;
+                    values.extend(piped.take());
+                    call(name, values)?
+                }
+                Some((operand, [])) if piped.is_none() => self.operand(operand, dot)?,
+                _ => bail!("only functions take arguments"),
+            };
+            piped = Some(value);
+        }
+        let value = piped.unwrap_or(Value::Null);
+        if pipeline.vars.len() == 1 {
+            self.set_vars(pipeline, std::slice::from_ref(&value))?;
+        }
+        Ok(value)
+    }
+
+    fn run(&mut self, nodes: &[Node], dot: &Value) -> Result<()> {
+        for node in nodes {
+            if self.halted {
+                break;
+            }
+            match node {
+                Node::Text(text) => self.out.push_str(text),
+                Node::Action(pipeline) => {
+                    let value = self.pipeline(pipeline, dot)?;
+                    if pipeline.vars.is_empty() {
+                        print_value(&value, &mut self.out);
+                        if self.halt_at_response && is_response(pipeline) {
+                            self.halted = true;
+                        }
+                    }
+                }
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let scope = self.vars.len();
+                    let mut body = otherwise;
+                    for (condition, nodes) in branches {
+                        if truth(&self.pipeline(condition, dot)?) {
+                            body = nodes;
+                            break;
+                        }
+                    }
+                    self.run(body, dot)?;
+                    self.vars.truncate(scope);
+                }
+                Node::With {
+                    pipeline,
+                    body,
+                    otherwise,
+                } => {
+                    let scope = self.vars.len();
+                    let value = self.pipeline(pipeline, dot)?;
+                    if truth(&value) {
+                        self.run(body, &value)?;
+                    } else {
+                        self.run(otherwise, dot)?;
+                    }
+                    self.vars.truncate(scope);
+                }
+                Node::Range {
+                    pipeline,
+                    body,
+                    otherwise,
+                } => {
+                    let scope = self.vars.len();
+                    let ranged = Pipeline {
+                        vars: Vec::new(),
+                        declare: true,
+                        commands: pipeline.commands.clone(),
+                    };
+                    let items: Vec<(Value, Value)> = match self.pipeline(&ranged, dot)? {
+                        Value::Array(items) => items
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, item)| (json!(i), item))
+                            .collect(),
+                        Value::Object(map) => map
+                            .into_iter()
+                            .map(|(key, item)| (Value::String(key), item))
+                            .collect(),
+                        Value::Null => Vec::new(),
+                        value => bail!("range can't iterate over {value}"),
+                    };
+                    if items.is_empty() {
+                        self.run(otherwise, dot)?;
+                    }
+                    for (key, item) in items {
+                        let iteration = self.vars.len();
+                        match pipeline.vars.len() {
+                            2 => self.set_vars(pipeline, &[key, item.clone()])?,
+                            1 => self.set_vars(pipeline, std::slice::from_ref(&item))?,
+                            _ => {}
+                        }
+                        self.run(body, &item)?;
+                        if self.halted {
+                            break;
+                        }
+                        self.vars.truncate(iteration);
+                    }
+                    self.vars.truncate(scope);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the pipeline only reads the `Response` field.
+fn is_response(pipeline: &Pipeline) -> bool {
+    match pipeline.commands.as_slice() {
+        [command] => matches!(
+            command.as_slice(),
+            [Operand::Field(path)] | [Operand::Var(_, path)] if path == &["Response"]
+        ),
+        _ => false,
+    }
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut fields = HashSet::new();
+        let items = lex(source, &mut fields)?;
+        let nodes = match parse_list(&mut items.into_iter())? {
+            (nodes, None) => nodes,
+            (_, Some(Action::End)) => bail!("unexpected {{{{ end }}}}"),
+            (_, Some(_)) => bail!("unexpected {{{{ else }}}}"),
+        };
+        Ok(Self {
+            source: source.to_string(),
+            nodes,
+            fields,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Render the template with `data` as the dot.
+    pub fn render(&self, data: &Value) -> Result<String> {
+        self.execute(data, false)
+    }
+
+    fn execute(&self, data: &Value, halt_at_response: bool) -> Result<String> {
+        let mut exec = Exec {
+            root: data,
+            vars: Vec::new(),
+            out: String::new(),
+            halt_at_response,
+            halted: false,
+        };
+        exec.run(&self.nodes, data)?;
+        Ok(exec.out)
+    }
+
+    /// Prompt of a conversation, ending where the reply of the assistant starts.
+    ///
+    /// As in Ollama, templates reading `.Messages` get the whole conversation, with
+    /// consecutive messages of the same role merged and the system messages also joined in
+    /// `.System`. Older templates only know `.System`, `.Prompt` and `.Response`: they are
+    /// rendered once per exchange, and the last rendering stops after `.Response`.
+    pub fn chat_prompt(&self, messages: &[ChatMessage]) -> Result<String> {
+        if self.fields.contains("Messages") {
+            let mut merged: Vec<ChatMessage> = Vec::new();
+            for message in messages {
+                match merged.last_mut() {
+                    Some(last) if last.role == message.role && message.role != "tool" => {
+                        last.content.push_str("\n\n");
+                        last.content.push_str(&message.content);
+                    }
+                    _ => merged.push(message.clone()),
+                }
+            }
+            let system: Vec<_> = merged
+                .iter()
+                .filter(|m| m.role == "system")
+                .map(|m| m.content.as_str())
+                .collect();
+            let messages: Vec<_> = merged
+                .iter()
+                .map(|m| json!({"Role": m.role, "Content": m.content}))
+                .collect();
+            let data = json!({
+                "System": system.join("\n\n"),
+                "Messages": messages,
+                "Prompt": "",
+                "Response": "",
+            });
+            return self.render(&data);
+        }
+
+        let mut prompt = String::new();
+        let (mut system, mut user, mut response) = (String::new(), String::new(), String::new());
+        let turn = |system: &str, user: &str, response: &str| json!({"System": system, "Prompt": user, "Response": response});
+        for message in messages {
+            let done = match message.role.as_str() {
+                "system" => !user.is_empty() || !response.is_empty(),
+                "user" => !response.is_empty(),
+                _ => false,
+            };
+            if done {
+                prompt.push_str(&self.render(&turn(&system, &user, &response))?);
+                system.clear();
+                user.clear();
+                response.clear();
+            }
+            match message.role.as_str() {
+                "system" => system = message.content.clone(),
+                "user" => user = message.content.clone(),
+                "assistant" => response = message.content.clone(),
+                _ => {}
+            }
+        }
+        prompt.push_str(&self.execute(&turn(&system, &user, &response), true)?);
+        Ok(prompt)
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        Self::parse(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn render(source: &str, data: Value) -> String {
+        Template::parse(source).unwrap().render(&data).unwrap()
+    }
+
+    const LLAMA3: &str = "{{ if .System }}<|start_header_id|>system<|end_header_id|>
+
+{{ .System }}<|eot_id|>{{ end }}{{ if .Prompt }}<|start_header_id|>user<|end_header_id|>
+
+{{ .Prompt }}<|eot_id|>{{ end }}<|start_header_id|>assistant<|end_header_id|>
+
+{{ .Response }}<|eot_id|>";
+
+    const LLAMA31: &str = r#"{{- if .System }}<|start_header_id|>system<|end_header_id|>
+
+{{ .System }}<|eot_id|>
+{{- end }}
+{{- range $i, $_ := .Messages }}
+{{- $last := eq (len (slice $.Messages $i)) 1 }}
+{{- if eq .Role "user" }}<|start_header_id|>user<|end_header_id|>
+
+{{ .Content }}<|eot_id|>{{ if $last }}<|start_header_id|>assistant<|end_header_id|>
+
+{{ end }}
+{{- else if eq .Role "assistant" }}<|start_header_id|>assistant<|end_header_id|>
+
+{{ .Content }}{{ if not $last }}<|eot_id|>{{ end }}
+{{- end }}
+{{- end }}"#;
+
+    #[test]
+    fn renders_fields_conditions_and_trimming() {
+        let data = json!({"Name": "llama", "Tags": ["a", "b"], "Empty": ""});
+        assert_eq!(render("Hi {{ .Name }}!", data.clone()), "Hi llama!");
+        assert_eq!(render("a  {{- .Name -}}  b", data.clone()), "allamab");
+        assert_eq!(
+            render(
+                "{{ if .Empty }}x{{ else if .Name }}y{{ else }}z{{ end }}",
+                data.clone()
+            ),
+            "y"
+        );
+        assert_eq!(
+            render("{{ if .Missing }}x{{ end }}{{/* note */}}", data.clone()),
+            ""
+        );
+        assert_eq!(
+            render(
+                "{{ range $i, $t := .Tags }}{{ $i }}={{ . }};{{ end }}",
+                data.clone()
+            ),
+            "0=a;1=b;"
+        );
+        assert_eq!(
+            render(
+                "{{ range .Empty }}x{{ else }}none{{ end }}",
+                json!({"Empty": []})
+            ),
+            "none"
+        );
+        assert_eq!(
+            render("{{ with .Name }}{{ . }}{{ end }}", data.clone()),
+            "llama"
+        );
+    }
+
+    #[test]
+    fn calls_functions() {
+        let data = json!({"N": 2, "S": "abc", "L": [1, 2, 3], "M": {"k": "v"}});
+        assert_eq!(
+            render(
+                r#"{{ eq .N 2 }} {{ ne .S "abc" }} {{ lt 1 .N }} {{ ge .S "b" }}"#,
+                data.clone()
+            ),
+            "true false true false"
+        );
+        assert_eq!(
+            render(
+                r#"{{ len .L }} {{ index .L 1 }} {{ index .M "k" }}"#,
+                data.clone()
+            ),
+            "3 2 v"
+        );
+        assert_eq!(
+            render("{{ slice .L 1 }} {{ slice .S 0 2 }}", data.clone()),
+            "[2 3] ab"
+        );
+        assert_eq!(
+            render(
+                "{{ and .N .S }} {{ or .Missing `raw` }} {{ not .N }}",
+                data.clone()
+            ),
+            "abc raw false"
+        );
+        assert_eq!(
+            render("{{ json .M }} {{ .S | len }}", data.clone()),
+            r#"{"k":"v"} 3"#
+        );
+        assert_eq!(render("{{ $x := 1 }}{{ $x = 2 }}{{ $x }}", data), "2");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for source in [
+            "{{ if .X }}",
+            "{{ end }}",
+            "{{ .X",
+            "{{ range .X }}{{ else if .Y }}{{ end }}",
+            r#"{{ template "x" }}"#,
+            r#"{{ "unterminated }}"#,
+            "{{ .X .Y }}",
+        ] {
+            assert!(Template::parse(source).is_err(), "{source}");
+        }
+        let template = Template::parse("{{ $undefined }}").unwrap();
+        assert!(template.render(&json!({})).is_err());
+        let template = Template::parse("{{ nope 1 }}").unwrap();
+        assert!(template.render(&json!({})).is_err());
+    }
+
+    #[test]
+    fn legacy_templates_render_every_exchange() {
+        let template = Template::parse(LLAMA3).unwrap();
+        let prompt = template
+            .chat_prompt(&[
+                message("system", "Be brief."),
+                message("user", "Hi"),
+                message("assistant", "Hello."),
+                message("user", "Bye"),
+            ])
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "<|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\nHello.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nBye<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+
+    #[test]
+    fn message_templates_get_the_whole_conversation() {
+        let template = Template::parse(LLAMA31).unwrap();
+        let prompt = template
+            .chat_prompt(&[
+                message("system", "Be brief."),
+                message("user", "Hi"),
+                message("assistant", "Hello."),
+                message("user", "Bye"),
+                message("user", "now"),
+            ])
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "<|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\nHello.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nBye\n\nnow<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+}
@@ -0,0 +1,560 @@
+use {
+    crate::{BlockCopy, BlockTable},
+    candle_core::{DType, Device, IndexOp, Result, Tensor, bail},
+    candle_nn::ops::softmax_last_dim,
+    kv_cache::KvQuantization,
+};
+
+/// Rows of keys or values, with their scales when they are quantized.
+#[derive(Debug, Clone)]
+struct Rows {
+    data: Tensor,
+    scales: Option<Tensor>,
+}
+
+impl Rows {
+    fn narrow(&self, start: usize, len: usize) -> Result<Self> {
+        Ok(Self {
+            data: self.data.narrow(0, start, len)?,
+            scales: match &self.scales {
+                Some(scales) => Some(scales.narrow(0, start, len)?),
+                None => None,
+            },
+        })
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            data: self.data.copy()?,
+            scales: match &self.scales {
+                Some(scales) => Some(scales.copy()?),
+                None => None,
+            },
+        })
+    }
+
+    fn index_select(&self, indices: &Tensor) -> Result<Self> {
+        Ok(Self {
+            data: self.data.index_select(indices, 0)?,
+            scales: match &self.scales {
+                Some(scales) => Some(scales.index_select(indices, 0)?),
+                None => None,
+            },
+        })
+    }
+
+    /// Overwrite the rows from `start` with `src`.
+    fn set(&self, src: &Self, start: usize) -> Result<()> {
+        self.data.slice_set(&src.data, 0, start)?;
+        if let (Some(scales), Some(src)) = (&self.scales, &src.scales) {
+            scales.slice_set(src, 0, start)?;
+        }
+        Ok(())
+    }
+}
+
+/// 分页 KV 存储
+///
+/// Keys and values of the pool of blocks, each of shape
+/// `(num_blocks * block_size, num_kv_heads, head_dim)` so that the token in slot `s` is row `s`.
+///
+/// A cache made with [`PagedKvCache::quantized`] stores the rows as integers with one scale per
+/// token and head, and only dequantizes the rows it gathers.
+#[derive(Debug, Clone)]
+pub struct PagedKvCache {
+    k: Rows,
+    v: Rows,
+    block_size: usize,
+    head_dim: usize,
+    dtype: DType,
+    quantization: Option<KvQuantization>,
+}
+
+impl PagedKvCache {
+    pub fn new(
+        num_blocks: usize,
+        block_size: usize,
+        num_kv_heads: usize,
+        head_dim: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let shape = (num_blocks * block_size, num_kv_heads, head_dim);
+        let rows = || -> Result<Rows> {
+            Ok(Rows {
+                data: Tensor::zeros(shape, dtype, device)?,
+                scales: None,
+            })
+        };
+        Ok(Self {
+            k: rows()?,
+            v: rows()?,
+            block_size,
+            head_dim,
+            dtype,
+            quantization: None,
+        })
+    }
+
+    /// Like [`PagedKvCache::new`] with the keys and values quantized, they are gathered in
+    /// `dtype`.
+    pub fn quantized(
+        num_blocks: usize,
+        block_size: usize,
+        num_kv_heads: usize,
+        head_dim: usize,
+        quantization: KvQuantization,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let packed = match quantization {
+            KvQuantization::Int8 => head_dim,
+            KvQuantization::Int4 if head_dim.is_multiple_of(2) => head_dim / 2,
+            KvQuantization::Int4 => {
+                bail!("int4 KV entries need an even head dimension, got {head_dim}")
+            }
+        };
+        let num_slots = num_blocks * block_size;
+        let rows = || -> Result<Rows> {
+            Ok(Rows {
+                data: Tensor::zeros((num_slots, num_kv_heads, packed), DType::U8, device)?,
+                scales: Some(Tensor::zeros(
+                    (num_slots, num_kv_heads, 1),
+                    DType::F32,
+                    device,
+                )?),
+            })
+        };
+        Ok(Self {
+            k: rows()?,
+            v: rows()?,
+            block_size,
+            head_dim,
+            dtype,
+            quantization: Some(quantization),
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_kv_heads(&self) -> usize {
+        self.k.data.dims()[1]
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+    }
+
+    pub fn quantization(&self) -> Option<KvQuantization> {
+        self.quantization
+    }
+
+    fn encode(&self, src: &Tensor) -> Result<Rows> {
+        Ok(match self.quantization {
+            Some(quantization) => {
+                let (data, scales) = quantization.quantize(src)?;
+                Rows {
+                    data,
+                    scales: Some(scales),
+                }
+            }
+            None => Rows {
+                data: src.clone(),
+                scales: None,
+            },
+        })
+    }
+
+    fn decode(&self, rows: Rows) -> Result<Tensor> {
+        match (self.quantization, rows.scales) {
+            (Some(quantization), Some(scales)) => {
+                quantization.dequantize(&rows.data, &scales, self.dtype)
+            }
+            _ => Ok(rows.data),
+        }
+    }
+
+    /// Store the keys and values of shape `(slots.len(), num_kv_heads, head_dim)` in `slots`, as
+    /// returned by [`crate::BlockManager::append_slots`] after its copy is made.
+    pub fn write(&mut self, slots: &[usize], k: &Tensor, v: &Tensor) -> Result<()> {
+        if k.dim(0)? != slots.len() || v.dim(0)? != slots.len() {
+            bail!(
+                "{} slots for {} keys and {} values",
+                slots.len(),
+                k.dim(0)?,
+                v.dim(0)?
+            )
+        }
+        let (k, v) = (self.encode(k)?, self.encode(v)?);
+        // Consecutive slots of a block are written at once.
+        let mut start = 0;
+        while start < slots.len() {
+            let mut end = start + 1;
+            while end < slots.len()
+                && slots[end] == slots[end - 1] + 1
+                && !slots[end].is_multiple_of(self.block_size)
+            {
+                end += 1;
+            }
+            let len = end - start;
+            self.k.set(&k.narrow(start, len)?, slots[start])?;
+            self.v.set(&v.narrow(start, len)?, slots[start])?;
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Copy the keys and values of a block to another one.
+    pub fn copy_block(&mut self, copy: BlockCopy) -> Result<()> {
+        let size = self.block_size;
+        // Copied out first, the source and destination share their storage.
+        let k = self.k.narrow(copy.src * size, size)?.copy()?;
+        let v = self.v.narrow(copy.src * size, size)?.copy()?;
+        self.k.set(&k, copy.dst * size)?;
+        self.v.set(&v, copy.dst * size)
+    }
+
+    /// Keys and values of the tokens of a sequence, each of shape
+    /// `(num_tokens, num_kv_heads, head_dim)`.
+    pub fn gather(&self, table: &BlockTable) -> Result<(Tensor, Tensor)> {
+        self.gather_prefix(table, table.num_tokens())
+    }
+
+    /// Keys and values of the first `num_tokens` tokens of a sequence, the ones already written
+    /// when its table holds slots for more.
+    pub fn gather_prefix(&self, table: &BlockTable, num_tokens: usize) -> Result<(Tensor, Tensor)> {
+        if num_tokens > table.num_tokens() {
+            bail!(
+                "{num_tokens} tokens gathered from a sequence of {}",
+                table.num_tokens()
+            )
+        }
+        let slots: Vec<u32> = (0..num_tokens)
+            .map(|position| table.slot(position, self.block_size) as u32)
+            .collect();
+        let slots = Tensor::new(slots, self.k.data.device())?;
+        Ok((
+            self.decode(self.k.index_select(&slots)?)?,
+            self.decode(self.v.index_select(&slots)?)?,
+        ))
+    }
+}
+
+/// Attention of one decode query per sequence over the keys and values of its block table.
+///
+/// `q` has shape `(batch, num_heads, head_dim)` with `block_tables[i]` the context of row `i`,
+/// the contexts may have different lengths. With grouped-query attention `num_heads` is a
+/// multiple of the number of KV heads and query head `h` attends to KV head
+/// `h / (num_heads / num_kv_heads)`. Returns a tensor of the shape of `q`.
+pub fn paged_attention(
+    q: &Tensor,
+    cache: &PagedKvCache,
+    block_tables: &[&BlockTable],
+) -> Result<Tensor> {
+    let (batch, num_heads, head_dim) = q.dims3()?;
+    let num_kv_heads = cache.num_kv_heads();
+    if batch != block_tables.len() {
+        bail!("{batch} queries for {} block tables", block_tables.len())
+    }
+    if head_dim != cache.head_dim() || num_heads % num_kv_heads != 0 {
+        bail!(
+            "{num_heads} query heads of size {head_dim} do not fit {num_kv_heads} KV heads of size {}",
+            cache.head_dim()
+        )
+    }
+    let group = num_heads / num_kv_heads;
+    let scale = 1.0 / (head_dim as f64).sqrt();
+    let mut outputs = Vec::with_capacity(batch);
+    for (i, table) in block_tables.iter().enumerate() {
+        if table.num_tokens() == 0 {
+            bail!("the sequence {i} has an empty context")
+        }
+        let (k, v) = cache.gather(table)?;
+        // (num_kv_heads, num_tokens, head_dim)
+        let k = k.transpose(0, 1)?.contiguous()?;
+        let v = v.transpose(0, 1)?.contiguous()?;
+        // The query heads sharing a KV head attend together.
+        let q = q.i(i)?.reshape((num_kv_heads, group, head_dim))?;
+        let weights = (q.matmul(&k.t()?)? * scale)?;
+        let output = softmax_last_dim(&weights)?.matmul(&v)?;
+        outputs.push(output.reshape((num_heads, head_dim))?);
+    }
+    Tensor::stack(&outputs, 0)
+}
+
+/// Causal attention of a chunk of prompt tokens over the keys and values of its block table.
+///
+/// `q` has shape `(len, num_heads, head_dim)` and holds the queries of the positions
+/// `num_tokens - len..num_tokens` of the sequence, whose keys and values are already written.
+/// Each query attends to its own position and the ones before it. Returns a tensor of the shape
+/// of `q`.
+pub fn paged_prefill_attention(
+    q: &Tensor,
+    cache: &PagedKvCache,
+    table: &BlockTable,
+    num_tokens: usize,
+) -> Result<Tensor> {
+    let (len, num_heads, head_dim) = q.dims3()?;
+    let num_kv_heads = cache.num_kv_heads();
+    if len == 0 || len > num_tokens {
+        bail!("{len} queries for a context of {num_tokens} tokens")
+    }
+    if head_dim != cache.head_dim() || num_heads % num_kv_heads != 0 {
+        bail!(
+            "{num_heads} query heads of size {head_dim} do not fit {num_kv_heads} KV heads of size {}",
+            cache.head_dim()
+        )
+    }
+    let group = num_heads / num_kv_heads;
+    let scale = 1.0 / (head_dim as f64).sqrt();
+    let (k, v) = cache.gather_prefix(table, num_tokens)?;
+    // (num_kv_heads, num_tokens, head_dim)
+    let k = k.transpose(0, 1)?.contiguous()?;
+    let v = v.transpose(0, 1)?.contiguous()?;
+    // (num_kv_heads, group * len, head_dim), the query heads sharing a KV head attend together.
+    let q = q
+        .reshape((len, num_kv_heads, group, head_dim))?
+        .permute((1, 2, 0, 3))?
+        .reshape((num_kv_heads, group * len, head_dim))?;
+    let weights = (q.matmul(&k.t()?)? * scale)?;
+    let start = num_tokens - len;
+    let mask: Vec<f32> = (0..group * len)
+        .flat_map(|row| {
+            let position = start + row % len;
+            (0..num_tokens).map(move |j| if j > position { f32::NEG_INFINITY } else { 0.0 })
+        })
+        .collect();
+    let mask = Tensor::from_vec(mask, (1, group * len, num_tokens), q.device())?;
+    let weights = weights.broadcast_add(&mask.to_dtype(weights.dtype())?)?;
+    let output = softmax_last_dim(&weights)?.matmul(&v)?;
+    output
+        .reshape((num_kv_heads, group, len, head_dim))?
+        .permute((2, 0, 1, 3))?
+        .reshape((len, num_heads, head_dim))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*, crate::BlockManager, all_close::TensorAllClose,
+        scaled_dot_product_attention::scaled_dot_product_attention,
+    };
+
+    const BLOCK_SIZE: usize = 4;
+    const NUM_KV_HEADS: usize = 2;
+    const HEAD_DIM: usize = 8;
+
+    /// Contiguous keys and values of `len` tokens, each of shape `(len, NUM_KV_HEADS, HEAD_DIM)`.
+    fn kv(len: usize, device: &Device) -> Result<(Tensor, Tensor)> {
+        let shape = (len, NUM_KV_HEADS, HEAD_DIM);
+        Ok((
+            Tensor::randn(0f32, 1.0, shape, device)?,
+            Tensor::randn(0f32, 1.0, shape, device)?,
+        ))
+    }
+
+    /// Attention of the queries `(num_heads, head_dim)` of a sequence over its contiguous keys
+    /// and values, with the KV heads repeated for every query head of their group.
+    fn expected(q: &Tensor, k: &Tensor, v: &Tensor) -> Result<Tensor> {
+        let num_heads = q.dim(0)?;
+        let repeat = |x: &Tensor| -> Result<Tensor> {
+            let (len, num_kv_heads, head_dim) = x.dims3()?;
+            x.transpose(0, 1)?
+                .unsqueeze(1)?
+                .expand((num_kv_heads, num_heads / num_kv_heads, len, head_dim))?
+                .reshape((num_heads, len, head_dim))
+        };
+        let output = scaled_dot_product_attention(&q.unsqueeze(1)?, &repeat(k)?, &repeat(v)?)?;
+        output.squeeze(1)
+    }
+
+    fn check(num_heads: usize, lens: &[usize]) -> Result<()> {
+        let device = &Device::Cpu;
+        let mut manager = BlockManager::new(16, BLOCK_SIZE);
+        let mut cache =
+            PagedKvCache::new(16, BLOCK_SIZE, NUM_KV_HEADS, HEAD_DIM, DType::F32, device)?;
+        let mut contexts = Vec::new();
+        // The sequences grow in turns so that their blocks interleave in the pool.
+        for (seq, &len) in lens.iter().enumerate() {
+            manager.allocate(seq as u64, 0).unwrap();
+            contexts.push(kv(len, device)?);
+        }
+        for start in (0..*lens.iter().max().unwrap()).step_by(3) {
+            for (seq, &len) in lens.iter().enumerate() {
+                if start >= len {
+                    continue;
+                }
+                let count = (len - start).min(3);
+                let slots = manager.append_slots(seq as u64, count).unwrap().slots;
+                let (k, v) = &contexts[seq];
+                cache.write(
+                    &slots,
+                    &k.narrow(0, start, count)?,
+                    &v.narrow(0, start, count)?,
+                )?;
+            }
+        }
+
+        let q = Tensor::randn(0f32, 1.0, (lens.len(), num_heads, HEAD_DIM), device)?;
+        let tables: Vec<_> = (0..lens.len())
+            .map(|seq| manager.block_table(seq as u64).unwrap())
+            .collect();
+        let output = paged_attention(&q, &cache, &tables)?;
+        assert_eq!(output.dims(), q.dims());
+        for (seq, (k, v)) in contexts.iter().enumerate() {
+            let expected = expected(&q.i(seq)?, k, v)?;
+            assert!(output.i(seq)?.all_close(&expected, 1e-5)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn paged_attention_matches_contiguous_attention() -> Result<()> {
+        check(NUM_KV_HEADS, &[5, 1, 12])
+    }
+
+    #[test]
+    fn paged_attention_shares_kv_heads_across_query_groups() -> Result<()> {
+        check(3 * NUM_KV_HEADS, &[9, 4, 13, 2])
+    }
+
+    #[test]
+    fn forks_attend_over_the_shared_prompt_and_their_own_tokens() -> Result<()> {
+        let device = &Device::Cpu;
+        let mut manager = BlockManager::new(8, BLOCK_SIZE);
+        let mut cache =
+            PagedKvCache::new(8, BLOCK_SIZE, NUM_KV_HEADS, HEAD_DIM, DType::F32, device)?;
+        let (prompt_k, prompt_v) = kv(6, device)?;
+        manager.allocate(0, 0).unwrap();
+        let slots = manager.append_slots(0, 6).unwrap().slots;
+        cache.write(&slots, &prompt_k, &prompt_v)?;
+        manager.fork(0, 1).unwrap();
+
+        let mut contexts = Vec::new();
+        for seq in [0, 1] {
+            let (k, v) = kv(3, device)?;
+            let appended = manager.append_slots(seq, 3).unwrap();
+            if let Some(copy) = appended.copy {
+                cache.copy_block(copy)?;
+            }
+            cache.write(&appended.slots, &k, &v)?;
+            contexts.push((
+                Tensor::cat(&[&prompt_k, &k], 0)?,
+                Tensor::cat(&[&prompt_v, &v], 0)?,
+            ));
+        }
+
+        let q = Tensor::randn(0f32, 1.0, (2, NUM_KV_HEADS, HEAD_DIM), device)?;
+        let tables = [
+            manager.block_table(0).unwrap(),
+            manager.block_table(1).unwrap(),
+        ];
+        let output = paged_attention(&q, &cache, &tables)?;
+        for (seq, (k, v)) in contexts.iter().enumerate() {
+            let expected = expected(&q.i(seq)?, k, v)?;
+            assert!(output.i(seq)?.all_close(&expected, 1e-5)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn prefill_attention_only_sees_earlier_positions() -> Result<()> {
+        let device = &Device::Cpu;
+        let num_heads = 2 * NUM_KV_HEADS;
+        let mut manager = BlockManager::new(4, BLOCK_SIZE);
+        let mut cache =
+            PagedKvCache::new(4, BLOCK_SIZE, NUM_KV_HEADS, HEAD_DIM, DType::F32, device)?;
+        // Slots for the whole prompt, of which the first 7 tokens are written.
+        manager.allocate(0, 10).unwrap();
+        let table = manager.block_table(0).unwrap();
+        let (k, v) = kv(7, device)?;
+        let slots: Vec<_> = (0..7)
+            .map(|position| table.slot(position, BLOCK_SIZE))
+            .collect();
+        cache.write(&slots, &k, &v)?;
+
+        let q = Tensor::randn(0f32, 1.0, (3, num_heads, HEAD_DIM), device)?;
+        let output = paged_prefill_attention(&q, &cache, table, 7)?;
+        assert_eq!(output.dims(), q.dims());
+        for i in 0..3 {
+            let context = 4 + i + 1;
+            let expected = expected(
+                &q.i(i)?,
+                &k.narrow(0, 0, context)?,
+                &v.narrow(0, 0, context)?,
+            )?;
+            assert!(output.i(i)?.all_close(&expected, 1e-5)?);
+        }
+        assert!(paged_prefill_attention(&q, &cache, table, 11).is_err());
+        assert!(paged_prefill_attention(&q, &cache, table, 2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn quantized_cache_gathers_close_to_the_written_values() -> Result<()> {
+        let device = &Device::Cpu;
+        for (quantization, tolerance) in
+            [(KvQuantization::Int8, 2e-2), (KvQuantization::Int4, 3e-1)]
+        {
+            let mut manager = BlockManager::new(4, BLOCK_SIZE);
+            let mut cache = PagedKvCache::quantized(
+                4,
+                BLOCK_SIZE,
+                NUM_KV_HEADS,
+                HEAD_DIM,
+                quantization,
+                DType::F32,
+                device,
+            )?;
+            let (k, v) = kv(6, device)?;
+            manager.allocate(0, 0).unwrap();
+            let slots = manager.append_slots(0, 6).unwrap().slots;
+            cache.write(&slots, &k, &v)?;
+            // The copy of the shared last block keeps the scales of its rows.
+            manager.fork(0, 1).unwrap();
+            let appended = manager.append_slots(1, 1).unwrap();
+            cache.copy_block(appended.copy.unwrap())?;
+            let (one_k, one_v) = kv(1, device)?;
+            cache.write(&appended.slots, &one_k, &one_v)?;
+
+            let (gathered_k, gathered_v) = cache.gather(manager.block_table(1).unwrap())?;
+            assert_eq!(gathered_k.dims(), [7, NUM_KV_HEADS, HEAD_DIM]);
+            assert!(gathered_k.all_close(&Tensor::cat(&[&k, &one_k], 0)?, tolerance)?);
+            assert!(gathered_v.all_close(&Tensor::cat(&[&v, &one_v], 0)?, tolerance)?);
+        }
+        assert!(
+            PagedKvCache::quantized(
+                1,
+                BLOCK_SIZE,
+                1,
+                3,
+                KvQuantization::Int4,
+                DType::F32,
+                device
+            )
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_inputs_are_rejected() -> Result<()> {
+        let device = &Device::Cpu;
+        let mut manager = BlockManager::new(2, BLOCK_SIZE);
+        let mut cache =
+            PagedKvCache::new(2, BLOCK_SIZE, NUM_KV_HEADS, HEAD_DIM, DType::F32, device)?;
+        let (k, v) = kv(2, device)?;
+        assert!(cache.write(&[0], &k, &v).is_err());
+
+        manager.allocate(0, 0).unwrap();
+        let table = manager.block_table(0).unwrap();
+        let q = Tensor::zeros((1, 3, HEAD_DIM), DType::F32, device)?;
+        assert!(paged_attention(&q, &cache, &[table]).is_err());
+        let q = Tensor::zeros((1, 2, HEAD_DIM), DType::F32, device)?;
+        assert!(paged_attention(&q, &cache, &[table]).is_err());
+        assert!(paged_attention(&q, &cache, &[]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+use {
+    crate::prefix_cache::{PrefixCache, PrefixCacheStats},
+    std::{collections::HashMap, fmt},
+};
+
+/// Id of a sequence, chosen by the caller.
+pub type SequenceId = u64;
+
+/// 块分配错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The pool does not have `needed` free blocks, nothing was allocated.
+    OutOfBlocks { needed: usize, free: usize },
+    /// The sequence already has a block table.
+    SequenceExists(SequenceId),
+    /// The sequence has no block table.
+    UnknownSequence(SequenceId),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfBlocks { needed, free } => {
+                write!(f, "out of KV blocks: {needed} needed, {free} free")
+            }
+            Self::SequenceExists(id) => write!(f, "the sequence {id} is already allocated"),
+            Self::UnknownSequence(id) => write!(f, "the sequence {id} is not allocated"),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// Copy of the tokens of block `src` to block `dst`, made before a sequence writes to a block it
+/// shares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockCopy {
+    pub src: usize,
+    pub dst: usize,
+}
+
+/// Slots made by [`BlockManager::append_slots`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Appended {
+    pub slots: Vec<usize>,
+    /// Block to copy before writing to the slots, when the last block of the sequence was
+    /// shared.
+    pub copy: Option<BlockCopy>,
+}
+
+/// 块表, the blocks holding the tokens of a sequence in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockTable {
+    blocks: Vec<usize>,
+    num_tokens: usize,
+}
+
+impl BlockTable {
+    pub fn blocks(&self) -> &[usize] {
+        &self.blocks
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        self.num_tokens
+    }
+
+    /// Slot of the token at `position`, its index in the pool seen as `num_blocks * block_size`
+    /// tokens.
+    pub fn slot(&self, position: usize, block_size: usize) -> usize {
+        self.blocks[position / block_size] * block_size + position % block_size
+    }
+}
+
+/// 块管理器
+///
+/// Hands out the blocks of a pool of `num_blocks` blocks of `block_size` tokens to sequences.
+/// Blocks are reference counted and return to the free list when their last sequence frees them.
+///
+/// A [forked](Self::fork) sequence shares the blocks of its parent, the parallel completions of a
+/// prompt or the branches of a beam search only hold their prompt once. Shared blocks are copied
+/// on write: the full ones are never written again, and a sequence appending to a partly filled
+/// shared block first gets its own copy.
+///
+/// With [prefix caching](Self::with_prefix_cache) the full blocks of the prompts stay cached once
+/// their sequences are freed, and a prompt starting with cached blocks shares them instead of
+/// computing them again. The cache holds a reference to its blocks, those only it references are
+/// unloaded, least recently used first, when the free blocks run out.
+#[derive(Debug, Clone)]
+pub struct BlockManager {
+    block_size: usize,
+    ref_counts: Vec<usize>,
+    // Freed blocks are reused first, while their memory is likely cached.
+    free: Vec<usize>,
+    tables: HashMap<SequenceId, BlockTable>,
+    prefix_cache: Option<PrefixCache>,
+}
+
+impl BlockManager {
+    pub fn new(num_blocks: usize, block_size: usize) -> Self {
+        assert!(block_size > 0, "block_size must be positive");
+        Self {
+            block_size,
+            ref_counts: vec![0; num_blocks],
+            free: (0..num_blocks).rev().collect(),
+            tables: HashMap::new(),
+            prefix_cache: None,
+        }
+    }
+
+    /// Cache the full blocks of the prompts, see [`Self::allocate_prompt`].
+    pub fn with_prefix_cache(mut self) -> Self {
+        self.prefix_cache = Some(PrefixCache::default());
+        self
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.ref_counts.len()
+    }
+
+    pub fn num_free_blocks(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Free blocks and cached blocks no sequence uses, which are unloaded when needed.
+    pub fn num_available_blocks(&self) -> usize {
+        self.free.len() + self.num_evictable_blocks()
+    }
+
+    fn num_evictable_blocks(&self) -> usize {
+        self.prefix_cache.as_ref().map_or(0, |cache| {
+            cache
+                .blocks()
+                .filter(|&block| self.ref_counts[block] == 1)
+                .count()
+        })
+    }
+
+    pub fn prefix_cache_stats(&self) -> Option<PrefixCacheStats> {
+        self.prefix_cache.as_ref().map(PrefixCache::stats)
+    }
+
+    /// Number of sequences referencing `block`, 0 for a free block.
+    pub fn ref_count(&self, block: usize) -> usize {
+        self.ref_counts[block]
+    }
+
+    /// Number of blocks holding `num_tokens` tokens.
+    pub fn blocks_for(&self, num_tokens: usize) -> usize {
+        num_tokens.div_ceil(self.block_size)
+    }
+
+    pub fn block_table(&self, seq: SequenceId) -> Option<&BlockTable> {
+        self.tables.get(&seq)
+    }
+
+    /// Whether a new sequence of `num_tokens` tokens fits in the available blocks.
+    pub fn can_allocate(&self, num_tokens: usize) -> bool {
+        self.blocks_for(num_tokens) <= self.num_available_blocks()
+    }
+
+    /// Block table of a new sequence holding `num_tokens` tokens.
+    pub fn allocate(
+        &mut self,
+        seq: SequenceId,
+        num_tokens: usize,
+    ) -> Result<&BlockTable, BlockError> {
+        if self.tables.contains_key(&seq) {
+            return Err(BlockError::SequenceExists(seq));
+        }
+        let blocks = self.take(self.blocks_for(num_tokens))?;
+        let table = BlockTable { blocks, num_tokens };
+        Ok(self.tables.entry(seq).or_insert(table))
+    }
+
+    /// Block table of a new sequence holding `tokens`, starting with the longest prefix of
+    /// cached blocks. Returns the number of tokens found in the cache, whose keys and values are
+    /// already stored, the others are left to compute. Without a prefix cache, nothing is found.
+    pub fn allocate_prompt(
+        &mut self,
+        seq: SequenceId,
+        tokens: &[u32],
+    ) -> Result<usize, BlockError> {
+        if self.tables.contains_key(&seq) {
+            return Err(BlockError::SequenceExists(seq));
+        }
+        let block_size = self.block_size;
+        let mut blocks = match self.prefix_cache.as_mut() {
+            Some(cache) => cache.lookup(tokens, block_size),
+            None => Vec::new(),
+        };
+        // Referenced first, so that making room for the rest does not unload them.
+        for &block in blocks.iter() {
+            self.ref_counts[block] += 1;
+        }
+        let cached = blocks.len() * block_size;
+        match self.take(self.blocks_for(tokens.len()) - blocks.len()) {
+            Ok(rest) => blocks.extend(rest),
+            Err(err) => {
+                for block in blocks {
+                    self.release(block);
+                }
+                return Err(err);
+            }
+        }
+        let table = BlockTable {
+            blocks,
+            num_tokens: tokens.len(),
+        };
+        self.tables.insert(seq, table);
+        Ok(cached)
+    }
+
+    /// Add the full blocks of `seq` to the prefix cache once their keys and values are stored,
+    /// `tokens` being the tokens of the sequence.
+    pub fn cache_prefix(&mut self, seq: SequenceId, tokens: &[u32]) -> Result<(), BlockError> {
+        let table = self
+            .tables
+            .get(&seq)
+            .ok_or(BlockError::UnknownSequence(seq))?;
+        let Some(cache) = self.prefix_cache.as_mut() else {
+            return Ok(());
+        };
+        let tokens = &tokens[..tokens.len().min(table.num_tokens)];
+        for block in cache.insert(&table.blocks, tokens, self.block_size) {
+            self.ref_counts[block] += 1;
+        }
+        Ok(())
+    }
+
+    /// Block table of `child`, sharing all the blocks of `seq`.
+    pub fn fork(&mut self, seq: SequenceId, child: SequenceId) -> Result<&BlockTable, BlockError> {
+        if self.tables.contains_key(&child) {
+            return Err(BlockError::SequenceExists(child));
+        }
+        let table = self
+            .tables
+            .get(&seq)
+            .ok_or(BlockError::UnknownSequence(seq))?
+            .clone();
+        for &block in table.blocks.iter() {
+            self.ref_counts[block] += 1;
+        }
+        Ok(self.tables.entry(child).or_insert(table))
+    }
+
+    /// Make room for `num_tokens` more tokens of `seq`, returning their slots and the copy to
+    /// make first when the last block of `seq` is shared. On [`BlockError::OutOfBlocks`] the
+    /// sequence is left as it was.
+    pub fn append_slots(
+        &mut self,
+        seq: SequenceId,
+        num_tokens: usize,
+    ) -> Result<Appended, BlockError> {
+        let table = self
+            .tables
+            .get(&seq)
+            .ok_or(BlockError::UnknownSequence(seq))?;
+        let start = table.num_tokens;
+        // The next token goes to a partly filled block that other sequences see.
+        let shared_last = match table.blocks.last() {
+            Some(&last) if num_tokens > 0 && !start.is_multiple_of(self.block_size) => {
+                (self.ref_counts[last] > 1).then_some((table.blocks.len() - 1, last))
+            }
+            _ => None,
+        };
+        let needed = self.blocks_for(start + num_tokens) - table.blocks.len();
+        let mut blocks = self.take(needed + usize::from(shared_last.is_some()))?;
+        let copy = shared_last.map(|(index, src)| {
+            let dst = blocks.remove(0);
+            self.ref_counts[src] -= 1;
+            let table = self.tables.get_mut(&seq).expect("the table was just found");
+            table.blocks[index] = dst;
+            BlockCopy { src, dst }
+        });
+        let table = self.tables.get_mut(&seq).expect("the table was just found");
+        table.blocks.extend(blocks);
+        table.num_tokens += num_tokens;
+        let slots = (start..table.num_tokens)
+            .map(|position| table.slot(position, self.block_size))
+            .collect();
+        Ok(Appended { slots, copy })
+    }
+
+    /// Release the blocks of `seq`, the blocks no other sequence references become free.
+    pub fn free(&mut self, seq: SequenceId) -> Result<(), BlockError> {
+        let table = self
+            .tables
+            .remove(&seq)
+            .ok_or(BlockError::UnknownSequence(seq))?;
+        for block in table.blocks {
+            self.release(block);
+        }
+        Ok(())
+    }
+
+    /// Take `count` free blocks, all or none, unloading cached blocks if needed.
+    fn take(&mut self, count: usize) -> Result<Vec<usize>, BlockError> {
+        if count > self.free.len() {
+            let available = self.num_available_blocks();
+            if count > available {
+                return Err(BlockError::OutOfBlocks {
+                    needed: count,
+                    free: available,
+                });
+            }
+            let cache = self
+                .prefix_cache
+                .as_mut()
+                .expect("cached blocks are available");
+            while self.free.len() < count {
+                let ref_counts = &self.ref_counts;
+                let block = cache
+                    .evict(|block| ref_counts[block] == 1)
+                    .expect("the cache has evictable blocks");
+                self.ref_counts[block] = 0;
+                self.free.push(block);
+            }
+        }
+        let blocks = self.free.split_off(self.free.len() - count);
+        let blocks: Vec<_> = blocks.into_iter().rev().collect();
+        for &block in blocks.iter() {
+            self.ref_counts[block] = 1;
+        }
+        Ok(blocks)
+    }
+
+    fn release(&mut self, block: usize) {
+        let count = &mut self.ref_counts[block];
+        debug_assert!(*count > 0, "block {block} is already free");
+        *count -= 1;
+        if *count == 0 {
+            self.free.push(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequences_take_the_blocks_their_tokens_fill() {
+        let mut manager = BlockManager::new(4, 4);
+        let table = manager.allocate(1, 5).unwrap();
+        assert_eq!(table.blocks(), [0, 1]);
+        assert_eq!(table.num_tokens(), 5);
+        assert_eq!(manager.num_free_blocks(), 2);
+
+        // Three more tokens fill the second block, the fourth starts a new one.
+        assert_eq!(manager.append_slots(1, 3).unwrap().slots, [5, 6, 7]);
+        assert_eq!(manager.num_free_blocks(), 2);
+        assert_eq!(manager.append_slots(1, 1).unwrap().slots, [8]);
+        assert_eq!(manager.block_table(1).unwrap().blocks(), [0, 1, 2]);
+        assert_eq!(manager.ref_count(2), 1);
+        assert_eq!(
+            manager.allocate(1, 1).unwrap_err(),
+            BlockError::SequenceExists(1)
+        );
+    }
+
+    #[test]
+    fn running_out_of_blocks_allocates_nothing() {
+        let mut manager = BlockManager::new(3, 2);
+        manager.allocate(1, 4).unwrap();
+        assert!(!manager.can_allocate(3));
+        assert_eq!(
+            manager.allocate(2, 3).unwrap_err(),
+            BlockError::OutOfBlocks { needed: 2, free: 1 }
+        );
+        assert!(manager.block_table(2).is_none());
+        assert_eq!(
+            manager.append_slots(1, 3).unwrap_err(),
+            BlockError::OutOfBlocks { needed: 2, free: 1 }
+        );
+        assert_eq!(manager.block_table(1).unwrap().num_tokens(), 4);
+        assert_eq!(manager.num_free_blocks(), 1);
+    }
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let mut manager = BlockManager::new(4, 2);
+        manager.allocate(1, 4).unwrap();
+        manager.allocate(2, 2).unwrap();
+        manager.free(1).unwrap();
+        assert_eq!(manager.num_free_blocks(), 3);
+        assert_eq!(manager.ref_count(0), 0);
+        assert_eq!(manager.free(1).unwrap_err(), BlockError::UnknownSequence(1));
+        let table = manager.allocate(3, 6).unwrap();
+        assert_eq!(table.blocks(), [1, 0, 3]);
+        assert_eq!(manager.num_free_blocks(), 0);
+    }
+
+    #[test]
+    fn forks_share_blocks_until_they_write_to_them() {
+        let mut manager = BlockManager::new(6, 4);
+        manager.allocate(1, 6).unwrap();
+        assert_eq!(manager.fork(1, 2).unwrap().blocks(), [0, 1]);
+        manager.fork(1, 3).unwrap();
+        assert_eq!(manager.ref_count(0), 3);
+        assert_eq!(manager.num_free_blocks(), 4);
+        assert_eq!(
+            manager.fork(1, 2).unwrap_err(),
+            BlockError::SequenceExists(2)
+        );
+
+        // The partly filled block is copied before the fork writes to it, the full one stays
+        // shared.
+        let appended = manager.append_slots(2, 3).unwrap();
+        assert_eq!(appended.copy, Some(BlockCopy { src: 1, dst: 2 }));
+        assert_eq!(appended.slots, [10, 11, 12]);
+        assert_eq!(manager.block_table(2).unwrap().blocks(), [0, 2, 3]);
+        assert_eq!(manager.ref_count(0), 3);
+        assert_eq!(manager.ref_count(1), 2);
+
+        manager.free(3).unwrap();
+        // The parent is the last one to use its block, it writes to it in place.
+        let appended = manager.append_slots(1, 1).unwrap();
+        assert_eq!(appended.copy, None);
+        assert_eq!(appended.slots, [6]);
+
+        manager.free(1).unwrap();
+        manager.free(2).unwrap();
+        assert_eq!(manager.num_free_blocks(), 6);
+    }
+
+    #[test]
+    fn copies_count_against_the_free_blocks() {
+        let mut manager = BlockManager::new(2, 4);
+        manager.allocate(1, 2).unwrap();
+        manager.fork(1, 2).unwrap();
+        manager.append_slots(2, 1).unwrap();
+        assert_eq!(
+            manager.append_slots(1, 3).unwrap_err(),
+            BlockError::OutOfBlocks { needed: 1, free: 0 }
+        );
+        assert_eq!(manager.block_table(1).unwrap().num_tokens(), 2);
+        // Without a copy the parent writes to its own block.
+        assert_eq!(manager.append_slots(1, 2).unwrap().slots, [2, 3]);
+    }
+}
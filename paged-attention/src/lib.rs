@@ -1,14 +1,19 @@
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
+//! Paged KV cache, see <https://arxiv.org/abs/2309.06180>.
+//!
+//! The keys and values of all sequences live in a fixed pool of blocks of `block_size` tokens.
+//! Each sequence maps its positions to blocks through its block table, so a sequence only holds
+//! the blocks its tokens fill instead of a buffer sized for the longest sequence.
+//!
+//! [`BlockManager`] hands out the blocks, [`PagedKvCache`] stores their keys and values,
+//! [`paged_attention`] attends over them through the block tables for decoding and
+//! [`paged_prefill_attention`] for the prompt chunks. The block manager can also keep the full
+//! blocks of the prompts in a prefix cache, so that prompts sharing a system prompt or few-shot
+//! examples skip the prefill of the shared part.
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+mod attention;
+mod block_manager;
+mod prefix_cache;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+pub use attention::{PagedKvCache, paged_attention, paged_prefill_attention};
+pub use block_manager::{Appended, BlockCopy, BlockError, BlockManager, BlockTable, SequenceId};
+pub use prefix_cache::PrefixCacheStats;
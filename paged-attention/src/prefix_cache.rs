@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+/// 前缀缓存统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefixCacheStats {
+    /// Prompts looked up.
+    pub lookups: u64,
+    /// Prompts that found at least one cached block.
+    pub hits: u64,
+    /// Tokens of the prompts looked up.
+    pub query_tokens: u64,
+    /// Tokens of the prompts found in the cache.
+    pub hit_tokens: u64,
+    /// Blocks unloaded from the cache to make room for new ones.
+    pub evictions: u64,
+    /// Blocks in the cache.
+    pub cached_blocks: usize,
+}
+
+impl PrefixCacheStats {
+    /// Share of the prompt tokens found in the cache.
+    pub fn hit_rate(&self) -> f64 {
+        if self.query_tokens == 0 {
+            0.0
+        } else {
+            self.hit_tokens as f64 / self.query_tokens as f64
+        }
+    }
+}
+
+/// A full block in the tree, keyed in its parent by its tokens.
+#[derive(Debug, Clone)]
+struct Node {
+    parent: Option<usize>,
+    tokens: Vec<u32>,
+    children: HashMap<Vec<u32>, usize>,
+    // Tick of the last lookup or insertion through the node.
+    used: u64,
+}
+
+/// Radix tree of full blocks: the path from a root to a node spells the tokens of the prefix
+/// held by the blocks along it. Nodes are indexed by their block.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PrefixCache {
+    roots: HashMap<Vec<u32>, usize>,
+    nodes: HashMap<usize, Node>,
+    tick: u64,
+    stats: PrefixCacheStats,
+}
+
+impl PrefixCache {
+    pub(crate) fn stats(&self) -> PrefixCacheStats {
+        PrefixCacheStats {
+            cached_blocks: self.nodes.len(),
+            ..self.stats
+        }
+    }
+
+    pub(crate) fn blocks(&self) -> impl Iterator<Item = usize> + '_ {
+        self.nodes.keys().copied()
+    }
+
+    fn child(&self, parent: Option<usize>, tokens: &[u32]) -> Option<usize> {
+        match parent {
+            None => self.roots.get(tokens).copied(),
+            Some(parent) => self.nodes[&parent].children.get(tokens).copied(),
+        }
+    }
+
+    /// Blocks of the longest cached prefix of `tokens` made of whole blocks, leaving at least the
+    /// last token out so that it is computed again.
+    pub(crate) fn lookup(&mut self, tokens: &[u32], block_size: usize) -> Vec<usize> {
+        self.tick += 1;
+        let mut blocks = Vec::new();
+        let mut parent = None;
+        for chunk in tokens[..tokens.len().saturating_sub(1)].chunks_exact(block_size) {
+            let Some(block) = self.child(parent, chunk) else {
+                break;
+            };
+            self.nodes.get_mut(&block).expect("children are nodes").used = self.tick;
+            blocks.push(block);
+            parent = Some(block);
+        }
+        self.stats.lookups += 1;
+        self.stats.query_tokens += tokens.len() as u64;
+        if !blocks.is_empty() {
+            self.stats.hits += 1;
+            self.stats.hit_tokens += (blocks.len() * block_size) as u64;
+        }
+        blocks
+    }
+
+    /// Cache the full blocks of a sequence holding `tokens`, returning the blocks added.
+    ///
+    /// Insertion stops at a block whose tokens are already cached in another block, so that the
+    /// sequences using a cached block always use its ancestors as well.
+    pub(crate) fn insert(
+        &mut self,
+        blocks: &[usize],
+        tokens: &[u32],
+        block_size: usize,
+    ) -> Vec<usize> {
+        self.tick += 1;
+        let mut added = Vec::new();
+        let mut parent = None;
+        for (&block, chunk) in blocks.iter().zip(tokens.chunks_exact(block_size)) {
+            match self.child(parent, chunk) {
+                Some(cached) if cached == block => {}
+                Some(_) => break,
+                None if self.nodes.contains_key(&block) => break,
+                None => {
+                    let node = Node {
+                        parent,
+                        tokens: chunk.to_vec(),
+                        children: HashMap::new(),
+                        used: self.tick,
+                    };
+                    self.nodes.insert(block, node);
+                    match parent {
+                        None => self.roots.insert(chunk.to_vec(), block),
+                        Some(parent) => self
+                            .nodes
+                            .get_mut(&parent)
+                            .expect("parents are nodes")
+                            .children
+                            .insert(chunk.to_vec(), block),
+                    };
+                    added.push(block);
+                }
+            }
+            self.nodes
+                .get_mut(&block)
+                .expect("the block is cached")
+                .used = self.tick;
+            parent = Some(block);
+        }
+        added
+    }
+
+    /// Remove the least recently used leaf whose block `evictable` accepts, returning its block.
+    pub(crate) fn evict(&mut self, evictable: impl Fn(usize) -> bool) -> Option<usize> {
+        let block = self
+            .nodes
+            .iter()
+            .filter(|(block, node)| node.children.is_empty() && evictable(**block))
+            .min_by_key(|(_, node)| node.used)
+            .map(|(&block, _)| block)?;
+        let node = self.nodes.remove(&block).expect("the leaf is a node");
+        match node.parent {
+            None => self.roots.remove(&node.tokens),
+            Some(parent) => self
+                .nodes
+                .get_mut(&parent)
+                .expect("parents are nodes")
+                .children
+                .remove(&node.tokens),
+        };
+        self.stats.evictions += 1;
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BlockError, BlockManager};
+
+    #[test]
+    fn prompts_share_the_cached_blocks_of_their_prefix() {
+        let mut manager = BlockManager::new(8, 4).with_prefix_cache();
+        let system: Vec<u32> = (0..8).collect();
+        let first = [system.as_slice(), &[100, 101]].concat();
+        assert_eq!(manager.allocate_prompt(1, &first).unwrap(), 0);
+        manager.cache_prefix(1, &first).unwrap();
+        manager.free(1).unwrap();
+        // The full blocks stay cached, the last one is free.
+        assert_eq!(manager.num_free_blocks(), 6);
+        assert_eq!(manager.num_available_blocks(), 8);
+
+        let second = [system.as_slice(), &[200]].concat();
+        assert_eq!(manager.allocate_prompt(2, &second).unwrap(), 8);
+        let table = manager.block_table(2).unwrap();
+        assert_eq!(&table.blocks()[..2], [0, 1]);
+        assert_eq!(table.num_tokens(), 9);
+        assert_eq!(manager.ref_count(0), 2);
+        // The last token is computed again, even when the whole prompt is cached.
+        assert_eq!(manager.allocate_prompt(3, &system).unwrap(), 4);
+
+        let stats = manager.prefix_cache_stats().unwrap();
+        assert_eq!(stats.lookups, 3);
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.query_tokens, 10 + 9 + 8);
+        assert_eq!(stats.hit_tokens, 12);
+        assert_eq!(stats.cached_blocks, 2);
+        assert!((stats.hit_rate() - 12.0 / 27.0).abs() < 1e-9);
+        assert!(BlockManager::new(1, 4).prefix_cache_stats().is_none());
+    }
+
+    #[test]
+    fn least_recently_used_prefixes_are_unloaded_when_blocks_run_out() {
+        let mut manager = BlockManager::new(4, 2).with_prefix_cache();
+        for (seq, tokens) in [(1, [1, 2, 3, 4, 0]), (2, [5, 6, 7, 8, 0])] {
+            manager.allocate_prompt(seq, &tokens).unwrap();
+            manager.cache_prefix(seq, &tokens).unwrap();
+            manager.free(seq).unwrap();
+        }
+        // The cached blocks do not fit with the prompt, the oldest prefix goes first, leaf first.
+        manager.allocate_prompt(3, &[9, 10, 11, 12, 13]).unwrap();
+        let stats = manager.prefix_cache_stats().unwrap();
+        assert_eq!(stats.evictions, 3);
+        assert_eq!(stats.cached_blocks, 1);
+        assert_eq!(
+            manager.allocate_prompt(4, &[1, 2, 3]).unwrap_err(),
+            BlockError::OutOfBlocks { needed: 2, free: 1 }
+        );
+        manager.free(3).unwrap();
+        assert_eq!(manager.allocate_prompt(4, &[5, 6, 7]).unwrap(), 2);
+        assert_eq!(manager.prefix_cache_stats().unwrap().cached_blocks, 1);
+    }
+}
@@ -9,8 +9,6 @@ macro_rules! round_multiple_checked {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn round_multiple_checked_works() {
         assert_eq!(round_multiple_checked!(7, 4), 8); // 7 → 8 (4×2)
@@ -21,6 +19,6 @@ mod tests {
     #[test]
     #[should_panic(expected = "m must be positive")]
     fn round_multiple_checked_panic_with_zero_works() {
-        round_multiple_checked!(10, 0);
+        let _ = round_multiple_checked!(10, 0);
     }
 }